@@ -0,0 +1,1103 @@
+//! Analysis passes over the fragment graph of a `GrammarRust`
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::grammar::{Fragment, FragmentId, GenOptions, GrammarRust};
+use crate::scalars::RandomFloat;
+
+impl GrammarRust {
+    /// Determine which fragments can be reached by expanding from the start
+    /// fragment. Returns a vector indexed by fragment identifier.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.fragments.len()];
+
+        let mut queue = vec![self.start.unwrap()];
+        while let Some(fragment_id) = queue.pop() {
+            if !reachable[fragment_id.0] {
+                reachable[fragment_id.0] = true;
+                queue.extend_from_slice(
+                    self.fragments[fragment_id.0].children());
+            }
+        }
+
+        reachable
+    }
+
+    /// Determine whether expanding fragment `id` can capture, recall or push
+    /// values or emit message boundaries, which affect more than the
+    /// fragment's own output
+    pub fn reaches_state(&self, id: FragmentId) -> bool {
+        let mut seen = BTreeSet::new();
+        let mut queue = vec![id];
+        while let Some(id) = queue.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let fragment = &self.fragments[id.0];
+            if matches!(fragment, Fragment::Capture { .. } |
+                    Fragment::Recall { .. } | Fragment::Push(_) |
+                    Fragment::Pop | Fragment::Message(_)) {
+                return true;
+            }
+            queue.extend_from_slice(fragment.children());
+        }
+        false
+    }
+
+    /// Determine which fragments are recursive, that is, they are part of a
+    /// cycle in the fragment graph and can expand back into themselves. Only
+    /// recursive fragments need depth checks for generation to terminate.
+    /// Returns a vector indexed by fragment identifier.
+    pub fn recursive(&self) -> Vec<bool> {
+        // Tarjan's strongly connected components algorithm, done iteratively
+        // so huge grammars can't overflow our stack
+        let len = self.fragments.len();
+        let mut index = vec![usize::MAX; len];
+        let mut lowlink = vec![0; len];
+        let mut on_stack = vec![false; len];
+        let mut stack = Vec::new();
+        let mut recursive = vec![false; len];
+        let mut next_index = 0;
+
+        for root in 0..len {
+            if index[root] != usize::MAX {
+                continue;
+            }
+
+            // Stack of fragments being visited along with the index of the
+            // next child to visit
+            let mut work = vec![(root, 0)];
+            while let Some(&mut (node, ref mut child)) = work.last_mut() {
+                if *child == 0 {
+                    index[node] = next_index;
+                    lowlink[node] = next_index;
+                    next_index += 1;
+                    stack.push(node);
+                    on_stack[node] = true;
+                }
+
+                let children = self.fragments[node].children();
+                if let Some(next) = children.get(*child) {
+                    *child += 1;
+                    if index[next.0] == usize::MAX {
+                        work.push((next.0, 0));
+                    } else if on_stack[next.0] {
+                        lowlink[node] = lowlink[node].min(index[next.0]);
+                    }
+                    continue;
+                }
+
+                // All children visited, pop this node
+                work.pop();
+                if let Some(&(parent, _)) = work.last() {
+                    lowlink[parent] = lowlink[parent].min(lowlink[node]);
+                }
+
+                if lowlink[node] == index[node] {
+                    // `node` is the root of a component, pop it off
+                    let mut component = Vec::new();
+                    loop {
+                        let member = stack.pop().unwrap();
+                        on_stack[member] = false;
+                        component.push(member);
+                        if member == node {
+                            break;
+                        }
+                    }
+
+                    // Components with multiple fragments are cycles, single
+                    // fragments are only cycles if they refer to themselves
+                    let cyclic = component.len() > 1 ||
+                        children.iter().any(|x| x.0 == node);
+                    for member in component {
+                        recursive[member] = cyclic;
+                    }
+                }
+            }
+        }
+
+        recursive
+    }
+
+    /// Returns `true` if every one of `options` would be cut off by the
+    /// depth limit when expanded at `max_depth`. Only recursive fragments
+    /// are ever cut off, and nops have nothing to cut.
+    pub fn all_cut_off(&self, recursive: &[bool], options: &[FragmentId])
+            -> bool {
+        options.iter().all(|x| {
+            recursive[x.0] || matches!(self.fragments[x.0], Fragment::Nop)
+        })
+    }
+
+    /// Compute, for every fragment, the minimum depth budget required to
+    /// expand it completely without any part of it being cut off by the
+    /// depth limit. A fragment invoked at `depth` is fully expanded when
+    /// `depth + min_depth <= max_depth`. `None` indicates the fragment can
+    /// never be fully expanded, no matter the depth.
+    ///
+    /// Fragments with a single child don't consume any depth as the
+    /// optimizer replaces them with their child. This gives the same answer
+    /// before and after optimization. Fragments which aren't recursive are
+    /// never cut off themselves, so they only need depth if something they
+    /// expand to does.
+    pub fn min_depths(&self) -> Vec<Option<usize>> {
+        let recursive = self.recursive();
+        let mut depths: Vec<Option<usize>> = vec![None; self.fragments.len()];
+
+        // Iterate until we reach a fixed point. Depths only ever decrease
+        // from infinity so this is guaranteed to terminate.
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for (idx, fragment) in self.fragments.iter().enumerate() {
+                // Depth consumed by this fragment itself. Helpers are never
+                // replaced by their children.
+                let cost = match fragment {
+                    Fragment::Length(..) | Fragment::Chunked(_) |
+                        Fragment::Capture { .. } |
+                        Fragment::Recall { .. } | Fragment::Push(_) |
+                        Fragment::Transform(..) => 1,
+                    _ => (fragment.children().len() > 1) as usize,
+                };
+
+                // Depth needed by the sub-fragments
+                let children = match fragment {
+                    // Pick the cheapest option
+                    Fragment::NonTerminal(options) => {
+                        options.iter().filter_map(|x| depths[x.0]).min()
+                    }
+
+                    // Every sub-fragment must be expanded
+                    Fragment::Expression(_) | Fragment::Length(..) => {
+                        fragment.children().iter().try_fold(0, |acc, x| {
+                            depths[x.0].map(|x| acc.max(x))
+                        })
+                    }
+
+                    // Loops can only end by picking a finishing option
+                    Fragment::Loop { options, repeat } => {
+                        options.iter().zip(repeat)
+                            .filter(|(_, &repeat)| !repeat)
+                            .filter_map(|(x, _)| depths[x.0]).min()
+                    }
+
+                    Fragment::Chunked(body) | Fragment::Capture { body, .. } |
+                        Fragment::Recall { body, .. } |
+                        Fragment::Push(body) |
+                        Fragment::Transform(body, _) => depths[body.0],
+
+                    // Embeds start over with the whole depth budget, the
+                    // rules of the embedded grammar get warnings of their own
+                    Fragment::Embed(body) => depths[body.0].map(|_| 0),
+
+                    Fragment::Terminal(_) | Fragment::Message(_) |
+                        Fragment::Pop | Fragment::Int(_) |
+                        Fragment::Float(_) | Fragment::Bytes(_) |
+                        Fragment::Nop => Some(0),
+                };
+
+                let depth = children.map(|children| {
+                    let depth = if children > 0 { children + cost } else { 0 };
+                    depth.max(recursive[idx] as usize)
+                });
+
+                if depth.is_some() &&
+                        (depths[idx].is_none() || depth < depths[idx]) {
+                    depths[idx] = depth;
+                    changed = true;
+                }
+            }
+        }
+
+        depths
+    }
+
+    /// Compute, for every non-terminal, which of its options should be picked
+    /// from as the depth limit approaches. Options which can't be fully
+    /// expanded within the remaining depth would only be cut off, so they're
+    /// excluded as long as some other option still fits.
+    ///
+    /// Each non-terminal gets a list of `(need, options)` bands, ordered by
+    /// decreasing `need`. A non-terminal expanded at `depth` picks from the
+    /// `options` of the first band where `depth + need < max_depth`, and from
+    /// all of its options if there is no such band. Fragments which never
+    /// need to restrict their options get no bands. `depths` are the
+    /// fragments' `min_depths`.
+    pub fn depth_bands(&self, depths: &[Option<usize>])
+            -> Vec<Vec<(usize, Vec<usize>)>> {
+        self.fragments.iter().map(|fragment| {
+            let options = match fragment {
+                Fragment::NonTerminal(options) => options,
+                _ => return Vec::new(),
+            };
+
+            // Get the distinct needs of the options, largest first
+            let needs: BTreeSet<usize> =
+                options.iter().filter_map(|x| depths[x.0]).collect();
+            let mut bands: Vec<(usize, Vec<usize>)> = needs.iter().rev()
+                .map(|&need| {
+                    (need, (0..options.len()).filter(|&x| {
+                        depths[options[x].0].is_some_and(|x| x <= need)
+                    }).collect())
+                }).collect();
+
+            // Nothing is ever restricted if every option has the same need
+            if bands.len() == 1 && bands[0].1.len() == options.len() {
+                bands.clear();
+            }
+            bands
+        }).collect()
+    }
+
+    /// Compute the shortest output of every fragment, `None` for fragments
+    /// which can't terminate. Returns a vector indexed by fragment
+    /// identifier.
+    pub fn shortest_outputs(&self) -> Vec<Option<Vec<u8>>> {
+        // Find the length of the shortest output of each fragment along with
+        // the option of non-terminals and loops it comes from. Choices are
+        // only updated on a strict improvement, which keeps them from ever
+        // forming a cycle.
+        let mut lens: Vec<Option<usize>> = vec![None; self.fragments.len()];
+        let mut choices = vec![0; self.fragments.len()];
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for (idx, fragment) in self.fragments.iter().enumerate() {
+                let best = match fragment {
+                    Fragment::NonTerminal(options) => {
+                        options.iter().enumerate()
+                            .filter_map(|(ii, x)| lens[x.0].map(|x| (x, ii)))
+                            .min()
+                    }
+                    Fragment::Expression(expr) => {
+                        expr.iter().try_fold(0, |acc, x| {
+                            lens[x.0].map(|x| acc + x)
+                        }).map(|x| (x, 0))
+                    }
+                    Fragment::Loop { options, repeat } => {
+                        options.iter().zip(repeat).enumerate()
+                            .filter(|(_, (_, &repeat))| !repeat)
+                            .filter_map(|(ii, (x, _))| {
+                                lens[x.0].map(|x| (x, ii))
+                            }).min()
+                    }
+                    Fragment::Length([mid, body], encoding) => {
+                        lens[mid.0].zip(lens[body.0]).map(|(mid, body)| {
+                            let len = encoding.encode(body as u64).len();
+                            (mid + body + len, 0)
+                        })
+                    }
+                    Fragment::Chunked(body) => {
+                        lens[body.0].map(|x| (chunked_len(x), 0))
+                    }
+                    Fragment::Transform(body, transform) => {
+                        lens[body.0].map(|x| (transform.min_len(x), 0))
+                    }
+                    Fragment::Capture { body, .. } |
+                            Fragment::Recall { body, .. } |
+                            Fragment::Embed(body) | Fragment::Push(body) => {
+                        lens[body.0].map(|x| (x, 0))
+                    }
+                    Fragment::Terminal(value) | Fragment::Message(value) => {
+                        Some((value.len(), 0))
+                    }
+                    Fragment::Int(int) => {
+                        Some((int.encode(int.low).len(), 0))
+                    }
+                    Fragment::Float(float) => {
+                        Some((RandomFloat::encode(float.low()).len(), 0))
+                    }
+                    Fragment::Bytes(_) => Some((1, 0)),
+
+                    // The shortest outputs never push anything, so there's
+                    // nothing to pop
+                    Fragment::Pop | Fragment::Nop => Some((0, 0)),
+                };
+
+                if let Some((len, choice)) = best {
+                    if lens[idx].is_none_or(|x| len < x) {
+                        lens[idx] = Some(len);
+                        choices[idx] = choice;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        // Build the outputs by following the choices
+        (0..self.fragments.len()).map(|root| {
+            lens[root]?;
+            Some(self.build_shortest(FragmentId(root), &choices))
+        }).collect()
+    }
+
+    /// Build the shortest output of `root`, following the `choices` made by
+    /// `shortest_outputs`
+    fn build_shortest(&self, root: FragmentId, choices: &[usize]) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut stack = vec![root];
+        while let Some(id) = stack.pop() {
+            match &self.fragments[id.0] {
+                Fragment::NonTerminal(options) |
+                        Fragment::Loop { options, .. } => {
+                    stack.push(options[choices[id.0]]);
+                }
+                Fragment::Expression(expr) => {
+                    stack.extend(expr.iter().rev());
+                }
+                Fragment::Length([mid, body], encoding) => {
+                    // Helpers need the whole output of their children, so
+                    // build those on their own
+                    let mid = self.build_shortest(*mid, choices);
+                    let body = self.build_shortest(*body, choices);
+                    output.extend(encoding.encode(body.len() as u64));
+                    output.extend_from_slice(&mid);
+                    output.extend_from_slice(&body);
+                }
+                Fragment::Chunked(body) => {
+                    // The shortest encoding is a single chunk
+                    let body = self.build_shortest(*body, choices);
+                    if !body.is_empty() {
+                        output.extend_from_slice(
+                            format!("{:x}\r\n", body.len()).as_bytes());
+                        output.extend_from_slice(&body);
+                        output.extend_from_slice(b"\r\n");
+                    }
+                    output.extend_from_slice(b"0\r\n\r\n");
+                }
+                Fragment::Transform(body, transform) => {
+                    // Random case is left alone, which is as short as any
+                    // other case
+                    let start = output.len();
+                    output.extend(self.build_shortest(*body, choices));
+                    transform.apply(&mut output, start, || 1);
+                }
+                Fragment::Capture { body, .. } |
+                        Fragment::Recall { body, .. } |
+                        Fragment::Embed(body) | Fragment::Push(body) => {
+                    stack.push(*body);
+                }
+                Fragment::Terminal(value) | Fragment::Message(value) => {
+                    output.extend_from_slice(value);
+                }
+
+                // Stick to the smallest values
+                Fragment::Int(int) => output.extend(int.encode(int.low)),
+                Fragment::Float(float) => {
+                    output.extend(RandomFloat::encode(float.low()));
+                }
+                Fragment::Bytes(tree) => {
+                    output.extend(tree.bytes().first());
+                }
+                Fragment::Pop | Fragment::Nop => {}
+            }
+        }
+        output
+    }
+
+    /// Find the chain of rules that keeps `rule` from being fully expanded,
+    /// based on the `depths` computed by `min_depths`. For a rule which can
+    /// never terminate this ends in a cycle back to an earlier rule in the
+    /// chain.
+    pub fn depth_chain(&self, depths: &[Option<usize>], rule: FragmentId)
+            -> Vec<String> {
+        // Get names for all fragments which are rules
+        let names: BTreeMap<usize, &String> = self.name_to_fragment.iter()
+            .map(|(name, id)| (id.0, name)).collect();
+
+        let mut chain = Vec::new();
+        let mut visited = BTreeSet::new();
+        let mut cur = rule;
+        loop {
+            if let Some(name) = names.get(&cur.0) {
+                chain.push(name.to_string());
+            }
+
+            // Stop once we've closed a cycle
+            if !visited.insert(cur.0) {
+                break;
+            }
+
+            // Follow whichever child requires the most depth. For
+            // non-terminals that's the cheapest option, as we only need one
+            // of them, but for expressions every child is required.
+            let cost = |x: &&FragmentId| depths[x.0].unwrap_or(usize::MAX);
+            let next = match &self.fragments[cur.0] {
+                Fragment::NonTerminal(options) |
+                        Fragment::Loop { options, .. } => {
+                    options.iter().min_by_key(cost)
+                }
+                fragment @ (Fragment::Expression(_) | Fragment::Length(..)) => {
+                    fragment.children().iter().max_by_key(cost)
+                }
+                Fragment::Chunked(body) | Fragment::Capture { body, .. } |
+                    Fragment::Recall { body, .. } |
+                    Fragment::Embed(body) | Fragment::Push(body) |
+                    Fragment::Transform(body, _) => Some(body),
+                Fragment::Terminal(_) | Fragment::Message(_) |
+                    Fragment::Pop | Fragment::Int(_) | Fragment::Float(_) |
+                    Fragment::Bytes(_) | Fragment::Nop => None,
+            };
+
+            match next {
+                Some(&next) => cur = next,
+                None => break,
+            }
+        }
+
+        chain
+    }
+
+    /// Get a warning for every rule which can't be fully expanded within
+    /// `max_depth`, including the chain of rules responsible
+    pub fn termination_warnings(&self, max_depth: usize) -> Vec<String> {
+        let depths = self.min_depths();
+
+        let mut warnings = Vec::new();
+        for (name, &fragment_id) in self.name_to_fragment.iter() {
+            let message = match depths[fragment_id.0] {
+                Some(depth) if depth <= max_depth => continue,
+                Some(depth) => format!(
+                    "{} needs a max depth of at least {} to terminate, but \
+                     max depth is {}", name, depth, max_depth),
+                None => format!("{} can never terminate", name),
+            };
+
+            warnings.push(format!("{}: {}", message,
+                self.depth_chain(&depths, fragment_id).join(" -> ")));
+        }
+
+        warnings
+    }
+
+    /// Compute the maximum number of bytes a single input generated with
+    /// `options` can be when generation starts at each fragment. This
+    /// saturates at `u64::MAX` for grammars which can blow up exponentially.
+    /// A `%recall` or `%pop` may repeat a value produced at a shallower
+    /// depth, which isn't accounted for, so with those this is only an
+    /// estimate.
+    pub fn max_output_lens(&self, options: &GenOptions) -> Vec<u64> {
+        let recursive = self.recursive();
+
+        // Bodies of every `%push`, any of which a `%pop` may repeat
+        let pushed: Vec<usize> = self.fragments.iter().filter_map(|x| {
+            if let Fragment::Push(body) = x { Some(body.0) } else { None }
+        }).collect();
+
+        // `%embed` expands its body at a depth of zero, so it needs the
+        // lengths we're computing. Embeds can't be recursive, so start by
+        // assuming they're empty and repeat with the lengths from the last
+        // round until nothing changes, once per level of nesting.
+        let mut embedded = vec![0u64; self.fragments.len()];
+        loop {
+            let lens = self.max_lens(options, &recursive, &embedded,
+                &pushed);
+            if lens == embedded {
+                return lens;
+            }
+            embedded = lens;
+        }
+    }
+
+    /// Compute the expected output length of every fragment when generation
+    /// starts at it with `options`, by averaging over the random choices
+    /// made at every depth. The step budget isn't accounted for, nor is the
+    /// repetition of values by `%recall` and `%pop`. Lengths inserted by
+    /// `%length` and chunk headers are estimated from the expected length
+    /// of their body, and transforms are assumed to grow their body as much
+    /// as they can.
+    pub fn expected_output_lens(&self, options: &GenOptions) -> Vec<f64> {
+        let recursive = self.recursive();
+        let bands = self.depth_bands(&self.min_depths());
+
+        let pushed: Vec<usize> = self.fragments.iter().filter_map(|x| {
+            if let Fragment::Push(body) = x { Some(body.0) } else { None }
+        }).collect();
+
+        // Get the expected length of fragment `idx` when invoked at `depth`,
+        // given the expected lengths of its sub-fragments one depth deeper
+        // in `lens` and of the bodies of embeds in `embedded`
+        let expected = |idx: usize, depth: usize, lens: &[f64],
+                embedded: &[f64]| {
+            let mean = |ids: &mut dyn Iterator<Item = usize>| {
+                let (sum, count) = ids.fold((0., 0), |(sum, count), x| {
+                    (sum + lens[x], count + 1)
+                });
+                if count == 0 { 0. } else { sum / count as f64 }
+            };
+
+            match &self.fragments[idx] {
+                // Recursive fragments are cut off past the depth limit
+                _ if recursive[idx] && depth >= options.max_depth => 0.,
+                Fragment::NonTerminal(choices) => {
+                    // Only the options of the band which still fits are
+                    // picked
+                    let band = bands[idx].iter()
+                        .find(|(need, _)| depth + need < options.max_depth);
+                    match band {
+                        Some((_, subset)) => {
+                            mean(&mut subset.iter().map(|&x| choices[x].0))
+                        }
+                        None => mean(&mut choices.iter().map(|x| x.0)),
+                    }
+                }
+                Fragment::Expression(expr) => {
+                    expr.iter().map(|x| lens[x.0]).sum()
+                }
+                // Loops skip iterating if every option is cut off
+                Fragment::Loop { options: choices, .. }
+                        if depth + 1 >= options.max_depth &&
+                        self.all_cut_off(&recursive, choices) => 0.,
+                Fragment::Loop { options: choices, repeat } => {
+                    mean(&mut choices.iter().map(|x| x.0)) *
+                        loop_iterations(repeat, options.loop_budget)
+                }
+                Fragment::Length([mid, body], encoding) => {
+                    let len = encoding.encode(lens[body.0].round() as u64);
+                    lens[mid.0] + lens[body.0] + len.len() as f64
+                }
+                Fragment::Chunked(body) => {
+                    // Splitting `n` bytes into randomly sized chunks takes
+                    // the `n`th harmonic number of chunks on average, each
+                    // with a size line and a line break, plus the final
+                    // empty chunk
+                    let len = lens[body.0];
+                    if len <= 0. {
+                        return 5.;
+                    }
+                    let chunks = (len.ln() + 0.5772).max(1.);
+                    let size = format!("{:x}", (len / chunks).round() as u64);
+                    len + 5. + chunks * (size.len() as f64 + 4.)
+                }
+                Fragment::Transform(body, transform) => {
+                    transform.max_len(lens[body.0].round() as u64) as f64
+                }
+                Fragment::Capture { body, .. } |
+                        Fragment::Recall { body, .. } |
+                        Fragment::Push(body) => lens[body.0],
+                Fragment::Embed(body) => embedded[body.0],
+                Fragment::Pop => mean(&mut pushed.iter().copied()),
+                Fragment::Terminal(value) | Fragment::Message(value) => {
+                    value.len() as f64
+                }
+                Fragment::Int(int) => {
+                    let (min, max) = int.lens();
+                    (min + max) as f64 / 2.
+                }
+                Fragment::Float(float) => {
+                    let (min, max) = float.lens();
+                    (min + max) as f64 / 2.
+                }
+                Fragment::Bytes(_) => 1.,
+                Fragment::Nop => 0.,
+            }
+        };
+
+        // Embeds are handled the same way as by `max_output_lens`
+        let mut embedded = vec![0f64; self.fragments.len()];
+        loop {
+            // Past the depth limit the fragments which aren't cut off form a
+            // DAG, so their lengths settle after iterating as many times as
+            // the DAG is deep
+            let mut deeper = vec![0f64; self.fragments.len()];
+            loop {
+                let lens: Vec<f64> = (0..self.fragments.len()).map(|idx| {
+                    expected(idx, options.max_depth, &deeper, &embedded)
+                }).collect();
+                if lens == deeper {
+                    break;
+                }
+                deeper = lens;
+            }
+
+            for depth in (0..options.max_depth).rev() {
+                deeper = (0..self.fragments.len()).map(|idx| {
+                    expected(idx, depth, &deeper, &embedded)
+                }).collect();
+            }
+
+            if deeper == embedded {
+                return deeper;
+            }
+            embedded = deeper;
+        }
+    }
+
+    /// Determine which way every option of every non-terminal and loop
+    /// steers the output size, for `GenOptions::target_size`. Options of
+    /// non-terminals are `Greater` if they're expected to generate more than
+    /// the average option and `Less` if they're expected to generate less,
+    /// with `expected_output_lens`. Options of loops are `Greater` if they
+    /// repeat and `Less` if they finish the loop. Other fragments have no
+    /// options.
+    pub fn steering(&self, options: &GenOptions) -> Vec<Vec<Ordering>> {
+        let lens = self.expected_output_lens(options);
+        self.fragments.iter().map(|fragment| match fragment {
+            Fragment::NonTerminal(choices) => {
+                let mean = choices.iter().map(|x| lens[x.0]).sum::<f64>() /
+                    choices.len() as f64;
+                choices.iter().map(|x| lens[x.0].partial_cmp(&mean)
+                    .unwrap_or(Ordering::Equal)).collect()
+            }
+            Fragment::Loop { repeat, .. } => {
+                repeat.iter().map(|&x| if x {
+                    Ordering::Greater
+                } else {
+                    Ordering::Less
+                }).collect()
+            }
+            _ => Vec::new(),
+        }).collect()
+    }
+
+    /// Find every expansion of the non-terminals and loops which have at
+    /// most `GenOptions::precompute_max` distinct ones, for
+    /// `GenOptions::precompute`. Only fragments which aren't recursive and
+    /// only pick options, concatenate, loop and emit bytes have a finite set
+    /// of expansions. Fragments with a single expansion are left out, as
+    /// there's nothing to pick from. The expansions of every fragment are
+    /// sorted.
+    pub fn precomputed(&self, options: &GenOptions)
+            -> Vec<Option<Vec<Vec<u8>>>> {
+        let max = options.precompute_max;
+        let recursive = self.recursive();
+
+        // Expansions of every fragment once known, `None` if there are more
+        // than `max` or infinitely many. Fragments are resolved once all of
+        // their children are.
+        let mut sets: Vec<Option<Option<BTreeSet<Vec<u8>>>>> =
+            vec![None; self.fragments.len()];
+        loop {
+            let mut changed = false;
+            for (id, fragment) in self.fragments.iter().enumerate() {
+                if sets[id].is_some() {
+                    continue;
+                }
+                let children: Option<Vec<&Option<BTreeSet<Vec<u8>>>>> =
+                    fragment.children().iter()
+                        .map(|x| sets[x.0].as_ref()).collect();
+                let set = match (fragment, children) {
+                    _ if recursive[id] => None,
+                    (_, None) => continue,
+                    (Fragment::Terminal(value), _) => {
+                        Some(std::iter::once(value.clone()).collect())
+                    }
+                    (Fragment::Nop, _) => {
+                        Some(std::iter::once(Vec::new()).collect())
+                    }
+                    (Fragment::Bytes(tree), _) => {
+                        Some(tree.bytes().into_iter().map(|x| vec![x])
+                            .collect())
+                    }
+                    (Fragment::NonTerminal(_), Some(children)) => {
+                        children.iter().try_fold(BTreeSet::new(),
+                                |mut set, child| {
+                            set.extend(child.as_ref()?.iter().cloned());
+                            (set.len() <= max).then_some(set)
+                        })
+                    }
+                    (Fragment::Expression(_), Some(children)) => {
+                        children.iter().try_fold(
+                                std::iter::once(Vec::new()).collect(),
+                                |set: BTreeSet<Vec<u8>>, child| {
+                            let child = child.as_ref()?;
+                            if set.len().saturating_mul(child.len()) > max {
+                                return None;
+                            }
+                            Some(set.iter().flat_map(|x| {
+                                child.iter().map(move |y| [&x[..], y].concat())
+                            }).collect())
+                        })
+                    }
+                    (Fragment::Loop { repeat, .. }, Some(children)) => {
+                        loop_expansions(&children, repeat,
+                            options.loop_budget, max)
+                    }
+                    _ => None,
+                };
+                sets[id] = Some(set);
+                changed = true;
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        self.fragments.iter().zip(sets).map(|(fragment, set)| {
+            match (fragment, set.flatten()) {
+                (Fragment::NonTerminal(_) | Fragment::Loop { .. }, Some(set))
+                        if set.len() > 1 => {
+                    Some(set.into_iter().collect())
+                }
+                _ => None,
+            }
+        }).collect()
+    }
+
+    /// Compute the expected number of times every fragment is expanded while
+    /// generating a single input with `options`, from the chances of the
+    /// random choices leading to it. Expansions of recursive fragments which
+    /// are immediately cut off by the depth limit count too. The step budget
+    /// isn't accounted for.
+    pub fn expected_expansions(&self, options: &GenOptions) -> Vec<f64> {
+        let recursive = self.recursive();
+        let bands = self.depth_bands(&self.min_depths());
+
+        let mut expansions = vec![0f64; self.fragments.len()];
+
+        // Expansions at depth zero, which are those of the start fragment
+        // and then those of the bodies of embeds, which start over at depth
+        // zero. Embeds can't be recursive, so this ends after as many rounds
+        // as embeds are nested.
+        let mut embedded = vec![0f64; self.fragments.len()];
+        embedded[self.start.unwrap().0] = 1.;
+        while embedded.iter().any(|&x| x > 0.) {
+            let mut layer = std::mem::replace(&mut embedded,
+                vec![0f64; self.fragments.len()]);
+
+            // Walk down one depth at a time. Past the depth limit only the
+            // fragments which aren't recursive remain, which form a DAG.
+            for depth in 0.. {
+                if layer.iter().all(|&x| x == 0.) {
+                    break;
+                }
+
+                let mut next = vec![0f64; self.fragments.len()];
+                for (idx, &count) in layer.iter().enumerate() {
+                    if count == 0. {
+                        continue;
+                    }
+                    expansions[idx] += count;
+                    if recursive[idx] && depth >= options.max_depth {
+                        continue;
+                    }
+
+                    match &self.fragments[idx] {
+                        Fragment::NonTerminal(choices) => {
+                            // Only the options of the band which still fits
+                            // are picked
+                            let band = bands[idx].iter().find(|(need, _)| {
+                                depth + need < options.max_depth
+                            });
+                            let picked: Vec<usize> = match band {
+                                Some((_, subset)) => subset.clone(),
+                                None => (0..choices.len()).collect(),
+                            };
+                            for &x in &picked {
+                                next[choices[x].0] +=
+                                    count / picked.len() as f64;
+                            }
+                        }
+                        Fragment::Loop { options: choices, .. }
+                                if depth + 1 >= options.max_depth &&
+                                self.all_cut_off(&recursive, choices) => {}
+                        Fragment::Loop { options: choices, repeat } => {
+                            let iterations =
+                                loop_iterations(repeat, options.loop_budget);
+                            for x in choices {
+                                next[x.0] += count * iterations /
+                                    choices.len() as f64;
+                            }
+                        }
+                        Fragment::Embed(body) => embedded[body.0] += count,
+                        fragment => {
+                            for x in fragment.children() {
+                                next[x.0] += count;
+                            }
+                        }
+                    }
+                }
+                layer = next;
+            }
+        }
+
+        expansions
+    }
+
+    /// Compute the maximum length of every fragment when invoked at a depth
+    /// of zero, given the same for the bodies of embeds in `embedded`, and
+    /// the bodies of every push in `pushed`
+    fn max_lens(&self, options: &GenOptions, recursive: &[bool],
+            embedded: &[u64], pushed: &[usize]) -> Vec<u64> {
+        // Compute the maximum length of each fragment when invoked at a
+        // depth where the depth limit has been hit. Recursive fragments
+        // produce nothing, the rest form a DAG which we walk in post order.
+        let mut cut_off = vec![0u64; self.fragments.len()];
+        let mut done = vec![false; self.fragments.len()];
+        for root in 0..self.fragments.len() {
+            let mut work = vec![root];
+            while let Some(&node) = work.last() {
+                if done[node] {
+                    work.pop();
+                    continue;
+                }
+
+                if recursive[node] {
+                    done[node] = true;
+                    continue;
+                }
+
+                // Make sure all children are computed first
+                let pending: Vec<usize> = self.fragments[node].children()
+                    .iter().map(|x| x.0).filter(|&x| !done[x]).collect();
+                if !pending.is_empty() {
+                    work.extend(pending);
+                    continue;
+                }
+
+                cut_off[node] = self.fragment_max_len(node, &cut_off,
+                    embedded, pushed, options.loop_budget);
+                done[node] = true;
+            }
+        }
+
+        // Maximum lengths of every fragment when invoked at one depth deeper
+        // than the depth we're computing
+        let mut deeper = cut_off;
+
+        for depth in (0..options.max_depth).rev() {
+            let lens: Vec<u64> = (0..self.fragments.len()).map(|idx| {
+                match &self.fragments[idx] {
+                    // Loops skip iterating if every option is cut off
+                    Fragment::Loop { options: loop_options, .. }
+                            if depth + 1 >= options.max_depth &&
+                            self.all_cut_off(recursive, loop_options) => 0,
+                    _ => self.fragment_max_len(idx, &deeper, embedded,
+                        pushed, options.loop_budget),
+                }
+            }).collect();
+
+            deeper = lens;
+        }
+
+        deeper
+    }
+
+    /// Compute the maximum length of fragment `idx` given the maximum
+    /// lengths of its sub-fragments in `lens`, of the bodies of embeds in
+    /// `embedded`, and of the bodies of pushes in `pushed`
+    fn fragment_max_len(&self, idx: usize, lens: &[u64], embedded: &[u64],
+            pushed: &[usize], loop_budget: usize) -> u64 {
+        match &self.fragments[idx] {
+            Fragment::NonTerminal(options) => {
+                options.iter().map(|x| lens[x.0]).max().unwrap_or(0)
+            }
+            Fragment::Expression(expr) => {
+                expr.iter().fold(0u64, |acc, x| acc.saturating_add(lens[x.0]))
+            }
+            Fragment::Loop { options, .. } => {
+                // Bound this by every iteration picking the longest option
+                options.iter().map(|x| lens[x.0]).max().unwrap_or(0)
+                    .saturating_mul(loop_budget as u64)
+            }
+            Fragment::Length([mid, body], encoding) => {
+                lens[mid.0].saturating_add(lens[body.0])
+                    .saturating_add(encoding.encode(lens[body.0]).len() as u64)
+            }
+            Fragment::Chunked(body) => {
+                // Bound this by every chunk being a single byte
+                lens[body.0].saturating_mul(6).saturating_add(5)
+            }
+            Fragment::Transform(body, transform) => {
+                transform.max_len(lens[body.0])
+            }
+            Fragment::Capture { body, .. } |
+                    Fragment::Recall { body, .. } |
+                    Fragment::Push(body) => lens[body.0],
+            Fragment::Embed(body) => embedded[body.0],
+            Fragment::Pop => {
+                pushed.iter().map(|&x| lens[x]).max().unwrap_or(0)
+            }
+            Fragment::Terminal(value) | Fragment::Message(value) => {
+                value.len() as u64
+            }
+            Fragment::Int(int) => int.lens().1 as u64,
+            Fragment::Float(float) => float.lens().1 as u64,
+            Fragment::Bytes(_) => 1,
+            Fragment::Nop => 0,
+        }
+    }
+}
+
+/// Get the expected number of iterations of a `Fragment::Loop` whose options
+/// repeat as in `repeat`, with a budget of `budget` iterations. Every
+/// iteration after the first happens if the previous one picked a repeating
+/// option.
+fn loop_iterations(repeat: &[bool], budget: usize) -> f64 {
+    let repeats = repeat.iter().filter(|&&x| x).count() as f64 /
+        repeat.len() as f64;
+    (0..budget).scan(1., |chance, _| {
+        let iteration = *chance;
+        *chance *= repeats;
+        Some(iteration)
+    }).take_while(|&x| x > 1e-12).sum()
+}
+
+/// Narrow `subset`, indices of options steering the output size as in
+/// `steering`, down to the options steering it `toward` the given way. The
+/// whole subset is kept if none of its options do, or if `toward` is
+/// `Equal`.
+pub fn steered(steering: &[Ordering], subset: &[usize], toward: Ordering)
+        -> Vec<usize> {
+    let steered: Vec<usize> = subset.iter().copied()
+        .filter(|&x| steering[x] == toward).collect();
+    if toward == Ordering::Equal || steered.is_empty() {
+        subset.to_vec()
+    } else {
+        steered
+    }
+}
+
+/// Get the length of `len` bytes encoded as a single HTTP chunk, followed by
+/// the terminating empty chunk
+fn chunked_len(len: usize) -> usize {
+    if len == 0 {
+        5
+    } else {
+        format!("{:x}", len).len() + len + 9
+    }
+}
+
+/// Find every expansion of a loop whose options have the expansions
+/// `options`, and which repeats after the options in `repeat`, running for at
+/// most `budget` iterations. Returns `None` if there are more than `max`.
+fn loop_expansions(options: &[&Option<BTreeSet<Vec<u8>>>], repeat: &[bool],
+        budget: usize, max: usize) -> Option<BTreeSet<Vec<u8>>> {
+    let options: Vec<&BTreeSet<Vec<u8>>> =
+        options.iter().map(|x| x.as_ref()).collect::<Option<_>>()?;
+
+    // Outputs of the loop so far which keep iterating, and which are done
+    let mut looping: BTreeSet<Vec<u8>> = std::iter::once(Vec::new()).collect();
+    let mut done = BTreeSet::new();
+    for _ in 0..budget {
+        let mut next = BTreeSet::new();
+        for (option, &repeat) in options.iter().zip(repeat) {
+            let into = if repeat { &mut next } else { &mut done };
+            for prefix in looping.iter() {
+                for expansion in option.iter() {
+                    into.insert([&prefix[..], expansion].concat());
+                    if into.len() > max {
+                        return None;
+                    }
+                }
+            }
+        }
+        if next == looping {
+            break;
+        }
+        looping = next;
+    }
+    done.extend(looping);
+    (done.len() <= max).then_some(done)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{ConvertOptions, Grammar, GrammarRust};
+
+    /// Convert the json grammar `source` without optimizing it
+    fn grammar(source: &str) -> GrammarRust {
+        let (grammar, _, _) = Grammar::parse(source.as_bytes(), false)
+            .unwrap();
+        GrammarRust::new(&grammar, &ConvertOptions::default()).unwrap()
+    }
+
+    /// Get the min depth of the rule `name`
+    fn min_depth(grammar: &GrammarRust, name: &str) -> Option<usize> {
+        grammar.min_depths()[grammar.name_to_fragment[name].0]
+    }
+
+    /// Get the shortest output of the rule `name`
+    fn shortest(grammar: &GrammarRust, name: &str) -> Option<Vec<u8>> {
+        grammar.shortest_outputs()[grammar.name_to_fragment[name].0].clone()
+    }
+
+    #[test]
+    fn min_depths_of_terminals() {
+        let grammar = grammar(r#"{
+            "<start>": [["<a>", "<b>"]],
+            "<a>": [["a"], ["aa"]],
+            "<b>": [["<a>"]]
+        }"#);
+
+        // Nothing recursive is ever cut off, so no depth is needed at all
+        assert_eq!(min_depth(&grammar, "<start>"), Some(0));
+        assert_eq!(min_depth(&grammar, "<a>"), Some(0));
+        assert_eq!(min_depth(&grammar, "<b>"), Some(0));
+    }
+
+    #[test]
+    fn min_depths_of_recursive_rules() {
+        let grammar = grammar(r#"{
+            "<start>": [["<list>"]],
+            "<list>": [["x"], ["(", "<list>", ")"]],
+            "<outer>": [["[", "<outer>", "]"], ["<list>", "<list>"]]
+        }"#);
+
+        // A recursive rule needs a level for itself even when it can finish
+        // right away
+        assert_eq!(min_depth(&grammar, "<list>"), Some(1));
+        assert_eq!(min_depth(&grammar, "<start>"), Some(1));
+
+        // Finishing `<outer>` takes a level to pick its option, one for the
+        // expression holding both `<list>`s and one for `<list>` itself
+        assert_eq!(min_depth(&grammar, "<outer>"), Some(3));
+    }
+
+    #[test]
+    fn min_depths_of_cycles() {
+        let grammar = grammar(r#"{
+            "<start>": [["<escape>"], ["<stuck>"]],
+            "<stuck>": [["x", "<stuck>"], ["<ping>"]],
+            "<ping>": [["<pong>", "y"]],
+            "<pong>": [["<ping>", "z"]],
+            "<escape>": [["<stuck>"], ["e"]],
+            "<unreachable>": [["<stuck>"]]
+        }"#);
+
+        // Rules which only ever expand into each other never finish
+        assert_eq!(min_depth(&grammar, "<stuck>"), None);
+        assert_eq!(min_depth(&grammar, "<ping>"), None);
+        assert_eq!(min_depth(&grammar, "<pong>"), None);
+        assert_eq!(min_depth(&grammar, "<unreachable>"), None);
+
+        // An option which finishes is enough
+        assert_eq!(min_depth(&grammar, "<escape>"), Some(0));
+        assert_eq!(min_depth(&grammar, "<start>"), Some(0));
+
+        // Options which never finish are left out of every depth band
+        let start = grammar.name_to_fragment["<start>"];
+        let bands = grammar.depth_bands(&grammar.min_depths());
+        assert_eq!(bands[start.0], vec![(0, vec![0])]);
+    }
+
+    #[test]
+    fn min_depths_of_unreachable_rules() {
+        let grammar = grammar(r#"{
+            "<start>": [["s"]],
+            "<orphan>": [["o"], ["<orphan>", "o"]]
+        }"#);
+
+        // Rules are analyzed whether or not they're reachable
+        assert!(!grammar.reachable()[grammar.name_to_fragment["<orphan>"].0]);
+        assert_eq!(min_depth(&grammar, "<orphan>"), Some(1));
+        assert_eq!(min_depth(&grammar, "<start>"), Some(0));
+    }
+
+    #[test]
+    fn shortest_outputs() {
+        let grammar = grammar(r#"{
+            "<start>": [["<list>"], ["<stuck>"]],
+            "<list>": [["(", "<list>", ")"], ["xx"], ["<item>", "<item>"]],
+            "<item>": [["y"]],
+            "<stuck>": [["<stuck>", "z"]]
+        }"#);
+
+        assert_eq!(shortest(&grammar, "<list>"), Some(b"xx".to_vec()));
+        assert_eq!(shortest(&grammar, "<start>"), Some(b"xx".to_vec()));
+        assert_eq!(shortest(&grammar, "<stuck>"), None);
+    }
+}
@@ -0,0 +1,1515 @@
+//! Conversion of ASN.1 modules into grammars which generate values in the
+//! DER encoding. Every type assignment of the modules gets a rule generating
+//! the whole tag, length and contents of a value of the type, with lengths
+//! inserted by `%der_length`, so generated values are always well framed
+//! however deeply they nest.
+//!
+//! The 1988-style subset used by most protocol specifications is supported:
+//! `SEQUENCE`, `SET`, `SEQUENCE OF`, `SET OF`, `CHOICE`, tagged types with
+//! the module's tagging default, including automatic tagging, `INTEGER`,
+//! `ENUMERATED`, `BOOLEAN`, `NULL`, `BIT STRING`, `OCTET STRING`,
+//! `OBJECT IDENTIFIER`, `REAL`, the character string and time types, and
+//! `ANY`. Size and value range constraints are applied, and object
+//! identifiers assigned in the modules are generated as interesting values.
+//! Assignments using anything else, such as information object classes and
+//! parameterized types, are warned about and generate arbitrary values.
+
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use crate::grammar::{bytes_token, referenced, Grammar};
+
+/// Maximum number of optional elements, bytes or characters emitted for
+/// values with a maximum size, larger maximums are cut down to this
+const MAX_OPTIONAL: usize = 16;
+
+/// Maximum length of a chain of references from one type to another
+const MAX_DEPTH: usize = 64;
+
+/// Create an error for a malformed module
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// A tag, the class and number identifying the type of a value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Tag {
+    /// Class, in the top two bits of the identifier
+    class: u8,
+
+    /// Number within the class
+    number: u64,
+}
+
+impl Tag {
+    /// Get the universal tag `number`
+    const fn universal(number: u64) -> Self {
+        Tag { class: 0, number }
+    }
+
+    /// Encode the identifier octets of a value with this tag
+    fn identifier(self, constructed: bool) -> Vec<u8> {
+        let first = self.class | if constructed { 0x20 } else { 0 };
+        if self.number < 31 {
+            return vec![first | self.number as u8];
+        }
+        let mut ret = vec![first | 0x1f];
+        ret.extend(base128(self.number));
+        ret
+    }
+
+    /// Get the name used for the rules of implicitly tagged references
+    fn name(self) -> String {
+        let class = match self.class {
+            0x00 => "universal",
+            0x40 => "application",
+            0x80 => "context",
+            _    => "private",
+        };
+        format!("{}{}", class, self.number)
+    }
+}
+
+/// Encode `value` in base 128, most significant group first, with the top
+/// bit set on all but the last byte
+fn base128(mut value: u64) -> Vec<u8> {
+    let mut ret = vec![value as u8 & 0x7f];
+    value >>= 7;
+    while value > 0 {
+        ret.insert(0, value as u8 | 0x80);
+        value >>= 7;
+    }
+    ret
+}
+
+/// Encode `value` as the contents of a DER `INTEGER`, the shortest two's
+/// complement big endian form
+fn encode_integer(value: i128) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < bytes.len() - 1 &&
+            ((bytes[start] == 0x00 && bytes[start + 1] < 0x80) ||
+             (bytes[start] == 0xff && bytes[start + 1] >= 0x80)) {
+        start += 1;
+    }
+    bytes[start..].to_vec()
+}
+
+/// Encode the arcs of an object identifier as the contents of a DER
+/// `OBJECT IDENTIFIER`
+fn encode_oid(arcs: &[u64]) -> Option<Vec<u8>> {
+    let (&first, rest) = arcs.split_first()?;
+    let (&second, rest) = rest.split_first()?;
+    let mut ret = base128(first.checked_mul(40)?.checked_add(second)?);
+    for &arc in rest {
+        ret.extend(base128(arc));
+    }
+    Some(ret)
+}
+
+/// Bounds of a size or value range constraint. Bounds are kept as the
+/// tokens they were written as, as they may refer to values assigned later
+/// in the module. `None` is an unbounded end, `MIN` or `MAX`.
+type Range = (Option<String>, Option<String>);
+
+/// A component of a `SEQUENCE`, `SET` or `CHOICE`
+#[derive(Clone, Debug)]
+enum Component {
+    /// A named component
+    Field {
+        /// Name of the component
+        name: String,
+
+        /// Type of the component
+        ty: Type,
+
+        /// Whether the component may be left out, with `OPTIONAL` or
+        /// `DEFAULT`
+        optional: bool,
+    },
+
+    /// `COMPONENTS OF`, the components of another `SEQUENCE` or `SET`
+    ComponentsOf(Type),
+}
+
+/// An ASN.1 type
+#[derive(Clone, Debug)]
+enum Type {
+    /// `BOOLEAN`
+    Boolean,
+
+    /// `NULL`
+    Null,
+
+    /// `INTEGER`, with its named values and value range constraint
+    Integer(Vec<i128>, Option<Range>),
+
+    /// `ENUMERATED`, with the values of its items
+    Enumerated(Vec<i128>),
+
+    /// `BIT STRING`
+    BitString,
+
+    /// `OCTET STRING`, with its size constraint
+    OctetString(Option<Range>),
+
+    /// `OBJECT IDENTIFIER`
+    ObjectIdentifier,
+
+    /// `REAL`
+    Real,
+
+    /// A character string type, by its universal tag number, with its size
+    /// constraint
+    String(u64, Option<Range>),
+
+    /// `UTCTime` or `GeneralizedTime`, by their universal tag numbers
+    Time(u64),
+
+    /// `SEQUENCE` or `SET`, by their universal tags
+    Sequence(Tag, Vec<Component>),
+
+    /// `SEQUENCE OF` or `SET OF`, with the size constraint
+    SequenceOf(Tag, Box<Type>, Option<Range>),
+
+    /// `CHOICE`
+    Choice(Vec<Component>),
+
+    /// `ANY`, or a type we don't support, which generates an arbitrary value
+    Any,
+
+    /// A reference to a type assigned in a module
+    Reference(String),
+
+    /// A tagged type, and whether the tag is implicit
+    Tagged(Tag, bool, Box<Type>),
+}
+
+/// Tagging default of a module, for tags which are neither `IMPLICIT` nor
+/// `EXPLICIT`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Tagging {
+    Explicit,
+    Implicit,
+    Automatic,
+}
+
+/// Returns `true` if `token` is a word, an identifier or keyword
+fn is_word(token: &str) -> bool {
+    token.starts_with(|x: char| x.is_ascii_alphabetic() || x == '&')
+}
+
+/// Returns `true` if `token` is a reference to a type, or a keyword, rather
+/// than a reference to a value
+fn is_type_word(token: &str) -> bool {
+    token.starts_with(|x: char| x.is_ascii_uppercase())
+}
+
+/// Get the range of the tokens of a constraint, if it starts with one. Only
+/// the first range is used, eg. the root of an extensible constraint.
+fn range(tokens: &[String]) -> Option<Range> {
+    let bound = |x: &String| (x != "MIN" && x != "MAX").then(|| x.clone());
+    match tokens {
+        [lo, dots, hi, ..] if dots == ".." => Some((bound(lo), bound(hi))),
+        [value, ..] if value.parse::<i128>().is_ok() ||
+                (is_word(value) && !is_type_word(value)) => {
+            Some((Some(value.clone()), Some(value.clone())))
+        }
+        _ => None,
+    }
+}
+
+/// Split ASN.1 source into tokens, along with the line each is on
+fn tokenize(source: &str) -> std::io::Result<Vec<(String, usize)>> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut chars = source.chars().peekable();
+    while let Some(chr) = chars.next() {
+        match chr {
+            '\n' => line += 1,
+            _ if chr.is_whitespace() => {}
+            '-' if chars.peek() == Some(&'-') => {
+                // Comment, up to the end of the line or the next `--`
+                chars.next();
+                let mut prev = ' ';
+                for chr in chars.by_ref() {
+                    if chr == '\n' {
+                        line += 1;
+                        break;
+                    }
+                    if chr == '-' && prev == '-' {
+                        break;
+                    }
+                    prev = chr;
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                // Block comment
+                chars.next();
+                let mut prev = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if prev == '*' => break,
+                        Some(chr) => {
+                            line += (chr == '\n') as usize;
+                            prev = chr;
+                        }
+                        None => {
+                            return Err(invalid(format!(
+                                "Unterminated comment on line {}", line)));
+                        }
+                    }
+                }
+            }
+            '"' | '\'' => {
+                // Strings are kept along with their quotes, and binary and
+                // hex strings along with their suffix
+                let mut token = String::from(chr);
+                loop {
+                    match chars.next() {
+                        Some(end) if end == chr => {
+                            if chr == '"' && chars.peek() == Some(&'"') {
+                                token.push(chars.next().unwrap());
+                                continue;
+                            }
+                            break;
+                        }
+                        Some(other) => {
+                            line += (other == '\n') as usize;
+                            token.push(other);
+                        }
+                        None => {
+                            return Err(invalid(format!(
+                                "Unterminated string on line {}", line)));
+                        }
+                    }
+                }
+                token.push(chr);
+                if chr == '\'' {
+                    token.extend(chars.next_if(|x| x.is_ascii_alphabetic()));
+                }
+                tokens.push((token, line));
+            }
+            _ if chr.is_ascii_alphanumeric() || chr == '&' ||
+                    (chr == '-' && chars.peek()
+                        .is_some_and(|x| x.is_ascii_digit())) => {
+                // Identifiers, which may contain single hyphens, and
+                // numbers, which may be negative
+                let mut token = String::from(chr);
+                while let Some(&chr) = chars.peek() {
+                    if chr == '-' {
+                        let mut ahead = chars.clone();
+                        ahead.next();
+                        if ahead.peek() == Some(&'-') ||
+                                token.starts_with(|x: char| {
+                                    x == '-' || x.is_ascii_digit()
+                                }) {
+                            break;
+                        }
+                    } else if !chr.is_ascii_alphanumeric() {
+                        break;
+                    }
+                    token.push(chr);
+                    chars.next();
+                }
+                tokens.push((token, line));
+            }
+            ':' if chars.peek() == Some(&':') => {
+                chars.next();
+                if chars.next() != Some('=') {
+                    return Err(invalid(format!(
+                        "Expected `::=` on line {}", line)));
+                }
+                tokens.push(("::=".into(), line));
+            }
+            '.' if chars.peek() == Some(&'.') => {
+                chars.next();
+                if chars.next_if_eq(&'.').is_some() {
+                    tokens.push(("...".into(), line));
+                } else {
+                    tokens.push(("..".into(), line));
+                }
+            }
+            '[' | ']' if chars.peek() == Some(&chr) => {
+                // Version brackets around extension additions
+                chars.next();
+                tokens.push((format!("{}{}", chr, chr), line));
+            }
+            _ => tokens.push((chr.to_string(), line)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parser for the tokens of a single assignment
+struct Parser<'a> {
+    tokens: &'a [(String, usize)],
+    pos: usize,
+
+    /// Path of the file, for errors
+    path: &'a Path,
+
+    /// Tagging default of the module
+    tagging: Tagging,
+}
+
+impl Parser<'_> {
+    /// Peek at the next token
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|x| x.0.as_str())
+    }
+
+    /// Create an error at the current token
+    fn error(&self, message: &str) -> std::io::Error {
+        match self.tokens.get(self.pos) {
+            Some((token, line)) => invalid(format!("{}:{}: {}, found `{}`",
+                self.path.display(), line, message, token)),
+            None => invalid(format!("{}: {}, found the end of the assignment",
+                self.path.display(), message)),
+        }
+    }
+
+    /// Take the next token
+    fn next(&mut self) -> std::io::Result<String> {
+        let token = self.peek().ok_or_else(|| {
+            self.error("Unexpected end of assignment")
+        })?.to_string();
+        self.pos += 1;
+        Ok(token)
+    }
+
+    /// Take the next token, which must be `expected`
+    fn expect(&mut self, expected: &str) -> std::io::Result<()> {
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("Expected `{}`", expected)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Take the next token if it's `token`
+    fn eat(&mut self, token: &str) -> bool {
+        let found = self.peek() == Some(token);
+        self.pos += found as usize;
+        found
+    }
+
+    /// Take the tokens of a group in braces or parentheses, starting at its
+    /// opening token, and return the tokens inside it
+    fn group(&mut self) -> std::io::Result<Vec<String>> {
+        let open = self.next()?;
+        let close = match open.as_str() {
+            "{" => "}",
+            "(" => ")",
+            _ => return Err(self.error("Expected `{` or `(`")),
+        };
+        let mut tokens = Vec::new();
+        let mut nesting = 0usize;
+        loop {
+            let token = self.next()?;
+            if token == close && nesting == 0 {
+                return Ok(tokens);
+            }
+            if token == open {
+                nesting += 1;
+            } else if token == close {
+                nesting -= 1;
+            }
+            tokens.push(token);
+        }
+    }
+
+    /// Take an integer
+    fn int(&mut self) -> std::io::Result<i128> {
+        let value = self.peek().and_then(|x| x.parse().ok())
+            .ok_or_else(|| self.error("Expected an integer"))?;
+        self.pos += 1;
+        Ok(value)
+    }
+
+    /// Parse a list of named numbers, `{ name(1), other(2) }`, returning
+    /// the numbers. Names without numbers, as in `ENUMERATED`, are
+    /// numbered after the numbers which are given.
+    fn named_numbers(&mut self) -> std::io::Result<Vec<i128>> {
+        self.expect("{")?;
+        let mut numbers = Vec::new();
+        let mut unnumbered = 0;
+        loop {
+            match self.next()?.as_str() {
+                "}" => break,
+                "," => {}
+                "..." => {
+                    // An exception specification may follow
+                    if self.eat("!") {
+                        self.next()?;
+                    }
+                }
+                _ if self.eat("(") => {
+                    // Numbers may also be value references, which we
+                    // can't use here
+                    if let Ok(number) = self.int() {
+                        numbers.push(number);
+                    }
+                    self.expect(")")?;
+                }
+                _ => unnumbered += 1,
+            }
+        }
+
+        let mut next = 0;
+        for _ in 0..unnumbered {
+            while numbers.contains(&next) {
+                next += 1;
+            }
+            numbers.push(next);
+        }
+        Ok(numbers)
+    }
+
+    /// Parse a constraint in parentheses, returning the range of sizes if
+    /// it's a size constraint, and otherwise the range of values
+    fn constraint(&mut self) -> std::io::Result<(bool, Option<Range>)> {
+        let tokens = self.group()?;
+        Ok(match tokens.split_first() {
+            Some((first, rest)) if first == "SIZE" => {
+                (true, range(rest.strip_prefix(&["(".to_string()][..])
+                    .unwrap_or(rest)))
+            }
+            _ => (false, range(&tokens)),
+        })
+    }
+
+    /// Parse a tag, `[APPLICATION 1]`, after its opening bracket
+    fn tag(&mut self) -> std::io::Result<Tag> {
+        let class = match self.peek() {
+            Some("UNIVERSAL") => 0x00,
+            Some("APPLICATION") => 0x40,
+            Some("PRIVATE") => 0xc0,
+            _ => 0x80,
+        };
+        if class != 0x80 {
+            self.pos += 1;
+        }
+        let number = self.int()?;
+        self.expect("]")?;
+        let number = u64::try_from(number).map_err(|_| {
+            self.error("Tag numbers can't be negative")
+        })?;
+        Ok(Tag { class, number })
+    }
+
+    /// Parse the components of a `SEQUENCE`, `SET` or `CHOICE`
+    fn components(&mut self) -> std::io::Result<Vec<Component>> {
+        self.expect("{")?;
+        let mut components = Vec::new();
+        loop {
+            match self.peek() {
+                Some("}") => {
+                    self.pos += 1;
+                    break;
+                }
+                Some("," | "[[" | "]]") => self.pos += 1,
+                Some("...") => {
+                    self.pos += 1;
+                    if self.eat("!") {
+                        self.next()?;
+                    }
+                }
+                Some("COMPONENTS") => {
+                    self.pos += 1;
+                    self.expect("OF")?;
+                    components.push(Component::ComponentsOf(self.ty()?));
+                }
+                Some(name) if is_word(name) && !is_type_word(name) => {
+                    let name = name.to_string();
+                    self.pos += 1;
+                    let ty = self.ty()?;
+                    let mut optional = self.eat("OPTIONAL");
+                    if self.eat("DEFAULT") {
+                        // Skip the default value
+                        optional = true;
+                        if self.peek() == Some("{") {
+                            self.group()?;
+                        } else {
+                            self.next()?;
+                        }
+                    }
+                    components.push(Component::Field { name, ty, optional });
+                }
+                _ => return Err(self.error("Expected a component")),
+            }
+        }
+
+        // Automatic tagging numbers the components with context specific
+        // tags, unless any of them are tagged already
+        let tagged = components.iter().any(|x| {
+            matches!(x, Component::Field { ty: Type::Tagged(..), .. })
+        });
+        if self.tagging == Tagging::Automatic && !tagged {
+            let mut number = 0;
+            for component in &mut components {
+                if let Component::Field { ty, .. } = component {
+                    let inner = std::mem::replace(ty, Type::Null);
+                    *ty = Type::Tagged(Tag { class: 0x80, number }, true,
+                        Box::new(inner));
+                    number += 1;
+                }
+            }
+        }
+        Ok(components)
+    }
+
+    /// Parse a type, along with its constraints
+    fn ty(&mut self) -> std::io::Result<Type> {
+        if self.eat("[") {
+            let tag = self.tag()?;
+            let implicit = if self.eat("IMPLICIT") {
+                true
+            } else if self.eat("EXPLICIT") {
+                false
+            } else {
+                self.tagging != Tagging::Explicit
+            };
+            return Ok(Type::Tagged(tag, implicit, Box::new(self.ty()?)));
+        }
+
+        let word = self.next()?;
+        let mut ty = match word.as_str() {
+            "BOOLEAN" => Type::Boolean,
+            "NULL" => Type::Null,
+            "INTEGER" => {
+                let named = if self.peek() == Some("{") {
+                    self.named_numbers()?
+                } else {
+                    Vec::new()
+                };
+                Type::Integer(named, None)
+            }
+            "ENUMERATED" => Type::Enumerated(self.named_numbers()?),
+            "BIT" => {
+                self.expect("STRING")?;
+                if self.peek() == Some("{") {
+                    self.group()?;
+                }
+                Type::BitString
+            }
+            "OCTET" => {
+                self.expect("STRING")?;
+                Type::OctetString(None)
+            }
+            "OBJECT" => {
+                self.expect("IDENTIFIER")?;
+                Type::ObjectIdentifier
+            }
+            "REAL" => Type::Real,
+            "UTF8String" => Type::String(12, None),
+            "NumericString" => Type::String(18, None),
+            "PrintableString" => Type::String(19, None),
+            "T61String" | "TeletexString" => Type::String(20, None),
+            "VideotexString" => Type::String(21, None),
+            "IA5String" => Type::String(22, None),
+            "GraphicString" => Type::String(25, None),
+            "VisibleString" | "ISO646String" => Type::String(26, None),
+            "GeneralString" => Type::String(27, None),
+            "UniversalString" => Type::String(28, None),
+            "BMPString" => Type::String(30, None),
+            "ObjectDescriptor" => Type::String(7, None),
+            "UTCTime" => Type::Time(23),
+            "GeneralizedTime" => Type::Time(24),
+            "SEQUENCE" | "SET" => {
+                let tag = Tag::universal(if word == "SET" { 17 } else { 16 });
+                if self.peek() == Some("{") {
+                    Type::Sequence(tag, self.components()?)
+                } else {
+                    // A size constraint may come before the `OF`, with or
+                    // without parentheses
+                    let mut size = None;
+                    if self.eat("SIZE") {
+                        size = range(&self.group()?);
+                    } else if self.peek() == Some("(") {
+                        size = self.constraint()?.1;
+                    }
+                    self.expect("OF")?;
+
+                    // The element may be named
+                    if self.peek().is_some_and(|x| {
+                        is_word(x) && !is_type_word(x)
+                    }) {
+                        self.pos += 1;
+                    }
+                    Type::SequenceOf(tag, Box::new(self.ty()?), size)
+                }
+            }
+            "CHOICE" => Type::Choice(self.components()?),
+            "ANY" => {
+                if self.eat("DEFINED") {
+                    self.expect("BY")?;
+                    self.next()?;
+                }
+                Type::Any
+            }
+            _ if is_type_word(&word) && !word.contains('&') => {
+                // A reference, possibly qualified with its module, or a
+                // field of an information object class, which we don't
+                // support
+                let mut name = word;
+                while self.eat(".") {
+                    let field = self.next()?;
+                    if field.starts_with('&') {
+                        return Ok(Type::Any);
+                    }
+                    name = field;
+                }
+                if matches!(name.as_str(), "CLASS" | "EXTERNAL" | "EMBEDDED" |
+                        "CHARACTER" | "INSTANCE" | "RELATIVE-OID" |
+                        "TYPE-IDENTIFIER" | "ABSTRACT-SYNTAX") {
+                    self.pos -= 1;
+                    return Err(self.error(&format!("{} isn't supported",
+                        name)));
+                }
+                if self.peek() == Some("{") {
+                    return Err(self.error(&format!(
+                        "Parameterized type {} isn't supported", name)));
+                }
+                Type::Reference(name)
+            }
+            _ => {
+                self.pos -= 1;
+                return Err(self.error("Expected a type"));
+            }
+        };
+
+        // Apply the constraints on the type
+        while self.peek() == Some("(") {
+            let (size, range) = self.constraint()?;
+            ty = match ty {
+                Type::Integer(named, _) if !size => {
+                    Type::Integer(named, range)
+                }
+                Type::OctetString(_) if size => Type::OctetString(range),
+                Type::String(tag, _) if size => Type::String(tag, range),
+                Type::SequenceOf(tag, item, _) if size => {
+                    Type::SequenceOf(tag, item, range)
+                }
+                ty => ty,
+            };
+        }
+        Ok(ty)
+    }
+}
+
+/// An ASN.1 schema, made of the assignments of one or more modules
+#[derive(Default)]
+pub struct Schema {
+    /// Types by name
+    types: BTreeMap<String, Type>,
+
+    /// Integer values by name, for constraints
+    values: BTreeMap<String, i128>,
+
+    /// Object identifier values by name, as the tokens of their arcs
+    oids: BTreeMap<String, Vec<String>>,
+
+    /// First type assigned
+    first: Option<String>,
+}
+
+impl Schema {
+    /// Load every module in the files at `paths`. Modules may import from
+    /// each other, and all assignments share a single namespace.
+    pub fn from_modules(paths: &[PathBuf]) -> std::io::Result<Self> {
+        let mut schema = Schema::default();
+        for path in paths {
+            let source = std::fs::read_to_string(path).map_err(|err| {
+                std::io::Error::new(err.kind(),
+                    format!("{}: {}", path.display(), err))
+            })?;
+            let tokens = tokenize(&source)?;
+            let mut pos = 0;
+            while pos < tokens.len() {
+                pos = schema.module(&tokens, pos, path)?;
+            }
+        }
+        Ok(schema)
+    }
+
+    /// Load the module starting at `tokens[pos]`, returning the position
+    /// after its end
+    fn module(&mut self, tokens: &[(String, usize)], pos: usize, path: &Path)
+            -> std::io::Result<usize> {
+        let mut parser = Parser {
+            tokens,
+            pos,
+            path,
+            tagging: Tagging::Explicit,
+        };
+
+        // The header, `Name { oid } DEFINITIONS tagging TAGS ::= BEGIN`
+        let name = parser.next()?;
+        if !is_type_word(&name) {
+            parser.pos -= 1;
+            return Err(parser.error("Expected a module definition"));
+        }
+        if parser.peek() == Some("{") {
+            parser.group()?;
+        }
+        parser.expect("DEFINITIONS")?;
+        for (keyword, tagging) in [("EXPLICIT", Tagging::Explicit),
+                ("IMPLICIT", Tagging::Implicit),
+                ("AUTOMATIC", Tagging::Automatic)] {
+            if parser.eat(keyword) {
+                parser.expect("TAGS")?;
+                parser.tagging = tagging;
+            }
+        }
+        if parser.eat("EXTENSIBILITY") {
+            parser.expect("IMPLIED")?;
+        }
+        parser.expect("::=")?;
+        parser.expect("BEGIN")?;
+
+        // Imports and exports don't matter, as all modules share a
+        // namespace
+        for keyword in ["EXPORTS", "IMPORTS"] {
+            if parser.eat(keyword) {
+                while parser.next()? != ";" {}
+            }
+        }
+
+        // Find where each assignment starts, they have no terminator of
+        // their own
+        let body = parser.pos;
+        let mut starts = Vec::new();
+        let mut nesting = 0usize;
+        let mut idx = body;
+        let end = loop {
+            let token = tokens.get(idx).map(|x| x.0.as_str()).ok_or_else(|| {
+                invalid(format!("{}: Module {} has no END", path.display(),
+                    name))
+            })?;
+            match token {
+                "{" | "(" | "[" => nesting += 1,
+                "}" | ")" | "]" => nesting = nesting.saturating_sub(1),
+                "END" if nesting == 0 => break idx,
+                _ if nesting == 0 && is_word(token) => {
+                    if let Some(assign) = assignment_at(tokens, idx) {
+                        starts.push(idx);
+                        idx = assign;
+                    }
+                }
+                _ => {}
+            }
+            idx += 1;
+        };
+
+        for (ii, &start) in starts.iter().enumerate() {
+            let stop = starts.get(ii + 1).copied().unwrap_or(end);
+            self.assignment(&tokens[start..stop], path, parser.tagging);
+        }
+        Ok(end + 1)
+    }
+
+    /// Load a single assignment. Type assignments which can't be parsed
+    /// are warned about and generate arbitrary values.
+    fn assignment(&mut self, tokens: &[(String, usize)], path: &Path,
+            tagging: Tagging) {
+        let mut parser = Parser { tokens, pos: 0, path, tagging };
+        let name = tokens[0].0.clone();
+
+        if is_type_word(&name) {
+            if self.first.is_none() {
+                self.first = Some(name.clone());
+            }
+            let ty = if tokens[1].0 == "{" {
+                eprintln!("warning: {}:{}: parameterized type {} isn't \
+                    supported, it generates arbitrary values",
+                    path.display(), tokens[0].1, name);
+                Type::Any
+            } else {
+                parser.pos = 2;
+                let ty = parser.ty().and_then(|ty| {
+                    match parser.peek() {
+                        None => Ok(ty),
+                        Some(_) => Err(parser.error("Expected the end of \
+                            the assignment")),
+                    }
+                });
+                ty.unwrap_or_else(|err| {
+                    eprintln!("warning: {}, {} generates arbitrary values",
+                        err, name);
+                    Type::Any
+                })
+            };
+            self.types.entry(name).or_insert(ty);
+            return;
+        }
+
+        // Value assignments are only used for constraints and as
+        // interesting object identifiers
+        let Some(assign) = tokens.iter().position(|x| x.0 == "::=") else {
+            return;
+        };
+        let ty: Vec<&str> = tokens[1..assign].iter().map(|x| x.0.as_str())
+            .collect();
+        let value = &tokens[assign + 1..];
+        if ty == ["OBJECT", "IDENTIFIER"] &&
+                value.first().is_some_and(|x| x.0 == "{") {
+            parser.pos = assign + 1;
+            if let Ok(arcs) = parser.group() {
+                self.oids.insert(name, arcs);
+            }
+        } else if let [(value, _)] = value {
+            if let Ok(value) = value.parse() {
+                self.values.insert(name, value);
+            }
+        }
+    }
+
+    /// Resolve the object identifier value `name` into its arcs
+    fn oid(&self, name: &str, depth: usize) -> Option<Vec<u64>> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        let mut arcs = Vec::new();
+        let tokens = self.oids.get(name)?;
+        let mut idx = 0;
+        while let Some(token) = tokens.get(idx) {
+            // Arcs are numbers, names with numbers, `name(1)`, well-known
+            // names, or references to other object identifiers at the start
+            if tokens.get(idx + 1).map(|x| x.as_str()) == Some("(") {
+                arcs.push(tokens.get(idx + 2)?.parse().ok()?);
+                idx += 4;
+                continue;
+            }
+            if let Ok(arc) = token.parse() {
+                arcs.push(arc);
+            } else if idx == 0 {
+                match token.as_str() {
+                    "itu-t" | "ccitt" => arcs.push(0),
+                    "iso" => arcs.push(1),
+                    "joint-iso-itu-t" | "joint-iso-ccitt" => arcs.push(2),
+                    _ => arcs.extend(self.oid(token, depth + 1)?),
+                }
+            } else {
+                return None;
+            }
+            idx += 1;
+        }
+        Some(arcs)
+    }
+
+    /// Resolve the bound of a constraint, a number or the name of an
+    /// integer value
+    fn bound(&self, bound: &Option<String>) -> Option<i128> {
+        let bound = bound.as_ref()?;
+        let value = bound.parse().ok().or_else(|| {
+            self.values.get(bound).copied()
+        });
+        if value.is_none() {
+            eprintln!("warning: bound {} of a constraint isn't an integer, \
+                it's ignored", bound);
+        }
+        value
+    }
+
+    /// Generate a grammar for values of the type `name`, or the first type
+    /// assigned if it's `None`
+    pub fn grammar(&self, name: Option<&str>) -> std::io::Result<Grammar> {
+        let root = match name {
+            Some(name) => {
+                if !self.types.contains_key(name) {
+                    return Err(std::io::Error::other(format!(
+                        "Type {} is not defined", name)));
+                }
+                name.to_string()
+            }
+            None => self.first.clone().ok_or_else(|| {
+                std::io::Error::other("The modules assign no types")
+            })?,
+        };
+
+        // Object identifiers assigned in the modules are interesting
+        // values for every `OBJECT IDENTIFIER`
+        let mut oids: Vec<Vec<u8>> = self.oids.keys()
+            .filter_map(|x| encode_oid(&self.oid(x, 0)?)).collect();
+        oids.sort();
+        oids.dedup();
+
+        let mut gen = Generator {
+            schema: self,
+            grammar: Grammar::default(),
+            queue: Vec::new(),
+            oids,
+        };
+        let start = gen.reference(&root, None);
+        gen.grammar.0.insert("<start>".into(), vec![vec![start]]);
+        while let Some((rule, name, tag)) = gen.queue.pop() {
+            let path = rule[1..rule.len() - 1].to_string();
+            let tokens = gen.tlv(&path, &self.types[&name], tag)?;
+            gen.grammar.0.insert(rule, vec![tokens]);
+        }
+
+        // Add the built-in rules which are used, and the ones they use
+        let mut queue: Vec<String> = gen.grammar.0.values().flatten()
+            .flatten().map(|x| referenced(x))
+            .filter(|x| x.starts_with("<der.")).map(String::from).collect();
+        while let Some(name) = queue.pop() {
+            if let Entry::Vacant(entry) = gen.grammar.0.entry(name) {
+                let alts = builtin_rule(entry.key());
+                queue.extend(alts.iter().flatten().map(|x| referenced(x))
+                    .filter(|x| x.starts_with("<der.")).map(String::from));
+                entry.insert(alts);
+            }
+        }
+        Ok(gen.grammar)
+    }
+}
+
+/// If an assignment starts at `tokens[idx]`, get the position of its `::=`.
+/// Type assignments are `Name ::=` or, when parameterized,
+/// `Name { params } ::=`, and value assignments are `name Type ::= value`.
+fn assignment_at(tokens: &[(String, usize)], idx: usize) -> Option<usize> {
+    let token = |x: usize| tokens.get(x).map(|x| x.0.as_str());
+    let name = token(idx)?;
+    if is_type_word(name) {
+        match token(idx + 1)? {
+            "::=" => return Some(idx + 1),
+            "{" => {
+                let close = (idx + 2..tokens.len())
+                    .find(|&x| token(x) == Some("}"))?;
+                return (token(close + 1)? == "::=").then_some(close + 1);
+            }
+            _ => return None,
+        }
+    }
+
+    // The type of a value assignment is a few words, and its value isn't a
+    // type, which tells it apart from a type assignment following a value
+    let assign = (idx + 2..idx + 5).find(|&x| token(x) == Some("::="))?;
+    let words = (idx + 1..assign).all(|x| {
+        token(x).is_some_and(|x| is_word(x) || x == ".")
+    });
+    let value = token(assign + 1)?;
+    let typed = is_type_word(value) &&
+        !matches!(value, "TRUE" | "FALSE" | "NULL");
+    (words && !typed && value != "[").then_some(assign)
+}
+
+/// Get the alternatives for one of `values`, encoded as integers
+fn integer_alts(values: &[i128]) -> Vec<Vec<String>> {
+    values.iter().map(|&x| vec![bytes_token(&encode_integer(x))]).collect()
+}
+
+/// Get the alternatives of single characters for each of `chars`
+fn chars(chars: &str) -> Vec<Vec<String>> {
+    chars.chars().map(|x| vec![x.to_string()]).collect()
+}
+
+/// Get the alternatives of single bytes for each of `bytes`
+fn bytes(bytes: impl Iterator<Item = u8>) -> Vec<Vec<String>> {
+    bytes.map(|x| vec![bytes_token(&[x])]).collect()
+}
+
+/// Get the alternatives of a built-in rule, which are named `<der.*>`
+fn builtin_rule(name: &str) -> Vec<Vec<String>> {
+    let alts: &[&[&str]] = match name {
+        "<der.byte>" => return bytes(0..=0xff),
+        "<der.bytes>" => &[&[], &["<der.byte>", "<der.bytes>"]],
+        "<der.low>" => return bytes(0..=0x7f),
+        "<der.high>" => return bytes(0x80..=0xfe),
+        "<der.lead>" => return bytes(0x01..=0x7f),
+
+        // Integers of any size, positive or negative, in their shortest
+        // form
+        "<der.integer>" => &[&["<der.low>"], &["<der.lead>", "<der.byte>",
+            "<der.bytes>"], &["<der.high>", "<der.bytes>"]],
+
+        // Object identifiers under common roots, with arcs of one or two
+        // bytes
+        "<der.oid>" => &[&["<der.oid.root>", "<der.oid.arcs>"]],
+        "<der.oid.root>" => &[&["%bytes(2a)"], &["%bytes(2b)"],
+            &["%bytes(55)"], &["%bytes(60 86 48)"]],
+        "<der.oid.arcs>" => &[&[], &["<der.low>", "<der.oid.arcs>"],
+            &["<der.oid.high>", "<der.low>", "<der.oid.arcs>"]],
+        "<der.oid.high>" => return bytes(0x81..=0xff),
+
+        // Zero, infinities, NaN, minus zero, and a few binary values
+        "<der.real>" => &[&[], &["%bytes(40)"], &["%bytes(41)"],
+            &["%bytes(42)"], &["%bytes(43)"], &["%bytes(80 00 01)"],
+            &["%bytes(80 01 03)"], &["%bytes(c0 ff 01)"],
+            &["%bytes(81 00 80 01)"]],
+
+        // Values of any type, for `ANY`
+        "<der.any>" => &[&["%bytes(05 00)"], &["%bytes(01 01 ff)"],
+            &["%bytes(02 01)", "<der.low>"],
+            &["%bytes(04)", "%der_length(<der.bytes>)", "<der.bytes>"],
+            &["%bytes(0c)", "%der_length(<der.utf8s>)", "<der.utf8s>"],
+            &["%bytes(06)", "%der_length(<der.oid>)", "<der.oid>"],
+            &["%bytes(30)", "%der_length(<der.anys>)", "<der.anys>"]],
+        "<der.anys>" => &[&[], &["<der.any>", "<der.anys>"]],
+
+        // Characters of the string types, each alternative is a single
+        // character so sizes can be counted in them
+        "<der.printable>" => {
+            return chars("ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz\
+                0123456789 '()+,-./:=?");
+        }
+        "<der.numeric>" => return chars("0123456789 "),
+        "<der.visible>" => {
+            return (0x20u8..0x7f).map(|x| vec![(x as char).to_string()])
+                .collect();
+        }
+        "<der.utf8>" => {
+            let mut alts = builtin_rule("<der.visible>");
+            alts.extend(["\u{e9}", "\u{20ac}", "\u{1f600}", "\u{0}"].iter()
+                .map(|x| vec![x.to_string()]));
+            return alts;
+        }
+        "<der.utf8s>" => &[&[], &["<der.utf8>", "<der.utf8s>"]],
+        "<der.bmp>" => {
+            let mut alts = bytes(0x20..0x7f);
+            for alt in &mut alts {
+                alt.insert(0, "%bytes(00)".into());
+            }
+            alts.push(vec!["%bytes(00 e9)".into()]);
+            alts.push(vec!["%bytes(20 ac)".into()]);
+            return alts;
+        }
+        "<der.universal>" => {
+            let mut alts = bytes(0x20..0x7f);
+            for alt in &mut alts {
+                alt.insert(0, "%bytes(00 00 00)".into());
+            }
+            alts.push(vec!["%bytes(00 01 f6 00)".into()]);
+            return alts;
+        }
+
+        // Times, in the restricted forms DER requires
+        "<der.utctime>" => &[&["<der.digit>", "<der.digit>", "<der.month>",
+            "<der.day>", "<der.hour>", "<der.minute>", "<der.minute>", "Z"]],
+        "<der.generalizedtime>" => &[&["<der.year>", "<der.month>",
+            "<der.day>", "<der.hour>", "<der.minute>", "<der.minute>", "Z"]],
+        "<der.year>" => &[&["1970"], &["2000"], &["2049"], &["2050"],
+            &["9999"], &["<der.digit>", "<der.digit>", "<der.digit>",
+              "<der.digit>"]],
+        "<der.month>" => &[&["0", "<der.digit1>"], &["10"], &["11"],
+            &["12"]],
+        "<der.day>" => &[&["0", "<der.digit1>"], &["1", "<der.digit>"],
+            &["2", "<der.digit08>"]],
+        "<der.hour>" => &[&["0", "<der.digit>"], &["1", "<der.digit>"],
+            &["20"], &["23"]],
+        "<der.minute>" => &[&["0", "<der.digit>"], &["30"], &["59"]],
+        "<der.digit>" => return chars("0123456789"),
+        "<der.digit1>" => return chars("123456789"),
+        "<der.digit08>" => return chars("012345678"),
+        _ => unreachable!("Unknown built-in rule {}", name),
+    };
+    alts.iter().map(|alt| alt.iter().map(|x| x.to_string()).collect())
+        .collect()
+}
+
+/// Get the built-in rule for the characters of the string type with the
+/// universal tag `number`
+fn string_chars(number: u64) -> &'static str {
+    match number {
+        12 => "<der.utf8>",
+        18 => "<der.numeric>",
+        19 => "<der.printable>",
+        28 => "<der.universal>",
+        30 => "<der.bmp>",
+        _  => "<der.visible>",
+    }
+}
+
+/// Get `tokens` repeated `count` times
+fn repeat(tokens: &[String], count: usize) -> Vec<String> {
+    std::iter::repeat_n(tokens, count).flatten().cloned().collect()
+}
+
+/// State used while generating a grammar from a schema
+struct Generator<'a> {
+    /// The schema to generate values of
+    schema: &'a Schema,
+
+    /// The grammar generated so far
+    grammar: Grammar,
+
+    /// Rules for assigned types which still need to be generated, along
+    /// with the type and the tag replacing its own, if any
+    queue: Vec<(String, String, Option<Tag>)>,
+
+    /// Encoded object identifiers assigned in the modules
+    oids: Vec<Vec<u8>>,
+}
+
+impl Generator<'_> {
+    /// Get the rule for the assigned type `name`, with its tag implicitly
+    /// replaced by `tag`, queueing it to be generated if it's new
+    fn reference(&mut self, name: &str, tag: Option<Tag>) -> String {
+        let rule = match tag {
+            Some(tag) => format!("<asn1.{}:{}>", name, tag.name()),
+            None => format!("<asn1.{}>", name),
+        };
+        if !self.grammar.0.contains_key(&rule) {
+            self.grammar.0.insert(rule.clone(), Vec::new());
+            self.queue.push((rule.clone(), name.to_string(), tag));
+        }
+        rule
+    }
+
+    /// Follow references from `ty` to the type they refer to
+    fn resolve<'b>(&'b self, mut ty: &'b Type) -> std::io::Result<&'b Type> {
+        for _ in 0..MAX_DEPTH {
+            let Type::Reference(name) = ty else { return Ok(ty) };
+            ty = self.schema.types.get(name).ok_or_else(|| {
+                invalid(format!("Type {} is not defined", name))
+            })?;
+        }
+        Err(invalid("Types refer to each other in a cycle".into()))
+    }
+
+    /// Get the tokens for a tag, length and contents, with the contents
+    /// in a rule named after `path`
+    fn wrap(&mut self, path: &str, tag: Tag, constructed: bool,
+            contents: Vec<Vec<String>>) -> Vec<String> {
+        let rule = format!("<{}:value>", path);
+        self.grammar.0.insert(rule.clone(), contents);
+        vec![bytes_token(&tag.identifier(constructed)),
+            format!("%der_length({})", rule), rule]
+    }
+
+    /// Get the tokens for `count` of `unit`, where `count` is within the
+    /// size constraint `size`, with helper rules named after `path`
+    fn sized(&mut self, path: &str, unit: Vec<String>,
+            size: &Option<Range>) -> Vec<String> {
+        let (min, max) = match size {
+            Some((min, max)) => {
+                (self.schema.bound(min), self.schema.bound(max))
+            }
+            None => (None, None),
+        };
+        let min = min.and_then(|x| usize::try_from(x).ok()).unwrap_or(0);
+        let max = max.and_then(|x| usize::try_from(x).ok());
+
+        let mut ret = repeat(&unit, min);
+        let more = format!("<{}:more>", path);
+        match max {
+            None => {
+                let mut alt = unit;
+                alt.push(more.clone());
+                self.grammar.0.insert(more.clone(), vec![Vec::new(), alt]);
+                ret.push(more);
+            }
+            Some(max) if max > min => {
+                let optional = (max - min).min(MAX_OPTIONAL);
+                let alts = (0..=optional).map(|x| repeat(&unit, x))
+                    .collect();
+                self.grammar.0.insert(more.clone(), alts);
+                ret.push(more);
+            }
+            Some(_) => {}
+        }
+        ret
+    }
+
+    /// Get the tokens for a value of `ty`, with helper rules named after
+    /// `path`. `tag` implicitly replaces the type's own tag.
+    fn tlv(&mut self, path: &str, ty: &Type, tag: Option<Tag>)
+            -> std::io::Result<Vec<String>> {
+        Ok(match ty {
+            Type::Reference(name) => {
+                self.resolve(ty)?;
+                vec![self.reference(name, tag)]
+            }
+            Type::Tagged(own, implicit, inner) => {
+                // An implicit tag replaces the inner type's, apart from
+                // `CHOICE` and `ANY` which have no tag of their own and so
+                // are always tagged explicitly
+                let outer = tag.unwrap_or(*own);
+                let untagged = matches!(self.resolve(inner)?,
+                    Type::Choice(_) | Type::Any);
+                if *implicit && !untagged {
+                    self.tlv(path, inner, Some(outer))?
+                } else {
+                    let inner = self.tlv(&format!("{}:inner", path), inner,
+                        None)?;
+                    self.wrap(path, outer, true, vec![inner])
+                }
+            }
+            Type::Choice(components) => {
+                let mut alts = Vec::new();
+                for component in components {
+                    if let Component::Field { name, ty, .. } = component {
+                        alts.push(self.tlv(&format!("{}.{}", path, name), ty,
+                            None)?);
+                    }
+                }
+                let rule = format!("<{}:choice>", path);
+                self.grammar.0.insert(rule.clone(), alts);
+                match tag {
+                    Some(tag) => self.wrap(path, tag, true, vec![vec![rule]]),
+                    None => vec![rule],
+                }
+            }
+            Type::Any => match tag {
+                Some(tag) => {
+                    self.wrap(path, tag, true, vec![vec!["<der.any>".into()]])
+                }
+                None => vec!["<der.any>".into()],
+            },
+            _ => {
+                let (own, constructed, contents) = self.contents(path, ty)?;
+                self.wrap(path, tag.unwrap_or(own), constructed, contents)
+            }
+        })
+    }
+
+    /// Add the tokens for the components of a `SEQUENCE` or `SET` to
+    /// `tokens`
+    fn components(&mut self, path: &str, components: &[Component],
+            tokens: &mut Vec<String>, depth: usize) -> std::io::Result<()> {
+        for component in components {
+            match component {
+                Component::Field { name, ty, optional } => {
+                    let path = format!("{}.{}", path, name);
+                    let tlv = self.tlv(&path, ty, None)?;
+                    if *optional {
+                        let rule = format!("<{}:opt>", path);
+                        self.grammar.0.insert(rule.clone(),
+                            vec![Vec::new(), tlv]);
+                        tokens.push(rule);
+                    } else {
+                        tokens.extend(tlv);
+                    }
+                }
+                Component::ComponentsOf(ty) => {
+                    let Type::Sequence(_, inner) = self.resolve(ty)? else {
+                        return Err(invalid(format!("COMPONENTS OF in {} \
+                            isn't of a SEQUENCE or SET", path)));
+                    };
+                    if depth > MAX_DEPTH {
+                        return Err(invalid(format!("COMPONENTS OF in {} \
+                            refer to each other in a cycle", path)));
+                    }
+                    let inner = inner.clone();
+                    self.components(path, &inner, tokens, depth + 1)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the universal tag of a type, whether it's constructed, and the
+    /// alternatives for its contents, with helper rules named after `path`
+    fn contents(&mut self, path: &str, ty: &Type)
+            -> std::io::Result<(Tag, bool, Vec<Vec<String>>)> {
+        let primitive = |number, alts| {
+            Ok((Tag::universal(number), false, alts))
+        };
+        match ty {
+            Type::Boolean => {
+                primitive(1, vec![vec!["%bytes(00)".into()],
+                    vec!["%bytes(ff)".into()]])
+            }
+            Type::Null => primitive(5, vec![Vec::new()]),
+            Type::Integer(named, range) => {
+                let (min, max) = match range {
+                    Some((min, max)) => {
+                        (self.schema.bound(min), self.schema.bound(max))
+                    }
+                    None => (None, None),
+                };
+                let in_range = |x: i128| {
+                    min.is_none_or(|min| x >= min) &&
+                        max.is_none_or(|max| x <= max)
+                };
+
+                // Interesting values, the bounds and the values around
+                // them, and the named values
+                let mut values: Vec<i128> = vec![0, 1, -1, 127, 128, -128,
+                    -129, 255, 256, 32767, 32768, -32768,
+                    i32::MAX as i128, i32::MIN as i128, 1 << 32,
+                    i64::MAX as i128, i64::MIN as i128, 1 << 64];
+                for bound in min.iter().chain(max.iter()) {
+                    values.extend([bound - 1, *bound, bound + 1]);
+                }
+                values.extend(named);
+                values.retain(|&x| in_range(x));
+                values.sort();
+                values.dedup();
+                let mut alts = integer_alts(&values);
+
+                // Along with arbitrary values, when they're in range
+                if min.is_none_or(|x| x <= 0) && max.is_none() {
+                    alts.push(vec!["<der.low>".into()]);
+                    alts.push(vec!["<der.lead>".into(), "<der.byte>".into(),
+                        "<der.bytes>".into()]);
+                    if min.is_none() {
+                        alts.push(vec!["<der.high>".into(),
+                            "<der.bytes>".into()]);
+                    }
+                }
+                primitive(2, alts)
+            }
+            Type::Enumerated(values) => primitive(10, integer_alts(values)),
+            Type::BitString => {
+                // No unused bits, as DER requires them to be zero
+                primitive(3, vec![vec!["%bytes(00)".into(),
+                    "<der.bytes>".into()]])
+            }
+            Type::OctetString(size) => {
+                let tokens = self.sized(path, vec!["<der.byte>".into()],
+                    size);
+                primitive(4, vec![tokens])
+            }
+            Type::ObjectIdentifier => {
+                let mut alts: Vec<Vec<String>> = self.oids.iter()
+                    .map(|x| vec![bytes_token(x)]).collect();
+                alts.push(vec!["<der.oid>".into()]);
+                primitive(6, alts)
+            }
+            Type::Real => primitive(9, vec![vec!["<der.real>".into()]]),
+            Type::String(number, size) => {
+                let tokens = self.sized(path,
+                    vec![string_chars(*number).into()], size);
+                primitive(*number, vec![tokens])
+            }
+            Type::Time(number) => {
+                let rule = if *number == 23 {
+                    "<der.utctime>"
+                } else {
+                    "<der.generalizedtime>"
+                };
+                primitive(*number, vec![vec![rule.into()]])
+            }
+            Type::Sequence(tag, components) => {
+                let mut tokens = Vec::new();
+                self.components(path, components, &mut tokens, 0)?;
+                Ok((*tag, true, vec![tokens]))
+            }
+            Type::SequenceOf(tag, item, size) => {
+                let unit = self.tlv(&format!("{}.item", path), item, None)?;
+                let tokens = self.sized(path, unit, size);
+                Ok((*tag, true, vec![tokens]))
+            }
+            Type::Choice(_) | Type::Any | Type::Reference(_) |
+                    Type::Tagged(..) => {
+                unreachable!("{:?} is handled by tlv", ty)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{ConvertOptions, GrammarRust};
+    use crate::grammar::tests::{generate, options};
+    use super::*;
+
+    const MODULE: &str = "
+        Demo DEFINITIONS IMPLICIT TAGS ::= BEGIN
+        Message ::= SEQUENCE {
+            id    INTEGER (0..10),
+            flag  BOOLEAN OPTIONAL,
+            name  [0] IA5String (SIZE (1..4)),
+            body  Body
+        }
+        Body ::= CHOICE {
+            none  NULL,
+            data  OCTET STRING (SIZE (0..3))
+        }
+        END
+    ";
+
+    /// Load `MODULE`
+    fn schema() -> Schema {
+        let path = std::env::temp_dir()
+            .join(format!("fzero_asn1_{}.asn", std::process::id()));
+        std::fs::write(&path, MODULE).unwrap();
+        let schema = Schema::from_modules(std::slice::from_ref(&path));
+        std::fs::remove_file(&path).unwrap();
+        schema.unwrap()
+    }
+
+    /// Split the DER value at the start of `data` into its identifier and
+    /// contents, checking its length is the shortest encoding, and get
+    /// what follows it
+    fn tlv(data: &[u8]) -> (u8, &[u8], &[u8]) {
+        let (len, header) = match data[1] {
+            len @ 0..=0x7f => (len as usize, 2),
+            long => {
+                let count = (long & 0x7f) as usize;
+                let bytes = &data[2..2 + count];
+                assert!(bytes[0] != 0, "length has a leading zero");
+                let len = bytes.iter().fold(0, |acc, &x| acc << 8 | x as usize);
+                assert!(len >= 0x80, "long form of a short length");
+                (len, 2 + count)
+            }
+        };
+        let (contents, rest) = data[header..].split_at(len);
+        (data[0], contents, rest)
+    }
+
+    #[test]
+    fn asn1_rules() {
+        let grammar = schema().grammar(None).unwrap();
+        let rule = |name: &str| grammar.0[name].clone();
+
+        // The first type assigned is the default
+        assert_eq!(rule("<start>"), [["<asn1.Message>"]]);
+        assert_eq!(rule("<asn1.Message>"), [["%bytes(30)",
+            "%der_length(<asn1.Message:value>)", "<asn1.Message:value>"]]);
+
+        // Integers are picked from the bounds and the values around them
+        // which are in range
+        assert_eq!(rule("<asn1.Message.id:value>"),
+            [["%bytes(00)"], ["%bytes(01)"], ["%bytes(09)"], ["%bytes(0a)"]]);
+
+        // Choices have no tag of their own
+        assert_eq!(rule("<asn1.Body>"), [["<asn1.Body:choice>"]]);
+    }
+
+    #[test]
+    fn asn1_values_conform() {
+        let grammar = schema().grammar(None).unwrap();
+        let grammar = GrammarRust::new(&grammar, &ConvertOptions::default())
+            .unwrap();
+        for value in generate(&grammar, &options(32, 32), 500) {
+            let (tag, fields, rest) = tlv(&value);
+            assert_eq!((tag, rest), (0x30, &[][..]));
+
+            let (tag, id, mut fields) = tlv(fields);
+            assert_eq!(tag, 0x02);
+            assert!(id.len() == 1 && id[0] <= 10);
+
+            // The optional flag, which must be canonical
+            let (mut tag, mut contents, mut rest) = tlv(fields);
+            if tag == 0x01 {
+                assert!(contents == [0x00] || contents == [0xff]);
+                fields = rest;
+                (tag, contents, rest) = tlv(fields);
+            }
+
+            // The name is implicitly tagged
+            assert_eq!(tag, 0x80);
+            assert!((1..=4).contains(&contents.len()));
+            assert!(contents.is_ascii());
+
+            let (tag, contents, rest) = tlv(rest);
+            match tag {
+                0x05 => assert!(contents.is_empty()),
+                0x04 => assert!(contents.len() <= 3),
+                _ => panic!("unexpected body tag {:#x}", tag),
+            }
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn asn1_errors() {
+        assert_eq!(schema().grammar(Some("Missing")).err().unwrap()
+            .to_string(), "Type Missing is not defined");
+    }
+}
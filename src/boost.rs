@@ -0,0 +1,127 @@
+//! Keyword boosting. Security testing often wants inputs to hit known
+//! dangerous constructs, like `UNION SELECT` or `script`, more often than a
+//! plain random walk over the grammar would. The `%boost` directive lists
+//! keywords, eg. `"%boost": [["UNION SELECT"], ["script", "8"]]`, with an
+//! optional factor as a second token. In every rule, the alternatives which
+//! lead to a terminal containing the keyword in the fewest references are
+//! repeated that many times, so each choice along the shortest way to the
+//! keyword is that much more likely to be taken.
+
+use std::collections::BTreeMap;
+use crate::grammar::{bytes_literal, referenced, Directives, Grammar};
+
+/// Factor alternatives are boosted by when none is given
+pub const DEFAULT_FACTOR: usize = 4;
+
+/// Largest factor allowed, as every boost multiplies the size of the rules
+pub const MAX_FACTOR: usize = 100;
+
+/// A keyword to steer generation towards
+#[derive(Debug)]
+pub struct Boost {
+    /// Bytes a terminal has to contain to be boosted
+    pub keyword: String,
+
+    /// Number of times the alternatives leading to the keyword are repeated
+    pub factor: usize,
+}
+
+impl Directives {
+    /// Get the keywords listed in the `%boost` directive, if any
+    pub fn boosts(&self) -> std::io::Result<Vec<Boost>> {
+        let mut boosts = Vec::new();
+        for alt in self.0.get("%boost").into_iter().flatten() {
+            let boost = match alt.as_slice() {
+                [keyword] => Some((keyword, DEFAULT_FACTOR)),
+                [keyword, factor] => factor.parse().ok()
+                    .filter(|x| (1..=MAX_FACTOR).contains(x))
+                    .map(|factor| (keyword, factor)),
+                _ => None,
+            };
+            let (keyword, factor) = boost.filter(|(x, _)| !x.is_empty())
+                .ok_or_else(|| std::io::Error::other(format!(
+                    "Invalid %boost directive, expected a keyword and \
+                     optionally a factor from 1 to {}", MAX_FACTOR)))?;
+            boosts.push(Boost { keyword: keyword.clone(), factor });
+        }
+        Ok(boosts)
+    }
+}
+
+impl Grammar {
+    /// Repeat the alternatives which lead to a terminal containing the
+    /// keyword of `boost` most directly, see the module documentation.
+    /// Returns the number of alternatives boosted.
+    pub fn boost(&mut self, boost: &Boost) -> usize {
+        let keyword = boost.keyword.as_bytes();
+
+        // Get the number of references an alternative needs to get to the
+        // keyword, given the distances of the rules found so far
+        let distance = |alt: &[String], distances: &BTreeMap<&str, usize>| {
+            alt.iter().filter_map(|token| {
+                let rule = referenced(token);
+                if self.0.contains_key(rule) {
+                    return distances.get(rule).map(|x| x + 1);
+                }
+                let bytes = match bytes_literal(token) {
+                    Some(bytes) => bytes,
+                    None if token.starts_with('%') => return None,
+                    None => token.as_bytes().to_vec(),
+                };
+                bytes.windows(keyword.len()).any(|x| x == keyword)
+                    .then_some(0)
+            }).min()
+        };
+
+        // Find the distance of every rule which can get to the keyword,
+        // relaxing them until nothing gets any closer
+        let mut distances = BTreeMap::new();
+        loop {
+            let mut changed = false;
+            for (name, alts) in self.0.iter() {
+                let closest = alts.iter()
+                    .filter_map(|x| distance(x, &distances)).min();
+                let known = distances.get(name.as_str()).copied();
+                if let Some(closest) = closest {
+                    if known.is_none_or(|x| closest < x) {
+                        distances.insert(name.as_str(), closest);
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        // Pick the closest alternatives of every rule, unless they all are
+        // and repeating them wouldn't change anything
+        let mut boosted: Vec<(String, Vec<bool>)> = Vec::new();
+        for (name, alts) in self.0.iter() {
+            let Some(&closest) = distances.get(name.as_str()) else {
+                continue;
+            };
+            let closer: Vec<bool> = alts.iter()
+                .map(|x| distance(x, &distances) == Some(closest)).collect();
+            if closer.contains(&false) {
+                boosted.push((name.clone(), closer));
+            }
+        }
+
+        let mut count = 0;
+        for (name, closer) in boosted {
+            let alts = self.0.get_mut(&name).unwrap();
+            let mut repeated = Vec::with_capacity(alts.len());
+            for (alt, closer) in alts.drain(..).zip(closer) {
+                if closer {
+                    repeated.extend(std::iter::repeat_n(alt, boost.factor));
+                    count += 1;
+                } else {
+                    repeated.push(alt);
+                }
+            }
+            *alts = repeated;
+        }
+        count
+    }
+}
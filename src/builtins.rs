@@ -0,0 +1,207 @@
+//! Builtin generators of realistic data. Targets often validate fields like
+//! identifiers and addresses early and reject anything malformed, so the
+//! tokens `%uuid`, `%timestamp`, `%ipv4`, `%ipv6` and `%mac` emit well formed
+//! values without the grammar spelling out their syntax:
+//!
+//! * `%uuid` is a random version 4 UUID, eg.
+//!   `1b4e28ba-2fa1-4d2e-883f-0016d3cca427`
+//! * `%timestamp` is an RFC 3339 timestamp, eg. `2021-06-14T09:05:31.250Z`,
+//!   from 1970 to 2099 and always a valid date, so days only go up to 28
+//! * `%ipv4` is a dotted decimal IPv4 address, eg. `192.168.0.17`
+//! * `%ipv6` is an IPv6 address, written in full, compressed with `::` or
+//!   with an embedded IPv4 address, eg. `fe80::1:a2`
+//! * `%mac` is a colon separated MAC address, eg. `00:1a:2b:3c:4d:5e`
+//!
+//! Each is built out of ordinary fragments when the grammar is converted, so
+//! generation, parsing and exporting handle them like any other rule.
+
+use crate::grammar::{Fragment, FragmentId, GrammarRust};
+use crate::ints::RandomInt;
+
+/// Names of the builtin generators
+pub const BUILTINS: &[&str] =
+    &["%uuid", "%timestamp", "%ipv4", "%ipv6", "%mac"];
+
+impl GrammarRust {
+    /// If `name` is a builtin generator, build the fragments generating it
+    /// and return the root
+    pub fn builtin(&mut self, name: &str) -> Option<FragmentId> {
+        let fragment = match name {
+            "%uuid" => {
+                // Version 4 in the third group and the RFC 4122 variant in
+                // the fourth
+                let mut parts = self.hex(8);
+                parts.push(self.literal("-"));
+                parts.extend(self.hex(4));
+                parts.push(self.literal("-4"));
+                parts.extend(self.hex(3));
+                parts.push(self.literal("-"));
+                parts.push(self.bytes(b"89ab"));
+                parts.extend(self.hex(3));
+                parts.push(self.literal("-"));
+                parts.extend(self.hex(12));
+                self.sequence(parts)
+            }
+            "%timestamp" => {
+                let year = RandomInt::decimal(1970, 2099).unwrap();
+                let year = self.allocate_fragment(Fragment::Int(year));
+                let month = self.two_digits(&[(b'0', b"123456789"),
+                    (b'1', b"012")]);
+                let day = self.two_digits(&[(b'0', b"123456789"),
+                    (b'1', b"0123456789"), (b'2', b"012345678")]);
+                let hour = self.two_digits(&[(b'0', b"0123456789"),
+                    (b'1', b"0123456789"), (b'2', b"0123")]);
+                let minute = self.sixty();
+                let second = self.sixty();
+
+                // Optional fractions of a second, in milliseconds or
+                // microseconds
+                let none = self.sequence(Vec::new());
+                let mut millis = vec![self.literal(".")];
+                millis.extend(self.digits(3));
+                let millis = self.sequence(millis);
+                let mut micros = vec![self.literal(".")];
+                micros.extend(self.digits(6));
+                let micros = self.sequence(micros);
+                let fraction = self.choice(vec![none, millis, micros]);
+
+                // UTC or an offset of up to 14 hours
+                let utc = self.literal("Z");
+                let sign = self.bytes(b"+-");
+                let offset_hour = self.two_digits(&[(b'0', b"0123456789"),
+                    (b'1', b"01234")]);
+                let colon = self.literal(":");
+                let offset_minute = self.sixty();
+                let offset = self.sequence(vec![sign, offset_hour, colon,
+                    offset_minute]);
+                let zone = self.choice(vec![utc, offset]);
+
+                let parts = vec![year, self.literal("-"), month,
+                    self.literal("-"), day, self.literal("T"), hour,
+                    self.literal(":"), minute, self.literal(":"), second,
+                    fraction, zone];
+                self.sequence(parts)
+            }
+            "%ipv4" => self.ipv4(),
+            "%ipv6" => {
+                // Written in full
+                let full = self.groups(8);
+
+                // Compressed, with up to three groups on either side
+                let gap = self.literal("::");
+                let mut heads = Vec::new();
+                let mut tails = Vec::new();
+                for count in 1..=3 {
+                    heads.push(self.groups(count));
+                    tails.push(self.groups(count));
+                }
+                let tail = self.choice(tails.clone());
+                let leading = self.sequence(vec![gap, tail]);
+                let head = self.choice(heads.clone());
+                let trailing = self.sequence(vec![head, gap]);
+                let head = self.choice(heads);
+                let tail = self.choice(tails);
+                let middle = self.sequence(vec![head, gap, tail]);
+
+                // An IPv4 address mapped into IPv6
+                let prefix = self.literal("::ffff:");
+                let ipv4 = self.ipv4();
+                let mapped = self.sequence(vec![prefix, ipv4]);
+
+                self.choice(vec![full, gap, leading, trailing, middle,
+                    mapped])
+            }
+            "%mac" => {
+                let mut parts = self.hex(2);
+                for _ in 1..6 {
+                    parts.push(self.literal(":"));
+                    parts.extend(self.hex(2));
+                }
+                self.sequence(parts)
+            }
+            _ => return None,
+        };
+        Some(fragment)
+    }
+
+    /// Allocate a fragment emitting `text`
+    fn literal(&mut self, text: &str) -> FragmentId {
+        self.allocate_fragment(Fragment::Terminal(text.as_bytes().to_vec()))
+    }
+
+    /// Allocate a fragment emitting one of `bytes`
+    fn bytes(&mut self, bytes: &[u8]) -> FragmentId {
+        let options = bytes.iter().map(|&x| {
+            self.allocate_fragment(Fragment::Terminal(vec![x]))
+        }).collect();
+        self.choice(options)
+    }
+
+    /// Allocate a fragment expanding one of `options`
+    fn choice(&mut self, options: Vec<FragmentId>) -> FragmentId {
+        self.allocate_fragment(Fragment::NonTerminal(options))
+    }
+
+    /// Allocate a fragment expanding all of `parts` in order
+    fn sequence(&mut self, parts: Vec<FragmentId>) -> FragmentId {
+        self.allocate_fragment(Fragment::Expression(parts))
+    }
+
+    /// Allocate `count` fragments emitting a lowercase hex digit each
+    fn hex(&mut self, count: usize) -> Vec<FragmentId> {
+        (0..count).map(|_| self.bytes(b"0123456789abcdef")).collect()
+    }
+
+    /// Allocate `count` fragments emitting a decimal digit each
+    fn digits(&mut self, count: usize) -> Vec<FragmentId> {
+        (0..count).map(|_| self.bytes(b"0123456789")).collect()
+    }
+
+    /// Allocate a fragment emitting two digits, a first digit from `ranges`
+    /// followed by one of the second digits it allows
+    fn two_digits(&mut self, ranges: &[(u8, &[u8])]) -> FragmentId {
+        let options = ranges.iter().map(|&(first, second)| {
+            let first = self.allocate_fragment(Fragment::Terminal(vec![first]));
+            let second = self.bytes(second);
+            self.sequence(vec![first, second])
+        }).collect();
+        self.choice(options)
+    }
+
+    /// Allocate a fragment emitting minutes or seconds, from `00` to `59`
+    fn sixty(&mut self) -> FragmentId {
+        let tens = self.bytes(b"012345");
+        let ones = self.bytes(b"0123456789");
+        self.sequence(vec![tens, ones])
+    }
+
+    /// Allocate a fragment emitting an IPv4 address
+    fn ipv4(&mut self) -> FragmentId {
+        let mut parts = Vec::new();
+        for ii in 0..4 {
+            if ii > 0 {
+                parts.push(self.literal("."));
+            }
+            let octet = RandomInt::decimal(0, 255).unwrap();
+            parts.push(self.allocate_fragment(Fragment::Int(octet)));
+        }
+        self.sequence(parts)
+    }
+
+    /// Allocate a fragment emitting `count` colon separated IPv6 groups of
+    /// one to four hex digits
+    fn groups(&mut self, count: usize) -> FragmentId {
+        let mut parts = Vec::new();
+        for ii in 0..count {
+            if ii > 0 {
+                parts.push(self.literal(":"));
+            }
+            let options = (1..=4).map(|digits| {
+                let hex = self.hex(digits);
+                self.sequence(hex)
+            }).collect();
+            parts.push(self.choice(options));
+        }
+        self.sequence(parts)
+    }
+}
@@ -0,0 +1,1860 @@
+//! Rust source code generation for a `GrammarRust`
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use crate::analysis::steered;
+use crate::grammar::{ByteTree, Fragment, FragmentId, GenOptions, GrammarRust};
+use crate::grammar::{LengthEncoding, Transform, AVOID_RETRIES};
+use crate::ints::{IntFormat, RandomInt};
+use crate::scalars::RandomFloat;
+use crate::runtime;
+use crate::selftest::{self, Fnv};
+use crate::uniform::Counts;
+
+/// Version of the code generator, written to the header of the generated
+/// source. Bump it whenever the source generated for the same grammar and
+/// options changes, so sources from different versions are told apart.
+pub const CODEGEN_VERSION: u32 = 24;
+
+/// Initial random seed used by generated programs (and by the interpreter, so
+/// that both produce identical inputs)
+pub const DEFAULT_SEED: u64 = 0x34cc028e11b4f89c;
+
+/// Terminals up to this many bytes are emitted directly where they are used,
+/// rather than being called through their own function
+pub const INLINE_TERMINAL_MAX: usize = 16;
+
+/// Terminals of at least this many bytes, a cache line, start on a cache
+/// line boundary in the terminal pool and are copied by `emit_large`, which
+/// isn't inlined into every call site
+pub const LARGE_TERMINAL_MIN: usize = 64;
+
+/// Maximum number of bytes the generated program reserves for its output
+/// buffer up front. Grammars which can generate larger inputs than this will
+/// grow the buffer on demand, the rest never reallocate.
+const MAX_RESERVE: u64 = 16 * 1024 * 1024;
+
+/// Fragment functions with bodies of at most this many lines are always
+/// inlined into their callers, unless they're recursive. Calling them costs
+/// about as much as running them.
+const INLINE_ALWAYS_MAX: usize = 3;
+
+/// Fragment functions with bodies of at least this many lines are never
+/// inlined. Copying them into every caller bloats the program and its compile
+/// time for little gain, as the call is nothing next to the body.
+const INLINE_NEVER_MIN: usize = 64;
+
+/// Maximum length of the part of a fragment function's name taken from its
+/// rule
+const FUNCTION_RULE_MAX: usize = 40;
+
+/// Grammars needing at least this many fragment functions report progress
+/// while their code is generated
+const PROGRESS_MIN: usize = 10_000;
+
+/// Fragments expected to be expanded fewer times than this per input, at the
+/// build time max depth, get cold functions which are never inlined
+const COLD_EXPANSIONS: f64 = 0.01;
+
+/// Number of inputs the tests emitted with `GenOptions::tests` generate at
+/// every max depth
+const TEST_INPUTS: u64 = 100;
+
+/// Kinds of Rust source which can be generated for a grammar
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// Standalone program which benchmarks generation
+    Program,
+
+    /// Library exposing an API for generating inputs, to be included as a
+    /// module by other programs
+    Library,
+
+    /// Library which also exposes a C API, to be built as a `cdylib` or
+    /// `staticlib` for C and C++ programs. The API is described by the
+    /// header from `c_header`.
+    CLibrary,
+
+    /// Standalone program which runs a target with generated inputs and
+    /// mutates the inputs which find new coverage
+    Feedback,
+}
+
+/// All terminal bytes used by a grammar, packed into a single buffer which is
+/// emitted as one `static` in the generated program. Each terminal copies
+/// from its offset in this buffer rather than materializing its own array.
+/// The buffer itself is aligned to a cache line, and so are large terminals
+/// within it, such that copying them never straddles more cache lines than
+/// needed.
+#[derive(Default)]
+struct TerminalPool {
+    /// Packed bytes of all terminals
+    bytes: Vec<u8>,
+
+    /// Offset of each distinct terminal value in `bytes`
+    offsets: BTreeMap<Vec<u8>, usize>,
+}
+
+impl TerminalPool {
+    /// Add a terminal value to the pool, returning its offset. Identical
+    /// values share storage.
+    fn add(&mut self, value: &[u8]) -> usize {
+        if let Some(&offset) = self.offsets.get(value) {
+            return offset;
+        }
+
+        if value.len() >= LARGE_TERMINAL_MIN {
+            let padded = self.bytes.len().next_multiple_of(LARGE_TERMINAL_MIN);
+            self.bytes.resize(padded, 0);
+        }
+
+        let offset = self.bytes.len();
+        self.bytes.extend_from_slice(value);
+        self.offsets.insert(value.to_vec(), offset);
+        offset
+    }
+
+    /// Write the Rust byte string literal holding the pool contents to
+    /// `out`
+    fn write_literal(&self, out: &mut impl std::io::Write)
+            -> std::io::Result<()> {
+        out.write_all(b"b\"")?;
+        for &byte in self.bytes.iter() {
+            match byte {
+                b'"'  => out.write_all(b"\\\"")?,
+                b'\\' => out.write_all(b"\\\\")?,
+                0x20..=0x7e => out.write_all(&[byte])?,
+                _ => write!(out, "\\x{:02x}", byte)?,
+            }
+        }
+        out.write_all(b"\"")
+    }
+}
+
+/// Where a fragment comes from in the grammar, used to name its function and
+/// explain it in the generated source
+#[derive(Clone, Debug)]
+struct Origin {
+    /// Name of the rule the fragment belongs to
+    rule: String,
+
+    /// Index of the option of the rule the fragment belongs to, if it's
+    /// within one
+    option: Option<usize>,
+
+    /// Whether the fragment is the rule or the option itself, rather than
+    /// a part of it
+    whole: bool,
+}
+
+impl Origin {
+    /// Find the origin of every fragment of `grammar`. Fragments of rules
+    /// which survived optimization are named after them, their options
+    /// after the option, and everything else after the first of those it's
+    /// reachable from. Fragments only reachable from others inlined by the
+    /// optimizer have no known origin.
+    fn find(grammar: &GrammarRust) -> Vec<Option<Origin>> {
+        let mut origins: Vec<Option<Origin>> =
+            vec![None; grammar.fragments.len()];
+        for (name, &id) in grammar.name_to_fragment.iter() {
+            origins[id.0].get_or_insert_with(|| Origin {
+                rule:   name.clone(),
+                option: None,
+                whole:  true,
+            });
+        }
+        for (name, &id) in grammar.name_to_fragment.iter() {
+            let options = match &grammar.fragments[id.0] {
+                Fragment::NonTerminal(options) |
+                    Fragment::Loop { options, .. } => options,
+                _ => continue,
+            };
+            for (option, &child) in options.iter().enumerate() {
+                origins[child.0].get_or_insert_with(|| Origin {
+                    rule:   name.clone(),
+                    option: Some(option),
+                    whole:  true,
+                });
+            }
+        }
+
+        // Hand the origins down to the fragments they expand
+        let mut queue: VecDeque<usize> = (0..origins.len())
+            .filter(|&x| origins[x].is_some()).collect();
+        while let Some(id) = queue.pop_front() {
+            for &child in grammar.fragments[id].children() {
+                if origins[child.0].is_none() {
+                    origins[child.0] = origins[id].clone()
+                        .map(|x| Origin { whole: false, ..x });
+                    queue.push_back(child.0);
+                }
+            }
+        }
+        origins
+    }
+
+    /// Get the part of a function name identifying the rule, the rule's
+    /// name in lowercase with everything but letters and digits collapsed
+    /// into underscores, eg. `expr_stmt` for `<Expr-Stmt>`
+    fn slug(&self) -> String {
+        let mut slug = String::new();
+        for chr in self.rule.chars() {
+            if chr.is_ascii_alphanumeric() {
+                slug.push(chr.to_ascii_lowercase());
+            } else if !slug.is_empty() && !slug.ends_with('_') {
+                slug.push('_');
+            }
+        }
+        slug.truncate(FUNCTION_RULE_MAX);
+        slug.trim_end_matches('_').to_string()
+    }
+
+    /// Describe the origin for the comment of its function
+    fn describe(&self) -> String {
+        let part = if self.whole { "" } else { "Part of " };
+        match self.option {
+            Some(option) if self.whole => {
+                format!("Option {} of {}", option, self.rule)
+            }
+            Some(option) => {
+                format!("{}option {} of {}", part, option, self.rule)
+            }
+            None if self.whole => format!("Rule {}", self.rule),
+            None => format!("{}{}", part, self.rule),
+        }
+    }
+}
+
+/// Table of every `ByteTree` in the grammar, walked by the generated
+/// `byte_tree` method. Every choice is a node made of the number of options
+/// followed by an entry per option, which is either a byte, or 256 plus the
+/// index of the node to continue from.
+#[derive(Default)]
+struct TreePool {
+    /// Packed nodes of all trees
+    table: Vec<u32>,
+
+    /// Entry of each distinct tree in `table`
+    entries: BTreeMap<ByteTree, u32>,
+}
+
+impl TreePool {
+    /// Add a tree to the pool, returning its entry. Identical trees share
+    /// storage.
+    fn add(&mut self, tree: &ByteTree) -> u32 {
+        if let Some(&entry) = self.entries.get(tree) {
+            return entry;
+        }
+
+        let entry = match tree {
+            ByteTree::Byte(byte) => *byte as u32,
+            ByteTree::Choice(options) => {
+                let node = self.table.len();
+                self.table.push(options.len() as u32);
+                self.table.resize(node + 1 + options.len(), 0);
+                for (idx, option) in options.iter().enumerate() {
+                    self.table[node + 1 + idx] = self.add(option);
+                }
+                256 + node as u32
+            }
+        };
+        self.entries.insert(tree.clone(), entry);
+        entry
+    }
+}
+
+/// Table of the regular regions of the grammar, walked by the generated
+/// `walk` method when building with `GenOptions::compact_regular`. Every
+/// fragment in a region gets a sequence of instructions in the table, see
+/// `runtime::WALK` for their encoding.
+#[derive(Default)]
+struct WalkTable {
+    /// Packed instructions of all fragments in regions
+    table: Vec<u32>,
+
+    /// Entry of each fragment in `table`
+    entries: BTreeMap<FragmentId, u32>,
+
+    /// Size of the stacks of calls and loop iterations needed to walk the
+    /// table
+    depth: usize,
+}
+
+/// State used while generating the Rust program for a grammar
+struct Codegen<'a> {
+    /// Grammar we're generating code for
+    grammar: &'a GrammarRust,
+
+    /// Options controlling generation
+    options: &'a GenOptions,
+
+    /// Pool of all terminal bytes in the grammar
+    pool: TerminalPool,
+
+    /// Pool of all byte trees in the grammar
+    trees: TreePool,
+
+    /// Which fragments can be expanded by walking `walk`, see `regular`
+    regular: Vec<bool>,
+
+    /// Table of the instructions of the regular fragments
+    walk: WalkTable,
+
+    /// Pool of every fragment of a rule in `GenOptions::pool`
+    pools: BTreeMap<FragmentId, usize>,
+
+    /// Hole of every fragment of a rule in `GenOptions::holes`
+    holes: BTreeMap<FragmentId, usize>,
+
+    /// Name of every rule in `GenOptions::holes` with the hole filling it,
+    /// rules merged into the same fragment share a hole
+    hole_names: Vec<(String, usize)>,
+
+    /// Index of the first and number of precomputed expansions in
+    /// `expansion_table` of every non-terminal with them, see
+    /// `GenOptions::precompute`
+    expansions: BTreeMap<FragmentId, (usize, usize)>,
+
+    /// Terminal pool offset and length of every precomputed expansion
+    expansion_table: Vec<u32>,
+
+    /// Which fragments are recursive. Only these need depth checks.
+    recursive: Vec<bool>,
+
+    /// Minimum depth budget every fragment needs to expand completely, see
+    /// `GrammarRust::min_depths`
+    min_depths: Vec<Option<usize>>,
+
+    /// Fragments which are expanded so rarely that their functions are
+    /// marked cold, keeping them out of the way of the hot paths
+    cold: Vec<bool>,
+
+    /// Derivation counts when sampling uniformly
+    counts: Option<Counts>,
+
+    /// Options non-terminals pick from near the depth limit, see
+    /// `GrammarRust::depth_bands`
+    bands: Vec<Vec<(usize, Vec<usize>)>>,
+
+    /// Way every option of every non-terminal and loop steers the output
+    /// size, when aiming for `GenOptions::target_size`. Empty otherwise.
+    steering: Vec<Vec<Ordering>>,
+
+    /// Offsets into the count tables used to weight the options of every
+    /// non-terminal and loop when sampling uniformly, packed one fragment
+    /// after another into a single table
+    option_tables: Vec<u32>,
+
+    /// Index of the first entry of every fragment in `option_tables`
+    option_bases: Vec<usize>,
+
+    /// Swarm index of every option of every non-terminal which swarm testing
+    /// may disable, when generating the benchmark program
+    swarm: Vec<Vec<Option<usize>>>,
+
+    /// Start fragment of every grammar the program is built from. There's
+    /// more than one when building from several grammars, then `GRAMMAR`
+    /// picks which one inputs are generated from.
+    starts: Vec<FragmentId>,
+
+    /// Names of the grammars when building from several, empty otherwise
+    names: Vec<String>,
+
+    /// Name of the function of every fragment, after its rule if known
+    functions: Vec<String>,
+
+    /// Where every fragment comes from in the grammar, if known
+    origins: Vec<Option<Origin>>,
+
+    /// Fragments and the depths they're expanded at which get a copy of
+    /// their function specialized for that depth, see `GenOptions::unroll`
+    unrolled: BTreeSet<(FragmentId, usize)>,
+
+    /// Kind of source being generated
+    output: Output,
+
+    /// Max depths the emitted tests generate inputs at, with the hash of the
+    /// first `TEST_INPUTS` inputs the interpreter generates at each of them,
+    /// see `GenOptions::tests`
+    test_hashes: Vec<(usize, u64)>,
+}
+
+impl Codegen<'_> {
+    /// Get the Rust code which appends `value` to the output buffer
+    fn terminal(&self, value: &[u8]) -> String {
+        let emit = if value.len() >= LARGE_TERMINAL_MIN {
+            "emit_large"
+        } else {
+            "emit"
+        };
+        format!("self.{}({}, {});", emit, self.pool.offsets[value],
+            value.len())
+    }
+
+    /// Get the Rust code for the `emit` method of the generated fuzzer, which
+    /// appends bytes from the terminal pool to the output buffer
+    fn emit(&self) -> String {
+        // The buffer is reserved up front, so this is a bounds checked copy
+        // into spare capacity. With constant offsets and lengths inlined the
+        // bounds checks fold away, and it's as fast as the `unsafe` copy
+        // into spare capacity which was used before, see the README.
+        let body =
+            "self.buf.extend_from_slice(&TERMINALS[offset..offset + len]);";
+
+        // Large terminals go through a copy of their own which isn't
+        // inlined, a call is nothing next to the copy and it keeps big
+        // grammars from blowing up in size. The copy ends up in the libc
+        // `memcpy`, which already picks the best vector instructions for the
+        // CPU at runtime, so there are no `#[target_feature]` variants.
+        format!(r#"
+    #[inline(always)]
+    fn emit(&mut self, offset: usize, len: usize) {{
+        {}
+    }}
+
+    #[inline(never)]
+    fn emit_large(&mut self, offset: usize, len: usize) {{
+        {}
+    }}
+"#, body, body)
+    }
+
+    /// Returns `true` if the fragment is emitted directly at its call sites
+    /// rather than being called
+    fn is_inlined(&self, id: FragmentId) -> bool {
+        match &self.grammar.fragments[id.0] {
+            Fragment::Terminal(value) => value.len() <= INLINE_TERMINAL_MAX,
+            Fragment::Message(_) | Fragment::Pop | Fragment::Nop => true,
+            _ => false,
+        }
+    }
+
+    /// Find the fragments which derive regular languages and can be
+    /// expanded by walking the `WALK` table, when building with
+    /// `GenOptions::compact_regular`. These aren't recursive, so they never
+    /// need the depth, and only pick options, concatenate, loop and emit
+    /// bytes all the way down. Loops which are cut off at the depth limit
+    /// would need the depth after all and are left out, and so are pooled
+    /// fragments, holes and those with precomputed expansions, which need
+    /// their functions. Nothing is walked when steering towards a target size,
+    /// which the walk doesn't know about.
+    fn regular(&self) -> Vec<bool> {
+        let fragments = &self.grammar.fragments;
+        if !self.options.compact_regular ||
+                self.options.target_size.is_some() {
+            return vec![false; fragments.len()];
+        }
+
+        let mut regular: Vec<bool> = fragments.iter().enumerate()
+            .map(|(id, fragment)| match fragment {
+                _ if self.recursive[id] ||
+                    self.pools.contains_key(&FragmentId(id)) ||
+                    self.holes.contains_key(&FragmentId(id)) ||
+                    self.expansions.contains_key(&FragmentId(id)) => false,
+                Fragment::Loop { options, .. } => {
+                    !self.grammar.all_cut_off(&self.recursive, options)
+                }
+                Fragment::NonTerminal(_) | Fragment::Expression(_) |
+                    Fragment::Terminal(_) | Fragment::Bytes(_) |
+                    Fragment::Nop => true,
+                _ => false,
+            }).collect();
+
+        // Drop the fragments reaching anything else until nothing changes
+        loop {
+            let mut changed = false;
+            for (id, fragment) in fragments.iter().enumerate() {
+                if regular[id] &&
+                        fragment.children().iter().any(|x| !regular[x.0]) {
+                    regular[id] = false;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break regular;
+            }
+        }
+    }
+
+    /// Returns `true` if the function of fragment `id` walks its region of
+    /// the `WALK` table rather than expanding the fragment itself. Regular
+    /// terminals, byte samplers and nops are simpler to expand directly.
+    fn walked(&self, id: FragmentId) -> bool {
+        self.regular[id.0] && matches!(self.grammar.fragments[id.0],
+            Fragment::NonTerminal(_) | Fragment::Expression(_) |
+            Fragment::Loop { .. })
+    }
+
+    /// Compile every walked fragment and everything it expands into the
+    /// `WALK` table, see `runtime::WALK`
+    fn walk_table(&self) -> WalkTable {
+        let fragments = &self.grammar.fragments;
+        let mut walk = WalkTable::default();
+        let mut queue: Vec<FragmentId> = (0..fragments.len())
+            .map(FragmentId).filter(|&x| self.walked(x)).collect();
+
+        // Targets are patched in once every fragment has an entry
+        let mut targets = Vec::new();
+        let mut target = |table: &mut Vec<u32>, queue: &mut Vec<FragmentId>,
+                id: FragmentId| {
+            targets.push((table.len(), id));
+            table.push(0);
+            queue.push(id);
+        };
+        while let Some(id) = queue.pop() {
+            if walk.entries.contains_key(&id) {
+                continue;
+            }
+            let table = &mut walk.table;
+            walk.entries.insert(id, table.len() as u32);
+
+            // Terminals, byte samplers and nops are emitted in place when
+            // expanded by expressions, the rest are called
+            let emit = |table: &mut Vec<u32>, id: FragmentId| {
+                match &fragments[id.0] {
+                    Fragment::Terminal(value) => {
+                        table.extend([1, self.pool.offsets[value] as u32,
+                            value.len() as u32]);
+                    }
+                    Fragment::Bytes(tree) => {
+                        table.extend([2, self.trees.entries[tree]]);
+                    }
+                    Fragment::Nop => {}
+                    _ => return false,
+                }
+                true
+            };
+            match &fragments[id.0] {
+                Fragment::NonTerminal(options) => {
+                    table.extend([4, options.len() as u32]);
+                    for &option in options.iter() {
+                        target(table, &mut queue, option);
+                    }
+                }
+                Fragment::Expression(expr) => {
+                    for &exp in expr.iter() {
+                        if !emit(table, exp) {
+                            table.push(3);
+                            target(table, &mut queue, exp);
+                        }
+                    }
+                    table.push(0);
+                }
+                Fragment::Loop { options, repeat } => {
+                    table.extend([5, 6, options.len() as u32, 0]);
+                    let head = table.len() - 3;
+                    for (&option, &repeat) in options.iter().zip(repeat) {
+                        target(table, &mut queue, option);
+                        table.push(repeat as u32);
+                    }
+                    table[head + 2] = table.len() as u32;
+                    table.extend([7, 0]);
+                }
+                _ => {
+                    emit(table, id);
+                    table.push(0);
+                }
+            }
+        }
+        for (offset, id) in targets {
+            walk.table[offset] = walk.entries[&id];
+        }
+
+        // Expressions push a call for every child they call and loops for
+        // every iteration, non-terminals continue at their option. Loops
+        // are only ever nested within calls, so their iteration counts fit
+        // in a stack as deep as the calls.
+        let mut depths = vec![0; fragments.len()];
+        loop {
+            let mut changed = false;
+            for &id in walk.entries.keys() {
+                let fragment = &fragments[id.0];
+                let deepest = fragment.children().iter()
+                    .map(|x| depths[x.0]).max().unwrap_or(0);
+                let depth = match fragment {
+                    Fragment::Expression(_) | Fragment::Loop { .. } => {
+                        deepest + 1
+                    }
+                    _ => deepest,
+                };
+                if depth != depths[id.0] {
+                    depths[id.0] = depth;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        walk.depth = depths.iter().max().map_or(0, |x| x + 1);
+        walk
+    }
+
+    /// Get the Rust code which expands fragment `id`, passing it the Rust
+    /// expression `arg` as its depth or size. Terminals are never recursive
+    /// so they are never cut off by the depth limit, and can be inlined
+    /// unconditionally. When generating for coverage feedback, every call
+    /// records a node in the derivation tree.
+    fn call(&self, id: FragmentId, arg: &str) -> String {
+        match &self.grammar.fragments[id.0] {
+            Fragment::Terminal(value) if self.is_inlined(id) => {
+                self.terminal(value)
+            }
+            Fragment::Message(separator) => {
+                format!("self.message(&{:?});", separator)
+            }
+            Fragment::Pop => "self.pop();".into(),
+            Fragment::Nop => String::new(),
+            _ if self.output == Output::Feedback => {
+                format!("{{ let node = self.enter({}, {}); \
+                    self.{}({}); self.leave(node); }}",
+                    id.0, arg, self.functions[id.0], arg)
+            }
+            _ => self.traced(id,
+                format!("self.{}({});", self.functions[id.0], arg)),
+        }
+    }
+
+    /// Find the fragments to unroll, see `unrolled`. Every fragment with a
+    /// function of its own reachable from the start within the depths to
+    /// unroll gets a copy for each depth it's reachable at, apart from the
+    /// walked ones, which don't need the depth. Embedded grammars start over
+    /// from a depth of zero.
+    fn unrolled(&self) -> BTreeSet<(FragmentId, usize)> {
+        let mut unrolled = BTreeSet::new();
+        if self.counts.is_some() || self.output == Output::Feedback {
+            return unrolled;
+        }
+
+        let mut queue: Vec<(FragmentId, usize)> =
+            self.starts.iter().map(|&x| (x, 0)).collect();
+        while let Some((id, depth)) = queue.pop() {
+            if depth >= self.options.unroll || self.walked(id) ||
+                    !unrolled.insert((id, depth)) {
+                continue;
+            }
+            let fragment = &self.grammar.fragments[id.0];
+            for &child in fragment.children() {
+                if !self.is_inlined(child) {
+                    let depth = match fragment {
+                        Fragment::Embed(_) => 0,
+                        _ => depth + 1,
+                    };
+                    queue.push((child, depth));
+                }
+            }
+        }
+        unrolled
+    }
+
+    /// Get the Rust code which calls the function of fragment `id` to expand
+    /// it at `depth`, its copy specialized for the depth if it has one
+    fn invoke(&self, id: FragmentId, depth: usize) -> String {
+        if self.unrolled.contains(&(id, depth)) {
+            format!("self.{}_at_{}();", self.functions[id.0], depth)
+        } else {
+            format!("self.{}({});", self.functions[id.0], depth)
+        }
+    }
+
+    /// Get the Rust code which expands child `id` of a fragment expanded at
+    /// `depth`, or at the runtime depth `depth` if it's `None`
+    fn child(&self, id: FragmentId, depth: Option<usize>) -> String {
+        match depth {
+            Some(depth) if !self.is_inlined(id) &&
+                    self.output != Output::Feedback => {
+                self.traced(id, self.invoke(id, depth + 1))
+            }
+            Some(depth) => self.call(id, &(depth + 1).to_string()),
+            None => self.call(id, "depth + 1"),
+        }
+    }
+
+    /// Wrap the Rust code `call`, which calls the function of fragment `id`,
+    /// in tracing of the expansion when building with tracing
+    fn traced(&self, id: FragmentId, call: String) -> String {
+        if !self.options.trace {
+            return call;
+        }
+        let (what, rule) = match &self.origins[id.0] {
+            Some(origin) => (format!("{}: {}", self.functions[id.0],
+                origin.describe()), origin.rule.as_str()),
+            None => (self.functions[id.0].clone(), ""),
+        };
+        format!("{{ trace_enter({:?}, {:?}); {} trace_leave(); }}", what,
+            rule, call)
+    }
+
+    /// Get the number of bytes to reserve for the output buffer. This is the
+    /// maximum possible output length, up to `MAX_RESERVE`.
+    fn reserve(&self) -> u64 {
+        let max = match &self.counts {
+            // Every expansion emits at most one terminal
+            Some(counts) => {
+                let longest = self.grammar.fragments.iter().map(|x| match x {
+                    Fragment::Terminal(value) => value.len() as u64,
+                    _ => 0,
+                }).max().unwrap_or(0);
+                longest.saturating_mul(counts.size as u64)
+            }
+            None => {
+                let lens = self.grammar.max_output_lens(self.options);
+                self.starts.iter().map(|x| lens[x.0]).max().unwrap_or(0)
+            }
+        };
+        max.min(MAX_RESERVE)
+    }
+
+    /// Get the comment at the top of the generated source, recording what it
+    /// was generated from. The grammar hash covers the fragment graph after
+    /// optimization, so it reflects every option which changes the grammar.
+    /// Nothing here depends on the time, the paths or the machine, so the
+    /// same grammar and options always give byte identical source.
+    fn header(&self) -> String {
+        let mut hash = Fnv::new();
+        write!(hash, "{:?} {:?} {:?}", self.starts, self.names,
+            self.grammar.fragments).unwrap();
+        format!("// Generated by fzero {}, codegen version {}. Do not edit.\n\
+            // Grammar hash: {:016x}\n\
+            // Output: {:?}, {:?}\n",
+            env!("CARGO_PKG_VERSION"), CODEGEN_VERSION, hash.0, self.output,
+            self.options)
+    }
+
+    /// Fill in the placeholders of a template from `runtime`
+    fn template(&self, template: &str) -> String {
+        template
+            .replace("__SEED__", &format!("{:#x}", DEFAULT_SEED))
+            .replace("__RESERVE__", &self.reserve().to_string())
+            .replace("__MAX_DEPTH__", &self.options.max_depth.to_string())
+            .replace("__LOOP_BUDGET__", &self.options.loop_budget.to_string())
+            .replace("__GRAMMARS__", &format!("{:?}", self.names))
+            .replace("__HOLES__", &format!("{:?}", self.hole_names))
+            .replace("__TEST_INPUTS__", &TEST_INPUTS.to_string())
+            .replace("__TEST_HASHES__", &self.test_hashes.iter()
+                .map(|(depth, hash)| format!("({}, {:#018x})", depth, hash))
+                .collect::<Vec<_>>().join(", "))
+    }
+
+    /// Get the Rust code which randomly picks one of the `subset` of the
+    /// `options` of non-terminal `id` and expands it, indented by `indent`.
+    /// Options disabled by swarm testing are picked again.
+    fn pick(&self, id: FragmentId, options: &[FragmentId], subset: &[usize],
+            depth: Option<usize>, indent: &str) -> String {
+        let swarm = &self.swarm[id.0];
+        let reroll = subset.iter().any(|&x| swarm[x].is_some());
+        let indent = if reroll { format!("{}    ", indent) }
+            else { indent.to_string() };
+
+        let mut program = format!("{}match self.rand() % {} {{\n", indent,
+            subset.len());
+        for (option_id, &option) in subset.iter().enumerate() {
+            if let Some(swarm) = swarm[option] {
+                program += &format!("{}    {} if self.disabled({}) => \
+                    continue,\n", indent, option_id, swarm);
+            }
+            program += &format!("{}    {} => {{ {} }}\n", indent, option_id,
+                self.child(options[option], depth));
+        }
+        program += &format!("{}    _ => unreachable!(),\n", indent);
+        program += &format!("{}}}\n", indent);
+
+        if reroll {
+            let outer = &indent[4..];
+            program = format!("{}loop {{\n{}{}break;\n{}}}\n", outer,
+                program, indent, outer);
+        }
+        program
+    }
+
+    /// Get the Rust code which randomly picks one of the `subset` of the
+    /// `options` of non-terminal `id` like `pick`, steering towards the
+    /// target size if there is one
+    fn steer(&self, id: FragmentId, options: &[FragmentId], subset: &[usize],
+            depth: Option<usize>, indent: &str) -> String {
+        self.steered(id, subset, indent, |subset, indent| {
+            self.pick(id, options, subset, depth, indent)
+        })
+    }
+
+    /// Get the Rust code which runs one iteration of a loop, picking a random
+    /// one of the `subset` of its `options` and finishing the loop unless it
+    /// repeats as in `repeat`, indented by `indent`
+    fn iteration(&self, options: &[FragmentId], repeat: &[bool],
+            subset: &[usize], depth: Option<usize>, indent: &str) -> String {
+        let mut program = format!("{}match self.rand() % {} {{\n", indent,
+            subset.len());
+        for (option_id, &option) in subset.iter().enumerate() {
+            program += &format!("{}    {} => {{ {}{} }}\n", indent, option_id,
+                self.child(options[option], depth),
+                if repeat[option] { "" } else { " return;" });
+        }
+        program += &format!("{}    _ => unreachable!(),\n", indent);
+        program += &format!("{}}}\n", indent);
+        program
+    }
+
+    /// Get the Rust code which picks from the `subset` of the options of
+    /// `id` with the code `pick` generates for a subset and an indent. When
+    /// aiming for a target size, the subset is narrowed down to the options
+    /// growing the output while it's too short, and to those finishing it
+    /// while it's too long, see `GrammarRust::steering`.
+    fn steered(&self, id: FragmentId, subset: &[usize], indent: &str,
+            pick: impl Fn(&[usize], &str) -> String) -> String {
+        let (Some((min, max)), Some(steering)) =
+                (self.options.target_range(), self.steering.get(id.0)) else {
+            return pick(subset, indent);
+        };
+        let grow = steered(steering, subset, Ordering::Greater);
+        let finish = steered(steering, subset, Ordering::Less);
+        if grow == subset && finish == subset {
+            return pick(subset, indent);
+        }
+
+        // Swarm testing may disable every option steering one way, the whole
+        // subset is picked from then
+        let enabled = |steered: &[usize]| {
+            let disabled: Option<Vec<String>> = steered.iter().map(|&x| {
+                self.swarm[id.0].get(x).copied().flatten()
+                    .map(|x| format!("self.disabled({})", x))
+            }).collect();
+            disabled.map(|x| format!(" && !({})", x.join(" && ")))
+                .unwrap_or_default()
+        };
+
+        let inner = format!("{}    ", indent);
+        format!("{}if self.buf.len() < {}{} {{\n{}{}}} else if \
+            self.buf.len() > {}{} {{\n{}{}}} else {{\n{}{}}}\n", indent, min,
+            enabled(&grow), pick(&grow, &inner), indent, max,
+            enabled(&finish), pick(&finish, &inner), indent,
+            pick(subset, &inner), indent)
+    }
+
+    /// Returns `true` if the program needs the `byte_tree` method, which the
+    /// walk uses for byte samplers even when no function does
+    fn byte_trees(&self) -> bool {
+        !self.trees.table.is_empty() || !self.walk.table.is_empty()
+    }
+
+    /// Append the Rust code for the body of the function for fragment `id`
+    /// to `program`, which randomly descends into the fragment until the
+    /// depth limit. The body is specialized for being expanded at `depth`,
+    /// or takes the depth as an argument if it's `None`. `program` is passed
+    /// in so its allocation is reused between fragments.
+    fn descent(&self, id: FragmentId, depth: Option<usize>,
+            mut program: String) -> String {
+        // The max depth is only known at runtime, the build time max depth
+        // is merely its default
+        let max_depth = "self.max_depth";
+        let at = depth.map_or_else(|| "depth".to_string(), |x| x.to_string());
+        let ahead = |n: usize| depth.map_or_else(
+            || format!("depth + {}", n), |x| (x + n).to_string());
+
+        // Regular fragments are expanded by walking their instructions
+        if self.walked(id) {
+            program += &format!("        self.walk({});\n",
+                self.walk.entries[&id]);
+            return program;
+        }
+
+        // Holes are filled by their provider if they have one, see
+        // `runtime::HOLE`
+        if let Some(hole) = self.holes.get(&id) {
+            program += &format!("        if self.fill_hole({}) {{\n            \
+                return;\n        }}\n", hole);
+        }
+
+        // Pooled fragments are mostly emitted from their pool when pooling
+        // is on, see `runtime::POOL`. The expansion which fills the pool
+        // calls the function again, bypassing the pool.
+        if let Some(slot) = self.pools.get(&id) {
+            program += &format!("        if self.pool.size != 0 && \
+                !std::mem::take(&mut self.pool.bypass) {{\n            \
+                self.pooled({}, {}, Self::{});\n            \
+                return;\n        }}\n", slot, at, self.functions[id.0]);
+        }
+
+        // Non-terminals and loops with few expansions emit one of them
+        // precomputed some of the time, see `GrammarRust::precomputed`
+        if let Some(&(base, len)) = self.expansions.get(&id) {
+            program += &format!(r#"        if self.rand() % 100 < {} {{
+            let entry = 2 * ({} + self.rand() % {});
+            self.emit(EXPANSIONS[entry] as usize,
+                EXPANSIONS[entry + 1] as usize);
+            return;
+        }}
+"#, self.options.precompute, base, len);
+        }
+
+        // Add depth checking to terminate on depth exhaustion. Only
+        // recursive fragments can expand forever, the rest terminate on
+        // their own and skip the check.
+        // When there's a step budget, expansions of recursive fragments also
+        // use up a step each and stop once the budget is exhausted.
+        if self.recursive[id.0] {
+            match self.options.max_steps {
+                Some(max_steps) => {
+                    program += &format!("        if {} >= {} || \
+                        self.steps >= {} {{ self.cutoffs += 1; return; }}\n",
+                        at, max_depth, max_steps);
+                    program += "        self.steps += 1;\n";
+                }
+                None => {
+                    program += &format!(
+                        "        if {} >= {} {{ self.cutoffs += 1; \
+                        return; }}\n", at, max_depth);
+                }
+            }
+        }
+
+        match &self.grammar.fragments[id.0] {
+            Fragment::NonTerminal(options) => {
+                // For non-terminal cases pick a random variant to select
+                // and invoke that fragment's routine. Near the depth limit
+                // only pick from the variants which still fit.
+                let all: Vec<usize> = (0..options.len()).collect();
+                let bands = &self.bands[id.0];
+                if bands.is_empty() {
+                    program += &self.steer(id, options, &all, depth,
+                        "        ");
+                } else {
+                    for (ii, (need, subset)) in bands.iter().enumerate() {
+                        program += &format!("{}if {} < {} {{\n",
+                            if ii == 0 { "        " } else { " else " },
+                            ahead(*need), max_depth);
+                        program += &self.steer(id, options, subset, depth,
+                            "            ");
+                        program += "        }";
+                    }
+                    program += " else {\n";
+                    program += &self.steer(id, options, &all, depth,
+                        "            ");
+                    program += "        }\n";
+                }
+            }
+            Fragment::Expression(expr) => {
+                // Invoke all of the expression's routines in order
+                for &exp in expr.iter() {
+                    program += &format!("        {}\n",
+                        self.child(exp, depth));
+                }
+            }
+            Fragment::Loop { options: loop_options, repeat } => {
+                // Pick a random variant each iteration, stopping when we
+                // pick a variant which doesn't repeat or the iteration
+                // budget is exhausted. If the variants would be cut off
+                // by the depth limit or step budget there's no point in
+                // iterating.
+                if self.grammar.all_cut_off(&self.recursive, loop_options) {
+                    let steps = self.options.max_steps.map(|x| {
+                        format!(" || self.steps >= {}", x)
+                    }).unwrap_or_default();
+                    program += &format!(
+                        "        if {} >= {}{} {{ self.cutoffs += 1; \
+                        return; }}\n",
+                        ahead(1), max_depth, steps);
+                }
+                program += &format!("        for _ in 0..{} {{\n",
+                    self.options.loop_budget);
+                let all: Vec<usize> = (0..loop_options.len()).collect();
+                program += &self.steered(id, &all, "            ", |subset,
+                        indent| {
+                    self.iteration(loop_options, repeat, subset, depth,
+                        indent)
+                });
+                program += "        }\n";
+            }
+            Fragment::Length([mid, body], encoding) => {
+                // Expand both, then insert the length of the body where we
+                // started
+                program += "        let start = self.buf.len();\n";
+                program += &self.helper_enter();
+                program += &format!("        {}\n",
+                    self.child(*mid, depth));
+                program += "        let body = self.buf.len();\n";
+                program += &format!("        {}\n",
+                    self.child(*body, depth));
+                program += match encoding {
+                    LengthEncoding::Decimal => "        let len = \
+                        (self.buf.len() - body).to_string();\n        \
+                        self.buf.splice(start..start, len.bytes());\n",
+                    LengthEncoding::Varint => "        let len = \
+                        varint((self.buf.len() - body) as u64);\n        \
+                        self.buf.splice(start..start, len);\n",
+                    LengthEncoding::Der => "        let len = \
+                        der_length((self.buf.len() - body) as u64);\n        \
+                        self.buf.splice(start..start, len);\n",
+                };
+                program += &self.helper_leave();
+            }
+            Fragment::Chunked(body) => {
+                // Expand the body, then take it back out of the buffer and
+                // emit it again as randomly sized chunks
+                program += "        let start = self.buf.len();\n";
+                program += &self.helper_enter();
+                program += &format!("        {}\n",
+                    self.child(*body, depth));
+                program += r#"        let body = self.buf.split_off(start);
+        let mut rest = &body[..];
+        while !rest.is_empty() {
+            let len = 1 + self.rand() % rest.len();
+            self.buf.extend_from_slice(format!("{:x}\r\n", len).as_bytes());
+            self.buf.extend_from_slice(&rest[..len]);
+            self.buf.extend_from_slice(b"\r\n");
+            rest = &rest[len..];
+        }
+        self.buf.extend_from_slice(b"0\r\n\r\n");
+"#;
+                program += &self.helper_leave();
+            }
+            Fragment::Capture { body, slot } |
+                    Fragment::Recall { body, slot } => {
+                // Recall the variable if it has been captured, otherwise
+                // capture it
+                if let Fragment::Recall { .. } = self.grammar.fragments[id.0] {
+                    program += &format!("        if let Some(value) = \
+                        self.vars[{}].take() {{\n", slot);
+                    program += "            \
+                        self.buf.extend_from_slice(&value);\n";
+                    program += &format!("            \
+                        self.vars[{}] = Some(value);\n", slot);
+                    program += "            return;\n";
+                    program += "        }\n";
+                }
+                program += "        let start = self.buf.len();\n";
+                program += &format!("        {}\n",
+                    self.child(*body, depth));
+                program += &format!("        self.vars[{}] = \
+                    Some(self.buf[start..].to_vec());\n", slot);
+            }
+            Fragment::Embed(body) => {
+                // Start over with the whole depth budget
+                let call = if self.unrolled.is_empty() ||
+                        self.is_inlined(*body) {
+                    self.call(*body, "0")
+                } else {
+                    self.traced(*body, self.invoke(*body, 0))
+                };
+                program += &format!("        {}\n", call);
+            }
+            &Fragment::Transform(body, transform) => {
+                program += &self.transform(transform,
+                    self.child(body, depth));
+            }
+            Fragment::Push(body) => {
+                // Expand the body, then push a copy of it onto the context
+                // stack
+                program += "        let start = self.buf.len();\n";
+                program += &format!("        {}\n",
+                    self.child(*body, depth));
+                program += "        \
+                    self.context.push(self.buf[start..].to_vec());\n";
+            }
+            Fragment::Message(_) | Fragment::Pop => {
+                program += &format!("        {}\n", self.call(id, ""));
+            }
+            Fragment::Terminal(value) => {
+                // Append the terminal value to the output buffer
+                program += &format!("        {}\n", self.terminal(value));
+            }
+            Fragment::Int(int) => program += &self.int(int),
+            Fragment::Float(float) => program += &self.float(float),
+            Fragment::Bytes(tree) => {
+                program += &match self.trees.entries[tree] {
+                    byte @ 0..=255 => {
+                        format!("        self.buf.push({});\n", byte)
+                    }
+                    node => format!("        let byte = \
+                        self.byte_tree({});\n        \
+                        self.buf.push(byte);\n", node - 256),
+                };
+            }
+            Fragment::Nop => {}
+        }
+
+        program
+    }
+
+    /// Get the Rust code run before a helper expands its children. When
+    /// generating for coverage feedback the nodes recorded for the children
+    /// would have stale offsets once the helper rewrites their output, so the
+    /// helper remembers where they start and drops them afterwards. The
+    /// helper is then only ever mutated as a whole. Message boundaries
+    /// within the helper are dropped the same way.
+    fn helper_enter(&self) -> String {
+        let mut program = String::new();
+        if self.output == Output::Feedback {
+            program += "        let first = self.nodes.len();\n";
+        }
+        if self.has_messages() {
+            program += "        let first_message = self.messages.len();\n";
+        }
+        program
+    }
+
+    /// Get the Rust code run after a helper rewrote the output of its
+    /// children, see `helper_enter`
+    fn helper_leave(&self) -> String {
+        let mut program = String::new();
+        if self.output == Output::Feedback {
+            program += "        self.nodes.truncate(first);\n";
+        }
+        if self.has_messages() {
+            program += "        self.messages.truncate(first_message);\n";
+        }
+        program
+    }
+
+    /// Get the Rust code which expands a body with the Rust code `call` and
+    /// rewrites its output with `transform`. This must match
+    /// `Transform::apply`.
+    fn transform(&self, transform: Transform, call: String) -> String {
+        let mut program = String::from("        let start = self.buf.len();\n");
+        program += &self.helper_enter();
+        program += &format!("        {}\n", call);
+        program += match transform {
+            Transform::RandomCase => "        \
+                for idx in start..self.buf.len() {
+            if self.buf[idx].is_ascii_alphabetic() && self.rand() % 2 == 0 {
+                self.buf[idx] ^= 0x20;
+            }
+        }\n",
+            Transform::UrlEncode => "        let body = \
+                self.buf.split_off(start);\n        \
+                url_encode(&body, &mut self.buf);\n",
+            Transform::Escape => "        let body = \
+                self.buf.split_off(start);\n        \
+                escape(&body, &mut self.buf);\n",
+            Transform::Base64 => "        let body = \
+                self.buf.split_off(start);\n        \
+                base64(&body, &mut self.buf);\n",
+            Transform::Hex => "        let body = \
+                self.buf.split_off(start);\n        \
+                hex(&body, &mut self.buf);\n",
+            Transform::Pack => "        let body = \
+                self.buf.split_off(start);\n        \
+                pack(&body, &mut self.buf);\n",
+        };
+        program += &self.helper_leave();
+        program
+    }
+
+    /// Get the Rust code which draws a random value for `int` and emits it.
+    /// This must match `RandomInt::value` and `RandomInt::encode`.
+    fn int(&self, int: &RandomInt) -> String {
+        let mut program = match (int.format, int.span) {
+            (IntFormat::Varint, 0) => "        \
+                let bits = self.rand() % 64 + 1;\n        \
+                let value = self.rand() as u64 >> (64 - bits);\n".into(),
+            (_, 0) => "        let value = self.rand() as u64;\n".into(),
+            (_, span) => format!("        let value = \
+                {:#x}u64.wrapping_add(self.rand() as u64 % {:#x});\n",
+                int.low, span),
+        };
+        program += &match int.format {
+            IntFormat::Le(bytes) => format!("        self.buf.\
+                extend_from_slice(&value.to_le_bytes()[..{}]);\n", bytes),
+            IntFormat::Be(bytes) => format!("        self.buf.\
+                extend_from_slice(&value.to_be_bytes()[{}..]);\n", 8 - bytes),
+            IntFormat::Varint => "        \
+                self.buf.extend_from_slice(&varint(value));\n".into(),
+            IntFormat::Decimal if int.signed => "        self.buf.\
+                extend_from_slice((value as i64).to_string().as_bytes());\n"
+                .into(),
+            IntFormat::Decimal => "        self.buf.\
+                extend_from_slice(value.to_string().as_bytes());\n".into(),
+        };
+        program
+    }
+
+    /// Get the Rust code which draws a random value for `float` and emits
+    /// it. This must match `RandomFloat::value` and `RandomFloat::encode`.
+    fn float(&self, float: &RandomFloat) -> String {
+        format!("        let unit = (self.rand() >> 11) as f64 / \
+            9007199254740992.;
+        let value = f64::from_bits({:#x}) + f64::from_bits({:#x}) * unit;
+        self.buf.extend_from_slice(value.to_string().as_bytes());\n",
+            float.low().to_bits(), float.width().to_bits())
+    }
+
+    /// Returns `true` if the grammar has `%message` boundaries
+    fn has_messages(&self) -> bool {
+        self.grammar.fragments.iter()
+            .any(|x| matches!(x, Fragment::Message(_)))
+    }
+
+    /// Append the Rust code for the body of the function for fragment `id`
+    /// to `program`, which expands a derivation of `size` uniformly at
+    /// random using the derivation `counts`. This makes exactly the same
+    /// choices as the interpreter does.
+    fn uniform(&self, counts: &Counts, id: FragmentId, mut program: String)
+            -> String {
+        let base = self.option_bases[id.0];
+
+        match &self.grammar.fragments[id.0] {
+            Fragment::NonTerminal(options) => {
+                // Weight each option by its derivations of the size
+                // remaining after this expansion
+                program += &format!("        match self.choose({}, |x| \
+                    COUNTS[TABLES[{} + x] as usize + size - 1]) {{\n",
+                    options.len(), base);
+                for (option_id, &option) in options.iter().enumerate() {
+                    program += &format!("            {} => {{ {} }}\n",
+                        option_id, self.call(option, "size - 1"));
+                }
+                program += "            _ => unreachable!(),\n";
+                program += "        }\n";
+            }
+            Fragment::Expression(expr) => {
+                // Split the size between the children in order, the last
+                // one gets whatever is left
+                program += "        let size = size - 1;\n";
+                for (child, &exp) in expr.iter().enumerate() {
+                    if child + 1 == expr.len() {
+                        program += &format!("        {}\n",
+                            self.call(exp, "size"));
+                        break;
+                    }
+
+                    program += &format!("        let first = \
+                        self.split({}, {}, size);\n", counts.fragment(exp),
+                        counts.suffixes[&(id, child + 1)]);
+                    program += &format!("        {}\n",
+                        self.call(exp, "first"));
+                    program += "        let size = size - first;\n";
+                }
+            }
+            Fragment::Loop { options, repeat } => {
+                // Weight the options by their tables, see `option_tables`
+                program += "        let mut size = size;\n";
+                program += "        loop {\n";
+                program += &format!("            match self.choose({}, |x| \
+                    COUNTS[TABLES[{} + x] as usize + size - 1]) {{\n",
+                    options.len(), base);
+                for (option_id, (&option, &repeat)) in
+                        options.iter().zip(repeat).enumerate() {
+                    if repeat {
+                        program += &format!("                {} => {{ \
+                            let first = self.split({}, {}, size - 1); \
+                            {} size -= 1 + first; }}\n", option_id,
+                            counts.fragment(option), counts.fragment(id),
+                            self.call(option, "first"));
+                    } else {
+                        program += &format!("                {} => {{ \
+                            {} return; }}\n", option_id,
+                            self.call(option, "size - 1"));
+                    }
+                }
+                program += "                _ => unreachable!(),\n";
+                program += "            }\n";
+                program += "        }\n";
+            }
+            Fragment::Terminal(value) => {
+                program += &format!("        {}\n", self.terminal(value));
+            }
+            Fragment::Int(int) => program += &self.int(int),
+            Fragment::Float(float) => program += &self.float(float),
+            Fragment::Embed(body) => {
+                program += &format!("        {}\n",
+                    self.call(*body, "size - 1"));
+            }
+            &Fragment::Transform(body, transform) => {
+                program += &self.transform(transform,
+                    self.call(body, "size - 1"));
+            }
+            Fragment::Push(body) => {
+                program += "        let start = self.buf.len();\n";
+                program += &format!("        {}\n",
+                    self.call(*body, "size - 1"));
+                program += "        \
+                    self.context.push(self.buf[start..].to_vec());\n";
+            }
+            Fragment::Message(_) | Fragment::Pop => {
+                program += &format!("        {}\n", self.call(id, ""));
+            }
+            Fragment::Length(..) | Fragment::Chunked(_) |
+                    Fragment::Capture { .. } | Fragment::Recall { .. } => {
+                // Rejected when counting derivations
+                unreachable!()
+            }
+            Fragment::Bytes(_) => {
+                // Byte samplers aren't used when sampling uniformly
+                unreachable!()
+            }
+            Fragment::Nop => {}
+        }
+
+        program
+    }
+
+    /// Append the function of fragment `id` with the body `body` to
+    /// `program`, specialized for being expanded at `depth` if it's not
+    /// `None`. Rare fragments are moved out of the way, the rest get
+    /// inlining hints from the size of their bodies, as rustc's own
+    /// heuristics don't work well with thousands of tiny generated
+    /// functions.
+    fn function(&self, program: &mut String, id: FragmentId,
+            depth: Option<usize>, body: &str) {
+        // Explain where the fragment comes from, and how much depth the
+        // fragments which are cut off by the depth limit need, for anyone
+        // reading the source
+        if let Some(origin) = &self.origins[id.0] {
+            *program += &format!("    /// {}\n", origin.describe());
+        }
+        if let Some(depth) = depth {
+            *program += &format!("    /// Unrolled at a depth of {}\n",
+                depth);
+        } else if self.counts.is_none() && self.recursive[id.0] {
+            *program += &match self.min_depths[id.0] {
+                Some(depth) => format!("    /// Needs a depth budget of \
+                    {} to expand completely\n", depth),
+                None => "    /// Can never expand completely\n"
+                    .to_string(),
+            };
+        }
+
+        // Unrolled copies only call copies for deeper depths, so they can
+        // be inlined even when the fragment is recursive
+        let lines = body.lines().count();
+        let recursive = self.recursive[id.0] && depth.is_none();
+        if self.cold[id.0] && !self.starts.contains(&id) {
+            *program += "    #[cold]\n    #[inline(never)]\n";
+        } else if lines <= INLINE_ALWAYS_MAX && !recursive {
+            *program += "    #[inline(always)]\n";
+        } else if lines >= INLINE_NEVER_MIN {
+            *program += "    #[inline(never)]\n";
+        }
+        *program += &match depth {
+            Some(depth) => format!("    fn {}_at_{}(&mut self) {{\n",
+                self.functions[id.0], depth),
+            None => format!("    fn {}(&mut self, {}: usize) {{\n",
+                self.functions[id.0],
+                if self.counts.is_some() { "size" } else { "depth" }),
+        };
+        *program += body;
+        *program += "    }\n";
+    }
+
+    /// Write the Rust source for the kind of output we're generating to
+    /// `out`. The source is streamed out a function at a time, so huge
+    /// grammars never hold all of it in memory.
+    fn program(&self, out: &mut impl std::io::Write) -> std::io::Result<()> {
+        let mut program = self.header();
+        program += &self.template(match self.output {
+            Output::Program  => runtime::BENCHMARK,
+            Output::Library | Output::CLibrary => runtime::LIBRARY,
+            Output::Feedback => runtime::FEEDBACK,
+        });
+        if self.output == Output::CLibrary {
+            program += runtime::C_API;
+        }
+        if matches!(self.output, Output::Program | Output::Feedback) {
+            program += runtime::LOG;
+            program += runtime::METRICS;
+        }
+        if self.options.trace {
+            program += runtime::TRACE;
+        } else if self.output == Output::Program {
+            program += runtime::NO_TRACE;
+        }
+
+        // Construct the fuzzer itself, which is shared by all outputs
+        program += &self.template(runtime::FUZZER);
+        program += &self.emit();
+        if self.byte_trees() {
+            program += runtime::BYTE_TREE;
+        }
+        if !self.walk.table.is_empty() {
+            program += &self.template(runtime::WALK);
+        }
+        if !self.pools.is_empty() {
+            program += runtime::POOL;
+        }
+        if !self.holes.is_empty() {
+            program += runtime::HOLE;
+        }
+
+        // Entry point which generates a whole input, starting from depth zero
+        // or the full size
+        if self.counts.is_some() {
+            program += runtime::UNIFORM;
+        }
+        // Inputs containing patterns to avoid are generated again, by a
+        // `start` wrapping the one generating a single input
+        let start_fn = if self.grammar.avoid.is_empty() {
+            "start"
+        } else {
+            program += &format!(r#"
+    fn start(&mut self) {{
+        let base = self.buf.len();
+        for _ in 0..{} {{
+            self.buf.truncate(base);
+            self.start_once();
+            let input = &self.buf[base..];
+            if !AVOID.iter().any(|x| input.windows(x.len()).any(|y| y == *x)) {{
+                break;
+            }}
+        }}
+    }}
+"#, AVOID_RETRIES);
+            "start_once"
+        };
+
+        let start = self.grammar.start.unwrap();
+        if self.output == Output::Feedback {
+            program += &format!(r#"
+    fn {}(&mut self) {{
+        self.steps = 0;
+        self.cutoffs = 0;
+        self.nodes.clear();
+        self.vars.clear();
+        self.vars.resize({}, None);
+        self.messages.clear();
+        self.context.clear();
+        self.expand({}, 0);
+    }}
+"#, start_fn, self.grammar.variables.len(), start.0);
+        } else {
+            // Programs built from several grammars start from the grammar
+            // picked at runtime
+            let size = self.counts.as_ref().map_or(0, |x| x.size);
+            let expand = match self.starts.as_slice() {
+                [start] => self.traced(*start, self.invoke(*start, size)),
+                starts => {
+                    let mut expand = String::from(
+                        "match GRAMMAR.load(Ordering::Relaxed) {\n");
+                    for (idx, start) in starts.iter().enumerate() {
+                        expand += &format!("            {} => {{ {} }}\n",
+                            idx, self.traced(*start,
+                                self.invoke(*start, size)));
+                    }
+                    expand + "            _ => unreachable!(),\n        }"
+                }
+            };
+            let trace = if self.options.trace {
+                "trace_input();\n        "
+            } else {
+                ""
+            };
+            program += &format!(r#"
+    fn {}(&mut self) {{
+        self.steps = 0;
+        self.cutoffs = 0;
+        self.vars.clear();
+        self.vars.resize({}, None);
+        self.messages.clear();
+        self.context.clear();
+        {}{}
+    }}
+"#, start_fn, self.grammar.variables.len(), trace, expand);
+        }
+
+        out.write_all(program.as_bytes())?;
+        program.clear();
+
+        // Determine which fragments need their own function. Small terminals
+        // and nops are emitted directly at their call sites instead, and
+        // the children of walked fragments are expanded by the walk.
+        let mut needs_function = vec![false; self.grammar.fragments.len()];
+        for start in self.starts.iter() {
+            needs_function[start.0] = true;
+        }
+        for (id, fragment) in self.grammar.fragments.iter().enumerate() {
+            if self.walked(FragmentId(id)) {
+                continue;
+            }
+            for &child in fragment.children() {
+                if !self.is_inlined(child) {
+                    needs_function[child.0] = true;
+                }
+            }
+        }
+
+        // Go through each fragment in the list of fragments
+        let functions = needs_function.iter().filter(|&&x| x).count();
+        let mut done = 0;
+        let mut body = String::new();
+        for (id, &needed) in needs_function.iter().enumerate() {
+            if !needed {
+                continue;
+            }
+
+            // Create a new function for this fragment, followed by its
+            // copies specialized for the depths it's unrolled at
+            let id = FragmentId(id);
+            let unrolled = self.unrolled
+                .range((id, 0)..(FragmentId(id.0 + 1), 0))
+                .map(|&(_, depth)| Some(depth));
+            for depth in std::iter::once(None).chain(unrolled) {
+                body.clear();
+                body = match &self.counts {
+                    Some(counts) => self.uniform(counts, id, body),
+                    None => self.descent(id, depth, body),
+                };
+                self.function(&mut program, id, depth, &body);
+                out.write_all(program.as_bytes())?;
+                program.clear();
+            }
+
+            // Report every tenth of the way through
+            done += 1;
+            if functions >= PROGRESS_MIN &&
+                    done * 10 / functions != (done - 1) * 10 / functions {
+                eprintln!("Generated {}% of {} fragment functions",
+                    done * 100 / functions, functions);
+            }
+        }
+
+        // Mutation needs to expand any recorded fragment again, which is
+        // every fragment with a function
+        if self.output == Output::Feedback {
+            program += r#"
+    fn expand(&mut self, fragment: u32, depth: usize) {
+        let node = self.enter(fragment, depth);
+        match fragment {
+"#;
+            for (id, _) in needs_function.iter().enumerate()
+                    .filter(|(_, &needed)| needed) {
+                program += &format!("            {} => self.{}(depth),\n",
+                    id, self.functions[id]);
+            }
+            program += "            _ => unreachable!(),\n";
+            program += "        }\n";
+            program += "        self.leave(node);\n";
+            program += "    }\n";
+        }
+        program += "}\n";
+        out.write_all(program.as_bytes())?;
+
+        // Emit the pool of terminal bytes, aligned to a cache line
+        write!(out, r#"
+#[repr(C, align({}))]
+struct Aligned([u8; {}]);
+
+static TERMINALS_ALIGNED: Aligned = Aligned(*"#, LARGE_TERMINAL_MIN,
+            self.pool.bytes.len())?;
+        self.pool.write_literal(out)?;
+        out.write_all(b");
+static TERMINALS: &[u8] = &TERMINALS_ALIGNED.0;
+")?;
+
+        // Emit the table of byte trees
+        if self.byte_trees() {
+            write!(out, "\nstatic BYTE_TREES: &[u32] = &{:?};\n",
+                self.trees.table)?;
+        }
+
+        // Emit the precomputed expansions
+        if !self.expansion_table.is_empty() {
+            write!(out, "\nstatic EXPANSIONS: &[u32] = &{:?};\n",
+                self.expansion_table)?;
+        }
+
+        // Emit the table of regular regions and the size of the stacks
+        // needed to walk it
+        if !self.walk.table.is_empty() {
+            write!(out, "\nstatic WALK: &[u32] = &{:?};\n",
+                self.walk.table)?;
+            write!(out, "\nconst WALK_DEPTH: usize = {};\n",
+                self.walk.depth)?;
+        }
+
+        // Emit the derivation counts and the option tables indexing them
+        // when sampling uniformly
+        if let Some(counts) = &self.counts {
+            write!(out, "\nstatic COUNTS: &[f64] = &{:?};\n",
+                counts.tables)?;
+            write!(out, "\nstatic TABLES: &[u32] = &{:?};\n",
+                self.option_tables)?;
+        }
+
+        // Patterns generated inputs must not contain
+        if !self.grammar.avoid.is_empty() {
+            let patterns: Vec<String> = self.grammar.avoid.iter()
+                .map(|x| format!("&{:?}", x)).collect();
+            write!(out, "\nstatic AVOID: &[&[u8]] = &[{}];\n",
+                patterns.join(", "))?;
+        }
+
+        // Number of options swarm testing picks from in the benchmark
+        if self.output == Output::Program {
+            write!(out, "\nconst SWARM_OPTIONS: usize = {};\n",
+                self.swarm.iter().flatten().flatten().count())?;
+        }
+
+        if self.options.tests {
+            out.write_all(self.template(runtime::TESTS).as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Get the C header describing the C API of `Output::CLibrary` libraries
+/// generated with `options`
+pub fn c_header(options: &GenOptions) -> String {
+    runtime::C_HEADER
+        .replace("__SEED__", &format!("{:#x}", DEFAULT_SEED))
+        .replace("__MAX_DEPTH__", &options.max_depth.to_string())
+}
+
+/// Generate the Rust source of a benchmark program built from several
+/// `grammars`, each paired with its name, which generates inputs from the
+/// grammar picked with `--grammar-name` at runtime. The grammars share the
+/// runtime and are combined into one fragment graph, with their rules
+/// prefixed by their names.
+pub fn multi_program<P: AsRef<Path>>(path: P,
+        grammars: &[(String, GrammarRust)], options: &GenOptions)
+        -> std::io::Result<()> {
+    let mut combined = GrammarRust::default();
+    let mut starts = Vec::new();
+    let mut cold = Vec::new();
+    for (name, grammar) in grammars.iter() {
+        starts.push(combined.append(grammar, &format!("{}:", name)));
+
+        // Expected expansions are relative to each grammar's own start
+        cold.extend(grammar.expected_expansions(options).iter()
+            .map(|&x| x < COLD_EXPANSIONS));
+    }
+
+    // Inputs are generated from the first grammar unless another is picked,
+    // which is also where the interpreter starts for the emitted tests
+    combined.start = starts.first().copied();
+
+    let names = grammars.iter().map(|(name, _)| name.clone()).collect();
+    let mut out = BufWriter::new(File::create(path)?);
+    combined.source(&mut out, options, Output::Program, starts, names,
+        cold)?;
+    std::io::Write::flush(&mut out)
+}
+
+impl GrammarRust {
+    /// Generate Rust source for `output` that can be built and will generate
+    /// random inputs
+    pub fn program<P: AsRef<Path>>(&self, path: P, options: &GenOptions,
+            output: Output) -> std::io::Result<()> {
+        // Uniform sampling weights its choices, so the expected expansions
+        // only apply to random descent
+        let cold = match options.uniform_size {
+            Some(_) => vec![false; self.fragments.len()],
+            None => self.expected_expansions(options).iter()
+                .map(|&x| x < COLD_EXPANSIONS).collect(),
+        };
+        let mut out = BufWriter::new(File::create(path)?);
+        self.source(&mut out, options, output, vec![self.start.unwrap()],
+            Vec::new(), cold)?;
+        std::io::Write::flush(&mut out)
+    }
+
+    /// Write the Rust source for `output` to `out`, starting from the start
+    /// fragment of every grammar in `starts` and marking the fragments in
+    /// `cold` as rarely expanded
+    fn source(&self, out: &mut impl std::io::Write, options: &GenOptions,
+            output: Output, starts: Vec<FragmentId>, names: Vec<String>,
+            cold: Vec<bool>) -> std::io::Result<()> {
+        let min_depths = self.min_depths();
+        // Weight every option of non-terminals by its derivations of the
+        // size remaining after the expansion. Options of loops are weighted
+        // the same if they finish the loop, and by their derivations
+        // combined with the rest of the loop if they repeat.
+        let counts = options.uniform_size.map(|x| self.counts(x))
+            .transpose()?;
+        let mut option_tables = Vec::new();
+        let mut option_bases = vec![0; self.fragments.len()];
+        for (idx, fragment) in self.fragments.iter().enumerate() {
+            let Some(counts) = &counts else { break };
+            let id = FragmentId(idx);
+            option_bases[idx] = option_tables.len();
+            let offsets: Vec<usize> = match fragment {
+                Fragment::NonTerminal(options) => {
+                    options.iter().map(|&x| counts.fragment(x)).collect()
+                }
+                Fragment::Loop { options, repeat } => {
+                    options.iter().zip(repeat).enumerate()
+                        .map(|(option, (&x, &repeat))| if repeat {
+                            counts.repeats[&(id, option)]
+                        } else {
+                            counts.fragment(x)
+                        }).collect()
+                }
+                _ => continue,
+            };
+            for offset in offsets {
+                option_tables.push(u32::try_from(offset).map_err(|_| {
+                    std::io::Error::other("Derivation count tables are too \
+                        large, use a smaller --uniform-size")
+                })?);
+            }
+        }
+
+        // Swarm testing in the benchmark program may disable any option of a
+        // non-terminal apart from the ones which finish the soonest, which
+        // are needed to finish inputs near the depth limit
+        let mut swarm_options = 0;
+        let swarm = self.fragments.iter().map(|fragment| match fragment {
+            Fragment::NonTerminal(options) => {
+                let soonest = options.iter().filter_map(|x| min_depths[x.0])
+                    .min();
+                let swarm = output == Output::Program && counts.is_none() &&
+                    soonest.is_some();
+                options.iter().map(|x| {
+                    (swarm && min_depths[x.0] != soonest).then(|| {
+                        swarm_options += 1;
+                        swarm_options - 1
+                    })
+                }).collect()
+            }
+            _ => Vec::new(),
+        }).collect();
+
+        // Name functions after the rules they come from
+        let origins = Origin::find(self);
+        let functions = origins.iter().enumerate().map(|(id, origin)| {
+            match origin.as_ref().map(Origin::slug) {
+                Some(slug) if !slug.is_empty() => {
+                    format!("fragment_{}_{}", id, slug)
+                }
+                _ => format!("fragment_{}", id),
+            }
+        }).collect();
+
+        // Pooled rules are expanded through their functions, which rules
+        // emitted in place don't have. Their pools can't replay anything
+        // but the bytes they emitted.
+        let mut pools = BTreeMap::new();
+        for name in options.pool.iter() {
+            let id = *self.name_to_fragment.get(name).ok_or_else(|| {
+                std::io::Error::other(format!("Unknown rule {} to pool",
+                    name))
+            })?;
+            if matches!(self.fragments[id.0], Fragment::Terminal(_) |
+                    Fragment::Message(_) | Fragment::Pop | Fragment::Nop) {
+                eprintln!("warning: {} is emitted in place and can't be \
+                    pooled", name);
+                continue;
+            }
+            if self.reaches_state(id) {
+                eprintln!("warning: {} captures, recalls or pushes values or \
+                    emits messages, which its pool won't repeat", name);
+            }
+            let slot = pools.len();
+            pools.entry(id).or_insert(slot);
+        }
+
+        // Holes need functions to be filled in too. Rules which were merged
+        // into the same fragment are filled by the same hole.
+        let mut holes = BTreeMap::new();
+        let mut hole_names = Vec::new();
+        for name in options.holes.iter() {
+            let id = *self.name_to_fragment.get(name).ok_or_else(|| {
+                std::io::Error::other(format!("Unknown rule {} to make a \
+                    hole", name))
+            })?;
+            if matches!(self.fragments[id.0], Fragment::Terminal(_) |
+                    Fragment::Message(_) | Fragment::Pop | Fragment::Nop) {
+                eprintln!("warning: {} is emitted in place and can't be a \
+                    hole", name);
+                continue;
+            }
+            if self.reaches_state(id) {
+                eprintln!("warning: {} captures, recalls or pushes values or \
+                    emits messages, which filling it in skips", name);
+            }
+            let hole = holes.len();
+            let hole = *holes.entry(id).or_insert(hole);
+            if !hole_names.iter().any(|(x, _)| x == name) {
+                hole_names.push((name.clone(), hole));
+            }
+        }
+
+        let mut codegen = Codegen {
+            grammar: self,
+            options,
+            pool: TerminalPool::default(),
+            trees: TreePool::default(),
+            regular: Vec::new(),
+            walk: WalkTable::default(),
+            pools,
+            holes,
+            hole_names,
+            expansions: BTreeMap::new(),
+            expansion_table: Vec::new(),
+            recursive: self.recursive(),
+            counts,
+            bands: self.depth_bands(&min_depths),
+            steering: match (options.target_size, options.uniform_size) {
+                (Some(_), None) => self.steering(options),
+                _ => Vec::new(),
+            },
+            option_tables,
+            option_bases,
+            swarm,
+            min_depths,
+            cold,
+            starts,
+            names,
+            functions,
+            origins,
+            unrolled: BTreeSet::new(),
+            output,
+            test_hashes: Vec::new(),
+        };
+
+        // Pool up all of the terminals and byte trees
+        for fragment in self.fragments.iter() {
+            match fragment {
+                Fragment::Terminal(value) => {
+                    codegen.pool.add(value);
+                }
+                Fragment::Bytes(tree) => {
+                    codegen.trees.add(tree);
+                }
+                _ => {}
+            }
+        }
+
+        // Precomputed expansions are emitted from the terminal pool too
+        if options.precompute > 0 {
+            let precomputed = self.precomputed(options);
+            for (id, expansions) in precomputed.iter().enumerate() {
+                let Some(expansions) = expansions else { continue };
+                codegen.expansions.insert(FragmentId(id),
+                    (codegen.expansion_table.len() / 2, expansions.len()));
+                for expansion in expansions.iter() {
+                    let offset = codegen.pool.add(expansion);
+                    codegen.expansion_table.extend([offset as u32,
+                        expansion.len() as u32]);
+                }
+            }
+        }
+
+        codegen.regular = codegen.regular();
+        codegen.walk = codegen.walk_table();
+        codegen.unrolled = codegen.unrolled();
+
+        // The emitted tests check the compiled code against the interpreter
+        // at every max depth it can be run with. The depth limit doesn't
+        // apply when sampling uniformly, and steering towards a target size
+        // is worked out for the build time max depth, so only that one is
+        // checked then.
+        if options.tests {
+            let depths = match (options.uniform_size, options.target_size) {
+                (None, None) => 0..=options.max_depth,
+                _ => options.max_depth..=options.max_depth,
+            };
+            for max_depth in depths {
+                let options = GenOptions { max_depth, ..options.clone() };
+                codegen.test_hashes.push((max_depth,
+                    selftest::interpreter_hash(self, &options,
+                        TEST_INPUTS)?));
+            }
+        }
+
+        codegen.program(out)
+    }
+}
@@ -0,0 +1,144 @@
+//! Structural comparison of two grammars. Rather than comparing the json
+//! text, this compares rules by name and their alternatives as sets, so
+//! reformatting and reordering don't show up, and reports alternatives whose
+//! only difference is in their terminals as changed rather than as one
+//! removed and one added.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::grammar::{Directives, Grammar};
+
+/// A single difference between two grammars
+#[derive(Debug)]
+pub enum Change {
+    /// A rule only exists in the new grammar
+    RuleAdded(String, Vec<Vec<String>>),
+
+    /// A rule only exists in the old grammar
+    RuleRemoved(String),
+
+    /// An alternative of a rule only exists in the new grammar
+    AltAdded(String, Vec<String>),
+
+    /// An alternative of a rule only exists in the old grammar
+    AltRemoved(String, Vec<String>),
+
+    /// An alternative of a rule references the same rules in both grammars,
+    /// but has different terminals
+    AltChanged(String, Vec<String>, Vec<String>),
+
+    /// A directive was added, removed or changed, with its old and new value
+    Directive(String, Option<Vec<Vec<String>>>, Option<Vec<Vec<String>>>),
+}
+
+/// Format an alternative or a list of them as json, which keeps terminals
+/// containing whitespace or quotes unambiguous
+fn json<T: serde::Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap()
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::RuleAdded(rule, alts) => {
+                write!(f, "+ {}: {}", rule, json(alts))
+            }
+            Change::RuleRemoved(rule) => write!(f, "- {}", rule),
+            Change::AltAdded(rule, alt) => {
+                write!(f, "  {}: + {}", rule, json(alt))
+            }
+            Change::AltRemoved(rule, alt) => {
+                write!(f, "  {}: - {}", rule, json(alt))
+            }
+            Change::AltChanged(rule, old, new) => {
+                write!(f, "  {}: ~ {} -> {}", rule, json(old), json(new))
+            }
+            Change::Directive(name, None, Some(new)) => {
+                write!(f, "+ {}: {}", name, json(new))
+            }
+            Change::Directive(name, Some(_), None) => write!(f, "- {}", name),
+            Change::Directive(name, old, new) => {
+                write!(f, "~ {}: {} -> {}", name, json(old), json(new))
+            }
+        }
+    }
+}
+
+/// Returns `true` if `a` and `b` only differ in their terminals, that is
+/// they have the same length and the same references in the same places
+fn same_shape(old: &Grammar, new: &Grammar, a: &[String], b: &[String])
+        -> bool {
+    let is_rule = |token: &String| {
+        old.0.contains_key(token) || new.0.contains_key(token) ||
+            token.starts_with('%')
+    };
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| {
+        a == b || (!is_rule(a) && !is_rule(b))
+    })
+}
+
+/// Compare the alternatives of `rule`, which is defined by both grammars
+fn diff_rule(old: &Grammar, new: &Grammar, rule: &str,
+        changes: &mut Vec<Change>) {
+    // Alternatives are compared as multisets, so take out the ones which
+    // appear in both as many times as they do
+    let mut removed: Vec<&Vec<String>> = old.0[rule].iter().collect();
+    let mut added = Vec::new();
+    for alt in &new.0[rule] {
+        match removed.iter().position(|x| *x == alt) {
+            Some(idx) => { removed.remove(idx); }
+            None => added.push(alt),
+        }
+    }
+
+    // Pair up the remaining alternatives which only differ in terminals
+    for old_alt in removed {
+        match added.iter().position(|x| same_shape(old, new, old_alt, x)) {
+            Some(idx) => {
+                let new_alt = added.remove(idx);
+                changes.push(Change::AltChanged(rule.to_string(),
+                    old_alt.clone(), new_alt.clone()));
+            }
+            None => changes.push(Change::AltRemoved(rule.to_string(),
+                old_alt.clone())),
+        }
+    }
+    for alt in added {
+        changes.push(Change::AltAdded(rule.to_string(), alt.clone()));
+    }
+}
+
+/// Compare the `old` and `new` grammars along with their directives,
+/// returning the differences in the order of the rules
+pub fn diff(old: &Grammar, old_directives: &Directives, new: &Grammar,
+        new_directives: &Directives) -> Vec<Change> {
+    let mut changes = Vec::new();
+
+    // Directives configure the whole grammar, so they come first
+    let mut names: Vec<&String> = old_directives.0.keys()
+        .chain(new_directives.0.keys()).collect();
+    names.sort();
+    names.dedup();
+    for name in names {
+        let (a, b) = (old_directives.0.get(name), new_directives.0.get(name));
+        if a != b {
+            changes.push(Change::Directive(name.clone(), a.cloned(),
+                b.cloned()));
+        }
+    }
+
+    let rules: BTreeMap<&String, (bool, bool)> = old.0.keys()
+        .map(|x| (x, (true, new.0.contains_key(x))))
+        .chain(new.0.keys().map(|x| (x, (old.0.contains_key(x), true))))
+        .collect();
+    for (rule, presence) in rules {
+        match presence {
+            (true, true) => diff_rule(old, new, rule, &mut changes),
+            (true, false) => changes.push(Change::RuleRemoved(rule.clone())),
+            (false, _) => changes.push(Change::RuleAdded(rule.clone(),
+                new.0[rule].clone())),
+        }
+    }
+
+    changes
+}
@@ -0,0 +1,623 @@
+//! Exporting a `GrammarRust` to other formats for inspection or use by other
+//! tools
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use crate::grammar::{helper, is_reference_shaped, terminal_token};
+use crate::grammar::{Fragment, FragmentId};
+use crate::grammar::{Grammar, GrammarRust};
+use crate::scalars::RandomFloat;
+
+/// Maximum number of bytes of a terminal to show in a graph label
+const MAX_LABEL_LEN: usize = 32;
+
+/// Maximum length of a dictionary token. libFuzzer rejects dictionaries with
+/// anything longer, and AFL's limit is higher.
+const MAX_DICT_TOKEN_LEN: usize = 64;
+
+/// Escape a string such that it can be placed in a double quoted DOT label
+fn dot_escape(string: &str) -> String {
+    let mut ret = String::new();
+    for chr in string.chars() {
+        match chr {
+            '"'  => ret += "\\\"",
+            '\\' => ret += "\\\\",
+            '\n' => ret += "\\\\n",
+            '\r' => ret += "\\\\r",
+            '\t' => ret += "\\\\t",
+            _ if chr.is_control() => {
+                write!(ret, "\\\\x{:02x}", chr as u32).unwrap();
+            }
+            _ => ret.push(chr),
+        }
+    }
+    ret
+}
+
+/// A symbol in an alternative of a `ContextFree` grammar
+enum Symbol {
+    /// Reference to the rule for the fragment with this index
+    Rule(usize),
+
+    /// Terminal bytes
+    Bytes(Vec<u8>),
+}
+
+/// A plain context-free version of a grammar, for exporting to tools which
+/// have no equivalent of the generation helpers
+struct ContextFree {
+    /// Fragments which became rules, starting with the start rule, along
+    /// with their alternatives
+    rules: Vec<(usize, Vec<Vec<Symbol>>)>,
+
+    /// Unique names of the rules, keyed by fragment index
+    names: BTreeMap<usize, String>,
+
+    /// Helpers whose effect was lost, as their body is used as is
+    dropped: BTreeSet<&'static str>,
+
+    /// Random scalars replaced by their smallest value
+    scalars: BTreeSet<String>,
+}
+
+impl ContextFree {
+    /// Get warnings about everything which couldn't be exported faithfully
+    fn warnings(&self, format: &str) -> Vec<String> {
+        let mut warnings: Vec<String> = self.dropped.iter().map(|helper| {
+            format!("{} has no equivalent in {}, its rule is used as is",
+                helper, format)
+        }).collect();
+        for scalar in self.scalars.iter() {
+            warnings.push(format!("{} has no equivalent in {}, only its \
+                smallest value is used", scalar, format));
+        }
+
+        let binary = self.rules.iter().flat_map(|(_, alts)| alts).flatten()
+            .any(|x| matches!(x, Symbol::Bytes(x)
+                if std::str::from_utf8(x).is_err()));
+        if binary {
+            warnings.push(format!("{} grammars are text, terminals which \
+                aren't valid UTF-8 were converted lossily", format));
+        }
+        warnings
+    }
+}
+
+impl GrammarRust {
+    /// Lower the fragment graph to a plain context-free grammar. Helpers are
+    /// replaced by the rules they apply to. The start rule is named `start`
+    /// and every other rule gets its original name, or `fragment_N`, passed
+    /// through `rename` and made unique among the rules and terminals.
+    fn context_free(&self, start: &str, rename: impl Fn(&str) -> String)
+            -> ContextFree {
+        // Get the symbol used to refer to `id` from an alternative
+        let token = |id: FragmentId| match &self.fragments[id.0] {
+            Fragment::Terminal(value) | Fragment::Message(value) => {
+                Symbol::Bytes(value.clone())
+            }
+            _ => Symbol::Rule(id.0),
+        };
+
+        // Get the symbols of one alternative which expands `id`
+        let alternative = |id: FragmentId| match &self.fragments[id.0] {
+            Fragment::Expression(expr) => {
+                expr.iter().map(|&x| token(x)).collect()
+            }
+            Fragment::Nop => Vec::new(),
+            _ => vec![token(id)],
+        };
+
+        let mut cf = ContextFree {
+            rules: Vec::new(),
+            names: BTreeMap::new(),
+            dropped: BTreeSet::new(),
+            scalars: BTreeSet::new(),
+        };
+        let mut queue = vec![self.start.unwrap().0];
+        let mut seen = BTreeSet::new();
+        while let Some(id) = queue.pop() {
+            if !seen.insert(id) {
+                continue;
+            }
+
+            let alts = match &self.fragments[id] {
+                Fragment::NonTerminal(options) => {
+                    options.iter().map(|&x| alternative(x)).collect()
+                }
+                Fragment::Loop { options, repeat } => {
+                    // Turn repeating options back into right recursion
+                    options.iter().zip(repeat).map(|(&x, &repeat)| {
+                        let mut alt = alternative(x);
+                        if repeat {
+                            alt.push(Symbol::Rule(id));
+                        }
+                        alt
+                    }).collect()
+                }
+                Fragment::Length([mid, body], encoding) => {
+                    cf.dropped.insert(encoding.helper());
+                    let mut alt = alternative(*mid);
+                    alt.push(token(*body));
+                    vec![alt]
+                }
+                Fragment::Chunked(body) => {
+                    cf.dropped.insert("%chunked");
+                    vec![vec![token(*body)]]
+                }
+                Fragment::Recall { body, .. } => {
+                    cf.dropped.insert("%recall");
+                    vec![vec![token(*body)]]
+                }
+                Fragment::Transform(body, transform) => {
+                    cf.dropped.insert(transform.helper());
+                    vec![vec![token(*body)]]
+                }
+                Fragment::Pop => {
+                    cf.dropped.insert("%pop");
+                    vec![Vec::new()]
+                }
+
+                Fragment::Int(int) => {
+                    cf.scalars.insert(int.name());
+                    vec![vec![Symbol::Bytes(int.encode(int.low))]]
+                }
+                Fragment::Float(float) => {
+                    cf.scalars.insert(float.name());
+                    vec![vec![Symbol::Bytes(RandomFloat::encode(float.low()))]]
+                }
+                Fragment::Bytes(tree) => {
+                    tree.bytes().into_iter()
+                        .map(|x| vec![Symbol::Bytes(vec![x])]).collect()
+                }
+                Fragment::Capture { body, .. } | Fragment::Embed(body) |
+                        Fragment::Push(body) => {
+                    vec![vec![token(*body)]]
+                }
+                Fragment::Expression(_) | Fragment::Terminal(_) |
+                        Fragment::Message(_) | Fragment::Nop => {
+                    vec![alternative(FragmentId(id))]
+                }
+            };
+
+            // Visit the referenced rules in order
+            let refs = alts.iter().flatten().rev().filter_map(|x| match x {
+                Symbol::Rule(x) => Some(*x),
+                Symbol::Bytes(_) => None,
+            });
+            queue.extend(refs);
+            cf.rules.push((id, alts));
+        }
+
+        // Name the rules, making sure a name is never mistaken for another
+        // rule or a terminal
+        let original: BTreeMap<usize, &str> = self.name_to_fragment.iter()
+            .map(|(name, id)| {
+                (id.0, name.trim_start_matches('<').trim_end_matches('>'))
+            }).collect();
+        let mut used: BTreeSet<String> = self.fragments.iter()
+            .filter_map(|x| match x {
+                Fragment::Terminal(value) => {
+                    Some(String::from_utf8_lossy(value).into_owned())
+                }
+                _ => None,
+            }).collect();
+        used.insert(rename(start));
+        for (ii, &(id, _)) in cf.rules.iter().enumerate() {
+            if ii == 0 {
+                cf.names.insert(id, rename(start));
+                continue;
+            }
+
+            let mut base = original.get(&id).map_or_else(
+                || format!("fragment_{}", id), |x| x.to_string());
+            while used.contains(&rename(&base)) {
+                base.push('_');
+            }
+            used.insert(rename(&base));
+            cf.names.insert(id, rename(&base));
+        }
+        cf
+    }
+
+    /// Convert the grammar to the json format used by AFL++'s
+    /// Grammar-Mutator, which starts at `<entry>`. Returns the grammar and
+    /// warnings about anything which couldn't be converted faithfully.
+    pub fn grammar_mutator(&self) -> (String, Vec<String>) {
+        let cf = self.context_free("entry", |x| format!("<{}>", x));
+
+        let mut grammar: BTreeMap<&str, Vec<Vec<String>>> = BTreeMap::new();
+        for (id, alts) in &cf.rules {
+            grammar.insert(&cf.names[id], alts.iter().map(|alt| {
+                alt.iter().map(|x| match x {
+                    Symbol::Rule(x) => cf.names[x].clone(),
+                    Symbol::Bytes(x) => {
+                        String::from_utf8_lossy(x).into_owned()
+                    }
+                }).collect()
+            }).collect());
+        }
+
+        (serde_json::to_string_pretty(&grammar).unwrap(),
+            cf.warnings("Grammar-Mutator"))
+    }
+
+    /// Convert the grammar to the json format used by Nautilus and AFL++'s
+    /// Nautilus mode, a list of `[NONTERMINAL, RULE]` pairs starting at
+    /// `START`, where rules refer to non-terminals as `{NAME}`. Returns the
+    /// grammar and warnings about anything which couldn't be converted
+    /// faithfully.
+    pub fn nautilus(&self) -> (String, Vec<String>) {
+        // Non-terminal names can't contain braces or backslashes, so stick
+        // to identifier characters
+        let cf = self.context_free("START", |x| {
+            x.chars().map(|x| {
+                if x.is_ascii_alphanumeric() || x == '-' { x } else { '_' }
+            }).collect()
+        });
+
+        let mut rules = Vec::new();
+        for (id, alts) in &cf.rules {
+            for alt in alts {
+                let mut rule = String::new();
+                for symbol in alt {
+                    match symbol {
+                        Symbol::Rule(x) => write!(rule, "{{{}}}",
+                            cf.names[x]).unwrap(),
+                        Symbol::Bytes(x) => {
+                            // Literal braces are escaped with a backslash
+                            for chr in String::from_utf8_lossy(x).chars() {
+                                if chr == '{' || chr == '}' {
+                                    rule.push('\\');
+                                }
+                                rule.push(chr);
+                            }
+                        }
+                    }
+                }
+                rules.push(serde_json::to_string(&[&cf.names[id], &rule])
+                    .unwrap());
+            }
+        }
+
+        // One rule per line reads better than fully pretty printed json
+        (format!("[\n    {}\n]\n", rules.join(",\n    ")),
+            cf.warnings("Nautilus"))
+    }
+
+    /// Render the fragment graph in Graphviz DOT format. Non-terminals are
+    /// drawn as diamonds with dashed edges to each option, expressions as
+    /// boxes with solid edges numbered in expansion order, and terminals as
+    /// ellipses containing their bytes.
+    pub fn dot(&self) -> String {
+        // Get names for all fragments which are rules
+        let names: BTreeMap<usize, &String> = self.name_to_fragment.iter()
+            .map(|(name, id)| (id.0, name)).collect();
+
+        let mut dot = String::new();
+        dot += "digraph grammar {\n";
+        dot += "    node [fontname=\"monospace\"];\n";
+
+        for (id, fragment) in self.fragments.iter().enumerate() {
+            // Label the node with the rule name if it has one
+            let mut label = match names.get(&id) {
+                Some(name) => format!("{}\\n#{}", dot_escape(name), id),
+                None       => format!("#{}", id),
+            };
+
+            let shape = match fragment {
+                Fragment::NonTerminal(_) => "diamond",
+                Fragment::Loop { .. }    => "doubleoctagon",
+                Fragment::Expression(_)  => "box",
+                Fragment::Length(..)      => "house",
+                Fragment::Chunked(_)     => "hexagon",
+                Fragment::Transform(..)  => "octagon",
+                Fragment::Capture { .. } => "invhouse",
+                Fragment::Recall { .. }  => "invtriangle",
+                Fragment::Embed(_)       => "component",
+                Fragment::Message(_)     => "square",
+                Fragment::Push(_)        => "cds",
+                Fragment::Pop            => "invtrapezium",
+                Fragment::Int(int) => {
+                    write!(label, "\\n{}", dot_escape(&int.name())).unwrap();
+                    "parallelogram"
+                }
+                Fragment::Float(float) => {
+                    write!(label, "\\n{}", dot_escape(&float.name()))
+                        .unwrap();
+                    "parallelogram"
+                }
+                Fragment::Bytes(tree) => {
+                    write!(label, "\\n{} bytes", tree.bytes().len())
+                        .unwrap();
+                    "parallelogram"
+                }
+                Fragment::Terminal(value) => {
+                    // Show a truncated version of the terminal value
+                    let value = String::from_utf8_lossy(value);
+                    let mut shown: String =
+                        value.chars().take(MAX_LABEL_LEN).collect();
+                    if shown.len() < value.len() {
+                        shown += "...";
+                    }
+                    write!(label, "\\n\\\"{}\\\"", dot_escape(&shown))
+                        .unwrap();
+                    "ellipse"
+                }
+                Fragment::Nop => "point",
+            };
+
+            // Highlight the start node
+            let style = if Some(id) == self.start.map(|x| x.0) {
+                ", style=bold"
+            } else {
+                ""
+            };
+
+            writeln!(dot, "    f{} [shape={}, label=\"{}\"{}];",
+                id, shape, label, style).unwrap();
+
+            // Emit the edges from this fragment
+            match fragment {
+                Fragment::NonTerminal(options) => {
+                    for option in options {
+                        writeln!(dot, "    f{} -> f{} [style=dashed];",
+                            id, option.0).unwrap();
+                    }
+                }
+                Fragment::Expression(_) | Fragment::Length(..) => {
+                    for (idx, exp) in fragment.children().iter().enumerate() {
+                        writeln!(dot, "    f{} -> f{} [label=\"{}\"];",
+                            id, exp.0, idx).unwrap();
+                    }
+                }
+                Fragment::Loop { options, repeat } => {
+                    for (option, &repeat) in options.iter().zip(repeat) {
+                        writeln!(dot, "    f{} -> f{} [style=dashed{}];",
+                            id, option.0,
+                            if repeat { ", label=\"repeat\"" } else { "" })
+                            .unwrap();
+                    }
+                }
+                Fragment::Chunked(body) | Fragment::Capture { body, .. } |
+                        Fragment::Recall { body, .. } |
+                        Fragment::Embed(body) | Fragment::Push(body) |
+                        Fragment::Transform(body, _) => {
+                    writeln!(dot, "    f{} -> f{};", id, body.0).unwrap();
+                }
+                Fragment::Terminal(_) | Fragment::Message(_) |
+                    Fragment::Pop | Fragment::Int(_) | Fragment::Float(_) |
+                    Fragment::Bytes(_) | Fragment::Nop => {}
+            }
+        }
+
+        dot += "}\n";
+        dot
+    }
+
+    /// Build an AFL style dictionary, usable by AFL and libFuzzer, of every
+    /// distinct terminal and message separator reachable from the start
+    /// rule. Empty tokens and tokens longer than `MAX_DICT_TOKEN_LEN` bytes
+    /// are left out. Returns the dictionary and the number of tokens in it.
+    pub fn dictionary(&self) -> (String, usize) {
+        let mut tokens = BTreeSet::new();
+        let mut seen = vec![false; self.fragments.len()];
+        let mut queue = vec![self.start.unwrap()];
+        while let Some(id) = queue.pop() {
+            if std::mem::replace(&mut seen[id.0], true) {
+                continue;
+            }
+
+            let fragment = &self.fragments[id.0];
+            if let Fragment::Terminal(value) | Fragment::Message(value) =
+                    fragment {
+                if !value.is_empty() && value.len() <= MAX_DICT_TOKEN_LEN {
+                    tokens.insert(value.as_slice());
+                }
+            }
+            queue.extend_from_slice(fragment.children());
+        }
+
+        // Tokens are double quoted with backslashes, quotes and anything
+        // unprintable escaped in hex
+        let mut dict = String::new();
+        for token in &tokens {
+            dict.push('"');
+            for &byte in token.iter() {
+                match byte {
+                    b'"' | b'\\' | 0x00..=0x1f | 0x7f..=0xff => {
+                        write!(dict, "\\x{:02x}", byte).unwrap();
+                    }
+                    _ => dict.push(byte as char),
+                }
+            }
+            dict += "\"\n";
+        }
+        (dict, tokens.len())
+    }
+
+    /// Convert the fragment graph back into the json grammar format. Rules
+    /// keep their original names where they still exist and every other
+    /// fragment which needs a rule gets a synthesized `<fragment_N>` name.
+    /// Terminals are inlined into the alternatives which use them.
+    pub fn to_grammar(&self) -> Grammar {
+        /// State used while converting
+        struct Converter<'a> {
+            /// Grammar being converted
+            gram: &'a GrammarRust,
+
+            /// Names assigned to fragments which have been given rules
+            names: BTreeMap<usize, String>,
+
+            /// Fragments which still need rules to be emitted
+            queue: Vec<FragmentId>,
+
+            /// Variable names which need a rule of their own expanding to a
+            /// fragment, as the fragment has a different name
+            aliases: BTreeMap<String, FragmentId>,
+        }
+
+        impl Converter<'_> {
+            /// Get the rule name for a fragment, assigning one if it
+            /// doesn't have one yet, and queue the fragment for emission
+            fn name(&mut self, id: FragmentId) -> String {
+                self.queue.push(id);
+                if let Some(name) = self.names.get(&id.0) {
+                    return name.clone();
+                }
+
+                // Synthesize a name which doesn't collide with a real rule
+                let mut name = format!("<fragment_{}>", id.0);
+                while self.gram.name_to_fragment.contains_key(&name) {
+                    name.insert(name.len() - 1, '_');
+                }
+
+                self.names.insert(id.0, name.clone());
+                name
+            }
+
+            /// Get the rule name for the variable `slot` which expands to
+            /// `body`. Captures and recalls of a variable must all use the
+            /// same name, even if their bodies were copied or renamed.
+            fn variable(&mut self, body: FragmentId, slot: usize) -> String {
+                let name = self.gram.variables[slot].clone();
+                if self.gram.name_to_fragment.get(&name) == Some(&body) {
+                    self.name(body)
+                } else {
+                    self.aliases.insert(name.clone(), body);
+                    name
+                }
+            }
+
+            /// Get the token used to refer to `id` from an alternative
+            fn token(&mut self, id: FragmentId) -> String {
+                // Inline terminals as long as they can't be mistaken for a
+                // reference or a helper when loaded back in
+                match &self.gram.fragments[id.0] {
+                    Fragment::Terminal(value) => {
+                        let value = terminal_token(value);
+                        if !is_reference_shaped(&value) &&
+                                helper(&value).is_none() &&
+                                value != "%message" && value != "%pop" &&
+                                !self.gram.name_to_fragment
+                                    .contains_key(&value) {
+                            return value;
+                        }
+                    }
+                    Fragment::Message(_) => return "%message".into(),
+                    Fragment::Pop => return "%pop".into(),
+                    Fragment::Int(int) => return int.name(),
+                    Fragment::Float(float) => return float.name(),
+                    _ => {}
+                }
+
+                self.name(id)
+            }
+
+            /// Get the tokens for one alternative which expands `id`
+            fn alternative(&mut self, id: FragmentId) -> Vec<String> {
+                match &self.gram.fragments[id.0] {
+                    Fragment::Expression(expr) => {
+                        expr.iter().map(|&x| self.token(x)).collect()
+                    }
+                    Fragment::Nop => Vec::new(),
+                    _ => vec![self.token(id)],
+                }
+            }
+        }
+
+        let mut conv = Converter {
+            gram: self,
+            names: self.name_to_fragment.iter()
+                .map(|(name, id)| (id.0, name.clone())).collect(),
+            queue: Vec::new(),
+            aliases: BTreeMap::new(),
+        };
+
+        // Start by emitting the start rule
+        let mut grammar = Grammar::default();
+        conv.name(self.start.unwrap());
+
+        loop {
+            // Once every rule is emitted, emit the variables which still
+            // need a rule of their own
+            if conv.queue.is_empty() {
+                let alias = conv.aliases.iter()
+                    .find(|(name, _)| !grammar.0.contains_key(*name))
+                    .map(|(name, &body)| (name.clone(), body));
+                let Some((name, body)) = alias else { break };
+                let alt = conv.alternative(body);
+                grammar.0.insert(name, vec![alt]);
+                continue;
+            }
+            let id = conv.queue.pop().unwrap();
+
+            let name = conv.names[&id.0].clone();
+            if grammar.0.contains_key(&name) {
+                continue;
+            }
+
+            let alts = match &self.fragments[id.0] {
+                Fragment::NonTerminal(options) => {
+                    options.iter().map(|&x| conv.alternative(x)).collect()
+                }
+                Fragment::Loop { options, repeat } => {
+                    // Turn repeating options back into right recursion
+                    options.iter().zip(repeat).map(|(&x, &repeat)| {
+                        let mut alt = conv.alternative(x);
+                        if repeat {
+                            alt.push(name.clone());
+                        }
+                        alt
+                    }).collect()
+                }
+                Fragment::Expression(_) | Fragment::Nop => {
+                    vec![conv.alternative(id)]
+                }
+                Fragment::Length([mid, body], encoding) => {
+                    // The body must be referenced by name for the helper
+                    let body = conv.name(*body);
+                    let mut alt =
+                        vec![format!("{}({})", encoding.helper(), body)];
+                    alt.extend(conv.alternative(*mid));
+                    alt.push(body);
+                    vec![alt]
+                }
+                Fragment::Chunked(body) => {
+                    vec![vec![format!("%chunked({})", conv.name(*body))]]
+                }
+                Fragment::Capture { body, slot } => {
+                    vec![vec![format!("%capture({})",
+                        conv.variable(*body, *slot))]]
+                }
+                Fragment::Recall { body, slot } => {
+                    vec![vec![format!("%recall({})",
+                        conv.variable(*body, *slot))]]
+                }
+                Fragment::Embed(body) => {
+                    vec![vec![format!("%embed({})", conv.name(*body))]]
+                }
+                Fragment::Push(body) => {
+                    vec![vec![format!("%push({})", conv.name(*body))]]
+                }
+                Fragment::Transform(body, transform) => {
+                    vec![vec![format!("{}({})", transform.helper(),
+                        conv.name(*body))]]
+                }
+                Fragment::Message(_) => vec![vec!["%message".into()]],
+                Fragment::Pop => vec![vec!["%pop".into()]],
+                Fragment::Int(int) => vec![vec![int.name()]],
+                Fragment::Float(float) => vec![vec![float.name()]],
+                Fragment::Bytes(tree) => {
+                    tree.bytes().into_iter()
+                        .map(|x| vec![terminal_token(&[x])]).collect()
+                }
+                Fragment::Terminal(value) => {
+                    vec![vec![terminal_token(value)]]
+                }
+            };
+            grammar.0.insert(name, alts);
+        }
+
+        grammar
+    }
+}
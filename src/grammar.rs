@@ -0,0 +1,2100 @@
+//! Grammar loading and the in-memory fragment representation
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::{MapAccess, Visitor};
+use crate::builtins::BUILTINS;
+use crate::ints::RandomInt;
+use crate::scalars::{generator, Generator, RandomFloat};
+
+/// Representation of a grammar file in a Rust structure. This allows us to
+/// use Serde to serialize and deserialize the json grammar files
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Grammar(pub BTreeMap<String, Vec<Vec<String>>>);
+
+/// Directives from a grammar file. These are top-level entries whose name
+/// starts with `%`, which configure generation rather than defining a rule.
+/// They use the same shape as rules, a list of lists of strings.
+#[derive(Default, Debug)]
+pub struct Directives(pub BTreeMap<String, Vec<Vec<String>>>);
+
+impl Directives {
+    /// Get the `%tokens` table, mapping terminals to the `width` byte little
+    /// endian encoding of their token identifier. Each entry of the table is
+    /// a terminal followed by its identifier, in decimal or hex with a `0x`
+    /// prefix, eg. `"%tokens": [["if", "1"], ["else", "0x2"]]`.
+    pub fn tokens(&self, width: usize)
+            -> std::io::Result<BTreeMap<String, Vec<u8>>> {
+        let table = self.0.get("%tokens").ok_or_else(|| {
+            std::io::Error::other("The grammar has no %tokens table")
+        })?;
+
+        let mut tokens = BTreeMap::new();
+        for entry in table {
+            let id = match entry.as_slice() {
+                [_, id] => match id.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                    None => id.parse().ok(),
+                },
+                _ => None,
+            };
+
+            // Make sure the identifier fits in the token width
+            let id = id.filter(|&x| width >= 8 || x >> (width * 8) == 0)
+                .ok_or_else(|| std::io::Error::other(format!(
+                    "Invalid %tokens entry {:?}, expected a terminal and an \
+                     identifier which fits in {} bytes", entry, width)))?;
+
+            if tokens.insert(entry[0].clone(),
+                    id.to_le_bytes()[..width].to_vec()).is_some() {
+                return Err(std::io::Error::other(format!(
+                    "Terminal {:?} is in the %tokens table more than once",
+                    entry[0])));
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    /// Get the `%avoid` patterns, byte strings which generated inputs must
+    /// not contain. Each alternative is one pattern, the concatenation of its
+    /// tokens, which are text or `%bytes(HEX)`, eg.
+    /// `"%avoid": [["../"], ["%bytes(00 00)"]]`.
+    pub fn avoid(&self) -> std::io::Result<Vec<Vec<u8>>> {
+        self.0.get("%avoid").into_iter().flatten()
+            .map(|alt| avoid_pattern(alt)).collect()
+    }
+
+    /// Get the `%separator` emitted between the messages of an input, the
+    /// concatenation of the tokens of its only alternative, eg.
+    /// `"%separator": [["\r\n"]]`. Defaults to nothing.
+    pub fn separator(&self) -> std::io::Result<Vec<u8>> {
+        match self.0.get("%separator").map(|x| x.as_slice()) {
+            None => Ok(Vec::new()),
+            Some([alt]) => Ok(alt.concat().into_bytes()),
+            Some(_) => Err(std::io::Error::other(
+                "The %separator directive must have exactly one alternative")),
+        }
+    }
+}
+
+/// A grammar file exactly as it was written, with rule definitions in the
+/// order they appear. Unlike `Grammar` this keeps duplicate definitions of
+/// a rule, which a map would silently overwrite.
+#[derive(Default, Debug)]
+pub struct GrammarFile(pub Vec<(String, Vec<Vec<String>>)>);
+
+impl<'de> Deserialize<'de> for GrammarFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+            -> Result<Self, D::Error> {
+        /// Visitor which collects every entry of the top-level json object
+        struct FileVisitor;
+
+        impl<'de> Visitor<'de> for FileVisitor {
+            type Value = GrammarFile;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of rule names to lists of alternatives")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A)
+                    -> Result<Self::Value, A::Error> {
+                let mut rules = Vec::new();
+                while let Some(entry) = map.next_entry()? {
+                    rules.push(entry);
+                }
+                Ok(GrammarFile(rules))
+            }
+        }
+
+        deserializer.deserialize_map(FileVisitor)
+    }
+}
+
+impl Grammar {
+    /// Parse a json grammar from `source`. If a rule is defined more than
+    /// once this is an error, unless `merge_duplicates` is set, in which case
+    /// the alternatives of all definitions are combined. Returns the grammar
+    /// and its directives, along with a description of each merge that was
+    /// performed.
+    pub fn parse(source: &[u8], merge_duplicates: bool)
+            -> std::io::Result<(Self, Directives, Vec<String>)> {
+        let file: GrammarFile = serde_json::from_slice(source)?;
+
+        // Count the number of definitions of each rule for reporting
+        let mut definitions = BTreeMap::new();
+
+        let mut grammar = Grammar::default();
+        let mut directives = Directives::default();
+        for (name, alts) in file.0 {
+            if name.starts_with('%') {
+                directives.0.entry(name).or_default().extend(alts);
+                continue;
+            }
+
+            let defs = definitions.entry(name.clone())
+                .or_insert_with(Vec::new);
+            defs.push(alts.len());
+
+            if defs.len() > 1 && !merge_duplicates {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Duplicate non-terminal definition {}, use \
+                        --merge-duplicates to combine them", name)));
+            }
+
+            grammar.0.entry(name).or_default().extend(alts);
+        }
+
+        let merged = definitions.into_iter()
+            .filter(|(_, defs)| defs.len() > 1)
+            .map(|(name, defs)| {
+                format!("{} ({} definitions with {} alternatives)", name,
+                    defs.len(), defs.iter().map(|x| x.to_string())
+                        .collect::<Vec<_>>().join(" + "))
+            }).collect();
+
+        Ok((grammar, directives, merged))
+    }
+
+    /// Load a grammar file from `path`, see `parse`. Grammars named by the
+    /// `%embed` directive are loaded along with it and embedded, see
+    /// `embed`. Each entry of the directive is the rule to embed under, the
+    /// path of the grammar relative to this one, and optionally the start
+    /// rule of that grammar, eg. `"%embed": [["<js>", "js.json"]]`.
+    pub fn load(path: &Path, merge_duplicates: bool)
+            -> std::io::Result<(Self, Directives, Vec<String>)> {
+        Self::load_nested(path, merge_duplicates, &mut Vec::new())
+    }
+
+    /// Get the paths of a grammar file and every grammar it embeds, directly
+    /// or not. This is best effort: files which can't be read or parsed are
+    /// listed without looking into them, so they're still watched for fixes.
+    pub fn files(path: &Path) -> Vec<PathBuf> {
+        let mut files = vec![path.to_path_buf()];
+        let mut next = 0;
+        while let Some(path) = files.get(next).cloned() {
+            next += 1;
+            let Ok(source) = std::fs::read(&path) else { continue };
+            let Ok((_, directives, _)) = Self::parse(&source, true) else {
+                continue;
+            };
+            for entry in directives.0.get("%embed").into_iter().flatten() {
+                let Some(file) = entry.get(1) else { continue };
+                let file = path.parent().unwrap_or(Path::new("."))
+                    .join(file);
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        files
+    }
+
+    /// Load a grammar file, where `loading` are the grammars which embed it
+    fn load_nested(path: &Path, merge_duplicates: bool,
+            loading: &mut Vec<PathBuf>)
+            -> std::io::Result<(Self, Directives, Vec<String>)> {
+        let canonical = path.canonicalize()?;
+        if loading.contains(&canonical) {
+            return Err(std::io::Error::other(format!(
+                "Grammar {} embeds itself", path.display())));
+        }
+
+        let (mut grammar, directives, mut merged) =
+            Self::parse(&std::fs::read(path)?, merge_duplicates)?;
+        grammar.instantiate()?;
+        grammar.define_ints(&directives.ints()?)?;
+
+        loading.push(canonical);
+        for entry in directives.0.get("%embed").into_iter().flatten() {
+            let (rule, file, start) = match entry.as_slice() {
+                [rule, file] => (rule, file, "<start>"),
+                [rule, file, start] => (rule, file, start.as_str()),
+                _ => return Err(std::io::Error::other(format!(
+                    "Invalid %embed entry {:?}, expected a rule, a grammar \
+                     file and optionally a start rule", entry))),
+            };
+
+            let file = path.parent().unwrap_or(Path::new(".")).join(file);
+            let (other, _, other_merged) =
+                Self::load_nested(&file, merge_duplicates, loading)?;
+            merged.extend(other_merged);
+            grammar.embed(rule, other, start)?;
+        }
+        loading.pop();
+
+        Ok((grammar, directives, merged))
+    }
+
+    /// Embed the grammar `other` under the rule `rule`, which expands to
+    /// `start` of `other` with a depth budget of its own. The rules of
+    /// `other` are renamed with the name of `rule` as a prefix, eg. `<expr>`
+    /// embedded under `<js>` becomes `<js::expr>`, so they can't collide with
+    /// ours.
+    pub fn embed(&mut self, rule: &str, other: Grammar, start: &str)
+            -> std::io::Result<()> {
+        if !is_reference_shaped(rule) || self.0.contains_key(rule) {
+            return Err(std::io::Error::other(format!(
+                "Can't embed a grammar under {}, it must be a rule name \
+                 which isn't defined", rule)));
+        }
+        if !other.0.contains_key(start) {
+            return Err(std::io::Error::other(format!(
+                "Can't embed a grammar under {}, its start rule {} is not \
+                 defined", rule, start)));
+        }
+
+        let prefix = &rule[1..rule.len() - 1];
+        let rename = |name: &str| {
+            let name = name.strip_prefix('<').and_then(|x| x.strip_suffix('>'))
+                .unwrap_or(name);
+            format!("<{}::{}>", prefix, name)
+        };
+
+        for (name, alts) in other.0.iter() {
+            // Rename every reference, including those in helpers
+            let alts = alts.iter().map(|alt| alt.iter().map(|token| {
+                match helper(token) {
+                    Some((_, target)) if other.0.contains_key(target) => {
+                        let name = &token[..token.len() - target.len() - 1];
+                        format!("{}{})", name, rename(target))
+                    }
+                    _ if other.0.contains_key(token) => rename(token),
+                    _ => token.clone(),
+                }
+            }).collect()).collect();
+
+            let name = rename(name);
+            if self.0.insert(name.clone(), alts).is_some() {
+                return Err(std::io::Error::other(format!(
+                    "Embedded rule {} is already defined", name)));
+            }
+        }
+
+        self.0.insert(rule.to_string(),
+            vec![vec![format!("%embed({})", rename(start))]]);
+        Ok(())
+    }
+
+    /// Get the names of all rules which aren't referenced by any other rule.
+    /// These are the likely candidates for a start rule. If every rule is
+    /// referenced, all rule names are returned.
+    pub fn roots(&self) -> Vec<&str> {
+        let roots: Vec<&str> = self.0.keys().filter(|name| {
+            !self.0.iter().any(|(rule, alts)| {
+                rule != *name &&
+                    alts.iter().flatten().any(|x| referenced(x) == *name)
+            })
+        }).map(|x| x.as_str()).collect();
+
+        if roots.is_empty() {
+            self.0.keys().map(|x| x.as_str()).collect()
+        } else {
+            roots
+        }
+    }
+}
+
+/// Returns `true` if `token` has the shape of a non-terminal reference, eg.
+/// `<name>`, regardless of whether such a rule is defined
+pub fn is_reference_shaped(token: &str) -> bool {
+    token.len() > 2 && token.starts_with('<') && token.ends_with('>') &&
+        !token[1..token.len() - 1].contains(|x: char| {
+            x == '<' || x == '>' || x.is_whitespace()
+        })
+}
+
+/// How the length inserted by a `Fragment::Length` is encoded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LengthEncoding {
+    /// Decimal digits, eg. `123`
+    Decimal,
+
+    /// A protobuf style varint, 7 bits per byte starting with the least
+    /// significant, with the top bit set on all but the last byte
+    Varint,
+
+    /// An ASN.1 DER definite length, a single byte below 128, or otherwise
+    /// a byte of `0x80` plus the number of big endian bytes which follow
+    Der,
+}
+
+impl LengthEncoding {
+    /// Encode `len`
+    pub fn encode(self, mut len: u64) -> Vec<u8> {
+        match self {
+            LengthEncoding::Decimal => len.to_string().into_bytes(),
+            LengthEncoding::Varint => {
+                let mut ret = Vec::new();
+                while len >= 0x80 {
+                    ret.push(len as u8 | 0x80);
+                    len >>= 7;
+                }
+                ret.push(len as u8);
+                ret
+            }
+            LengthEncoding::Der => {
+                if len < 0x80 {
+                    return vec![len as u8];
+                }
+                let bytes = len.to_be_bytes();
+                let skip = bytes.iter().take_while(|&&x| x == 0).count();
+                let mut ret = vec![0x80 | (8 - skip) as u8];
+                ret.extend_from_slice(&bytes[skip..]);
+                ret
+            }
+        }
+    }
+
+    /// Get the name of the helper which inserts lengths in this encoding
+    pub fn helper(self) -> &'static str {
+        match self {
+            LengthEncoding::Decimal => "%length",
+            LengthEncoding::Varint  => "%varint_length",
+            LengthEncoding::Der     => "%der_length",
+        }
+    }
+}
+
+/// How a `Fragment::Transform` rewrites the output of its body
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Transform {
+    /// Flip the case of each ASCII letter with a 50% chance
+    RandomCase,
+
+    /// Percent-encode every byte apart from the unreserved characters of a
+    /// URL, eg. `a b` becomes `a%20b`
+    UrlEncode,
+
+    /// Backslash-escape quotes, backslashes and non-printable bytes as in a
+    /// C or JavaScript string literal
+    Escape,
+
+    /// Standard base64 with padding
+    Base64,
+
+    /// Lowercase hex, two digits per byte
+    Hex,
+
+    /// Bits packed into bytes, most significant bit first, with the lowest
+    /// bit of every byte of the body being one bit, so `"0"` and `"1"` are
+    /// the bits zero and one. A final partial byte is padded with zeros.
+    Pack,
+}
+
+impl Transform {
+    /// Rewrite the output in `buf` from `start` onwards, calling `rand` for
+    /// random numbers. This must match the generated code, see
+    /// `Codegen::transform`.
+    pub fn apply(self, buf: &mut Vec<u8>, start: usize,
+            mut rand: impl FnMut() -> usize) {
+        if self == Transform::RandomCase {
+            for byte in &mut buf[start..] {
+                if byte.is_ascii_alphabetic() && rand().is_multiple_of(2) {
+                    *byte ^= 0x20;
+                }
+            }
+            return;
+        }
+
+        let body = buf.split_off(start);
+        match self {
+            Transform::RandomCase => unreachable!(),
+            Transform::UrlEncode => {
+                for &byte in &body {
+                    if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                        buf.push(byte);
+                    } else {
+                        buf.extend_from_slice(
+                            format!("%{:02X}", byte).as_bytes());
+                    }
+                }
+            }
+            Transform::Escape => {
+                for &byte in &body {
+                    match byte {
+                        b'\\' | b'"' | b'\'' => buf.extend_from_slice(
+                            &[b'\\', byte]),
+                        b'\n' => buf.extend_from_slice(b"\\n"),
+                        b'\r' => buf.extend_from_slice(b"\\r"),
+                        b'\t' => buf.extend_from_slice(b"\\t"),
+                        0x20..=0x7e => buf.push(byte),
+                        _ => buf.extend_from_slice(
+                            format!("\\x{:02x}", byte).as_bytes()),
+                    }
+                }
+            }
+            Transform::Base64 => {
+                const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                    abcdefghijklmnopqrstuvwxyz0123456789+/";
+                for chunk in body.chunks(3) {
+                    let bits = chunk.iter().enumerate()
+                        .fold(0u32, |acc, (ii, &x)| {
+                            acc | (x as u32) << (16 - ii * 8)
+                        });
+                    for ii in 0..4 {
+                        if ii <= chunk.len() {
+                            let idx = (bits >> (18 - ii * 6)) & 0x3f;
+                            buf.push(ALPHABET[idx as usize]);
+                        } else {
+                            buf.push(b'=');
+                        }
+                    }
+                }
+            }
+            Transform::Hex => {
+                for &byte in &body {
+                    buf.extend_from_slice(format!("{:02x}", byte).as_bytes());
+                }
+            }
+            Transform::Pack => {
+                for chunk in body.chunks(8) {
+                    buf.push(chunk.iter().enumerate().fold(0, |acc, (ii, x)| {
+                        acc | (x & 1) << (7 - ii)
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Get the longest output of this transform for a body of `len` bytes
+    pub fn max_len(self, len: u64) -> u64 {
+        match self {
+            Transform::RandomCase => len,
+            Transform::UrlEncode  => len.saturating_mul(3),
+            Transform::Escape     => len.saturating_mul(4),
+            Transform::Base64     => len.div_ceil(3).saturating_mul(4),
+            Transform::Hex        => len.saturating_mul(2),
+            Transform::Pack       => len.div_ceil(8),
+        }
+    }
+
+    /// Get the shortest output of this transform for a body of `len` bytes
+    pub fn min_len(self, len: usize) -> usize {
+        match self {
+            Transform::RandomCase | Transform::UrlEncode |
+                Transform::Escape => len,
+            Transform::Base64 => len.div_ceil(3) * 4,
+            Transform::Hex    => len * 2,
+            Transform::Pack   => len.div_ceil(8),
+        }
+    }
+
+    /// Get the name of the helper which applies this transform
+    pub fn helper(self) -> &'static str {
+        match self {
+            Transform::RandomCase => "%random_case",
+            Transform::UrlEncode  => "%url_encode",
+            Transform::Escape     => "%escape",
+            Transform::Base64     => "%base64",
+            Transform::Hex        => "%hex",
+            Transform::Pack       => "%pack",
+        }
+    }
+}
+
+/// A generation helper, used as a token in an alternative of the form
+/// `%helper(<rule>)`. Helpers compute protocol framing from the output of
+/// `<rule>`, which plain context-free rules can't express.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Helper {
+    /// `%length(<rule>)`, the decimal length of the output of the next
+    /// `<rule>` in the same alternative, eg. for `Content-Length`
+    Length,
+
+    /// `%varint_length(<rule>)`, like `%length(<rule>)` but the length is a
+    /// protobuf style varint
+    VarintLength,
+
+    /// `%der_length(<rule>)`, like `%length(<rule>)` but the length is an
+    /// ASN.1 DER length
+    DerLength,
+
+    /// `%chunked(<rule>)`, the output of `<rule>` in HTTP chunked transfer
+    /// encoding
+    Chunked,
+
+    /// `%capture(<rule>)`, the output of `<rule>`, which is remembered for
+    /// the rest of the input
+    Capture,
+
+    /// `%recall(<rule>)`, the output remembered by the last
+    /// `%capture(<rule>)`, or a newly captured `<rule>` if there is none
+    Recall,
+
+    /// `%embed(<rule>)`, the output of `<rule>` expanded with a depth budget
+    /// of its own
+    Embed,
+
+    /// `%push(<rule>)`, the output of `<rule>`, which is also pushed onto
+    /// the context stack for a later `%pop` to repeat
+    Push,
+
+    /// `%random_case(<rule>)`, `%url_encode(<rule>)`, `%escape(<rule>)`,
+    /// `%base64(<rule>)`, `%hex(<rule>)` or `%pack(<rule>)`, the output of
+    /// `<rule>` rewritten by a transform
+    Transform(Transform),
+}
+
+/// If `token` is a generation helper, get the helper along with the rule it
+/// applies to
+pub fn helper(token: &str) -> Option<(Helper, &str)> {
+    let (name, rule) = token.strip_suffix(')')?.split_once('(')?;
+    let helper = match name {
+        "%length"        => Helper::Length,
+        "%varint_length" => Helper::VarintLength,
+        "%der_length"    => Helper::DerLength,
+        "%chunked"       => Helper::Chunked,
+        "%capture"       => Helper::Capture,
+        "%recall"        => Helper::Recall,
+        "%embed"         => Helper::Embed,
+        "%push"          => Helper::Push,
+        "%random_case"   => Helper::Transform(Transform::RandomCase),
+        "%url_encode"    => Helper::Transform(Transform::UrlEncode),
+        "%escape"        => Helper::Transform(Transform::Escape),
+        "%base64"        => Helper::Transform(Transform::Base64),
+        "%hex"           => Helper::Transform(Transform::Hex),
+        "%pack"          => Helper::Transform(Transform::Pack),
+        _ => return None,
+    };
+    Some((helper, rule))
+}
+
+/// Get the name of the rule `token` refers to if it's a reference. This is
+/// the token itself, or the rule a generation helper applies to.
+pub fn referenced(token: &str) -> &str {
+    helper(token).map_or(token, |(_, rule)| rule)
+}
+
+/// If `token` is a binary terminal, `%bytes(HEX)`, get the bytes it
+/// emits. Whitespace between the hex digits is ignored, eg. `%bytes(0a ff)`.
+pub fn bytes_literal(token: &str) -> Option<Vec<u8>> {
+    let hex: Vec<u8> = token.strip_prefix("%bytes(")?.strip_suffix(')')?
+        .bytes().filter(|x| !x.is_ascii_whitespace()).collect();
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2).map(|x| {
+        u8::from_str_radix(std::str::from_utf8(x).ok()?, 16).ok()
+    }).collect()
+}
+
+/// Get the bytes of the `%avoid` pattern made of `tokens`, see
+/// `Directives::avoid`
+pub fn avoid_pattern(tokens: &[String]) -> std::io::Result<Vec<u8>> {
+    let pattern: Vec<u8> = tokens.iter()
+        .flat_map(|x| bytes_literal(x).unwrap_or_else(|| x.as_bytes().to_vec()))
+        .collect();
+    if pattern.is_empty() {
+        return Err(std::io::Error::other(
+            "Patterns to avoid must not be empty"));
+    }
+    Ok(pattern)
+}
+
+/// Get the binary terminal token, `%bytes(HEX)`, which emits `value`
+pub fn bytes_token(value: &[u8]) -> String {
+    format!("%bytes({})", value.iter().map(|x| format!("{:02x}", x))
+        .collect::<Vec<_>>().join(" "))
+}
+
+/// Get the token which emits the terminal `value` when loaded back in. This
+/// is the text itself, unless it isn't UTF-8 or would be mistaken for a
+/// binary terminal or a generator, in which case it's written with
+/// `bytes_token`.
+pub fn terminal_token(value: &[u8]) -> String {
+    match std::str::from_utf8(value) {
+        Ok(text) if !text.starts_with("%bytes(") &&
+            generator(text).is_none() && !BUILTINS.contains(&text) => {
+            text.to_string()
+        }
+        _ => bytes_token(value),
+    }
+}
+
+/// Options controlling how a `Grammar` is converted to a `GrammarRust`
+#[derive(Clone, Debug)]
+pub struct ConvertOptions {
+    /// If set, any `<...>`-shaped token which does not refer to a defined
+    /// rule is an error rather than being silently treated as a terminal
+    pub strict: bool,
+
+    /// Name of the rule to start generation from
+    pub start: String,
+
+    /// If set, terminals are replaced with the token identifiers they map to
+    /// in this table, see `Directives::tokens`
+    pub tokens: Option<BTreeMap<String, Vec<u8>>>,
+
+    /// Bytes emitted at every `%message` boundary, see
+    /// `Directives::separator`
+    pub separator: Vec<u8>,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        ConvertOptions {
+            strict: true,
+            start:  "<start>".into(),
+            tokens: None,
+            separator: Vec::new(),
+        }
+    }
+}
+
+/// Options controlling how inputs are generated from a `GrammarRust`. These
+/// are shared by the code generator and the interpreter such that both
+/// generate the same inputs.
+#[derive(Clone, Debug)]
+pub struct GenOptions {
+    /// Maximum recursion depth, fragments at this depth expand to nothing
+    pub max_depth: usize,
+
+    /// Maximum number of iterations of a `Fragment::Loop`
+    pub loop_budget: usize,
+
+    /// Maximum number of expansions of recursive fragments in a single input,
+    /// in addition to the depth limit. Expansions past this expand to
+    /// nothing, like those past the max depth.
+    pub max_steps: Option<usize>,
+
+    /// If set, sample derivations of this size uniformly instead of by
+    /// random descent. The depth limit and loop budget don't apply, as the
+    /// size bounds generation on its own. See `uniform`.
+    pub uniform_size: Option<usize>,
+
+    /// Chance in percent of emitting one of the precomputed expansions of a
+    /// non-terminal with few expansions rather than expanding it, see
+    /// `GrammarRust::precomputed`. Zero never takes the shortcut.
+    pub precompute: usize,
+
+    /// Maximum number of distinct expansions of a non-terminal to precompute
+    pub precompute_max: usize,
+
+    /// Make the generated program print every expansion to stderr. This
+    /// doesn't affect the inputs generated.
+    pub trace: bool,
+
+    /// Number of depths, starting from zero, the generated program gets
+    /// copies of fragment functions specialized for, which know their depth
+    /// without being passed it. This doesn't affect the inputs generated.
+    pub unroll: usize,
+
+    /// Compile the regions of the grammar which derive regular languages
+    /// into a table walked by a single method of the generated program,
+    /// rather than a function per fragment. This doesn't affect the inputs
+    /// generated.
+    pub compact_regular: bool,
+
+    /// Rules the generated program can keep a pool of complete expansions
+    /// of, which it emits instead of expanding the rules again. Pools are
+    /// enabled at runtime, and the inputs generated are only affected then.
+    pub pool: Vec<String>,
+
+    /// Rules the generated program can fill in at runtime, from a callback
+    /// registered with the library or a file given to the program, rather
+    /// than expanding them. Rules are only filled in once they have a
+    /// provider, and the inputs generated are only affected then.
+    pub holes: Vec<String>,
+
+    /// Emit `#[cfg(test)]` unit tests into the generated source, which check
+    /// the compiled code generates the inputs the interpreter expects from
+    /// the default seed at every max depth. This doesn't affect the inputs
+    /// generated.
+    pub tests: bool,
+
+    /// Output size in bytes to aim for, by steering the choices of
+    /// non-terminals and loops towards growing the output while it's shorter
+    /// than `target_slack` percent below this, and towards finishing it once
+    /// it's longer than `target_slack` percent above this. See
+    /// `GrammarRust::steering`.
+    pub target_size: Option<usize>,
+
+    /// Percentage of `target_size` output sizes may be off by before choices
+    /// are steered
+    pub target_slack: usize,
+}
+
+impl GenOptions {
+    /// Get the shortest and longest output within the slack of the target
+    /// size, if there is one. Choices are steered while the output is
+    /// outside of this range.
+    pub fn target_range(&self) -> Option<(usize, usize)> {
+        self.target_size.map(|size| {
+            let slack = size.saturating_mul(self.target_slack) / 100;
+            (size.saturating_sub(slack), size.saturating_add(slack))
+        })
+    }
+}
+
+/// A strongly typed wrapper around a `usize` which selects different fragment
+/// identifiers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FragmentId(pub usize);
+
+/// A fragment which is specified by the grammar file
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Fragment {
+    /// A non-terminal fragment which refers to a list of `FragmentId`s to
+    /// randomly select from for expansion
+    NonTerminal(Vec<FragmentId>),
+
+    /// A list of `FragmentId`s that should be expanded in order
+    Expression(Vec<FragmentId>),
+
+    /// A terminal fragment which simply should expand directly to the
+    /// contained vector of bytes
+    Terminal(Vec<u8>),
+
+    /// A right-recursive non-terminal which has been converted into a loop.
+    /// Each iteration randomly selects one of `options` just like a
+    /// `NonTerminal`. Options with `repeat` set were expressions ending in a
+    /// call back to this fragment, they are expanded without that call and
+    /// then the loop continues. Other options are expanded and then the loop
+    /// ends. The loop runs for at most `GenOptions::loop_budget` iterations.
+    Loop {
+        /// Fragments to randomly select from on each iteration
+        options: Vec<FragmentId>,
+
+        /// For each option, whether it continues the loop
+        repeat: Vec<bool>,
+    },
+
+    /// Expands the first fragment and then the second, and inserts the
+    /// length of the output of the second fragment before the output of the
+    /// first. This comes from `%length(<body>)` in an alternative, where the
+    /// first fragment is everything between the helper and the following
+    /// `<body>`, eg. the rest of the headers of an HTTP request. The length
+    /// is written in decimal, as a varint for `%varint_length(<body>)` or as
+    /// a DER length for `%der_length(<body>)`.
+    Length([FragmentId; 2], LengthEncoding),
+
+    /// Expands a fragment and re-encodes its output with HTTP chunked
+    /// transfer encoding, splitting it into randomly sized chunks. This
+    /// comes from `%chunked(<body>)` in an alternative.
+    Chunked(FragmentId),
+
+    /// Expands `body` and remembers its output in the variable `slot` for the
+    /// rest of the input. This comes from `%capture(<body>)`.
+    Capture {
+        /// Fragment whose output is captured
+        body: FragmentId,
+
+        /// Variable the output is stored in, one per captured rule
+        slot: usize,
+    },
+
+    /// Emits the output last stored in the variable `slot`. If nothing has
+    /// been stored yet, expands and captures `body` instead. This comes from
+    /// `%recall(<body>)`.
+    Recall {
+        /// Fragment expanded when there's nothing to recall yet
+        body: FragmentId,
+
+        /// Variable to recall
+        slot: usize,
+    },
+
+    /// Expands a fragment starting over from a depth of zero, giving it the
+    /// whole depth budget. This comes from `%embed(<body>)`, which is how
+    /// embedded grammars are entered.
+    Embed(FragmentId),
+
+    /// Expands a fragment and pushes its output onto the context stack. This
+    /// comes from `%push(<body>)`.
+    Push(FragmentId),
+
+    /// Expands a fragment and rewrites its output with a transform, eg. to
+    /// embed it URL encoded in a query string. This comes from helpers such
+    /// as `%url_encode(<body>)`.
+    Transform(FragmentId, Transform),
+
+    /// Pops the output of the last `Fragment::Push` off the context stack
+    /// and emits it again, eg. to close an XML element with the tag name it
+    /// was opened with. Emits nothing if the stack is empty. This comes from
+    /// `%pop`.
+    Pop,
+
+    /// The boundary between two messages of an input. Emits the contained
+    /// separator bytes and records where the boundary is, so a sequence of
+    /// messages can be split back apart. This comes from `%message`.
+    Message(Vec<u8>),
+
+    /// Emits a random integer, encoded in binary, as a varint or in decimal.
+    /// This comes from a reference to a builtin like `<u16_le>`, see `ints`,
+    /// or from `%range(low, high)`, see `scalars`.
+    Int(RandomInt),
+
+    /// Emits a random floating point number in decimal. This comes from
+    /// `%float(low, high)`, see `scalars`.
+    Float(RandomFloat),
+
+    /// Emits a single random byte picked by walking a `ByteTree`. This
+    /// replaces non-terminals which only ever emit a single byte, like
+    /// `<digit>` or `<letter>`, see `GrammarRust::byte_samplers`.
+    Bytes(ByteTree),
+
+    /// A fragment which does nothing. This is used during optimization passes
+    /// to remove fragments with no effect.
+    Nop,
+}
+
+impl Fragment {
+    /// Get the fragment identifiers this fragment may expand to
+    pub fn children(&self) -> &[FragmentId] {
+        match self {
+            Fragment::NonTerminal(ids) | Fragment::Expression(ids) => ids,
+            Fragment::Loop { options, .. } => options,
+            Fragment::Length(ids, _) => ids,
+            Fragment::Chunked(id) | Fragment::Embed(id) |
+                    Fragment::Push(id) | Fragment::Transform(id, _) => {
+                std::slice::from_ref(id)
+            }
+            Fragment::Capture { body, .. } | Fragment::Recall { body, .. } => {
+                std::slice::from_ref(body)
+            }
+            Fragment::Terminal(_) | Fragment::Message(_) | Fragment::Pop |
+                Fragment::Int(_) | Fragment::Float(_) | Fragment::Bytes(_) |
+                Fragment::Nop => &[],
+        }
+    }
+
+    /// Get mutable access to the fragment identifiers this fragment may
+    /// expand to
+    pub fn children_mut(&mut self) -> &mut [FragmentId] {
+        match self {
+            Fragment::NonTerminal(ids) | Fragment::Expression(ids) => ids,
+            Fragment::Loop { options, .. } => options,
+            Fragment::Length(ids, _) => ids,
+            Fragment::Chunked(id) | Fragment::Embed(id) |
+                    Fragment::Push(id) | Fragment::Transform(id, _) => {
+                std::slice::from_mut(id)
+            }
+            Fragment::Capture { body, .. } | Fragment::Recall { body, .. } => {
+                std::slice::from_mut(body)
+            }
+            Fragment::Terminal(_) | Fragment::Message(_) | Fragment::Pop |
+                Fragment::Int(_) | Fragment::Float(_) | Fragment::Bytes(_) |
+                Fragment::Nop => &mut [],
+        }
+    }
+}
+
+/// The random choices of a non-terminal which only ever emits a single byte,
+/// and of the non-terminals among its options in turn
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ByteTree {
+    /// Emits this byte
+    Byte(u8),
+
+    /// Randomly picks one of these trees, like a `Fragment::NonTerminal`
+    Choice(Vec<ByteTree>),
+}
+
+impl ByteTree {
+    /// Pick a byte, drawing a random number from `rand` for every choice on
+    /// the way down, exactly like expanding the non-terminals would
+    pub fn sample(&self, mut rand: impl FnMut() -> usize) -> u8 {
+        let mut tree = self;
+        loop {
+            match tree {
+                ByteTree::Byte(byte) => return *byte,
+                ByteTree::Choice(options) => {
+                    tree = &options[rand() % options.len()];
+                }
+            }
+        }
+    }
+
+    /// Get every byte this tree may emit
+    pub fn bytes(&self) -> BTreeSet<u8> {
+        match self {
+            ByteTree::Byte(byte) => std::iter::once(*byte).collect(),
+            ByteTree::Choice(options) => {
+                options.iter().flat_map(|x| x.bytes()).collect()
+            }
+        }
+    }
+}
+
+/// A grammar representation in Rust that is designed to be easy to work with
+/// in-memory and optimized for code generation.
+#[derive(Debug, Default)]
+pub struct GrammarRust {
+    /// All types
+    pub fragments: Vec<Fragment>,
+
+    /// Cached fragment identifier for the start node
+    pub start: Option<FragmentId>,
+
+    /// Mapping of non-terminal names to fragment identifers
+    pub name_to_fragment: BTreeMap<String, FragmentId>,
+
+    /// Names of the rules used with `%capture` or `%recall`, the index of a
+    /// name is the variable slot it's stored in
+    pub variables: Vec<String>,
+
+    /// Byte strings generated inputs must not contain. Inputs containing
+    /// any of them are generated again, up to `AVOID_RETRIES` times.
+    pub avoid: Vec<Vec<u8>>,
+}
+
+/// Number of times an input containing a pattern to avoid is generated again
+/// before it's kept anyway, so grammars which can't avoid a pattern still
+/// generate inputs
+pub const AVOID_RETRIES: usize = 100;
+
+impl GrammarRust {
+    /// Create a new Rust version of a `Grammar` which was loaded via a
+    /// grammar json specification.
+    pub fn new(grammar: &Grammar, options: &ConvertOptions)
+            -> std::io::Result<Self> {
+        // Create a new grammar structure
+        let mut ret = GrammarRust::default();
+
+        // References which look like non-terminals but could not be resolved
+        let mut unresolved = BTreeSet::new();
+
+        // Terminals missing from the token table
+        let mut untokenized = BTreeSet::new();
+
+        // Parse the input grammar to resolve all fragment names
+        for (non_term, _) in grammar.0.iter() {
+            // Make sure that there aren't duplicates of fragment names
+            assert!(!ret.name_to_fragment.contains_key(non_term),
+                "Duplicate non-terminal definition, fail");
+
+            // Create a new, empty fragment
+            let fragment_id = ret.allocate_fragment(
+                Fragment::NonTerminal(Vec::new()));
+
+            // Add the name resolution for the fragment
+            ret.name_to_fragment.insert(non_term.clone(), fragment_id);
+        }
+
+        // Parse the input grammar
+        for (non_term, fragments) in grammar.0.iter() {
+            // Get the non-terminal fragment identifier
+            let fragment_id = ret.name_to_fragment[non_term];
+
+            // Create a vector to hold all of the variants possible under this
+            // non-terminal fragment
+            let mut variants = Vec::new();
+
+            // Go through all sub-fragments
+            for js_sub_fragment in fragments {
+                // Convert the tokens of this sub-fragment
+                let options = ret.convert_tokens(non_term, js_sub_fragment,
+                    options, &mut unresolved, &mut untokenized)?;
+
+                // Create a new fragment of all the options
+                variants.push(
+                    ret.allocate_fragment(Fragment::Expression(options)));
+            }
+
+            // Get access to the fragment we want to update based on the
+            // possible variants
+            let fragment = &mut ret.fragments[fragment_id.0];
+
+            // Overwrite the terminal definition
+            *fragment = Fragment::NonTerminal(variants);
+        }
+
+        // In strict mode, fail listing every unresolved reference
+        if options.strict && !unresolved.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("Unresolved non-terminal references:\n    {}",
+                    unresolved.into_iter().collect::<Vec<_>>()
+                        .join("\n    "))));
+        }
+
+        // Starting over from a depth of zero in a cycle would never
+        // terminate
+        let recursive = ret.recursive();
+        for (idx, fragment) in ret.fragments.iter().enumerate() {
+            if let Fragment::Embed(body) = fragment {
+                if recursive[idx] {
+                    let rule = ret.name_to_fragment.iter()
+                        .find(|(_, id)| Some(*id) == ret.fragments[body.0]
+                            .children().first())
+                        .map_or("?", |(name, _)| name.as_str());
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("%embed({}) can expand back into itself, \
+                            which would never terminate", rule)));
+                }
+            }
+        }
+
+        if !untokenized.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("Terminals missing from the %tokens table: {}",
+                    untokenized.iter().map(|x| format!("{:?}", x))
+                        .collect::<Vec<_>>().join(", "))));
+        }
+
+        // Resolve the start node
+        ret.start = Some(*ret.name_to_fragment.get(&options.start)
+            .ok_or_else(|| std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Start rule {} is not defined, top-level rules \
+                    are: {}", options.start, grammar.roots().join(", "))))?);
+
+        Ok(ret)
+    }
+
+    /// Convert the tokens of an alternative of `non_term` into fragments,
+    /// to be expanded in order. References which look like non-terminals but
+    /// could not be resolved are added to `unresolved`, and terminals missing
+    /// from the token table to `untokenized`.
+    fn convert_tokens(&mut self, non_term: &str, alternative: &[String],
+            convert: &ConvertOptions,
+            unresolved: &mut BTreeSet<String>,
+            untokenized: &mut BTreeSet<String>)
+            -> std::io::Result<Vec<FragmentId>> {
+        let mut options = Vec::new();
+
+        // Go through each option in the sub-fragment
+        let mut idx = 0;
+        while idx < alternative.len() {
+            let option = &alternative[idx];
+            idx += 1;
+
+            // Boundaries between messages
+            if option == "%message" {
+                options.push(self.allocate_fragment(
+                    Fragment::Message(convert.separator.clone())));
+                continue;
+            }
+
+            // Popping the context stack
+            if option == "%pop" {
+                options.push(self.allocate_fragment(Fragment::Pop));
+                continue;
+            }
+
+            // Builtin generators of realistic data
+            if let Some(builtin) = self.builtin(option) {
+                options.push(builtin);
+                continue;
+            }
+
+            if let Some((helper, rule)) = helper(option) {
+                // Generation helpers must apply to a defined rule
+                let &rule_id = self.name_to_fragment.get(rule).ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData,
+                        format!("{} uses {} on undefined rule {}", non_term,
+                            option, rule))
+                })?;
+                let body = self.allocate_fragment(
+                    Fragment::NonTerminal(vec![rule_id]));
+
+                let fragment = match helper {
+                    Helper::Length | Helper::VarintLength |
+                            Helper::DerLength => {
+                        // The length is of the next use of the rule, and
+                        // everything up to it is expanded in between
+                        let end = alternative[idx..].iter()
+                            .position(|x| x == rule).ok_or_else(|| {
+                                std::io::Error::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    format!("{} uses {} without {} later in \
+                                        the same alternative", non_term,
+                                        option, rule))
+                            })?;
+                        let mid = self.convert_tokens(non_term,
+                            &alternative[idx..idx + end], convert,
+                            unresolved, untokenized)?;
+                        let mid = self.allocate_fragment(
+                            Fragment::Expression(mid));
+                        idx += end + 1;
+                        Fragment::Length([mid, body], match helper {
+                            Helper::Length => LengthEncoding::Decimal,
+                            Helper::VarintLength => LengthEncoding::Varint,
+                            _ => LengthEncoding::Der,
+                        })
+                    }
+                    Helper::Chunked => Fragment::Chunked(body),
+                    Helper::Embed => Fragment::Embed(body),
+                    Helper::Push => Fragment::Push(body),
+                    Helper::Transform(transform) => {
+                        Fragment::Transform(body, transform)
+                    }
+                    Helper::Capture | Helper::Recall => {
+                        // Every rule gets its own variable
+                        let slot = self.variables.iter()
+                            .position(|x| x == rule).unwrap_or_else(|| {
+                                self.variables.push(rule.to_string());
+                                self.variables.len() - 1
+                            });
+                        if helper == Helper::Capture {
+                            Fragment::Capture { body, slot }
+                        } else {
+                            Fragment::Recall { body, slot }
+                        }
+                    }
+                };
+                options.push(self.allocate_fragment(fragment));
+                continue;
+            }
+
+            let fragment_id = if let Some(&non_terminal) =
+                    self.name_to_fragment.get(option) {
+                // If we can resolve the name of this fragment, it is a
+                // non-terminal fragment and should be allocated as such
+                self.allocate_fragment(
+                    Fragment::NonTerminal(vec![non_terminal]))
+            } else if let Some(generator) = generator(option) {
+                // Random scalars, including builtin integers which weren't
+                // defined by the grammar
+                match generator? {
+                    Generator::Int(int) => {
+                        self.allocate_fragment(Fragment::Int(int))
+                    }
+                    Generator::Float(float) => {
+                        self.allocate_fragment(Fragment::Float(float))
+                    }
+                    Generator::Bytes(bytes) => {
+                        let options = bytes.iter().map(|&x| {
+                            self.allocate_fragment(Fragment::Terminal(vec![x]))
+                        }).collect();
+                        self.allocate_fragment(Fragment::NonTerminal(options))
+                    }
+                }
+            } else {
+                // Track typos in references for strict mode
+                if is_reference_shaped(option) {
+                    unresolved.insert(format!("{} references {}",
+                        non_term, option));
+                }
+
+                // Convert the terminal bytes into a vector, or its token
+                // when emitting a token stream, and create a new fragment
+                // containing it
+                let value = match &convert.tokens {
+                    Some(tokens) => match tokens.get(option) {
+                        Some(token) => token.clone(),
+                        None => {
+                            if !option.is_empty() {
+                                untokenized.insert(option.clone());
+                            }
+                            Vec::new()
+                        }
+                    },
+                    None => bytes_literal(option)
+                        .unwrap_or_else(|| option.as_bytes().to_vec()),
+                };
+                self.allocate_fragment(Fragment::Terminal(value))
+            };
+
+            // Push this fragment as an option
+            options.push(fragment_id);
+        }
+
+        Ok(options)
+    }
+
+    /// Allocate a new fragment identifier and add it to the fragment list
+    pub fn allocate_fragment(&mut self, fragment: Fragment) -> FragmentId {
+        // Get a unique fragment identifier
+        let fragment_id = FragmentId(self.fragments.len());
+
+        // Store the fragment
+        self.fragments.push(fragment);
+
+        fragment_id
+    }
+
+    /// Optimize to remove fragments with non-random effects
+    pub fn optimize(&mut self) {
+        // Keeps track of fragment identifiers which resolve to nops
+        let mut nop_fragments = BTreeSet::new();
+
+        // Track if a optimization had an effect
+        let mut changed = true;
+        while changed {
+            // Start off assuming no effect from optimzation
+            changed = false;
+
+            // Go through each fragment, looking for potential optimizations
+            for idx in 0..self.fragments.len() {
+                // Clone the fragment such that we can inspect it, but we also
+                // can mutate it in place.
+                match self.fragments[idx].clone() {
+                    Fragment::NonTerminal(options) => {
+                        // If this non-terminal only has one option, replace
+                        // itself with the only option it resolves to
+                        if options.len() == 1 {
+                            self.fragments[idx] =
+                                self.fragments[options[0].0].clone();
+                            changed = true;
+                        }
+                    }
+                    Fragment::Expression(expr) => {
+                        // If this expression doesn't have anything to do at
+                        // all. Then simply replace it with a `Nop`
+                        if expr.is_empty() {
+                            self.fragments[idx] = Fragment::Nop;
+                            changed = true;
+
+                            // Track that this fragment identifier now resolves
+                            // to a nop
+                            nop_fragments.insert(idx);
+                        }
+
+                        // If this expression only does one thing, then replace
+                        // the expression with the thing that it does.
+                        if expr.len() == 1 {
+                            self.fragments[idx] =
+                                self.fragments[expr[0].0].clone();
+                            changed = true;
+                        }
+
+                        // Remove all `Nop`s from this expression, as they
+                        // wouldn't result in anything occuring.
+                        if let Fragment::Expression(exprs) =
+                                &mut self.fragments[idx] {
+                            // Only retain fragments which are not nops
+                            exprs.retain(|x| {
+                                if nop_fragments.contains(&x.0) {
+                                    // Fragment was a nop, remove it
+                                    changed = true;
+                                    false
+                                } else {
+                                    // Fragment was fine, keep it
+                                    true
+                                }
+                            });
+                        }
+
+                        // Merge runs of adjacent terminals in this
+                        // expression into a single terminal
+                        if self.merge_terminals(idx) {
+                            changed = true;
+                        }
+                    }
+                    Fragment::Terminal(_) | Fragment::Loop { .. } |
+                            Fragment::Length(..) | Fragment::Chunked(_) |
+                            Fragment::Capture { .. } |
+                            Fragment::Recall { .. } | Fragment::Embed(_) |
+                            Fragment::Push(_) | Fragment::Transform(..) |
+                            Fragment::Pop | Fragment::Message(_) |
+                            Fragment::Int(_) | Fragment::Float(_) |
+                            Fragment::Bytes(_) | Fragment::Nop => {
+                        // Already maximally optimized
+                    }
+                }
+            }
+        }
+    }
+
+    /// If fragment `idx` is an expression, replace every run of adjacent
+    /// terminals in it with a single terminal containing all of their bytes.
+    /// All sub-fragments of an expression are expanded at the same depth, so
+    /// this doesn't change what is generated. Returns `true` if the
+    /// expression was changed.
+    fn merge_terminals(&mut self, idx: usize) -> bool {
+        let expr = match &self.fragments[idx] {
+            Fragment::Expression(expr) => expr.clone(),
+            _ => return false,
+        };
+
+        let mut merged = Vec::new();
+        let mut changed = false;
+        let mut ii = 0;
+        while ii < expr.len() {
+            // Find the run of terminals starting here
+            let mut bytes = Vec::new();
+            let mut end = ii;
+            while let Some(Fragment::Terminal(value)) =
+                    expr.get(end).map(|x| &self.fragments[x.0]) {
+                bytes.extend_from_slice(value);
+                end += 1;
+            }
+
+            if end - ii > 1 {
+                // Replace the run with a single new terminal
+                merged.push(self.allocate_fragment(Fragment::Terminal(bytes)));
+                changed = true;
+                ii = end;
+            } else {
+                merged.push(expr[ii]);
+                ii += 1;
+            }
+        }
+
+        if changed {
+            self.fragments[idx] = Fragment::Expression(merged);
+        }
+        changed
+    }
+
+    /// Canonicalize structurally identical fragments, rewriting all
+    /// references to point to a single copy. Duplicates are left in place but
+    /// become dead, so this should be followed by `prune_unreachable`.
+    /// Returns the number of fragments which were found to be duplicates.
+    pub fn dedup(&mut self) -> usize {
+        // Mapping from every fragment identifier to its canonical identifier
+        let mut canonical: Vec<FragmentId> =
+            (0..self.fragments.len()).map(FragmentId).collect();
+
+        // Rewriting references can make more fragments identical, so keep
+        // going until nothing changes
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            // Find the first occurrence of every distinct fragment
+            let mut seen = BTreeMap::new();
+            for (idx, fragment) in self.fragments.iter().enumerate() {
+                if canonical[idx].0 != idx {
+                    // Already a known duplicate
+                    continue;
+                }
+
+                let first = *seen.entry(fragment).or_insert(FragmentId(idx));
+                if first.0 != idx {
+                    canonical[idx] = first;
+                    changed = true;
+                }
+            }
+
+            // Rewrite all references to use the canonical fragments
+            for fragment in self.fragments.iter_mut() {
+                for child in fragment.children_mut() {
+                    *child = canonical[child.0];
+                }
+            }
+        }
+
+        self.start = self.start.map(|x| canonical[x.0]);
+        for fragment_id in self.name_to_fragment.values_mut() {
+            *fragment_id = canonical[fragment_id.0];
+        }
+
+        canonical.iter().enumerate().filter(|(idx, x)| x.0 != *idx).count()
+    }
+
+    /// Convert directly right-recursive non-terminals, such as
+    /// `<items> -> <item> <items> | <item>`, into `Fragment::Loop`s so the
+    /// generated code iterates rather than recursing. This relies on `dedup`
+    /// having been run such that recursive references point directly back
+    /// at the non-terminal. Iterations draw the random numbers the recursion
+    /// would, so inputs only change where the depth limit used to cut a
+    /// list short, or where the loop budget now does, which ends the list
+    /// after the last iteration it had budget for. Returns the number of
+    /// loops created.
+    pub fn convert_loops(&mut self) -> usize {
+        let mut loops = 0;
+
+        for idx in 0..self.fragments.len() {
+            let options = match &self.fragments[idx] {
+                Fragment::NonTerminal(options) => options.clone(),
+                _ => continue,
+            };
+
+            // Classify each option as repeating or finishing the loop
+            let mut new_options = Vec::new();
+            let mut repeat = Vec::new();
+            let mut convertible = true;
+            for &option in options.iter() {
+                match &self.fragments[option.0] {
+                    // A direct reference to ourself, repeat with no effect
+                    _ if option.0 == idx => {
+                        new_options.push(None);
+                        repeat.push(true);
+                    }
+
+                    // An expression ending in a call to ourself, repeat with
+                    // the rest of the expression
+                    Fragment::Expression(expr)
+                            if expr.last().map(|x| x.0) == Some(idx) => {
+                        let prefix = &expr[..expr.len() - 1];
+                        if prefix.iter().any(|x| x.0 == idx) {
+                            convertible = false;
+                        }
+                        new_options.push(Some(prefix.to_vec()));
+                        repeat.push(true);
+                    }
+
+                    // Anything else must not recurse directly into us
+                    fragment => {
+                        if fragment.children().iter().any(|x| x.0 == idx) {
+                            convertible = false;
+                        }
+                        new_options.push(None);
+                        repeat.push(false);
+                    }
+                }
+            }
+
+            if !convertible || !repeat.contains(&true) {
+                continue;
+            }
+
+            // Allocate fragments for the repeating options without the
+            // recursive call
+            let options = options.iter().zip(new_options).zip(repeat.iter())
+                .map(|((&option, prefix), &repeat)| {
+                    match prefix {
+                        Some(prefix) if !prefix.is_empty() => self
+                            .allocate_fragment(Fragment::Expression(prefix)),
+                        _ if repeat => self.allocate_fragment(Fragment::Nop),
+                        _ => option,
+                    }
+                }).collect();
+
+            self.fragments[idx] = Fragment::Loop { options, repeat };
+            loops += 1;
+        }
+
+        loops
+    }
+
+    /// Canonicalize the shape of the grammar: options of a non-terminal
+    /// which are non-terminals themselves are replaced by their options,
+    /// sub-expressions of expressions are spliced into them, and the options
+    /// of every non-terminal are sorted with duplicates removed. Rules
+    /// written in different ways then become identical and can be shared by
+    /// `dedup`.
+    ///
+    /// Unlike the other passes this changes how likely options are, as
+    /// every distinct option ends up equally likely no matter how it was
+    /// nested or how many times it was repeated. Returns the number of
+    /// fragments spliced into their parents and the number of duplicate
+    /// options removed.
+    pub fn normalize(&mut self) -> (usize, usize) {
+        let mut spliced = 0;
+        let mut duplicates = 0;
+
+        for idx in 0..self.fragments.len() {
+            let nested = match &self.fragments[idx] {
+                Fragment::NonTerminal(ids) | Fragment::Expression(ids) =>
+                    ids.clone(),
+                _ => continue,
+            };
+            let choice = matches!(self.fragments[idx],
+                Fragment::NonTerminal(_));
+
+            // Splice nested fragments of the same kind until there are none
+            // left. Every fragment is spliced at most once so cycles of them
+            // can't keep this going forever.
+            let mut seen = BTreeSet::new();
+            let mut ids = Vec::new();
+            let mut count = 0;
+            let mut pending: Vec<FragmentId> = nested.into_iter().rev()
+                .collect();
+            while let Some(id) = pending.pop() {
+                let inner = match &self.fragments[id.0] {
+                    Fragment::NonTerminal(inner) if choice => inner,
+                    Fragment::Expression(inner) if !choice => inner,
+                    _ => {
+                        ids.push(id);
+                        continue;
+                    }
+                };
+                if id.0 == idx || !seen.insert(id) {
+                    ids.push(id);
+                    continue;
+                }
+                pending.extend(inner.iter().rev());
+                count += 1;
+            }
+
+            let mut removed = 0;
+            let fragment = if choice {
+                // Options are picked at random, so their order doesn't
+                // matter and a repeated option only makes it more likely
+                let len = ids.len();
+                ids.sort();
+                ids.dedup();
+                removed = len - ids.len();
+                Fragment::NonTerminal(ids)
+            } else {
+                Fragment::Expression(ids)
+            };
+
+            // Splicing a cycle of fragments can end up right where it
+            // started, which isn't a change
+            if fragment != self.fragments[idx] {
+                self.fragments[idx] = fragment;
+                spliced += count;
+                duplicates += removed;
+            }
+        }
+
+        (spliced, duplicates)
+    }
+
+    /// Factor a prefix shared by every option of a non-terminal out of the
+    /// options, so it's emitted by a single copy of the code, eg.
+    /// `<x> -> "GET /a" | "GET /b"` becomes `<x> -> "GET /" ("a" | "b")`.
+    /// Terminals are compared byte by byte, and other leading fragments by
+    /// identifier, so this relies on `dedup` having been run.
+    ///
+    /// Only non-terminals which can't reach a recursive fragment are
+    /// factored, and only prefixes which don't use random numbers, so the
+    /// depth limit doesn't apply and the same random numbers pick the same
+    /// option once the prefix has been emitted. Generated inputs are
+    /// unchanged. Returns the number of non-terminals factored.
+    pub fn factor_prefixes(&mut self) -> usize {
+        let recursive = self.recursive();
+
+        // Find the fragments which can't reach a recursive fragment, and of
+        // those the ones which never use random numbers. Both only ever
+        // change from `true` to `false`, so this reaches a fixed point.
+        let mut finite: Vec<bool> = recursive.iter().map(|x| !x).collect();
+        let mut fixed: Vec<bool> = self.fragments.iter().map(|x| !matches!(x,
+            Fragment::NonTerminal(_) | Fragment::Loop { .. } |
+            Fragment::Chunked(_) | Fragment::Transform(..) |
+            Fragment::Int(_) | Fragment::Float(_) |
+            Fragment::Bytes(_))).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (idx, fragment) in self.fragments.iter().enumerate() {
+                let children = fragment.children();
+                if finite[idx] && children.iter().any(|x| !finite[x.0]) {
+                    finite[idx] = false;
+                    changed = true;
+                }
+                if fixed[idx] && children.iter().any(|x| !fixed[x.0]) {
+                    fixed[idx] = false;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut factored = 0;
+        for (idx, &finite) in finite.iter().enumerate() {
+            let options = match &self.fragments[idx] {
+                Fragment::NonTerminal(options) if finite => options.clone(),
+                _ => continue,
+            };
+
+            // Every option as the sequence of fragments it expands to
+            let mut seqs: Vec<Vec<FragmentId>> = options.iter()
+                .map(|&x| match &self.fragments[x.0] {
+                    Fragment::Expression(expr) => expr.clone(),
+                    _ => vec![x],
+                }).collect();
+
+            // Find the leading fragments all options have in common. Options
+            // factored earlier on lead with fragments allocated since `fixed`
+            // was computed, which are left out rather than looked at again.
+            let mut prefix = Vec::new();
+            while let Some(&first) = seqs[0].get(prefix.len()) {
+                let len = prefix.len();
+                if fixed.get(first.0) != Some(&true) ||
+                        seqs.iter().any(|x| x.get(len) != Some(&first)) {
+                    break;
+                }
+                prefix.push(first);
+            }
+            for seq in seqs.iter_mut() {
+                seq.drain(..prefix.len());
+            }
+
+            // Then the bytes the terminals following them start with
+            let values: Option<Vec<Vec<u8>>> = seqs.iter()
+                .map(|seq| match seq.first().map(|x| &self.fragments[x.0]) {
+                    Some(Fragment::Terminal(value)) => Some(value.clone()),
+                    _ => None,
+                }).collect();
+            if let Some(values) = values {
+                let common = values.iter().skip(1).fold(values[0].len(),
+                    |len, value| {
+                        value.iter().zip(&values[0][..len])
+                            .take_while(|(a, b)| a == b).count()
+                    });
+                if common > 0 {
+                    prefix.push(self.allocate_fragment(
+                        Fragment::Terminal(values[0][..common].to_vec())));
+                    for (seq, value) in seqs.iter_mut().zip(values) {
+                        if value.len() == common {
+                            seq.remove(0);
+                        } else {
+                            seq[0] = self.allocate_fragment(
+                                Fragment::Terminal(value[common..].to_vec()));
+                        }
+                    }
+                }
+            }
+
+            if prefix.is_empty() {
+                continue;
+            }
+
+            // Pick from what's left of the options after the prefix
+            let rests = seqs.into_iter().map(|seq| match seq.len() {
+                0 => self.allocate_fragment(Fragment::Nop),
+                1 => seq[0],
+                _ => self.allocate_fragment(Fragment::Expression(seq)),
+            }).collect();
+            prefix.push(self.allocate_fragment(Fragment::NonTerminal(rests)));
+            self.fragments[idx] = Fragment::Expression(prefix);
+            factored += 1;
+        }
+
+        factored
+    }
+
+    /// Replace non-terminals whose options are all single bytes, or
+    /// non-terminals like that in turn, with `Fragment::Bytes`. Rules like
+    /// `<letter>` then become a table lookup rather than a match between
+    /// dozens of tiny functions, which compiles much faster and wastes less
+    /// time on calls. The byte is picked with the same random numbers the
+    /// non-terminals would use, so generated inputs are unchanged. This
+    /// isn't done when sampling uniformly, which counts derivations of the
+    /// non-terminals. Returns the number of non-terminals replaced.
+    pub fn byte_samplers(&mut self) -> usize {
+        // Find the tree of every fragment which only ever emits a single
+        // byte. Non-terminals get theirs once all of their options have one,
+        // which leaves out cycles.
+        let mut trees: Vec<Option<ByteTree>> = self.fragments.iter()
+            .map(|x| match x {
+                Fragment::Terminal(value) if value.len() == 1 => {
+                    Some(ByteTree::Byte(value[0]))
+                }
+                Fragment::Bytes(tree) => Some(tree.clone()),
+                _ => None,
+            }).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (idx, fragment) in self.fragments.iter().enumerate() {
+                let Fragment::NonTerminal(options) = fragment else {
+                    continue;
+                };
+                if trees[idx].is_some() {
+                    continue;
+                }
+                let tree: Option<Vec<ByteTree>> = options.iter()
+                    .map(|x| trees[x.0].clone()).collect();
+                if let Some(tree) = tree {
+                    trees[idx] = Some(ByteTree::Choice(tree));
+                    changed = true;
+                }
+            }
+        }
+
+        let mut replaced = 0;
+        for (fragment, tree) in self.fragments.iter_mut().zip(trees) {
+            if let (Fragment::NonTerminal(_), Some(tree)) = (&fragment, tree) {
+                *fragment = Fragment::Bytes(tree);
+                replaced += 1;
+            }
+        }
+        replaced
+    }
+
+    /// Remove all fragments which can't be reached from the start fragment,
+    /// renumbering the remaining fragments. Returns the names of the rules
+    /// which were removed.
+    pub fn prune_unreachable(&mut self) -> Vec<String> {
+        let reachable = self.reachable();
+
+        // Compute the new identifier for each reachable fragment
+        let mut new_ids = vec![None; self.fragments.len()];
+        let mut fragments = Vec::new();
+        for (idx, fragment) in self.fragments.drain(..).enumerate() {
+            if reachable[idx] {
+                new_ids[idx] = Some(FragmentId(fragments.len()));
+                fragments.push(fragment);
+            }
+        }
+
+        // Rewrite all references to the new identifiers
+        for fragment in fragments.iter_mut() {
+            for child in fragment.children_mut() {
+                *child = new_ids[child.0].unwrap();
+            }
+        }
+        self.fragments = fragments;
+        self.start = self.start.map(|x| new_ids[x.0].unwrap());
+
+        // Update the name mapping, dropping rules which no longer exist
+        let mut removed = Vec::new();
+        self.name_to_fragment.retain(|name, fragment_id| {
+            if let Some(new_id) = new_ids[fragment_id.0] {
+                *fragment_id = new_id;
+                true
+            } else {
+                removed.push(name.clone());
+                false
+            }
+        });
+
+        removed
+    }
+
+    /// Append the fragments of `other` to this grammar, to build a single
+    /// program from several grammars. The rule and variable names of `other`
+    /// are prefixed with `prefix`, and its variables get slots of their own.
+    /// The start fragment of the first grammar appended becomes the start
+    /// fragment of this one. Returns the new identifier of the start fragment
+    /// of `other`.
+    pub fn append(&mut self, other: &GrammarRust, prefix: &str)
+            -> FragmentId {
+        let base = self.fragments.len();
+        let slots = self.variables.len();
+        for fragment in other.fragments.iter() {
+            let mut fragment = fragment.clone();
+            for child in fragment.children_mut() {
+                child.0 += base;
+            }
+            if let Fragment::Capture { slot, .. } |
+                    Fragment::Recall { slot, .. } = &mut fragment {
+                *slot += slots;
+            }
+            self.fragments.push(fragment);
+        }
+
+        for (name, fragment_id) in other.name_to_fragment.iter() {
+            self.name_to_fragment.insert(format!("{}{}", prefix, name),
+                FragmentId(fragment_id.0 + base));
+        }
+        self.variables.extend(other.variables.iter()
+            .map(|x| format!("{}{}", prefix, x)));
+
+        for pattern in other.avoid.iter() {
+            if !self.avoid.contains(pattern) {
+                self.avoid.push(pattern.clone());
+            }
+        }
+
+        let start = FragmentId(other.start.unwrap().0 + base);
+        self.start.get_or_insert(start);
+        start
+    }
+
+    /// Returns `true` if `input` contains a pattern to avoid
+    pub fn avoided(&self, input: &[u8]) -> bool {
+        self.avoid.iter().any(|pattern| {
+            input.windows(pattern.len()).any(|x| x == pattern.as_slice())
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::interp::Interpreter;
+    use super::*;
+
+    /// Convert the json grammar `source` without optimizing it
+    pub fn grammar(source: &str) -> GrammarRust {
+        let (grammar, _, _) = Grammar::parse(source.as_bytes(), false)
+            .unwrap();
+        GrammarRust::new(&grammar, &ConvertOptions::default()).unwrap()
+    }
+
+    /// Convert the json grammar `source` and run the passes every other
+    /// pass relies on, like `optimize_grammar` does. Dead fragments are
+    /// pruned so passes only count what's reachable.
+    fn optimized(source: &str) -> GrammarRust {
+        let mut grammar = grammar(source);
+        grammar.optimize();
+        grammar.dedup();
+        grammar.prune_unreachable();
+        grammar
+    }
+
+    /// Options generating with a depth limit of `max_depth` and a loop
+    /// budget of `loop_budget`
+    pub fn options(max_depth: usize, loop_budget: usize) -> GenOptions {
+        GenOptions {
+            max_depth,
+            loop_budget,
+            max_steps:    None,
+            uniform_size: None,
+            precompute:   0,
+            precompute_max: 0,
+            trace:        false,
+            unroll:       0,
+            compact_regular: false,
+            pool:         Vec::new(),
+            holes:        Vec::new(),
+            tests:        false,
+            target_size:  None,
+            target_slack: 0,
+        }
+    }
+
+    /// Generate `count` inputs in a row from `grammar` with the interpreter
+    pub fn generate(grammar: &GrammarRust, options: &GenOptions,
+            count: usize) -> Vec<Vec<u8>> {
+        let mut interp = Interpreter::new(grammar, 0x1234, options).unwrap();
+        (0..count).map(|_| {
+            let mut buf = Vec::new();
+            interp.generate(&mut buf);
+            buf
+        }).collect()
+    }
+
+    /// Check `pass` leaves the inputs generated from `source` unchanged, and
+    /// returns what it returned
+    fn unchanged<T>(source: &str, options: &GenOptions,
+            pass: impl FnOnce(&mut GrammarRust) -> T) -> T {
+        let before = optimized(source);
+        let mut after = optimized(source);
+        let ret = pass(&mut after);
+
+        let inputs = generate(&before, options, 1000);
+        assert_eq!(inputs, generate(&after, options, 1000));
+
+        // Make sure the inputs compared actually differ from each other
+        assert!(inputs.iter().any(|x| *x != inputs[0]));
+        ret
+    }
+
+    #[test]
+    fn factor_shared_prefixes() {
+        let source = r#"{
+            "<start>": [["<req>"], ["<req>", "<req>"]],
+            "<req>": [["GET /index", "<query>"], ["GET /admin", "<query>"],
+                ["GET /", "<query>", "<query>"]],
+            "<query>": [["?a"], ["?b"], [""]]
+        }"#;
+        let factored = unchanged(source, &options(16, 16),
+            |x| x.factor_prefixes());
+        assert_eq!(factored, 1);
+
+        // The prefix is emitted once, before picking from what's left
+        let mut grammar = optimized(source);
+        grammar.factor_prefixes();
+        let Fragment::Expression(expr) =
+                &grammar.fragments[grammar.name_to_fragment["<req>"].0]
+                else { panic!("<req> wasn't factored") };
+        assert_eq!(grammar.fragments[expr[0].0],
+            Fragment::Terminal(b"GET /".to_vec()));
+        assert!(matches!(&grammar.fragments[expr[1].0],
+            Fragment::NonTerminal(x) if x.len() == 3));
+    }
+
+    #[test]
+    fn factor_whole_option_prefixes() {
+        // One option is nothing but the prefix, which leaves it empty
+        let source = r#"{
+            "<start>": [["<verb>", "<verb>"]],
+            "<verb>": [["GET"], ["GETS"], ["GET", "<tail>"]],
+            "<tail>": [["1"], ["2"]]
+        }"#;
+        let factored = unchanged(source, &options(16, 16),
+            |x| x.factor_prefixes());
+        assert_eq!(factored, 1);
+    }
+
+    #[test]
+    fn factor_single_options() {
+        // The optimizer inlines single options, so leave it out. The choice
+        // left after the prefix still draws the random number the original
+        // one did.
+        let source = r#"{
+            "<start>": [["<one>", "<pick>"], ["<pick>", "<one>"]],
+            "<one>": [["a", "<pick>", "b"]],
+            "<pick>": [["x"], ["y"], ["z"]]
+        }"#;
+        let before = grammar(source);
+        let mut after = grammar(source);
+
+        // `<one>` and both references to it, which are choices of a single
+        // option as well until optimized
+        assert_eq!(after.factor_prefixes(), 3);
+        let options = options(16, 16);
+        assert_eq!(generate(&before, &options, 1000),
+            generate(&after, &options, 1000));
+    }
+
+    /// A right-recursive list, for converting into a loop
+    const LIST: &str = r#"{
+        "<start>": [["[", "<items>", "]"]],
+        "<items>": [["<item>", ",", "<items>"], ["<item>"]],
+        "<item>": [["a"], ["b"], ["<num>"]],
+        "<num>": [["1"], ["2"]]
+    }"#;
+
+    #[test]
+    fn convert_loops_within_budget() {
+        // Lists never get anywhere near the depth limit or the loop budget,
+        // so every iteration draws the random numbers a recursion would
+        let loops = unchanged(LIST, &options(256, 256),
+            |x| x.convert_loops());
+        assert_eq!(loops, 1);
+
+        let mut grammar = optimized(LIST);
+        grammar.convert_loops();
+        let items = grammar.name_to_fragment["<items>"];
+        assert!(matches!(&grammar.fragments[items.0],
+            Fragment::Loop { repeat, .. } if *repeat == [true, false]));
+    }
+
+    #[test]
+    fn convert_loops_past_budget() {
+        let before = optimized(LIST);
+        let mut after = optimized(LIST);
+        assert_eq!(after.convert_loops(), 1);
+
+        // A list which runs out of loop budget ends right after the last
+        // iteration it had budget for, whichever option that picked
+        let mut cut = 0;
+        for seed in 1..=1000u64 {
+            let seed = seed.wrapping_mul(0x9e3779b97f4a7c15);
+            let mut list = Vec::new();
+            Interpreter::new(&before, seed, &options(256, 256)).unwrap()
+                .generate(&mut list);
+            let mut looped = Vec::new();
+            Interpreter::new(&after, seed, &options(256, 3)).unwrap()
+                .generate(&mut looped);
+
+            let items: Vec<&[u8]> = list[1..list.len() - 1]
+                .split(|&x| x == b',').collect();
+            if items.len() <= 3 {
+                assert_eq!(looped, list);
+            } else {
+                let mut expected = b"[".to_vec();
+                for item in &items[..3] {
+                    expected.extend_from_slice(item);
+                    expected.push(b',');
+                }
+                expected.push(b']');
+                assert_eq!(looped, expected);
+                cut += 1;
+            }
+        }
+        assert!(cut > 0);
+    }
+
+    #[test]
+    fn dedup_identical_rules() {
+        let mut grammar = grammar(r#"{
+            "<start>": [["<a>", "<b>"]],
+            "<a>": [["x"], ["y"]],
+            "<b>": [["x"], ["y"]],
+            "<c>": [["x"], ["z"]]
+        }"#);
+
+        // Both terminals of `<b>`, both of its options, `<b>` itself and the
+        // reference to it, which only become identical one after another,
+        // and the first option of `<c>` along with its terminal
+        assert_eq!(grammar.dedup(), 8);
+
+        // References to `<b>` point at `<a>` instead, while `<c>` only
+        // shares its first option
+        let a = grammar.name_to_fragment["<a>"];
+        assert_eq!(grammar.name_to_fragment["<b>"], a);
+        let c = grammar.name_to_fragment["<c>"];
+        let (Fragment::NonTerminal(a_options), Fragment::NonTerminal(
+                c_options)) = (&grammar.fragments[a.0],
+                &grammar.fragments[c.0]) else { unreachable!() };
+        assert_eq!(a_options[0], c_options[0]);
+        assert_ne!(a_options[1], c_options[1]);
+        let start = grammar.name_to_fragment["<start>"];
+        let Fragment::NonTerminal(options) = &grammar.fragments[start.0]
+            else { unreachable!() };
+        let Fragment::Expression(refs) = &grammar.fragments[options[0].0]
+            else { unreachable!() };
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[0], refs[1]);
+        assert_eq!(grammar.fragments[refs[0].0],
+            Fragment::NonTerminal(vec![a]));
+    }
+
+    #[test]
+    fn normalize_flattens_nested_rules() {
+        let mut grammar = optimized(r#"{
+            "<start>": [["<seq>", "<seq>"]],
+            "<seq>": [["<d>", "<pair>"]],
+            "<pair>": [["<d>", "<d>"]],
+            "<d>": [["<digit>"], ["-"]],
+            "<digit>": [["0"], ["1"]]
+        }"#);
+        let (spliced, duplicates) = grammar.normalize();
+
+        // `<digit>` into `<d>`, `<pair>` into `<seq>` and `<seq>` into
+        // `<start>`, which only splices each fragment once
+        assert_eq!(spliced, 3);
+        assert_eq!(duplicates, 0);
+
+        // The choice between digits is spliced into `<d>`
+        let d = grammar.name_to_fragment["<d>"];
+        let Fragment::NonTerminal(options) = &grammar.fragments[d.0]
+            else { unreachable!() };
+        let mut values: Vec<&Fragment> = options.iter()
+            .map(|x| &grammar.fragments[x.0]).collect();
+        values.sort();
+        assert_eq!(values, [&Fragment::Terminal(b"-".to_vec()),
+            &Fragment::Terminal(b"0".to_vec()),
+            &Fragment::Terminal(b"1".to_vec())]);
+
+        // And the pair into the sequence holding it
+        let seq = grammar.name_to_fragment["<seq>"];
+        assert_eq!(grammar.fragments[seq.0],
+            Fragment::Expression(vec![d, d, d]));
+    }
+
+    #[test]
+    fn normalize_removes_duplicate_options() {
+        let source = r#"{
+            "<start>": [["<ab>"], ["a"], ["a"]],
+            "<ab>": [["a"], ["b"]]
+        }"#;
+        let before = optimized(source);
+        let mut after = optimized(source);
+        assert_eq!(after.normalize(), (1, 2));
+
+        // Only one of each option is left, which makes them equally likely
+        // rather than `a` being picked five times out of six
+        let start = after.name_to_fragment["<start>"];
+        let Fragment::NonTerminal(ids) = &after.fragments[start.0]
+            else { unreachable!() };
+        assert_eq!(ids.len(), 2);
+        let options = options(16, 16);
+        let count = |grammar| generate(grammar, &options, 6000).iter()
+            .filter(|x| *x == b"a").count();
+        assert!((4500..5500).contains(&count(&before)));
+        assert!((2500..3500).contains(&count(&after)));
+    }
+
+    #[test]
+    fn prune_unreachable_rules() {
+        let source = r#"{
+            "<start>": [["<used>"]],
+            "<used>": [["u"], ["<shared>"]],
+            "<orphan>": [["<only>", "<shared>"]],
+            "<only>": [["o"], ["p"]],
+            "<shared>": [["s"], ["t"]]
+        }"#;
+        let before = grammar(source);
+        let mut after = grammar(source);
+        assert_eq!(after.prune_unreachable(), ["<only>", "<orphan>"]);
+
+        // The fragments only those rules used are gone, while the rule they
+        // share with reachable ones is kept
+        assert!(after.fragments.len() < before.fragments.len());
+        assert!(!after.fragments.iter().any(|x| matches!(x,
+            Fragment::Terminal(value) if value == b"o" || value == b"p")));
+        assert_eq!(after.name_to_fragment.keys().collect::<Vec<_>>(),
+            ["<shared>", "<start>", "<used>"]);
+
+        // Renumbering the rest doesn't change what's generated
+        let options = options(16, 16);
+        assert_eq!(generate(&before, &options, 1000),
+            generate(&after, &options, 1000));
+    }
+
+    #[test]
+    fn der_lengths() {
+        // The short form up to 0x7f, and the fewest big endian bytes after
+        let der = |len| LengthEncoding::Der.encode(len);
+        assert_eq!(der(0), [0x00]);
+        assert_eq!(der(0x7f), [0x7f]);
+        assert_eq!(der(0x80), [0x81, 0x80]);
+        assert_eq!(der(0xff), [0x81, 0xff]);
+        assert_eq!(der(0x100), [0x82, 0x01, 0x00]);
+        assert_eq!(der(u64::MAX), [0x88, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff]);
+    }
+}
@@ -0,0 +1,238 @@
+//! Inference of a starting grammar from a corpus of sample inputs. Samples
+//! are split into tokens using a user supplied specification of token
+//! classes, and the grammar is an n-gram model of the token sequences seen:
+//! each rule is a context of the previous few tokens, with an alternative
+//! for every token which was seen following that context. This is crude, but
+//! it generates inputs which look like the samples and is a lot easier to
+//! refine than writing a grammar from scratch.
+
+use std::collections::{BTreeMap, BTreeSet};
+use crate::grammar::{is_reference_shaped, Grammar};
+
+/// Specification of the classes of tokens to split samples into. Runs of
+/// characters from the same class form a single token, every other
+/// character is a token of its own.
+#[derive(Default, Debug)]
+pub struct TokenSpec {
+    /// Name of each class along with the inclusive character ranges in it
+    classes: Vec<(String, Vec<(char, char)>)>,
+}
+
+impl TokenSpec {
+    /// Parse a token specification from json. This is an object mapping
+    /// class names to the characters in the class, where `a-z` denotes a
+    /// range of characters and a `-` at the start or end is literal, eg.
+    /// `{"ident": "a-zA-Z0-9_", "number": "0-9"}`. If classes overlap, the
+    /// first one in alphabetical order wins.
+    pub fn parse(source: &[u8]) -> std::io::Result<Self> {
+        let classes: BTreeMap<String, String> =
+            serde_json::from_slice(source)?;
+
+        let mut spec = TokenSpec::default();
+        for (name, chars) in classes {
+            // Class names become rule names, so they must not collide with
+            // the rules we generate for the n-gram states
+            if !is_reference_shaped(&format!("<{}>", name)) ||
+                    name == "start" || name.starts_with("state_") {
+                return Err(std::io::Error::other(format!(
+                    "Invalid token class name `{}`", name)));
+            }
+
+            let chars: Vec<char> = chars.chars().collect();
+            let mut ranges = Vec::new();
+            let mut ii = 0;
+            while ii < chars.len() {
+                if ii + 2 < chars.len() && chars[ii + 1] == '-' {
+                    ranges.push((chars[ii], chars[ii + 2]));
+                    ii += 3;
+                } else {
+                    ranges.push((chars[ii], chars[ii]));
+                    ii += 1;
+                }
+            }
+            spec.classes.push((name, ranges));
+        }
+
+        Ok(spec)
+    }
+
+    /// Get the index of the class `chr` belongs to, if any
+    fn class(&self, chr: char) -> Option<usize> {
+        self.classes.iter().position(|(_, ranges)| {
+            ranges.iter().any(|&(start, end)| (start..=end).contains(&chr))
+        })
+    }
+
+    /// Split `input` into tokens
+    fn tokenize<'a>(&self, input: &'a str) -> Vec<(Symbol, &'a str)> {
+        let mut tokens = Vec::new();
+        let mut chars = input.char_indices().peekable();
+        while let Some((start, chr)) = chars.next() {
+            let symbol = match self.class(chr) {
+                Some(class) => {
+                    // Extend the token for as long as we stay in the class
+                    while chars.next_if(|&(_, x)| {
+                        self.class(x) == Some(class)
+                    }).is_some() {}
+                    Symbol::Class(class)
+                }
+                None => Symbol::Literal(chr),
+            };
+
+            let end = chars.peek().map_or(input.len(), |&(x, _)| x);
+            tokens.push((symbol, &input[start..end]));
+        }
+        tokens
+    }
+}
+
+/// Kind of a token
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Symbol {
+    /// Token from the class with this index in the spec
+    Class(usize),
+
+    /// Single character which isn't in any class
+    Literal(char),
+}
+
+/// Infer a grammar from `samples`, tokenized with `spec`. The next token
+/// depends on the previous `order - 1` tokens, and each token class rule
+/// gets up to `max_values` of the most common values seen for it.
+pub fn infer(samples: &[String], spec: &TokenSpec, order: usize,
+        max_values: usize) -> Grammar {
+    // For every context, the tokens which followed it, with `None` for the
+    // end of a sample
+    let mut contexts: BTreeMap<Vec<Symbol>, BTreeSet<Option<Symbol>>> =
+        BTreeMap::new();
+
+    // Number of times each value of each class was seen
+    let mut values: Vec<BTreeMap<&str, usize>> =
+        vec![BTreeMap::new(); spec.classes.len()];
+
+    for sample in samples {
+        let mut context = Vec::new();
+        for (symbol, value) in spec.tokenize(sample) {
+            contexts.entry(context.clone()).or_default()
+                .insert(Some(symbol));
+            if let Symbol::Class(class) = symbol {
+                *values[class].entry(value).or_default() += 1;
+            }
+
+            // Slide the context along
+            context.push(symbol);
+            if context.len() >= order {
+                context.remove(0);
+            }
+        }
+        contexts.entry(context).or_default().insert(None);
+    }
+
+    // Name every context, the empty context is where samples start
+    let names: BTreeMap<&Vec<Symbol>, String> = contexts.keys()
+        .enumerate().map(|(idx, context)| {
+            (context, if context.is_empty() {
+                "<start>".to_string()
+            } else {
+                format!("<state_{}>", idx)
+            })
+        }).collect();
+
+    let mut grammar = Grammar::default();
+    for (context, next) in contexts.iter() {
+        let options = next.iter().map(|symbol| {
+            let symbol = match symbol {
+                Some(symbol) => symbol,
+                None => return Vec::new(),
+            };
+
+            let mut next_context = context.clone();
+            next_context.push(*symbol);
+            if next_context.len() >= order {
+                next_context.remove(0);
+            }
+
+            let token = match symbol {
+                Symbol::Class(class) => format!("<{}>", spec.classes[*class].0),
+                Symbol::Literal(chr) => chr.to_string(),
+            };
+            vec![token, names[&next_context].clone()]
+        }).collect();
+        grammar.0.insert(names[context].clone(), options);
+    }
+
+    // Emit the most common values of every class which was used
+    for (class, counts) in values.iter().enumerate() {
+        if counts.is_empty() {
+            continue;
+        }
+
+        let mut counts: Vec<(&&str, &usize)> = counts.iter().collect();
+        counts.sort_by_key(|&(_, count)| std::cmp::Reverse(*count));
+        let options = counts.iter().take(max_values).map(|(value, _)| {
+            // Values which look like a rule reference are split into single
+            // characters so they stay literal
+            if is_reference_shaped(value) {
+                value.chars().map(|x| x.to_string()).collect()
+            } else {
+                vec![value.to_string()]
+            }
+        }).collect();
+        grammar.0.insert(format!("<{}>", spec.classes[class].0), options);
+    }
+
+    grammar
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{ConvertOptions, GrammarRust};
+    use crate::grammar::tests::{generate, options};
+    use super::*;
+
+    const SAMPLES: &[&str] = &["GET /a HTTP/1.0", "POST /bb HTTP/1.1",
+        "GET /bb HTTP/1.1"];
+
+    /// Infer a grammar from `SAMPLES`, keeping `max_values` of each class
+    fn inferred(max_values: usize) -> GrammarRust {
+        let spec = TokenSpec::parse(br#"{"word": "A-Za-z", "num": "0-9"}"#)
+            .unwrap();
+        let samples: Vec<String> = SAMPLES.iter().map(|x| x.to_string())
+            .collect();
+        let grammar = infer(&samples, &spec, 3, max_values);
+        GrammarRust::new(&grammar, &ConvertOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn infer_produces_samples() {
+        let grammar = inferred(16);
+        for sample in SAMPLES {
+            assert!(grammar.parse(sample.as_bytes()).is_some(),
+                "can't produce {:?}", sample);
+        }
+
+        // And only things put together from the samples, of which there
+        // are more than the samples themselves
+        let inputs = generate(&grammar, &options(64, 64), 100);
+        assert!(inputs.iter().all(|x| grammar.parse(x).is_some()));
+        assert!(inputs.iter().any(|x| {
+            !SAMPLES.contains(&std::str::from_utf8(x).unwrap())
+        }));
+    }
+
+    #[test]
+    fn infer_keeps_common_values() {
+        // `HTTP` is the most common word, and the only one kept
+        let grammar = inferred(1);
+        assert!(grammar.parse(b"HTTP /HTTP HTTP/1.1").is_some());
+        assert!(grammar.parse(SAMPLES[0].as_bytes()).is_none());
+    }
+
+    #[test]
+    fn infer_class_names() {
+        for name in ["start", "state_1", "a b"] {
+            let spec = format!(r#"{{"{}": "a-z"}}"#, name);
+            assert!(TokenSpec::parse(spec.as_bytes()).is_err());
+        }
+    }
+}
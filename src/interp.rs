@@ -0,0 +1,585 @@
+//! A direct interpreter for a `GrammarRust`. This walks the fragment graph
+//! exactly the way the generated Rust program does, consuming random numbers
+//! in the same order, so a given seed produces the same inputs from both.
+
+use std::cmp::Ordering;
+use crate::analysis::steered;
+use crate::grammar::{Fragment, FragmentId, GenOptions, GrammarRust};
+use crate::grammar::{LengthEncoding, Transform, AVOID_RETRIES};
+use crate::scalars::RandomFloat;
+use crate::uniform::{choose, Counts};
+
+/// A pending piece of work for the interpreter
+enum Work {
+    /// Expand a fragment at a given depth
+    Expand(FragmentId, usize),
+
+    /// Run the next iteration of a `Fragment::Loop` at a given depth, with
+    /// the number of iterations remaining
+    Loop(FragmentId, usize, usize),
+
+    /// Expand a derivation of a given size of a fragment, when sampling
+    /// uniformly
+    Sized(FragmentId, usize),
+
+    /// Expand the children of a `Fragment::Expression` from the given index
+    /// onwards, with a given combined size, when sampling uniformly
+    SizedExpr(FragmentId, usize, usize),
+
+    /// Record the current length of the output as a mark, for helpers which
+    /// rewrite the output of their children
+    Mark,
+
+    /// Finish a `Fragment::Length`, whose children started at the last two
+    /// marks
+    Length(LengthEncoding),
+
+    /// Finish a `Fragment::Chunked`, whose body started at the last mark
+    Chunked,
+
+    /// Finish a `Fragment::Capture` or the first `Fragment::Recall` of a
+    /// variable, storing the output since the last mark in the variable
+    Capture(usize),
+
+    /// Finish a `Fragment::Push`, pushing the output since the last mark
+    /// onto the context stack
+    Push,
+
+    /// Finish a `Fragment::Transform`, rewriting the output since the last
+    /// mark
+    Transform(Transform),
+}
+
+/// State for generating inputs from a grammar without compiling it
+pub struct Interpreter<'a> {
+    /// Grammar we are generating inputs for
+    grammar: &'a GrammarRust,
+
+    /// Current xorshift state for random number generation
+    seed: u64,
+
+    /// Options controlling generation
+    options: GenOptions,
+
+    /// Which fragments are recursive. Only these are subject to the depth
+    /// limit.
+    recursive: Vec<bool>,
+
+    /// Derivation counts when sampling uniformly
+    counts: Option<Counts>,
+
+    /// Options non-terminals pick from near the depth limit, see
+    /// `GrammarRust::depth_bands`
+    bands: Vec<Vec<(usize, Vec<usize>)>>,
+
+    /// Way every option of every non-terminal and loop steers the output
+    /// size, when aiming for `GenOptions::target_size`. Empty otherwise.
+    steering: Vec<Vec<Ordering>>,
+
+    /// Expansions of the non-terminals with few of them, which are emitted
+    /// directly `GenOptions::precompute` percent of the time. Empty when
+    /// they never are.
+    precomputed: Vec<Option<Vec<Vec<u8>>>>,
+
+    /// Number of steps used by the current input
+    steps: usize,
+
+    /// Output offsets recorded by `Work::Mark`, along with the number of
+    /// message boundaries at the time
+    marks: Vec<(usize, usize)>,
+
+    /// Values of the `%capture` variables for the current input
+    vars: Vec<Option<Vec<u8>>>,
+
+    /// Spans of the separators between the messages of the current input
+    messages: Vec<(usize, usize)>,
+
+    /// Values pushed by `%push` which haven't been popped yet
+    context: Vec<Vec<u8>>,
+
+    /// Pending work. This is used instead of recursion so deep grammars
+    /// can't overflow our own stack.
+    stack: Vec<Work>,
+}
+
+impl<'a> Interpreter<'a> {
+    /// Create a new interpreter for `grammar` seeded with `seed`
+    pub fn new(grammar: &'a GrammarRust, seed: u64, options: &GenOptions)
+            -> std::io::Result<Self> {
+        Ok(Interpreter {
+            grammar,
+            seed,
+            options: options.clone(),
+            recursive: grammar.recursive(),
+            counts: options.uniform_size.map(|x| grammar.counts(x))
+                .transpose()?,
+            bands: grammar.depth_bands(&grammar.min_depths()),
+            steering: if options.target_size.is_some() &&
+                    options.uniform_size.is_none() {
+                grammar.steering(options)
+            } else {
+                Vec::new()
+            },
+            precomputed: if options.precompute > 0 {
+                grammar.precomputed(options)
+            } else {
+                Vec::new()
+            },
+            steps: 0,
+            marks: Vec::new(),
+            vars: Vec::new(),
+            messages: Vec::new(),
+            context: Vec::new(),
+            stack: Vec::new(),
+        })
+    }
+
+    /// Get a new random number. This is the same xorshift used by the
+    /// generated program.
+    fn rand(&mut self) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 43;
+        self.seed as usize
+    }
+
+    /// Generate a new input from the start node into `buf`. `buf` is cleared
+    /// prior to generation. Inputs containing patterns to avoid are
+    /// generated again, like the generated program does.
+    pub fn generate(&mut self, buf: &mut Vec<u8>) {
+        for _ in 0..AVOID_RETRIES {
+            self.generate_once(buf);
+            if !self.grammar.avoided(buf) {
+                break;
+            }
+        }
+    }
+
+    /// Generate a single input into `buf`, whatever it contains
+    fn generate_once(&mut self, buf: &mut Vec<u8>) {
+        buf.clear();
+        self.vars.clear();
+        self.vars.resize(self.grammar.variables.len(), None);
+        self.messages.clear();
+        self.context.clear();
+
+        if self.counts.is_some() {
+            self.generate_uniform(buf);
+            return;
+        }
+
+        // Start expanding from the start node
+        self.steps = 0;
+        self.stack.push(Work::Expand(self.grammar.start.unwrap(), 0));
+
+        while let Some(work) = self.stack.pop() {
+            let (fragment_id, depth) = match work {
+                Work::Expand(fragment_id, depth) => (fragment_id, depth),
+                Work::Loop(fragment_id, depth, remaining) => {
+                    self.iterate(fragment_id, depth, remaining, buf.len());
+                    continue;
+                }
+                Work::Mark => {
+                    self.marks.push((buf.len(), self.messages.len()));
+                    continue;
+                }
+                Work::Capture(slot) => {
+                    let (start, _) = self.marks.pop().unwrap();
+                    self.vars[slot] = Some(buf[start..].to_vec());
+                    continue;
+                }
+                Work::Push => {
+                    self.push(buf);
+                    continue;
+                }
+                Work::Transform(transform) => {
+                    self.transform(buf, transform);
+                    continue;
+                }
+                Work::Length(encoding) => {
+                    self.length(buf, encoding);
+                    continue;
+                }
+                Work::Chunked => {
+                    self.chunked(buf);
+                    continue;
+                }
+                Work::Sized(..) | Work::SizedExpr(..) => unreachable!(),
+            };
+
+            // Take the shortcut of emitting a precomputed expansion, these
+            // are never recursive
+            let precomputed = self.precomputed.get(fragment_id.0)
+                .and_then(|x| x.as_ref()).map(|x| x.len());
+            if let Some(len) = precomputed {
+                if self.rand() % 100 < self.options.precompute {
+                    let expansion = self.rand() % len;
+                    let expansions = &self.precomputed[fragment_id.0];
+                    buf.extend_from_slice(&expansions.as_ref().unwrap()
+                        [expansion]);
+                    continue;
+                }
+            }
+
+            // Terminate on depth or step exhaustion. Non-recursive fragments
+            // always terminate on their own so they're never cut off.
+            if self.recursive[fragment_id.0] {
+                if depth >= self.options.max_depth || self.steps_exhausted() {
+                    continue;
+                }
+                self.steps += 1;
+            }
+
+            match &self.grammar.fragments[fragment_id.0] {
+                Fragment::NonTerminal(options) => {
+                    // Pick a random variant to expand, only from those which
+                    // still fit near the depth limit, and which steer
+                    // towards the target size if there is one
+                    let rand = self.rand();
+                    let band = self.bands[fragment_id.0].iter()
+                        .find(|(need, _)| depth + need < self.options.max_depth);
+                    let toward = self.toward(buf.len());
+                    let option = match band {
+                        _ if toward != Ordering::Equal => {
+                            let all: Vec<usize> = (0..options.len()).collect();
+                            let subset = band.map_or(&all[..], |(_, x)| x);
+                            let subset = steered(&self.steering[fragment_id.0],
+                                subset, toward);
+                            options[subset[rand % subset.len()]]
+                        }
+                        Some((_, subset)) => options[subset[rand % subset.len()]],
+                        None => options[rand % options.len()],
+                    };
+                    self.stack.push(Work::Expand(option, depth + 1));
+                }
+                Fragment::Expression(expr) => {
+                    // Push in reverse such that the expression is expanded in
+                    // order
+                    for &exp in expr.iter().rev() {
+                        self.stack.push(Work::Expand(exp, depth + 1));
+                    }
+                }
+                Fragment::Loop { options, .. } => {
+                    // Don't bother iterating if every option would be cut
+                    // off by the depth limit or step budget
+                    let cut_off = depth + 1 >= self.options.max_depth ||
+                        self.steps_exhausted();
+                    if !cut_off ||
+                            !self.grammar.all_cut_off(&self.recursive, options) {
+                        self.iterate(fragment_id, depth,
+                            self.options.loop_budget, buf.len());
+                    }
+                }
+                Fragment::Length([mid, body], encoding) => {
+                    self.stack.push(Work::Length(*encoding));
+                    self.stack.push(Work::Expand(*body, depth + 1));
+                    self.stack.push(Work::Mark);
+                    self.stack.push(Work::Expand(*mid, depth + 1));
+                    self.stack.push(Work::Mark);
+                }
+                Fragment::Chunked(body) => {
+                    self.stack.push(Work::Chunked);
+                    self.stack.push(Work::Expand(*body, depth + 1));
+                    self.stack.push(Work::Mark);
+                }
+                &Fragment::Recall { slot, .. } if self.vars[slot].is_some() => {
+                    buf.extend_from_slice(self.vars[slot].as_ref().unwrap());
+                }
+                &Fragment::Capture { body, slot } |
+                        &Fragment::Recall { body, slot } => {
+                    self.stack.push(Work::Capture(slot));
+                    self.stack.push(Work::Expand(body, depth + 1));
+                    self.stack.push(Work::Mark);
+                }
+                Fragment::Embed(body) => {
+                    // Start over with the whole depth budget
+                    self.stack.push(Work::Expand(*body, 0));
+                }
+                Fragment::Push(body) => {
+                    self.stack.push(Work::Push);
+                    self.stack.push(Work::Expand(*body, depth + 1));
+                    self.stack.push(Work::Mark);
+                }
+                &Fragment::Transform(body, transform) => {
+                    self.stack.push(Work::Transform(transform));
+                    self.stack.push(Work::Expand(body, depth + 1));
+                    self.stack.push(Work::Mark);
+                }
+                Fragment::Pop => {
+                    if let Some(value) = self.context.pop() {
+                        buf.extend_from_slice(&value);
+                    }
+                }
+                Fragment::Message(separator) => {
+                    self.message(buf, separator);
+                }
+                Fragment::Terminal(value) => {
+                    buf.extend_from_slice(value);
+                }
+                Fragment::Int(int) => {
+                    let value = int.value(|| self.rand());
+                    buf.extend(int.encode(value));
+                }
+                Fragment::Float(float) => {
+                    let value = float.value(|| self.rand());
+                    buf.extend(RandomFloat::encode(value));
+                }
+                Fragment::Bytes(tree) => {
+                    buf.push(tree.sample(|| self.rand()));
+                }
+                Fragment::Nop => {}
+            }
+        }
+    }
+
+    /// Split `buf`, the last input generated, into its messages. Inputs
+    /// without `%message` boundaries are a single message.
+    pub fn messages<'b>(&self, buf: &'b [u8]) -> Vec<&'b [u8]> {
+        let mut messages = Vec::new();
+        let mut start = 0;
+        for &(sep_start, sep_end) in self.messages.iter() {
+            messages.push(&buf[start..sep_start]);
+            start = sep_end;
+        }
+        messages.push(&buf[start..]);
+        messages
+    }
+
+    /// Emit a message boundary with `separator` into `buf`
+    fn message(&mut self, buf: &mut Vec<u8>, separator: &[u8]) {
+        let start = buf.len();
+        buf.extend_from_slice(separator);
+        self.messages.push((start, buf.len()));
+    }
+
+    /// Finish a `Fragment::Push` by pushing the output of its body onto the
+    /// context stack
+    fn push(&mut self, buf: &[u8]) {
+        let (start, _) = self.marks.pop().unwrap();
+        self.context.push(buf[start..].to_vec());
+    }
+
+    /// Finish a `Fragment::Transform` by rewriting the output of its body.
+    /// Message boundaries within it are dropped.
+    fn transform(&mut self, buf: &mut Vec<u8>, transform: Transform) {
+        let (start, messages) = self.marks.pop().unwrap();
+        self.messages.truncate(messages);
+        transform.apply(buf, start, || self.rand());
+    }
+
+    /// Finish a `Fragment::Length` by inserting the length of its body where
+    /// it started. Message boundaries within it would be moved, so they're
+    /// dropped.
+    fn length(&mut self, buf: &mut Vec<u8>, encoding: LengthEncoding) {
+        let (body, _) = self.marks.pop().unwrap();
+        let (start, messages) = self.marks.pop().unwrap();
+        buf.splice(start..start, encoding.encode((buf.len() - body) as u64));
+        self.messages.truncate(messages);
+    }
+
+    /// Finish a `Fragment::Chunked` by re-encoding its body as randomly sized
+    /// chunks. Message boundaries within it are dropped.
+    fn chunked(&mut self, buf: &mut Vec<u8>) {
+        let (start, messages) = self.marks.pop().unwrap();
+        self.messages.truncate(messages);
+        let body = buf.split_off(start);
+        let mut rest = &body[..];
+        while !rest.is_empty() {
+            let len = 1 + self.rand() % rest.len();
+            buf.extend_from_slice(format!("{:x}\r\n", len).as_bytes());
+            buf.extend_from_slice(&rest[..len]);
+            buf.extend_from_slice(b"\r\n");
+            rest = &rest[len..];
+        }
+        buf.extend_from_slice(b"0\r\n\r\n");
+    }
+
+    /// Returns `true` if the current input has used up the step budget
+    fn steps_exhausted(&self) -> bool {
+        self.options.max_steps.is_some_and(|x| self.steps >= x)
+    }
+
+    /// Get the way choices are steered once `len` bytes have been generated,
+    /// `Greater` to grow the output and `Less` to finish it. Choices aren't
+    /// steered, `Equal`, within the slack of the target size or without one.
+    fn toward(&self, len: usize) -> Ordering {
+        match self.options.target_range() {
+            Some((min, _)) if len < min => Ordering::Greater,
+            Some((_, max)) if len > max => Ordering::Less,
+            _ => Ordering::Equal,
+        }
+    }
+
+    /// Run one iteration of the `Fragment::Loop` `fragment_id`, with
+    /// `remaining` iterations left in the budget and `len` bytes generated
+    /// so far
+    fn iterate(&mut self, fragment_id: FragmentId, depth: usize,
+            remaining: usize, len: usize) {
+        let (options, repeat) = match &self.grammar.fragments[fragment_id.0] {
+            Fragment::Loop { options, repeat } => (options, repeat),
+            _ => unreachable!(),
+        };
+
+        if remaining == 0 {
+            return;
+        }
+
+        // Pick a random option, from those steering towards the target size
+        // if there is one. If it repeats then come back for another
+        // iteration once it has been expanded.
+        let toward = self.toward(len);
+        let option = if toward == Ordering::Equal {
+            self.rand() % options.len()
+        } else {
+            let all: Vec<usize> = (0..options.len()).collect();
+            let subset = steered(&self.steering[fragment_id.0], &all, toward);
+            subset[self.rand() % subset.len()]
+        };
+        if repeat[option] {
+            self.stack.push(Work::Loop(fragment_id, depth, remaining - 1));
+        }
+        self.stack.push(Work::Expand(options[option], depth + 1));
+    }
+
+    /// Generate a derivation of the target size uniformly at random into
+    /// `buf`, using the derivation counts
+    fn generate_uniform(&mut self, buf: &mut Vec<u8>) {
+        let counts = self.counts.take().unwrap();
+
+        self.stack.push(Work::Sized(self.grammar.start.unwrap(), counts.size));
+        while let Some(work) = self.stack.pop() {
+            let (fragment_id, size) = match work {
+                Work::Sized(fragment_id, size) => (fragment_id, size),
+                Work::SizedExpr(fragment_id, child, size) => {
+                    self.expand_children(&counts, fragment_id, child, size);
+                    continue;
+                }
+                Work::Mark => {
+                    self.marks.push((buf.len(), self.messages.len()));
+                    continue;
+                }
+                Work::Push => {
+                    self.push(buf);
+                    continue;
+                }
+                Work::Transform(transform) => {
+                    self.transform(buf, transform);
+                    continue;
+                }
+                Work::Expand(..) | Work::Loop(..) | Work::Length(_) |
+                        Work::Chunked | Work::Capture(_) => {
+                    unreachable!()
+                }
+            };
+
+            match &self.grammar.fragments[fragment_id.0] {
+                Fragment::NonTerminal(options) => {
+                    // Weight each option by its derivations of the size
+                    // remaining after this expansion
+                    let option = choose(self.rand() as u64, options.len(),
+                        |x| counts.tables[counts.fragment(options[x]) +
+                            size - 1]);
+                    self.stack.push(Work::Sized(options[option], size - 1));
+                }
+                Fragment::Expression(_) => {
+                    self.expand_children(&counts, fragment_id, 0, size - 1);
+                }
+                Fragment::Loop { options, repeat } => {
+                    // Weight finishing options by their own derivations and
+                    // repeating options by their derivations combined with
+                    // the rest of the loop
+                    let table = |x: usize| if repeat[x] {
+                        counts.repeats[&(fragment_id, x)]
+                    } else {
+                        counts.fragment(options[x])
+                    };
+                    let option = choose(self.rand() as u64, options.len(),
+                        |x| counts.tables[table(x) + size - 1]);
+
+                    if repeat[option] {
+                        // Split the size between this iteration and the rest
+                        // of the loop
+                        let first = counts.split(self.rand() as u64,
+                            counts.fragment(options[option]),
+                            counts.fragment(fragment_id), size - 1);
+                        self.stack.push(
+                            Work::Sized(fragment_id, size - 1 - first));
+                        self.stack.push(Work::Sized(options[option], first));
+                    } else {
+                        self.stack.push(Work::Sized(options[option], size - 1));
+                    }
+                }
+                Fragment::Terminal(value) => {
+                    buf.extend_from_slice(value);
+                }
+                Fragment::Int(int) => {
+                    let value = int.value(|| self.rand());
+                    buf.extend(int.encode(value));
+                }
+                Fragment::Float(float) => {
+                    let value = float.value(|| self.rand());
+                    buf.extend(RandomFloat::encode(value));
+                }
+                Fragment::Embed(body) => {
+                    self.stack.push(Work::Sized(*body, size - 1));
+                }
+                Fragment::Push(body) => {
+                    self.stack.push(Work::Push);
+                    self.stack.push(Work::Sized(*body, size - 1));
+                    self.stack.push(Work::Mark);
+                }
+                &Fragment::Transform(body, transform) => {
+                    self.stack.push(Work::Transform(transform));
+                    self.stack.push(Work::Sized(body, size - 1));
+                    self.stack.push(Work::Mark);
+                }
+                Fragment::Pop => {
+                    if let Some(value) = self.context.pop() {
+                        buf.extend_from_slice(&value);
+                    }
+                }
+                Fragment::Message(separator) => {
+                    self.message(buf, separator);
+                }
+                Fragment::Length(..) | Fragment::Chunked(_) |
+                        Fragment::Capture { .. } | Fragment::Recall { .. } => {
+                    // Rejected when counting derivations
+                    unreachable!()
+                }
+                Fragment::Bytes(_) => {
+                    // Byte samplers aren't used when sampling uniformly
+                    unreachable!()
+                }
+                Fragment::Nop => {}
+            }
+        }
+
+        self.counts = Some(counts);
+    }
+
+    /// Expand the children of the expression `fragment_id` from index `child`
+    /// onwards with a combined size of `size`, when sampling uniformly
+    fn expand_children(&mut self, counts: &Counts, fragment_id: FragmentId,
+            child: usize, size: usize) {
+        let expr = match &self.grammar.fragments[fragment_id.0] {
+            Fragment::Expression(expr) => expr,
+            _ => unreachable!(),
+        };
+
+        if child + 1 >= expr.len() {
+            // The last child gets whatever size is left
+            if let Some(&last) = expr.last() {
+                self.stack.push(Work::Sized(last, size));
+            }
+            return;
+        }
+
+        // Split the size between this child and the rest, and come back for
+        // the rest once this child has been expanded
+        let first = counts.split(self.rand() as u64,
+            counts.fragment(expr[child]),
+            counts.suffixes[&(fragment_id, child + 1)], size);
+        self.stack.push(Work::SizedExpr(fragment_id, child + 1, size - first));
+        self.stack.push(Work::Sized(expr[child], first));
+    }
+}
@@ -0,0 +1,496 @@
+//! Interesting integers. Uniformly random digits almost never hit the values
+//! which find bugs in numeric fields, like zero, the limits of the type or
+//! the values just past them. The `%int` directive defines rules as integers
+//! of a type, eg. `"%int": [["<port>", "u16"], ["<len>", "u32", "le"]]`,
+//! which half of the time are one of the boundary values of the type and
+//! otherwise random. An optional third token picks the encoding, `decimal`
+//! by default, `hex` for fixed width lowercase hex, `le` and `be` for binary
+//! or `bits` for a string of `0` and `1` to be packed with `%pack`. Bit
+//! fields may be of any width up to 64 bits, eg. `u13`.
+//!
+//! The rules are plain alternatives, so every other part of fzero handles
+//! them like any other rule.
+//!
+//! Binary formats more often just need a random integer of a given width and
+//! byte order. References to the builtin rules `<u8>`, `<u16_le>`,
+//! `<i32_be>`, `<varint>` and so on, optionally limited to a range like
+//! `<u16_be:0..=1500>`, become a `Fragment::Int` which draws the value with a
+//! single random number and encodes it directly, rather than picking from
+//! alternatives for every byte. A grammar defining a rule of the same name
+//! gets its own rule instead.
+
+use std::collections::BTreeSet;
+use crate::grammar::{bytes_token, Directives, Grammar, LengthEncoding};
+
+/// How the value of an integer rule is written out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    /// Decimal digits, with a minus sign if negative
+    Decimal,
+
+    /// Lowercase hex digits of the two's complement value, zero padded to
+    /// the width of the type
+    Hex,
+
+    /// Two's complement little endian bytes
+    Le,
+
+    /// Two's complement big endian bytes
+    Be,
+
+    /// Two's complement bits as `0` and `1`, most significant bit first
+    Bits,
+}
+
+/// A rule defined by the `%int` directive
+#[derive(Clone, Debug)]
+pub struct IntRule {
+    /// Name of the rule
+    pub rule: String,
+
+    /// Number of bits of the integer type
+    pub bits: u32,
+
+    /// Whether the type is signed
+    pub signed: bool,
+
+    /// How values are written out
+    pub encoding: Encoding,
+}
+
+impl IntRule {
+    /// Smallest and largest value of the type
+    fn range(&self) -> (i128, i128) {
+        if self.signed {
+            (-(1 << (self.bits - 1)), (1 << (self.bits - 1)) - 1)
+        } else {
+            (0, (1 << self.bits) - 1)
+        }
+    }
+
+    /// Get the boundary values of the type: zero, one, minus one, the limits
+    /// and their neighbours, and the powers of two along with their
+    /// neighbours. Decimal, and hex for unsigned types, also get the values
+    /// just past the limits, as parsers have to reject those rather than
+    /// wrap around.
+    fn boundaries(&self) -> BTreeSet<i128> {
+        let (min, max) = self.range();
+        let mut values: BTreeSet<i128> =
+            [0, 1, -1, 2, min, min + 1, max, max - 1].iter().copied().collect();
+        for power in (1..self.bits).map(|x| 1i128 << x) {
+            for value in [power - 1, power, power + 1] {
+                values.insert(value);
+                values.insert(-value);
+            }
+        }
+        values.insert(min - 1);
+        values.insert(max + 1);
+
+        // Hex is written as the two's complement, where signed values past
+        // the limits wrap around to the other limit
+        let overflow = self.encoding == Encoding::Decimal ||
+            (self.encoding == Encoding::Hex && !self.signed);
+        let (low, high) = if overflow { (min - 1, max + 1) } else {
+            (min, max)
+        };
+        values.retain(|x| {
+            (low..=high).contains(x) && (self.signed || *x >= 0)
+        });
+        values
+    }
+
+    /// Get the token emitting `value`
+    fn token(&self, value: i128) -> String {
+        let bytes = self.bits as usize / 8;
+        let twos = value.rem_euclid(1 << self.bits);
+        match self.encoding {
+            Encoding::Decimal => value.to_string(),
+
+            // Only the unsigned value past the limit doesn't wrap around,
+            // which is the one larger than its two's complement
+            Encoding::Hex => format!("{:01$x}", twos.max(value), bytes * 2),
+            Encoding::Le => bytes_token(&twos.to_le_bytes()[..bytes]),
+            Encoding::Be => bytes_token(&twos.to_be_bytes()[16 - bytes..]),
+            Encoding::Bits => format!("{:01$b}", twos, self.bits as usize),
+        }
+    }
+}
+
+impl Directives {
+    /// Get the integer rules defined by the `%int` directive, if any
+    pub fn ints(&self) -> std::io::Result<Vec<IntRule>> {
+        let mut ints = Vec::new();
+        for alt in self.0.get("%int").into_iter().flatten() {
+            let (rule, kind, encoding) = match alt.as_slice() {
+                [rule, kind] => (rule, kind, "decimal"),
+                [rule, kind, encoding] => (rule, kind, encoding.as_str()),
+                _ => return Err(invalid(alt)),
+            };
+            let encoding = match encoding {
+                "decimal" => Encoding::Decimal,
+                "hex" => Encoding::Hex,
+                "le" => Encoding::Le,
+                "be" => Encoding::Be,
+                "bits" => Encoding::Bits,
+                _ => return Err(invalid(alt)),
+            };
+
+            // Only bit fields can have widths other than the Rust types
+            let signed = kind.starts_with('i');
+            let bits = kind.strip_prefix(['i', 'u']).and_then(|x| {
+                x.parse().ok().filter(|x| if encoding == Encoding::Bits {
+                    (1..=64).contains(x)
+                } else {
+                    [8, 16, 32, 64].contains(x)
+                })
+            }).ok_or_else(|| invalid(alt))?;
+            ints.push(IntRule { rule: rule.clone(), bits, signed, encoding });
+        }
+        Ok(ints)
+    }
+}
+
+/// Create an error for the invalid `%int` entry `alt`
+fn invalid(alt: &[String]) -> std::io::Error {
+    std::io::Error::other(format!("Invalid %int entry {:?}, expected a rule, \
+        a type from u8 to u64 or i8 to i64 and optionally an encoding of \
+        decimal, hex, le, be or bits, which allows any width up to 64 bits",
+        alt))
+}
+
+/// Name of a rule generated for the integer rule `rule`, eg. `<port%random>`
+/// for `<port>`
+fn part(rule: &str, name: &str) -> String {
+    match rule.strip_suffix('>') {
+        Some(rule) => format!("{}%{}>", rule, name),
+        None => format!("{}%{}", rule, name),
+    }
+}
+
+impl Grammar {
+    /// Define the integer rules `ints`, see the module documentation
+    pub fn define_ints(&mut self, ints: &[IntRule]) -> std::io::Result<()> {
+        for int in ints {
+            let boundary = part(&int.rule, "boundary");
+            let random = part(&int.rule, "random");
+            for rule in [&int.rule, &boundary, &random] {
+                if self.0.contains_key(rule) {
+                    return Err(std::io::Error::other(format!(
+                        "Can't define the %int rule {}, {} is already defined",
+                        int.rule, rule)));
+                }
+            }
+
+            // Pick a boundary value half of the time
+            self.0.insert(int.rule.clone(),
+                vec![vec![boundary.clone()], vec![random.clone()]]);
+            self.0.insert(boundary, int.boundaries().into_iter()
+                .map(|x| vec![int.token(x)]).collect());
+            let alts = self.random(int);
+            self.0.insert(random, alts);
+        }
+        Ok(())
+    }
+
+    /// Get the alternatives of a random value of `int`, defining the rules
+    /// of digits and bytes it's made of
+    fn random(&mut self, int: &IntRule) -> Vec<Vec<String>> {
+        let bytes = int.bits as usize / 8;
+        match int.encoding {
+            // Every value is equally likely
+            Encoding::Le | Encoding::Be => {
+                let byte = self.digits("<%byte>", 256,
+                    |x| bytes_token(&[x as u8]));
+                vec![vec![byte; bytes]]
+            }
+            Encoding::Hex => {
+                let digit = self.digits("<%hex_digit>", 16,
+                    |x| format!("{:x}", x));
+                vec![vec![digit; bytes * 2]]
+            }
+            Encoding::Bits => {
+                let bit = self.digits("<%bit>", 2, |x| x.to_string());
+                vec![vec![bit; int.bits as usize]]
+            }
+
+            // Every number of digits which always fits in the type is equally
+            // likely, as small values are more interesting than large ones
+            Encoding::Decimal => {
+                let digit = self.digits("<%digit>", 10, |x| x.to_string());
+                let leading = self.digits("<%nonzero_digit>", 9,
+                    |x| (x + 1).to_string());
+                let digits = int.range().1.to_string().len();
+                let mut alts = vec![vec![digit.clone()]];
+                for len in 2..digits {
+                    let mut alt = vec![leading.clone()];
+                    alt.extend(std::iter::repeat_n(digit.clone(), len - 1));
+                    alts.push(alt);
+                }
+                if int.signed {
+                    let negative: Vec<Vec<String>> = alts.iter().map(|alt| {
+                        std::iter::once(String::from("-"))
+                            .chain(alt.iter().cloned()).collect()
+                    }).collect();
+                    alts.extend(negative);
+                }
+                alts
+            }
+        }
+    }
+
+    /// Define the rule `name` picking one of `count` terminals made by
+    /// `token`, unless an earlier integer rule already did, and return its
+    /// name
+    fn digits(&mut self, name: &str, count: usize,
+            token: impl Fn(usize) -> String) -> String {
+        self.0.entry(name.to_string())
+            .or_insert_with(|| (0..count).map(|x| vec![token(x)]).collect());
+        name.to_string()
+    }
+}
+
+/// How a `Fragment::Int` writes out its value
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IntFormat {
+    /// The given number of little endian bytes
+    Le(usize),
+
+    /// The given number of big endian bytes
+    Be(usize),
+
+    /// A protobuf style varint, see `LengthEncoding::Varint`
+    Varint,
+
+    /// Decimal digits, with a minus sign if negative. This comes from the
+    /// `%range(low, high)` generator, see `scalars`.
+    Decimal,
+}
+
+/// Builtin integer rules, by name without the angle brackets, along with
+/// their format and whether they're signed
+const BUILTINS: &[(&str, IntFormat, bool)] = &[
+    ("u8",     IntFormat::Le(1), false),
+    ("i8",     IntFormat::Le(1), true),
+    ("u16_le", IntFormat::Le(2), false),
+    ("u16_be", IntFormat::Be(2), false),
+    ("i16_le", IntFormat::Le(2), true),
+    ("i16_be", IntFormat::Be(2), true),
+    ("u32_le", IntFormat::Le(4), false),
+    ("u32_be", IntFormat::Be(4), false),
+    ("i32_le", IntFormat::Le(4), true),
+    ("i32_be", IntFormat::Be(4), true),
+    ("u64_le", IntFormat::Le(8), false),
+    ("u64_be", IntFormat::Be(8), false),
+    ("i64_le", IntFormat::Le(8), true),
+    ("i64_be", IntFormat::Be(8), true),
+    ("varint", IntFormat::Varint, false),
+];
+
+/// Get the smallest and largest value of an integer in `format`
+fn limits(format: IntFormat, signed: bool) -> (i128, i128) {
+    let bits = match format {
+        IntFormat::Le(bytes) | IntFormat::Be(bytes) => bytes as u32 * 8,
+        IntFormat::Varint | IntFormat::Decimal => 64,
+    };
+    if signed {
+        (-(1 << (bits - 1)), (1 << (bits - 1)) - 1)
+    } else {
+        (0, (1 << bits) - 1)
+    }
+}
+
+/// A random integer emitted by a reference to a builtin integer rule, see
+/// the module documentation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RandomInt {
+    /// Name of the builtin rule, without a range
+    pub builtin: &'static str,
+
+    /// How the value is written out
+    pub format: IntFormat,
+
+    /// Whether the value is signed, which only matters for its range
+    pub signed: bool,
+
+    /// Smallest value, as a two's complement
+    pub low: u64,
+
+    /// Number of values from `low` on, where zero is every value. Full range
+    /// varints pick a random number of bits first, as the short encodings
+    /// of small values are the interesting ones.
+    pub span: u64,
+}
+
+impl RandomInt {
+    /// Parse a reference to a builtin integer rule like `<u16_le>` or
+    /// `<i8:-5..=5>`. Returns `None` if `token` isn't one, and an error if
+    /// its range is invalid.
+    pub fn parse(token: &str) -> Option<std::io::Result<Self>> {
+        let name = token.strip_prefix('<')?.strip_suffix('>')?;
+        let (name, range) = match name.split_once(':') {
+            Some((name, range)) => (name, Some(range)),
+            None => (name, None),
+        };
+        let &(builtin, format, signed) =
+            BUILTINS.iter().find(|(x, _, _)| *x == name)?;
+
+        let (min, max) = limits(format, signed);
+        let (low, high) = match range {
+            None => (min, max),
+            Some(range) => {
+                let bounds = match range.split_once("..=") {
+                    Some((low, high)) => low.parse::<i128>().ok()
+                        .zip(high.parse::<i128>().ok()),
+                    None => range.split_once("..").and_then(|(low, high)| {
+                        low.parse::<i128>().ok()
+                            .zip(high.parse::<i128>().ok().map(|x| x - 1))
+                    }),
+                };
+                match bounds.filter(|&(low, high)| {
+                    min <= low && low <= high && high <= max
+                }) {
+                    Some(bounds) => bounds,
+                    None => return Some(Err(std::io::Error::other(format!(
+                        "Invalid range in {}, expected a non-empty range \
+                         like `1..=100` from {} to {}", token, min, max)))),
+                }
+            }
+        };
+
+        Some(Ok(RandomInt::new(builtin, format, signed, low, high)))
+    }
+
+    /// Create a random integer from `low` to `high`, which have to be within
+    /// the limits of the type
+    fn new(builtin: &'static str, format: IntFormat, signed: bool,
+            low: i128, high: i128) -> Self {
+        // The full range of 64-bit values wraps around to a span of zero
+        RandomInt {
+            builtin, format, signed,
+            low: low as u64,
+            span: (high - low + 1) as u64,
+        }
+    }
+
+    /// Create a random decimal integer from `low` to `high`, for
+    /// `%range(low, high)`. Returns `None` unless the range is non-empty and
+    /// fits in an `i64`, or a `u64` if `low` isn't negative.
+    pub fn decimal(low: i128, high: i128) -> Option<Self> {
+        let signed = low < 0;
+        let (min, max) = limits(IntFormat::Decimal, signed);
+        (min <= low && low <= high && high <= max).then(|| {
+            RandomInt::new("%range", IntFormat::Decimal, signed, low, high)
+        })
+    }
+
+    /// Get the smallest and largest value
+    fn bounds(&self) -> (i128, i128) {
+        let high = self.low.wrapping_add(self.span.wrapping_sub(1));
+        if self.signed {
+            (self.low as i64 as i128, high as i64 as i128)
+        } else {
+            (self.low as i128, high as i128)
+        }
+    }
+
+    /// Get the reference to this builtin, including its range
+    pub fn name(&self) -> String {
+        let (low, high) = self.bounds();
+        if self.format == IntFormat::Decimal {
+            format!("%range({}, {})", low, high)
+        } else if (low, high) == limits(self.format, self.signed) {
+            format!("<{}>", self.builtin)
+        } else {
+            format!("<{}:{}..={}>", self.builtin, low, high)
+        }
+    }
+
+    /// Draw a value from the random numbers of `rand`. This must match the
+    /// code generated by `Codegen`.
+    pub fn value(&self, mut rand: impl FnMut() -> usize) -> u64 {
+        match (self.format, self.span) {
+            (IntFormat::Varint, 0) => {
+                let bits = rand() % 64 + 1;
+                rand() as u64 >> (64 - bits)
+            }
+            (_, 0) => rand() as u64,
+            (_, span) => self.low.wrapping_add(rand() as u64 % span),
+        }
+    }
+
+    /// Encode `value` in the format of this integer
+    pub fn encode(&self, value: u64) -> Vec<u8> {
+        match self.format {
+            IntFormat::Le(bytes) => value.to_le_bytes()[..bytes].to_vec(),
+            IntFormat::Be(bytes) => value.to_be_bytes()[8 - bytes..].to_vec(),
+            IntFormat::Varint => LengthEncoding::Varint.encode(value),
+            IntFormat::Decimal if self.signed => {
+                (value as i64).to_string().into_bytes()
+            }
+            IntFormat::Decimal => value.to_string().into_bytes(),
+        }
+    }
+
+    /// Get the length of the encoding of a value in range at the start of
+    /// `input`, if there is one
+    pub fn recognize(&self, input: &[u8]) -> Option<usize> {
+        let (value, len, bits) = match self.format {
+            IntFormat::Le(bytes) | IntFormat::Be(bytes) => {
+                let mut raw = [0u8; 8];
+                let value = input.get(..bytes)?;
+                if let IntFormat::Le(_) = self.format {
+                    raw[..bytes].copy_from_slice(value);
+                    (u64::from_le_bytes(raw), bytes, bytes * 8)
+                } else {
+                    raw[8 - bytes..].copy_from_slice(value);
+                    (u64::from_be_bytes(raw), bytes, bytes * 8)
+                }
+            }
+            IntFormat::Varint => {
+                // Only the shortest encoding is ever generated
+                let len = input.iter().take(10).position(|x| x & 0x80 == 0)?
+                    + 1;
+                if len > 1 && input[len - 1] == 0 {
+                    return None;
+                }
+                let value = input[..len].iter().rev()
+                    .fold(0u64, |acc, x| acc << 7 | (x & 0x7f) as u64);
+                (value, len, 64)
+            }
+            IntFormat::Decimal => {
+                // Take every digit, as values are never followed by more
+                let sign = (input.first() == Some(&b'-')) as usize;
+                let digits = input[sign..].iter()
+                    .take_while(|x| x.is_ascii_digit()).count();
+                let text = std::str::from_utf8(&input[..sign + digits]).ok()?;
+                let value: i128 = text.parse().ok()?;
+                let (low, high) = self.bounds();
+                let canonical = value.to_string() == text;
+                return (canonical && (low..=high).contains(&value))
+                    .then_some(text.len());
+            }
+        };
+
+        let offset = value.wrapping_sub(self.low) & (u64::MAX >> (64 - bits));
+        (self.span == 0 || offset < self.span).then_some(len)
+    }
+
+    /// Get the shortest and longest encoding of any value
+    pub fn lens(&self) -> (usize, usize) {
+        match self.format {
+            IntFormat::Le(bytes) | IntFormat::Be(bytes) => (bytes, bytes),
+            IntFormat::Varint if self.span == 0 => (1, 10),
+            IntFormat::Varint => {
+                let high = self.low.wrapping_add(self.span - 1);
+                (self.encode(self.low).len(), self.encode(high).len())
+            }
+            IntFormat::Decimal => {
+                let (low, high) = self.bounds();
+                let closest = if low > 0 { low } else if high < 0 { high }
+                    else { 0 };
+                (closest.to_string().len(),
+                    low.to_string().len().max(high.to_string().len()))
+            }
+        }
+    }
+}
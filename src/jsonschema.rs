@@ -0,0 +1,1174 @@
+//! Conversion of JSON Schemas into grammars which generate documents
+//! conforming to them. Every schema gets a rule named after its JSON
+//! pointer, eg. `<#/properties/name>`, so local `$ref`s become references to
+//! rules and recursive schemas become recursive grammars. `allOf` is handled
+//! by merging its schemas, and `anyOf` and `oneOf` by generating each of
+//! their schemas as an alternative.
+//!
+//! Numbers with bounds are picked from interesting values within the
+//! bounds, such as the bounds themselves, powers of two and their
+//! neighbours. `pattern`, `uniqueItems`, `not` and `patternProperties`
+//! aren't supported, and are warned about.
+//!
+//! Near misses can also be generated, documents which break the schema in
+//! exactly one place. Every rule gets a near miss version which either
+//! breaks the schema itself, eg. with a value of the wrong type, a number
+//! just out of bounds, a string or array one element too long or too short,
+//! or an object missing its required properties, or which uses the near
+//! miss version of one of the rules it refers to. Half of the generated
+//! documents are near misses.
+
+use std::collections::BTreeSet;
+use serde_json::{Map, Value};
+use crate::grammar::Grammar;
+
+/// Maximum number of optional characters or elements emitted for strings
+/// and arrays with a maximum length, longer maximums are cut down to this
+const MAX_OPTIONAL: usize = 64;
+
+/// Maximum depth of `$ref`s and `allOf`s merged into a single schema
+const MAX_MERGE_DEPTH: usize = 64;
+
+/// Keywords which don't constrain documents
+const ANNOTATIONS: &[&str] = &["$schema", "$id", "$comment", "title",
+    "description", "default", "examples", "definitions", "$defs",
+    "deprecated", "readOnly", "writeOnly"];
+
+/// Keywords we don't support
+const UNSUPPORTED: &[&str] = &["pattern", "uniqueItems", "not",
+    "patternProperties", "dependencies", "dependentRequired",
+    "dependentSchemas", "if", "contains", "propertyNames"];
+
+/// A schema which applies to a value, along with the JSON pointer it was
+/// found at
+type Layer = (String, Map<String, Value>);
+
+/// Get the rule name for the schema at the JSON pointer `ptr`. Characters
+/// which can't be in a rule name are percent-encoded, the same as in the
+/// URI fragments used by `$ref`.
+fn pointer_name(ptr: &str) -> String {
+    let mut name = String::from("<#");
+    for chr in ptr.chars() {
+        if chr.is_whitespace() || chr.is_control() || "<>%".contains(chr) {
+            let mut buf = [0; 4];
+            for byte in chr.encode_utf8(&mut buf).bytes() {
+                name += &format!("%{:02X}", byte);
+            }
+        } else {
+            name.push(chr);
+        }
+    }
+    name + ">"
+}
+
+/// Get the name of a rule derived from the rule `name`, eg. a helper rule
+/// for the elements of an array
+fn derived(name: &str, suffix: &str) -> String {
+    format!("{}:{}>", &name[..name.len() - 1], suffix)
+}
+
+/// Get the name of the near miss version of the rule `name`
+fn miss(name: &str) -> String {
+    derived(name, "miss")
+}
+
+/// Returns `true` if `token` refers to a rule, rather than being a terminal.
+/// Terminals are JSON text, which never starts with `<#` or `<json.`.
+fn is_rule(token: &str) -> bool {
+    token.starts_with("<#") || token.starts_with("<json.") ||
+        token == "<start>"
+}
+
+/// Escape a key for use in a JSON pointer
+fn escape_key(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+/// Get the alternatives of single characters for each of `chars`
+fn chars(chars: &str) -> Vec<Vec<String>> {
+    chars.chars().map(|x| vec![x.to_string()]).collect()
+}
+
+/// Get the alternatives of a built-in rule, which are named `<json.*>`
+fn builtin_rule(name: &str) -> Vec<Vec<String>> {
+    let alts: &[&[&str]] = match name {
+        "<json.any>" => &[&["null"], &["true"], &["false"],
+            &["<json.number>"], &["<json.string>"],
+            &["[", "<json.elements>", "]"], &["{", "<json.members>", "}"]],
+        "<json.elements>" => &[&[], &["<json.any>", "<json.elements_rest>"]],
+        "<json.elements_rest>" => {
+            &[&[], &[",", "<json.any>", "<json.elements_rest>"]]
+        }
+        "<json.members>" => {
+            &[&[], &["<json.string>", ":", "<json.any>",
+                "<json.members_rest>"]]
+        }
+        "<json.members_rest>" => {
+            &[&[], &[",", "<json.string>", ":", "<json.any>",
+                "<json.members_rest>"]]
+        }
+        "<json.integer>" => &[&["<json.natural>"], &["-", "<json.natural>"]],
+        "<json.natural>" => &[&["0"], &["<json.digit1>", "<json.digits>"]],
+        "<json.digit>" => return chars("0123456789"),
+        "<json.digit1>" => return chars("123456789"),
+        "<json.digits>" => &[&[], &["<json.digit>", "<json.digits>"]],
+        "<json.number>" => &[&["<json.integer>"],
+            &["<json.integer>", "<json.fraction>"],
+            &["<json.integer>", "<json.exponent>"],
+            &["<json.integer>", "<json.fraction>", "<json.exponent>"]],
+        "<json.fraction>" => &[&[".", "<json.digit>", "<json.digits>"]],
+        "<json.exponent>" => {
+            &[&["e", "<json.sign>", "<json.digit>", "<json.digits>"],
+              &["E", "<json.sign>", "<json.digit>", "<json.digits>"]]
+        }
+        "<json.sign>" => &[&[], &["+"], &["-"]],
+        "<json.string>" => &[&["\"", "<json.chars>", "\""]],
+        "<json.chars>" => &[&[], &["<json.char>", "<json.chars>"]],
+        "<json.char>" => {
+            // Every alternative is a single code point, so lengths can be
+            // counted in them
+            let mut alts: Vec<Vec<String>> = (0x20u8..0x7f)
+                .filter(|&x| x != b'"' && x != b'\\')
+                .map(|x| vec![(x as char).to_string()]).collect();
+            alts.extend(["\\\"", "\\\\", "\\/", "\\b", "\\f", "\\n",
+                "\\r", "\\t", "\\u00e9", "\\ud83d\\ude00", "\u{e9}",
+                "\u{20ac}", "\u{1f600}"].iter().map(|x| vec![x.to_string()]));
+            return alts;
+        }
+
+        // Formats, as whole strings
+        "<json.format.date>" => &[&["\"", "<json.date>", "\""]],
+        "<json.format.time>" => &[&["\"", "<json.time>", "\""]],
+        "<json.format.date-time>" => {
+            &[&["\"", "<json.date>", "T", "<json.time>", "\""]]
+        }
+        "<json.date>" => &[&["<json.digit>", "<json.digit>", "<json.digit>",
+            "<json.digit>", "-", "<json.month>", "-", "<json.day>"]],
+        "<json.month>" => &[&["0", "<json.digit1>"], &["1", "0"], &["1", "1"],
+            &["1", "2"]],
+        "<json.day>" => &[&["0", "<json.digit1>"], &["1", "<json.digit>"],
+            &["2", "<json.digit1>"], &["2", "0"]],
+        "<json.time>" => &[&["<json.hour>", ":", "<json.minute>", ":",
+            "<json.minute>", "<json.seconds>", "<json.offset>"]],
+        "<json.hour>" => &[&["0", "<json.digit>"], &["1", "<json.digit>"],
+            &["2", "0"], &["2", "1"], &["2", "2"], &["2", "3"]],
+        "<json.minute>" => &[&["0", "<json.digit>"], &["1", "<json.digit>"],
+            &["2", "<json.digit>"], &["3", "<json.digit>"],
+            &["4", "<json.digit>"], &["5", "<json.digit>"]],
+        "<json.seconds>" => &[&[], &[".", "<json.digit>", "<json.digits>"]],
+        "<json.offset>" => &[&["Z"],
+            &["+", "<json.hour>", ":", "<json.minute>"],
+            &["-", "<json.hour>", ":", "<json.minute>"]],
+        "<json.format.email>" => {
+            &[&["\"", "<json.label>", "@", "<json.label>", ".",
+                "<json.hostname>", "\""]]
+        }
+        "<json.format.hostname>" => &[&["\"", "<json.hostname>", "\""]],
+        "<json.hostname>" => &[&["<json.label>"],
+            &["<json.label>", ".", "<json.hostname>"]],
+        "<json.label>" => &[&["<json.letter>", "<json.alnums>"]],
+        "<json.alnums>" => &[&[], &["<json.letter>", "<json.alnums>"],
+            &["<json.digit>", "<json.alnums>"]],
+        "<json.letter>" => {
+            return chars(
+                "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ");
+        }
+        "<json.format.ipv4>" => &[&["\"", "<json.octet>", ".", "<json.octet>",
+            ".", "<json.octet>", ".", "<json.octet>", "\""]],
+        "<json.octet>" => &[&["<json.digit>"],
+            &["<json.digit1>", "<json.digit>"],
+            &["1", "<json.digit>", "<json.digit>"],
+            &["2", "<json.digit04>", "<json.digit>"],
+            &["2", "5", "<json.digit05>"]],
+        "<json.digit04>" => return chars("01234"),
+        "<json.digit05>" => return chars("012345"),
+        "<json.format.ipv6>" => &[&["\"", "::", "\""], &["\"", "::1", "\""],
+            &["\"", "<json.hex4>", ":", "<json.hex4>", ":", "<json.hex4>",
+              ":", "<json.hex4>", ":", "<json.hex4>", ":", "<json.hex4>", ":",
+              "<json.hex4>", ":", "<json.hex4>", "\""],
+            &["\"", "<json.hex4>", "::", "<json.hex4>", "\""]],
+        "<json.hex4>" => &[&["<json.hex>"], &["<json.hex>", "<json.hex>"],
+            &["<json.hex>", "<json.hex>", "<json.hex>"],
+            &["<json.hex>", "<json.hex>", "<json.hex>", "<json.hex>"]],
+        "<json.hex>" => return chars("0123456789abcdef"),
+        "<json.format.uri>" => &[&["\"", "<json.scheme>", "://",
+            "<json.hostname>", "<json.path>", "\""]],
+        "<json.scheme>" => &[&["http"], &["https"], &["ftp"], &["file"]],
+        "<json.path>" => &[&[], &["/", "<json.alnums>", "<json.path>"]],
+        "<json.format.uuid>" => &[&["\"", "<json.hex8>", "-", "<json.hex4x>",
+            "-", "<json.hex4x>", "-", "<json.hex4x>", "-", "<json.hex4x>",
+            "<json.hex8>", "\""]],
+        "<json.hex4x>" => {
+            &[&["<json.hex>", "<json.hex>", "<json.hex>", "<json.hex>"]]
+        }
+        "<json.hex8>" => &[&["<json.hex4x>", "<json.hex4x>"]],
+        _ => {
+            // Up to `N` characters, `<json.uptoN>`
+            let count: usize = name.strip_prefix("<json.upto")
+                .and_then(|x| x.strip_suffix('>'))
+                .and_then(|x| x.parse().ok())
+                .unwrap_or_else(|| panic!("Unknown built-in rule {}", name));
+            if count == 0 {
+                return vec![vec![]];
+            }
+            return vec![vec![], vec!["<json.char>".into(),
+                format!("<json.upto{}>", count - 1)]];
+        }
+    };
+    alts.iter().map(|x| x.iter().map(|x| x.to_string()).collect()).collect()
+}
+
+/// Get interesting integers, which are checked against the bounds of a
+/// schema
+fn interesting_integers() -> Vec<i128> {
+    let mut values = vec![0, 1, 2, 10, 100, 1000];
+    for bits in [7, 8, 15, 16, 31, 32, 53, 63, 64] {
+        values.extend([(1 << bits) - 1, 1 << bits, (1 << bits) + 1]);
+    }
+    let negative: Vec<i128> = values.iter().map(|x| -x).collect();
+    values.extend(negative);
+    values
+}
+
+/// Format a number as JSON, integers without a fraction
+fn format_number(value: f64) -> String {
+    if value.fract() == 0. && value.abs() < 1e36 {
+        format!("{}", value as i128)
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// Bounds of a number schema
+#[derive(Default)]
+struct Bounds {
+    /// Lower bound, and whether it's exclusive
+    min: Option<(f64, bool)>,
+
+    /// Upper bound, and whether it's exclusive
+    max: Option<(f64, bool)>,
+
+    /// Numbers must be a multiple of this
+    multiple_of: Option<f64>,
+}
+
+impl Bounds {
+    /// Check if `value` satisfies the bounds
+    fn contains(&self, value: f64) -> bool {
+        self.min.is_none_or(|(min, exclusive)| {
+            if exclusive { value > min } else { value >= min }
+        }) && self.max.is_none_or(|(max, exclusive)| {
+            if exclusive { value < max } else { value <= max }
+        }) && self.multiple_of.is_none_or(|x| (value / x).fract() == 0.)
+    }
+
+    /// Get interesting integers within the bounds. These are computed
+    /// exactly, so large multiples don't lose precision.
+    fn integers(&self) -> Vec<i128> {
+        let min = self.min.map(|(x, exclusive)| {
+            if exclusive { x.floor() as i128 + 1 } else { x.ceil() as i128 }
+        });
+        let max = self.max.map(|(x, exclusive)| {
+            if exclusive { x.ceil() as i128 - 1 } else { x.floor() as i128 }
+        });
+        let step = self.multiple_of.filter(|x| x.fract() == 0.)
+            .map_or(1, |x| x as i128);
+
+        // Interesting multiples, and multiples around and between the
+        // bounds
+        let mut values: Vec<i128> = interesting_integers().iter()
+            .filter_map(|x| x.checked_mul(step)).collect();
+        let mut around: Vec<i128> = min.iter().chain(max.iter()).copied()
+            .collect();
+        if let (Some(min), Some(max)) = (min, max) {
+            around.push(min + (max - min) / 2);
+        }
+        for value in around {
+            let value = value - value.rem_euclid(step);
+            values.extend([value - step, value, value + step,
+                value + 2 * step]);
+        }
+
+        values.retain(|&x| {
+            min.is_none_or(|min| x >= min) && max.is_none_or(|max| x <= max) &&
+                self.multiple_of.is_none_or(|m| (x as f64 / m).fract() == 0.)
+        });
+        values
+    }
+}
+
+/// State of a conversion
+struct Converter<'a> {
+    /// The whole schema, which pointers are resolved in
+    root: &'a Value,
+
+    /// Whether to generate near misses
+    near_miss: bool,
+
+    /// Grammar created so far
+    grammar: Grammar,
+
+    /// Pointers of schemas which still need rules
+    queue: Vec<String>,
+
+    /// Pointers of schemas which got queued, so each only gets one rule
+    queued: BTreeSet<String>,
+
+    /// Unsupported keywords we've already warned about
+    warned: BTreeSet<&'static str>,
+}
+
+impl Converter<'_> {
+    /// Get the rule for the schema at `ptr`, queueing it to be created
+    fn rule(&mut self, ptr: &str) -> String {
+        if self.queued.insert(ptr.to_string()) {
+            self.queue.push(ptr.to_string());
+        }
+        pointer_name(ptr)
+    }
+
+    /// Get a built-in rule, adding it and the rules it uses to the grammar
+    fn builtin(&mut self, name: &str) -> String {
+        if !self.grammar.0.contains_key(name) {
+            let alts = builtin_rule(name);
+            self.grammar.0.insert(name.to_string(), alts.clone());
+            for token in alts.iter().flatten() {
+                if token.starts_with("<json.") {
+                    self.builtin(token);
+                }
+            }
+        }
+        name.to_string()
+    }
+
+    /// Resolve a `$ref` found at `ptr` into a JSON pointer
+    fn resolve_ref(&self, reference: &Value, ptr: &str)
+            -> std::io::Result<String> {
+        let target = reference.as_str().and_then(|x| x.strip_prefix('#'))
+            .ok_or_else(|| std::io::Error::other(format!(
+                "#{} uses $ref {}, only references within the schema are \
+                 supported", ptr, reference)))?;
+
+        // Undo the percent-encoding of the URI fragment
+        let mut bytes = Vec::new();
+        let mut rest = target.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            let hex = tail.get(..2).and_then(|x| std::str::from_utf8(x).ok())
+                .and_then(|x| u8::from_str_radix(x, 16).ok());
+            match hex {
+                Some(value) if byte == b'%' => {
+                    bytes.push(value);
+                    rest = &tail[2..];
+                }
+                _ => {
+                    bytes.push(byte);
+                    rest = tail;
+                }
+            }
+        }
+        let target = String::from_utf8_lossy(&bytes).into_owned();
+
+        if self.root.pointer(&target).is_none() {
+            return Err(std::io::Error::other(format!(
+                "#{} uses $ref {}, which doesn't exist", ptr, reference)));
+        }
+        Ok(target)
+    }
+
+    /// Get the layers of schemas which apply to a value of the schema
+    /// `value` at `ptr`, with the schemas it refers to or merges in with
+    /// `$ref` and `allOf` as layers of their own
+    fn flatten(&self, ptr: &str, value: &Value, depth: usize)
+            -> std::io::Result<Vec<Layer>> {
+        if depth > MAX_MERGE_DEPTH {
+            return Err(std::io::Error::other(format!(
+                "#{} merges in schemas more than {} levels deep, it \
+                 probably refers to itself through allOf", ptr,
+                MAX_MERGE_DEPTH)));
+        }
+
+        let map = match value {
+            Value::Object(map) => map.clone(),
+            Value::Bool(true) => Map::new(),
+            Value::Bool(false) => {
+                // Matches nothing
+                let mut map = Map::new();
+                map.insert("not".into(), Value::Object(Map::new()));
+                map
+            }
+            _ => {
+                return Err(std::io::Error::other(format!(
+                    "#{} is not a schema", ptr)));
+            }
+        };
+
+        let mut layers = Vec::new();
+        if let Some(reference) = map.get("$ref") {
+            let target = self.resolve_ref(reference, ptr)?;
+            layers.extend(self.flatten(&target,
+                self.root.pointer(&target).unwrap(), depth + 1)?);
+        }
+        if let Some(all) = map.get("allOf") {
+            let all = all.as_array().ok_or_else(|| {
+                std::io::Error::other(format!(
+                    "#{}/allOf is not an array", ptr))
+            })?;
+            for (idx, schema) in all.iter().enumerate() {
+                layers.extend(self.flatten(&format!("{}/allOf/{}", ptr, idx),
+                    schema, depth + 1)?);
+            }
+        }
+
+        let mut own = map;
+        own.remove("$ref");
+        own.remove("allOf");
+        layers.push((ptr.to_string(), own));
+        Ok(layers)
+    }
+
+    /// Create the rule for the schema at `ptr`
+    fn define(&mut self, ptr: &str) -> std::io::Result<()> {
+        let name = pointer_name(ptr);
+        let value = self.root.pointer(ptr).unwrap();
+
+        // Schemas which are only a reference refer to its rule, which keeps
+        // recursive schemas recursive
+        if let (Some(map), Some(reference)) =
+                (value.as_object(), value.get("$ref")) {
+            if map.keys().all(|x| x == "$ref" ||
+                    ANNOTATIONS.contains(&x.as_str())) {
+                let target = self.resolve_ref(reference, ptr)?;
+                let alts = vec![vec![self.rule(&target)]];
+                self.insert(name, alts);
+                return Ok(());
+            }
+        }
+
+        // Near misses break either the schema itself, or one of the schemas
+        // it's made of
+        let layers = self.flatten(ptr, value, 0)?;
+        let alts = self.alternatives(&name, &layers)?;
+        let misses = if self.near_miss {
+            self.near_misses(&name, &layers)?
+        } else {
+            Vec::new()
+        };
+        self.insert(name.clone(), alts);
+        if let Some(alts) = self.grammar.0.get_mut(&miss(&name)) {
+            alts.extend(misses);
+        }
+        Ok(())
+    }
+
+    /// Add the rule `name` with the alternatives `alts` to the grammar. When
+    /// generating near misses, this also adds its near miss version, which
+    /// replaces one of the rules each alternative refers to with its own
+    /// near miss. Near misses of built-in rules don't exist, and those
+    /// alternatives are pruned once the whole grammar is created.
+    fn insert(&mut self, name: String, alts: Vec<Vec<String>>) {
+        if self.near_miss {
+            let mut misses = Vec::new();
+            for alt in &alts {
+                for (idx, token) in alt.iter().enumerate() {
+                    if token.starts_with("<#") {
+                        let mut alt = alt.clone();
+                        alt[idx] = miss(token);
+                        misses.push(alt);
+                    }
+                }
+            }
+            self.grammar.0.insert(miss(&name), misses);
+        }
+        self.grammar.0.insert(name, alts);
+    }
+
+    /// Get the value of `keyword` from the last layer which has it, along
+    /// with the pointer of that layer
+    fn get<'b>(layers: &'b [Layer], keyword: &str)
+            -> Option<(&'b str, &'b Value)> {
+        layers.iter().rev().find_map(|(ptr, map)| {
+            map.get(keyword).map(|x| (ptr.as_str(), x))
+        })
+    }
+
+    /// Get a keyword which is a number
+    fn number(layers: &[Layer], keyword: &str) -> Option<f64> {
+        Self::get(layers, keyword).and_then(|(_, x)| x.as_f64())
+    }
+
+    /// Get a keyword which is a count, eg. `maxLength`
+    fn count(layers: &[Layer], keyword: &str) -> Option<usize> {
+        Self::get(layers, keyword).and_then(|(_, x)| x.as_u64())
+            .map(|x| x as usize)
+    }
+
+    /// Get the types a value of `layers` may have, either from `type` or
+    /// from the keywords used. `None` means any type.
+    fn types(layers: &[Layer]) -> Option<Vec<String>> {
+        if let Some((_, types)) = Self::get(layers, "type") {
+            return Some(match types {
+                Value::Array(types) => types.iter()
+                    .filter_map(|x| x.as_str().map(|x| x.to_string()))
+                    .collect(),
+                _ => types.as_str().map(|x| x.to_string()).into_iter()
+                    .collect(),
+            });
+        }
+
+        let has = |keywords: &[&str]| {
+            keywords.iter().any(|x| Self::get(layers, x).is_some())
+        };
+        if has(&["properties", "required", "additionalProperties",
+                "minProperties", "maxProperties"]) {
+            Some(vec!["object".into()])
+        } else if has(&["items", "prefixItems", "additionalItems",
+                "minItems", "maxItems"]) {
+            Some(vec!["array".into()])
+        } else if has(&["minLength", "maxLength", "format"]) {
+            Some(vec!["string".into()])
+        } else if has(&["minimum", "maximum", "exclusiveMinimum",
+                "exclusiveMaximum", "multipleOf"]) {
+            Some(vec!["number".into()])
+        } else {
+            None
+        }
+    }
+
+    /// Get the bounds of numbers of `layers`. Booleans for the exclusive
+    /// bounds are from draft 4, later drafts give the bound itself.
+    fn bounds(layers: &[Layer]) -> Bounds {
+        let bound = |inclusive: &str, exclusive: &str| {
+            match Self::get(layers, exclusive).map(|(_, x)| x) {
+                Some(Value::Number(x)) => x.as_f64().map(|x| (x, true)),
+                Some(Value::Bool(exclusive)) => {
+                    Self::number(layers, inclusive).map(|x| (x, *exclusive))
+                }
+                _ => Self::number(layers, inclusive).map(|x| (x, false)),
+            }
+        };
+        Bounds {
+            min: bound("minimum", "exclusiveMinimum"),
+            max: bound("maximum", "exclusiveMaximum"),
+            multiple_of: Self::number(layers, "multipleOf")
+                .filter(|&x| x > 0.),
+        }
+    }
+
+    /// Warn about unsupported keywords used in `layers`, once per keyword
+    fn warn_unsupported(&mut self, layers: &[Layer]) {
+        for keyword in UNSUPPORTED {
+            // `"not": {}` is how `false` schemas are represented, which we
+            // do support
+            let Some((ptr, value)) = Self::get(layers, keyword) else {
+                continue;
+            };
+            if value != &Value::Object(Map::new()) &&
+                    self.warned.insert(keyword) {
+                eprintln!("warning: {} isn't supported, values of #{} and \
+                    any other schema using it may not conform", keyword, ptr);
+            }
+        }
+    }
+
+    /// Get the alternatives for values of the schema `layers`, using `name`
+    /// for any rules it needs
+    fn alternatives(&mut self, name: &str, layers: &[Layer])
+            -> std::io::Result<Vec<Vec<String>>> {
+        self.warn_unsupported(layers);
+
+        // Split into the schemas of the first `anyOf` or `oneOf`, each
+        // along with all the other layers
+        for (idx, (ptr, map)) in layers.iter().enumerate() {
+            for keyword in ["anyOf", "oneOf"] {
+                let Some(schemas) = map.get(keyword) else { continue };
+                let schemas = schemas.as_array().ok_or_else(|| {
+                    std::io::Error::other(format!(
+                        "#{}/{} is not an array", ptr, keyword))
+                })?;
+
+                let mut rest = layers.to_vec();
+                rest[idx].1.remove(keyword);
+                let mut alts = Vec::new();
+                for (branch, schema) in schemas.iter().enumerate() {
+                    let mut layers = rest.clone();
+                    layers.extend(self.flatten(
+                        &format!("{}/{}/{}", ptr, keyword, branch),
+                        schema, 0)?);
+                    alts.extend(self.alternatives(
+                        &derived(name, &format!("{}{}", keyword, branch)),
+                        &layers)?);
+                }
+                return Ok(alts);
+            }
+        }
+
+        // `false` schemas match nothing
+        if layers.iter().any(|(_, x)| x.get("not") == Some(&Value::Object(
+                Map::new()))) {
+            return Ok(Vec::new());
+        }
+
+        // Enumerated values are the only ones allowed
+        if let Some((_, value)) = Self::get(layers, "const") {
+            return Ok(vec![vec![value.to_string()]]);
+        }
+        if let Some((ptr, values)) = Self::get(layers, "enum") {
+            let values = values.as_array().ok_or_else(|| {
+                std::io::Error::other(format!("#{}/enum is not an array",
+                    ptr))
+            })?;
+            return Ok(values.iter().map(|x| vec![x.to_string()]).collect());
+        }
+
+        let Some(types) = Self::types(layers) else {
+            return Ok(vec![vec![self.builtin("<json.any>")]]);
+        };
+
+        let mut alts = Vec::new();
+        for ty in types {
+            match ty.as_str() {
+                "null" => alts.push(vec!["null".into()]),
+                "boolean" => {
+                    alts.push(vec!["true".into()]);
+                    alts.push(vec!["false".into()]);
+                }
+                "integer" | "number" => {
+                    alts.extend(self.numbers(layers, ty == "integer")?);
+                }
+                "string" => alts.extend(self.string(layers)),
+                "array" => alts.extend(self.array(name, layers)?),
+                "object" => alts.extend(self.object(name, layers)?),
+                _ => {
+                    return Err(std::io::Error::other(format!(
+                        "{} has unknown type {}", name, ty)));
+                }
+            }
+        }
+        Ok(alts)
+    }
+
+    /// Get the alternatives for numbers of `layers`
+    fn numbers(&mut self, layers: &[Layer], integer: bool)
+            -> std::io::Result<Vec<Vec<String>>> {
+        let bounds = Self::bounds(layers);
+        if bounds.min.is_none() && bounds.max.is_none() &&
+                bounds.multiple_of.is_none() {
+            let rule = if integer { "<json.integer>" } else { "<json.number>" };
+            return Ok(vec![vec![self.builtin(rule)]]);
+        }
+
+        let mut values: BTreeSet<String> = bounds.integers().iter()
+            .map(|x| x.to_string()).collect();
+        if !integer {
+            // Along with fractions, and values around and between the
+            // bounds
+            let mut fractions = match bounds.multiple_of {
+                Some(multiple) => {
+                    [1., 3., 5.].iter().map(|x| x * multiple).collect()
+                }
+                None => vec![0.5, 1.5, 1e-7, 3.25, 1e100],
+            };
+            if let (Some((min, _)), Some((max, _))) = (bounds.min, bounds.max) {
+                fractions.push((min + max) / 2.);
+            }
+            for (bound, _) in bounds.min.iter().chain(bounds.max.iter()) {
+                fractions.extend([bound - 0.5, *bound, bound + 0.5]);
+            }
+            let negative: Vec<f64> = fractions.iter().map(|x| -x).collect();
+            fractions.extend(negative);
+            values.extend(fractions.into_iter()
+                .filter(|&x| bounds.contains(x)).map(format_number));
+        }
+        Ok(values.into_iter().map(|x| vec![x]).collect())
+    }
+
+    /// Get the alternatives for strings of `layers`
+    fn string(&mut self, layers: &[Layer]) -> Vec<Vec<String>> {
+        // Known formats ignore the lengths, unknown ones are ignored
+        if let Some((_, format)) = Self::get(layers, "format") {
+            let rule = format!("<json.format.{}>",
+                format.as_str().unwrap_or(""));
+            if matches!(&rule[..], "<json.format.date>" |
+                    "<json.format.time>" | "<json.format.date-time>" |
+                    "<json.format.email>" | "<json.format.hostname>" |
+                    "<json.format.ipv4>" | "<json.format.ipv6>" |
+                    "<json.format.uri>" | "<json.format.uuid>") {
+                return vec![vec![self.builtin(&rule)]];
+            }
+        }
+
+        let min = Self::count(layers, "minLength").unwrap_or(0);
+        let max = Self::count(layers, "maxLength");
+        if min == 0 && max.is_none() {
+            return vec![vec![self.builtin("<json.string>")]];
+        }
+        if max.is_some_and(|max| max < min) {
+            return Vec::new();
+        }
+
+        let mut alt = vec!["\"".to_string()];
+        if min > 0 {
+            alt.extend(std::iter::repeat_n(self.builtin("<json.char>"), min));
+        }
+        alt.push(match max {
+            Some(max) => self.builtin(&format!("<json.upto{}>",
+                (max - min).min(MAX_OPTIONAL))),
+            None => self.builtin("<json.chars>"),
+        });
+        alt.push("\"".into());
+        vec![alt]
+    }
+
+    /// Get the schemas of the elements of arrays of `layers`, as the rules
+    /// for the fixed elements at the start, and the rule for the rest of
+    /// the elements if there can be more
+    fn elements(&mut self, layers: &[Layer])
+            -> std::io::Result<(Vec<String>, Option<String>)> {
+        // Tuples come from `prefixItems` followed by `items`, or before
+        // 2020-12 from `items` followed by `additionalItems`
+        let (tuple, rest) = match (Self::get(layers, "prefixItems"),
+                Self::get(layers, "items")) {
+            (Some((ptr, Value::Array(items))), _) => {
+                (Some((ptr, "prefixItems", items.len())), "items")
+            }
+            (_, Some((ptr, Value::Array(items)))) => {
+                (Some((ptr, "items", items.len())), "additionalItems")
+            }
+            _ => (None, "items"),
+        };
+
+        let mut prefix = Vec::new();
+        if let Some((ptr, keyword, len)) = tuple {
+            for idx in 0..len {
+                prefix.push(self.rule(&format!("{}/{}/{}", ptr, keyword,
+                    idx)));
+            }
+        }
+
+        let rest = match Self::get(layers, rest) {
+            Some((_, Value::Bool(false))) => None,
+            Some((ptr, _)) => Some(self.rule(&format!("{}/{}", ptr, rest))),
+            None => Some(self.builtin("<json.any>")),
+        };
+        Ok((prefix, rest))
+    }
+
+    /// Get the alternatives for arrays of `layers`
+    fn array(&mut self, name: &str, layers: &[Layer])
+            -> std::io::Result<Vec<Vec<String>>> {
+        let (prefix, rest) = self.elements(layers)?;
+        let min = Self::count(layers, "minItems").unwrap_or(0);
+        let mut max = Self::count(layers, "maxItems");
+        if rest.is_none() {
+            max = Some(max.unwrap_or(prefix.len()).min(prefix.len()));
+        }
+        if max.is_some_and(|max| max < min) {
+            return Ok(Vec::new());
+        }
+
+        // A chain of rules for the elements from each index onwards. Once
+        // every element is the same and optional, an unbounded array
+        // repeats the last rule of the chain.
+        let fixed = min.max(prefix.len());
+        let last = match max {
+            Some(max) => max.min(fixed + MAX_OPTIONAL),
+            None => fixed.max(1),
+        };
+        let rule = |idx| derived(name, &format!("items{}", idx));
+        for idx in (0..=last).rev() {
+            let mut alts = Vec::new();
+            if idx >= min {
+                alts.push(Vec::new());
+            }
+            if idx < last || max.is_none() {
+                let mut alt = Vec::new();
+                if idx > 0 {
+                    alt.push(",".to_string());
+                }
+                alt.push(prefix.get(idx).or(rest.as_ref()).unwrap().clone());
+                alt.push(rule((idx + 1).min(last)));
+                alts.push(alt);
+            }
+            self.insert(rule(idx), alts);
+        }
+
+        Ok(vec![vec!["[".into(), rule(0), "]".into()]])
+    }
+
+    /// Get the properties of objects of `layers`, along with the rules for
+    /// their values and whether they're required. Properties whose schema
+    /// is `false` can't be present, and are left out.
+    fn properties(&mut self, layers: &[Layer])
+            -> std::io::Result<Vec<(String, String, bool)>> {
+        let required: BTreeSet<&str> = layers.iter()
+            .filter_map(|(_, x)| x.get("required"))
+            .filter_map(|x| x.as_array()).flatten()
+            .filter_map(|x| x.as_str()).collect();
+
+        // Later layers override the schemas of earlier ones
+        let mut props: Vec<(String, String)> = Vec::new();
+        for (ptr, map) in layers {
+            let Some(Value::Object(properties)) = map.get("properties")
+                else { continue };
+            for (key, schema) in properties {
+                if schema == &Value::Bool(false) {
+                    props.retain(|x| &x.0 != key);
+                    continue;
+                }
+                let ptr = format!("{}/properties/{}", ptr, escape_key(key));
+                match props.iter_mut().find(|x| &x.0 == key) {
+                    Some(prop) => prop.1 = ptr,
+                    None => props.push((key.clone(), ptr)),
+                }
+            }
+        }
+
+        let mut ret = Vec::new();
+        for (key, ptr) in props {
+            let required = required.contains(key.as_str());
+            ret.push((key, self.rule(&ptr), required));
+        }
+
+        // Required properties without a schema of their own
+        for key in required {
+            if !ret.iter().any(|x| x.0 == key) {
+                let value = match self.additional(layers) {
+                    Some(rule) => rule,
+                    None => self.builtin("<json.any>"),
+                };
+                ret.push((key.to_string(), value, true));
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Get the rule for the values of additional properties of objects of
+    /// `layers`, if they're allowed. They're only generated if the schema
+    /// explicitly allows them.
+    fn additional(&mut self, layers: &[Layer]) -> Option<String> {
+        match Self::get(layers, "additionalProperties")? {
+            (_, Value::Bool(true)) => Some(self.builtin("<json.any>")),
+            (_, Value::Bool(false)) => None,
+            (ptr, _) => {
+                Some(self.rule(&format!("{}/additionalProperties", ptr)))
+            }
+        }
+    }
+
+    /// Get the alternatives for objects of `layers`
+    fn object(&mut self, name: &str, layers: &[Layer])
+            -> std::io::Result<Vec<Vec<String>>> {
+        let props = self.properties(layers)?;
+        let additional = self.additional(layers);
+
+        // Two chains of rules for the properties from each index onwards,
+        // `first` when no property has been emitted yet and `rest` when
+        // there has and a comma is needed
+        let first = |idx| derived(name, &format!("first{}", idx));
+        let rest = |idx| derived(name, &format!("rest{}", idx));
+        let key = |key: &str| format!("{}:", Value::from(key));
+
+        // Additional properties come last
+        let count = props.len();
+        let (mut first_alts, mut rest_alts) = (vec![vec![]], vec![vec![]]);
+        if let Some(value) = additional {
+            let string = self.builtin("<json.string>");
+            first_alts.push(vec![string.clone(), ":".into(), value.clone(),
+                rest(count)]);
+            rest_alts.push(vec![",".into(), string, ":".into(), value,
+                rest(count)]);
+        }
+        self.insert(first(count), first_alts);
+        self.insert(rest(count), rest_alts);
+
+        for (idx, (name, value, required)) in props.iter().enumerate().rev() {
+            let mut first_alts =
+                vec![vec![key(name), value.clone(), rest(idx + 1)]];
+            let mut rest_alts =
+                vec![vec![",".into(), key(name), value.clone(), rest(idx + 1)]];
+            if !required {
+                first_alts.push(vec![first(idx + 1)]);
+                rest_alts.push(vec![rest(idx + 1)]);
+            }
+            self.insert(first(idx), first_alts);
+            self.insert(rest(idx), rest_alts);
+        }
+
+        Ok(vec![vec!["{".into(), first(0), "}".into()]])
+    }
+
+    /// Get alternatives for values which break the schema `layers` in a
+    /// single way, see the module documentation
+    fn near_misses(&mut self, name: &str, layers: &[Layer])
+            -> std::io::Result<Vec<Vec<String>>> {
+        let mut alts = Vec::new();
+
+        // A value of the wrong type, or which isn't one of the enumerated
+        // values
+        let enumerated: Option<Vec<String>> = Self::get(layers, "const")
+            .map(|(_, x)| vec![x.to_string()]).or_else(|| {
+                Self::get(layers, "enum").and_then(|(_, x)| x.as_array())
+                    .map(|x| x.iter().map(|x| x.to_string()).collect())
+            });
+        let types = Self::types(layers);
+        let wrong = [("null", "null"), ("boolean", "true"),
+            ("integer", "0"), ("number", "0.5"), ("string", "\"\""),
+            ("array", "[]"), ("object", "{}")];
+        let wrong = wrong.iter().find(|&&(ty, value)| match &enumerated {
+            Some(values) => !values.iter().any(|x| x == value),
+            None => types.as_ref().is_some_and(|types| {
+                !types.iter().any(|x| x == ty ||
+                    (x == "number" && ty == "integer"))
+            }),
+        });
+        if let Some((_, value)) = wrong {
+            alts.push(vec![value.to_string()]);
+        }
+        if enumerated.is_some() {
+            return Ok(alts);
+        }
+        let types = types.unwrap_or_default();
+        let has = |ty: &str| types.iter().any(|x| x == ty);
+
+        // Numbers just out of bounds
+        if has("integer") || has("number") {
+            let bounds = Self::bounds(layers);
+            let step = if has("number") { 0.5 } else { 1. };
+            let mut values = Vec::new();
+            if let Some((min, exclusive)) = bounds.min {
+                values.push(if exclusive { min } else { min - step });
+            }
+            if let Some((max, exclusive)) = bounds.max {
+                values.push(if exclusive { max } else { max + step });
+            }
+            if let Some(multiple) = bounds.multiple_of {
+                values.push(multiple * 1.5);
+            }
+            alts.extend(values.into_iter().map(|x| vec![format_number(x)]));
+        }
+
+        // Strings and arrays one too long or one too short
+        if has("string") && Self::get(layers, "format").is_none() {
+            let char = self.builtin("<json.char>");
+            let min = Self::count(layers, "minLength").unwrap_or(0);
+            let max = Self::count(layers, "maxLength");
+            for len in min.checked_sub(1).into_iter()
+                    .chain(max.map(|x| x + 1)) {
+                let mut alt = vec!["\"".to_string()];
+                alt.extend(std::iter::repeat_n(char.clone(), len));
+                alt.push("\"".into());
+                alts.push(alt);
+            }
+        }
+        if has("array") {
+            let (prefix, rest) = self.elements(layers)?;
+            let min = Self::count(layers, "minItems").unwrap_or(0);
+            let max = Self::count(layers, "maxItems");
+            let any = self.builtin("<json.any>");
+            for len in min.checked_sub(1).into_iter()
+                    .chain(max.map(|x| x + 1)) {
+                let mut alt = vec!["[".to_string()];
+                for idx in 0..len {
+                    if idx > 0 {
+                        alt.push(",".into());
+                    }
+                    alt.push(prefix.get(idx).or(rest.as_ref())
+                        .unwrap_or(&any).clone());
+                }
+                alt.push("]".into());
+                alts.push(alt);
+            }
+        }
+
+        // Objects missing their required properties, or with a property
+        // which isn't allowed
+        if has("object") {
+            if self.properties(layers)?.iter().any(|x| x.2) {
+                alts.push(vec!["{}".into()]);
+            }
+            if matches!(Self::get(layers, "additionalProperties"),
+                    Some((_, Value::Bool(false)))) {
+                // Along with the rest of a valid object, unless the
+                // object's rules are split between `anyOf` branches
+                let rest = derived(name, "rest0");
+                alts.push(if self.grammar.0.contains_key(&rest) {
+                    vec!["{".into(), "\"fzero\":null".into(), rest,
+                        "}".into()]
+                } else {
+                    vec!["{\"fzero\":null}".into()]
+                });
+            }
+        }
+
+        Ok(alts)
+    }
+}
+
+/// Convert the JSON Schema `schema` into a grammar, see the module
+/// documentation
+pub fn convert(schema: &Value, near_miss: bool) -> std::io::Result<Grammar> {
+    let mut conv = Converter {
+        root: schema,
+        near_miss,
+        grammar: Grammar::default(),
+        queue: Vec::new(),
+        queued: BTreeSet::new(),
+        warned: BTreeSet::new(),
+    };
+
+    // Half of the documents are near misses when generating them
+    let root = conv.rule("");
+    let mut start = vec![vec![root.clone()]];
+    if near_miss {
+        start.push(vec![miss(&root)]);
+    }
+    conv.grammar.0.insert("<start>".into(), start);
+    while let Some(ptr) = conv.queue.pop() {
+        conv.define(&ptr)?;
+    }
+
+    // Drop the rules which can't produce anything, such as schemas which
+    // can't be satisfied and near misses of schemas which can't be broken,
+    // along with the alternatives using them
+    let mut grammar = conv.grammar;
+    let mut productive = BTreeSet::new();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (name, alts) in grammar.0.iter() {
+            if !productive.contains(name) && alts.iter().any(|alt| {
+                alt.iter().all(|x| !is_rule(x) || productive.contains(x))
+            }) {
+                productive.insert(name.clone());
+                changed = true;
+            }
+        }
+    }
+    if !productive.contains("<start>") {
+        return Err(std::io::Error::other("The schema can't be satisfied"));
+    }
+    grammar.0.retain(|name, _| productive.contains(name));
+    for alts in grammar.0.values_mut() {
+        alts.retain(|alt| {
+            alt.iter().all(|x| !is_rule(x) || productive.contains(x))
+        });
+    }
+
+    // Then drop the rules which ended up unused, eg. the chain of rules
+    // for the properties of an object which come before a required one
+    let mut reachable = BTreeSet::new();
+    let mut queue = vec!["<start>".to_string()];
+    while let Some(rule) = queue.pop() {
+        if reachable.insert(rule.clone()) {
+            queue.extend(grammar.0[&rule].iter().flatten()
+                .filter(|x| grammar.0.contains_key(*x)).cloned());
+        }
+    }
+    grammar.0.retain(|name, _| reachable.contains(name));
+    Ok(grammar)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use crate::grammar::{ConvertOptions, GrammarRust};
+    use crate::grammar::tests::{generate, options};
+    use super::*;
+
+    /// Convert `schema` and generate `count` documents from it. The depth
+    /// limit is high enough that recursive schemas are never cut short,
+    /// which would leave out required properties.
+    fn documents(schema: &Value, near_miss: bool, count: usize)
+            -> Vec<Value> {
+        let grammar = convert(schema, near_miss).unwrap();
+        let grammar = GrammarRust::new(&grammar, &ConvertOptions::default())
+            .unwrap();
+        generate(&grammar, &options(256, 256), count).iter()
+            .map(|x| serde_json::from_slice(x).unwrap()).collect()
+    }
+
+    /// A schema with a bit of everything
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "id": {"type": "integer", "minimum": 1, "maximum": 10},
+                "kind": {"enum": ["a", "b"]},
+                "tags": {
+                    "type": "array",
+                    "items": {"type": "string", "maxLength": 3},
+                    "maxItems": 2
+                },
+                "child": {"$ref": "#"}
+            },
+            "required": ["id"],
+            "additionalProperties": false
+        })
+    }
+
+    /// Check `value` conforms to `schema()`
+    fn conforms(value: &Value) -> bool {
+        let Some(object) = value.as_object() else { return false };
+        let id = object.get("id").and_then(|x| x.as_i64());
+        object.keys().all(|x| ["id", "kind", "tags", "child"]
+                .contains(&x.as_str())) &&
+            id.is_some_and(|x| (1..=10).contains(&x)) &&
+            object.get("kind").is_none_or(|x| x == "a" || x == "b") &&
+            object.get("tags").is_none_or(|x| {
+                x.as_array().is_some_and(|x| x.len() <= 2 && x.iter().all(
+                    |x| x.as_str().is_some_and(|x| x.chars().count() <= 3)))
+            }) &&
+            object.get("child").is_none_or(conforms)
+    }
+
+    #[test]
+    fn jsonschema_rules() {
+        let grammar = convert(&schema(), false).unwrap();
+        let rule = |name: &str| grammar.0[name].clone();
+
+        assert_eq!(rule("<start>"), [["<#>"]]);
+        assert_eq!(rule("<#/properties/kind>"), [["\"a\""], ["\"b\""]]);
+
+        // A schema which is only a reference refers to the rule of its
+        // target, which makes this one recursive
+        assert_eq!(rule("<#/properties/child>"), [["<#>"]]);
+
+        // Bounded integers are picked from interesting values within the
+        // bounds
+        let ids: Vec<i64> = rule("<#/properties/id>").iter()
+            .map(|x| x[0].parse().unwrap()).collect();
+        assert!(ids.contains(&1) && ids.contains(&10) && ids.contains(&5));
+        assert!(ids.iter().all(|x| (1..=10).contains(x)));
+    }
+
+    #[test]
+    fn jsonschema_documents_conform() {
+        let documents = documents(&schema(), false, 1000);
+        assert!(documents.iter().all(conforms));
+
+        // Every optional property shows up, along with some nesting
+        for key in ["kind", "tags", "child"] {
+            assert!(documents.iter().any(|x| x.get(key).is_some()));
+        }
+    }
+
+    #[test]
+    fn jsonschema_near_misses() {
+        // Half of the documents are near misses, which are still JSON
+        let documents = documents(&schema(), true, 1000);
+        let misses = documents.iter().filter(|x| !conforms(x)).count();
+        assert!((300..700).contains(&misses));
+    }
+
+    #[test]
+    fn jsonschema_errors() {
+        let error = |schema| convert(&schema, false).unwrap_err()
+            .to_string();
+        assert_eq!(error(json!({"type": "integer", "minimum": 5,
+            "maximum": 1})), "The schema can't be satisfied");
+        assert_eq!(error(json!({"$ref": "#/missing"})),
+            "# uses $ref \"#/missing\", which doesn't exist");
+        assert_eq!(error(json!({"type": "color"})),
+            "<#> has unknown type color");
+    }
+}
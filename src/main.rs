@@ -1,368 +1,1918 @@
-use std::collections::{BTreeMap, BTreeSet};
-use std::path::Path;
+mod analysis;
+mod asn1;
+mod boost;
+mod builtins;
+mod codegen;
+mod diff;
+mod export;
+mod grammar;
+mod infer;
+mod interp;
+mod ints;
+mod jsonschema;
+mod minimize;
+mod parse;
+mod proto;
+mod runtime;
+mod scalars;
+mod scaffold;
+mod selftest;
+mod template;
+mod uniform;
+mod validate;
+mod whitespace;
+mod xml;
+
+use std::collections::BTreeSet;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use serde::{Deserialize, Serialize};
-
-/// If this is `true` then the output file we generate will not emit any
-/// unsafe code. I'm not aware of any bugs with the unsafe code that I use and
-/// thus this is by default set to `false`. Feel free to set it to `true` if
-/// you are concerned.
-const SAFE_ONLY: bool = false;
-
-/// Representation of a grammar file in a Rust structure. This allows us to
-/// use Serde to serialize and deserialize the json grammar files
-#[derive(Serialize, Deserialize, Default, Debug)]
-struct Grammar(BTreeMap<String, Vec<Vec<String>>>);
-
-/// A strongly typed wrapper around a `usize` which selects different fragment
-/// identifiers
-#[derive(Clone, Copy, Debug)]
-struct FragmentId(usize);
-
-/// A fragment which is specified by the grammar file
-#[derive(Clone, Debug)]
-enum Fragment {
-    /// A non-terminal fragment which refers to a list of `FragmentId`s to
-    /// randomly select from for expansion
-    NonTerminal(Vec<FragmentId>),
-
-    /// A list of `FragmentId`s that should be expanded in order
-    Expression(Vec<FragmentId>),
-
-    /// A terminal fragment which simply should expand directly to the
-    /// contained vector of bytes
-    Terminal(Vec<u8>),
-
-    /// A fragment which does nothing. This is used during optimization passes
-    /// to remove fragments with no effect.
-    Nop,
-}
-
-/// A grammar representation in Rust that is designed to be easy to work with
-/// in-memory and optimized for code generation.
-#[derive(Debug, Default)]
-struct GrammarRust {
-    /// All types
-    fragments: Vec<Fragment>,
-
-    /// Cached fragment identifier for the start node
-    start: Option<FragmentId>,
-
-    /// Mapping of non-terminal names to fragment identifers
-    name_to_fragment: BTreeMap<String, FragmentId>,
-}
-
-impl GrammarRust {
-    /// Create a new Rust version of a `Grammar` which was loaded via a
-    /// grammar json specification.
-    fn new(grammar: &Grammar) -> Self {
-        // Create a new grammar structure
-        let mut ret = GrammarRust::default();
-
-        // Parse the input grammar to resolve all fragment names
-        for (non_term, _) in grammar.0.iter() {
-            // Make sure that there aren't duplicates of fragment names
-            assert!(!ret.name_to_fragment.contains_key(non_term),
-                "Duplicate non-terminal definition, fail");
-
-            // Create a new, empty fragment
-            let fragment_id = ret.allocate_fragment(
-                Fragment::NonTerminal(Vec::new()));
-
-            // Add the name resolution for the fragment
-            ret.name_to_fragment.insert(non_term.clone(), fragment_id);
-        }
+use clap::{Args, Parser, Subcommand};
+use crate::boost::Boost;
+use crate::codegen::Output;
+use crate::grammar::{ConvertOptions, Directives, GenOptions, Grammar};
+use crate::grammar::{Fragment, GrammarRust};
+use crate::interp::Interpreter;
+use crate::scaffold::Target;
+use crate::selftest::Manifest;
+use crate::validate::Severity;
+use crate::whitespace::Whitespace;
 
-        // Parse the input grammar
-        for (non_term, fragments) in grammar.0.iter() {
-            // Get the non-terminal fragment identifier
-            let fragment_id = ret.name_to_fragment[non_term];
-
-            // Create a vector to hold all of the variants possible under this
-            // non-terminal fragment
-            let mut variants = Vec::new();
-
-            // Go through all sub-fragments
-            for js_sub_fragment in fragments {
-                // Different options for this sub-fragment
-                let mut options = Vec::new();
-
-                // Go through each option in the sub-fragment
-                for option in js_sub_fragment {
-                    let fragment_id = if let Some(&non_terminal) =
-                            ret.name_to_fragment.get(option) {
-                        // If we can resolve the name of this fragment, it is a
-                        // non-terminal fragment and should be allocated as
-                        // such
-                        ret.allocate_fragment(
-                            Fragment::NonTerminal(vec![non_terminal]))
-                    } else {
-                        // Convert the terminal bytes into a vector and
-                        // create a new fragment containing it
-                        ret.allocate_fragment(Fragment::Terminal(
-                            option.as_bytes().to_vec()))
-                    };
-
-                    // Push this fragment as an option
-                    options.push(fragment_id);
-                }
+/// Command line options for fzero
+#[derive(Parser, Debug)]
+#[command(version, about = "Grammar-based fuzzer generator")]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
 
-                // Create a new fragment of all the options
-                variants.push(
-                    ret.allocate_fragment(Fragment::Expression(options)));
-            }
+/// Different modes of operation for fzero
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Generate a Rust fuzzer from a grammar and compile it
+    Build(BuildArgs),
 
-            // Get access to the fragment we want to update based on the
-            // possible variants
-            let fragment = &mut ret.fragments[fragment_id.0];
+    /// Generate inputs directly from a grammar using the interpreter
+    Generate(GenerateArgs),
 
-            // Overwrite the terminal definition
-            *fragment = Fragment::NonTerminal(variants);
-        }
+    /// Build a fuzzer to a temporary location and run its throughput loop
+    Bench(BenchArgs),
 
-        // Resolve the start node
-        ret.start = Some(ret.name_to_fragment["<start>"]);
-
-        ret
-    }
-
-    /// Allocate a new fragment identifier and add it to the fragment list
-    pub fn allocate_fragment(&mut self, fragment: Fragment) -> FragmentId {
-        // Get a unique fragment identifier
-        let fragment_id = FragmentId(self.fragments.len());
-
-        // Store the fragment
-        self.fragments.push(fragment);
-
-        fragment_id
-    }
-
-    /// Optimize to remove fragments with non-random effects
-    pub fn optimize(&mut self) {
-        // Keeps track of fragment identifiers which resolve to nops
-        let mut nop_fragments = BTreeSet::new();
-
-        // Track if a optimization had an effect
-        let mut changed = true;
-        while changed {
-            // Start off assuming no effect from optimzation
-            changed = false;
-
-            // Go through each fragment, looking for potential optimizations
-            for idx in 0..self.fragments.len() {
-                // Clone the fragment such that we can inspect it, but we also
-                // can mutate it in place.
-                match self.fragments[idx].clone() {
-                    Fragment::NonTerminal(options) => {
-                        // If this non-terminal only has one option, replace
-                        // itself with the only option it resolves to
-                        if options.len() == 1 {
-                            self.fragments[idx] =
-                                self.fragments[options[0].0].clone();
-                            changed = true;
-                        }
-                    }
-                    Fragment::Expression(expr) => {
-                        // If this expression doesn't have anything to do at
-                        // all. Then simply replace it with a `Nop`
-                        if expr.len() == 0 {
-                            self.fragments[idx] = Fragment::Nop;
-                            changed = true;
-
-                            // Track that this fragment identifier now resolves
-                            // to a nop
-                            nop_fragments.insert(idx);
-                        }
-
-                        // If this expression only does one thing, then replace
-                        // the expression with the thing that it does.
-                        if expr.len() == 1 {
-                            self.fragments[idx] =
-                                self.fragments[expr[0].0].clone();
-                            changed = true;
-                        }
-
-                        // Remove all `Nop`s from this expression, as they
-                        // wouldn't result in anything occuring.
-                        if let Fragment::Expression(exprs) =
-                                &mut self.fragments[idx] {
-                            // Only retain fragments which are not nops
-                            exprs.retain(|x| {
-                                if nop_fragments.contains(&x.0) {
-                                    // Fragment was a nop, remove it
-                                    changed = true;
-                                    false
-                                } else {
-                                    // Fragment was fine, keep it
-                                    true
-                                }
-                            });
-                        }
-                    }
-                    Fragment::Terminal(_) | Fragment::Nop => {
-                        // Already maximally optimized
-                    }
-                }
-            }
+    /// Write a cargo-fuzz harness feeding inputs generated from a grammar to
+    /// a function of a crate
+    CargoFuzz(CargoFuzzArgs),
+
+    /// Export a grammar to other formats
+    Export(ExportArgs),
+
+    /// Compare two grammars structurally, reporting added, removed and
+    /// changed rules and alternatives
+    Diff(DiffArgs),
+
+    /// Check that the inputs generated from a grammar by the interpreter and
+    /// the compiled program still match the hash recorded in a manifest
+    Selftest(SelftestArgs),
+
+    /// Build a fuzzer, run it for a bounded time and check it generated
+    /// inputs at least at an expected rate
+    Test(TestArgs),
+
+    /// Lint a grammar for undefined references, unreachable rules, rules
+    /// that can't terminate and other suspicious constructs
+    Validate(GrammarArgs),
+
+    /// Infer a starting grammar from a directory of sample inputs
+    Infer(InferArgs),
+
+    /// Minimize an input while keeping it valid for the grammar and
+    /// interesting according to a predicate command
+    Minimize(MinimizeArgs),
+
+    /// Parse existing inputs into derivation trees of the grammar
+    Parse(ParseArgs),
+
+    /// Convert a protobuf schema into a grammar generating messages in the
+    /// protobuf wire format
+    Proto(ProtoArgs),
+
+    /// Convert a JSON Schema into a grammar generating documents which
+    /// conform to it
+    JsonSchema(JsonSchemaArgs),
+
+    /// Convert a DTD or XSD into a grammar generating documents which
+    /// conform to it
+    XmlSchema(XmlSchemaArgs),
+
+    /// Convert ASN.1 modules into a grammar generating values in the DER
+    /// encoding
+    Asn1(Asn1Args),
+
+    /// Watch a grammar and the grammars it embeds, and every time they
+    /// change validate it, print sample inputs and optionally rebuild it
+    Watch(WatchArgs),
+}
+
+/// Options shared by every subcommand which loads a grammar
+#[derive(Args, Clone, Debug)]
+struct GrammarArgs {
+    /// Grammar json file to load
+    #[arg(short, long)]
+    grammar: PathBuf,
+
+    /// Treat `<...>`-shaped tokens which don't refer to a defined rule as
+    /// literal terminals instead of failing
+    #[arg(long)]
+    allow_undefined: bool,
+
+    /// Name of the rule to start generation from
+    #[arg(long, default_value = "<start>")]
+    start_rule: String,
+
+    /// Combine the alternatives of rules which are defined more than once
+    /// instead of failing
+    #[arg(long)]
+    merge_duplicates: bool,
+
+    /// Replace every terminal with its identifier from the grammar's
+    /// `%tokens` table, written as a little endian integer of this many
+    /// bytes, to generate token streams rather than text
+    #[arg(long, value_name = "WIDTH",
+        value_parser = clap::value_parser!(u64).range(1..=8))]
+    token_stream: Option<u64>,
+
+    /// Insert this rule between the tokens of every alternative, like the
+    /// grammar's `%whitespace` directive, which this overrides
+    #[arg(long, value_name = "RULE")]
+    whitespace: Option<String>,
+
+    /// Probability in percent of inserting whitespace between two tokens.
+    /// Defaults to the `%whitespace` directive's, or 50.
+    #[arg(long, value_name = "PERCENT",
+        value_parser = clap::value_parser!(u32).range(0..=100))]
+    whitespace_percent: Option<u32>,
+
+    /// Make the alternatives leading to terminals containing this keyword
+    /// more likely, in addition to the grammar's `%boost` directive
+    #[arg(long, value_name = "KEYWORD")]
+    boost: Vec<String>,
+
+    /// Never generate inputs containing this pattern, in addition to the
+    /// grammar's `%avoid` directive. Written as text, or as hex bytes like
+    /// `%bytes(00 00)`.
+    #[arg(long, value_name = "PATTERN")]
+    avoid: Vec<String>,
+
+    /// Number of times more likely `--boost` makes the alternatives leading
+    /// to its keywords
+    #[arg(long, value_name = "FACTOR",
+        default_value_t = boost::DEFAULT_FACTOR as u64,
+        value_parser = clap::value_parser!(u64)
+            .range(1..=boost::MAX_FACTOR as u64))]
+    boost_factor: u64,
+
+    /// Normalize the grammar before optimizing it, flattening nested rules
+    /// and removing duplicate alternatives. This changes the inputs
+    /// generated: a repeated alternative no longer weighs more than the
+    /// others, and neither do the alternatives of a nested rule, so every
+    /// distinct alternative of a rule becomes equally likely.
+    #[arg(long)]
+    normalize: bool,
+}
+
+impl GrammarArgs {
+    /// Load and parse the grammar file, reporting any merged definitions
+    fn load(&self) -> std::io::Result<(Grammar, Directives)> {
+        let (grammar, directives, merged) =
+            Grammar::load(&self.grammar, self.merge_duplicates)?;
+        if !merged.is_empty() {
+            eprintln!("warning: merged duplicate definitions of {}",
+                merged.join(", "));
         }
+        Ok((grammar, directives))
     }
 
-    /// Generate a new Rust program that can be built and will generate random
-    /// inputs and benchmark them
-    pub fn program<P: AsRef<Path>>(&self, path: P, max_depth: usize) {
-        let mut program = String::new();
-
-        // Construct the base of the application. This is a profiling loop that
-        // is used for testing.
-        program += &format!(r#"
-#![allow(unused)]
-use std::cell::Cell;
-use std::time::Instant;
-
-fn main() {{
-    let mut fuzzer = Fuzzer {{
-        seed:  Cell::new(0x34cc028e11b4f89c),
-        buf:   Vec::new(),
-    }};
-    
-    let mut generated = 0usize;
-    let it = Instant::now();
-
-    for iters in 1u64.. {{
-        fuzzer.buf.clear();
-        fuzzer.fragment_{}(0);
-        generated += fuzzer.buf.len();
-
-        // Filter to reduce the amount of times printing occurs
-        if (iters & 0xfffff) == 0 {{
-            let elapsed = (Instant::now() - it).as_secs_f64();
-            let bytes_per_sec = generated as f64 / elapsed;
-            print!("MiB/sec: {{:12.4}}\n", bytes_per_sec / 1024. / 1024.);
-        }}
-    }}
-}}
-
-struct Fuzzer {{
-    seed:  Cell<usize>,
-    buf:   Vec<u8>,
-}}
-
-impl Fuzzer {{
-    fn rand(&self) -> usize {{
-        let mut seed = self.seed.get();
-        seed ^= seed << 13;
-        seed ^= seed >> 17;
-        seed ^= seed << 43;
-        self.seed.set(seed);
-        seed
-    }}
-"#, self.start.unwrap().0);
-
-        // Go through each fragment in the list of fragments
-        for (id, fragment) in self.fragments.iter().enumerate() {
-            // Create a new function for this fragment
-            program += &format!("    fn fragment_{}(&mut self, depth: usize) {{\n", id);
-
-            // Add depth checking to terminate on depth exhaustion
-            program += &format!("        if depth >= {} {{ return; }}\n",
-                max_depth);
-
-            match fragment {
-                Fragment::NonTerminal(options) => {
-                    // For non-terminal cases pick a random variant to select
-                    // and invoke that fragment's routine
-                    program += &format!("        match self.rand() % {} {{\n", options.len());
-
-                    for (option_id, option) in options.iter().enumerate() {
-                        program += &format!("            {} => self.fragment_{}(depth + 1),\n", option_id, option.0);
-                    }
-                    program += &format!("            _ => unreachable!(),\n");
-
-                    program += &format!("        }}\n");
-                }
-                Fragment::Expression(expr) => {
-                    // Invoke all of the expression's routines in order
-                    for &exp in expr.iter() {
-                        program += &format!("        self.fragment_{}(depth + 1);\n", exp.0);
-                    }
-                }
-                Fragment::Terminal(value) => {
-                    // Append the terminal value to the output buffer
-                    if SAFE_ONLY {
-                        program += &format!("        self.buf.extend_from_slice(&{:?});\n",
-                            value);
-                    } else {
-                        // For some reason this is faster than
-                        // `extend_from_slice` even though it does the exact
-                        // same thing. This was observed to be over a 4-5x
-                        // speedup in some scenarios.
-                        program += &format!(r#"
-            unsafe {{
-                let old_size = self.buf.len();
-                let new_size = old_size + {};
-
-                if new_size > self.buf.capacity() {{
-                    self.buf.reserve(new_size - old_size);
-                }}
-
-                std::ptr::copy_nonoverlapping({:?}.as_ptr(), self.buf.as_mut_ptr().offset(old_size as isize), {});
-                self.buf.set_len(new_size);
-            }}
-    "#, value.len(), value, value.len());
-                    }
-                }
-                Fragment::Nop => {}
-            }
+    /// Get the grammar conversion options specified by the user
+    fn convert_options(&self, directives: &Directives)
+            -> std::io::Result<ConvertOptions> {
+        Ok(ConvertOptions {
+            strict: !self.allow_undefined,
+            start:  self.start_rule.clone(),
+            tokens: self.token_stream
+                .map(|x| directives.tokens(x as usize)).transpose()?,
+            separator: directives.separator()?,
+        })
+    }
 
-            program += "    }\n";
+    /// Get the whitespace injection configured by the grammar's directives,
+    /// overridden by the options specified by the user
+    fn whitespace(&self, directives: &Directives)
+            -> std::io::Result<Option<Whitespace>> {
+        let mut whitespace = directives.whitespace()?;
+        if let Some(rule) = &self.whitespace {
+            let whitespace = whitespace.get_or_insert_with(|| Whitespace {
+                rule:    rule.clone(),
+                percent: whitespace::DEFAULT_PERCENT,
+                exclude: BTreeSet::new(),
+            });
+            whitespace.rule = rule.clone();
         }
-        program += "}\n";
+        if let Some(percent) = self.whitespace_percent {
+            whitespace.as_mut().ok_or_else(|| std::io::Error::other(
+                "--whitespace-percent needs a whitespace rule, from \
+                 --whitespace or the %whitespace directive"))?
+                .percent = percent;
+        }
+
+        // Integers are made of digits which must stay together
+        if let Some(whitespace) = &mut whitespace {
+            whitespace.exclude.extend(directives.ints()?.into_iter()
+                .map(|x| x.rule));
+        }
+        Ok(whitespace)
+    }
+
+    /// Get the patterns to avoid from the grammar's directives and the
+    /// options specified by the user
+    fn avoid(&self, directives: &Directives)
+            -> std::io::Result<Vec<Vec<u8>>> {
+        let mut avoid = directives.avoid()?;
+        for pattern in self.avoid.iter() {
+            avoid.push(grammar::avoid_pattern(std::slice::from_ref(pattern))?);
+        }
+        Ok(avoid)
+    }
 
-        // Write out the test application
-        std::fs::write(path, program)
-            .expect("Failed to create output Rust application");
+    /// Get the keywords to boost from the grammar's directives and the
+    /// options specified by the user
+    fn boosts(&self, directives: &Directives) -> std::io::Result<Vec<Boost>> {
+        let mut boosts = directives.boosts()?;
+        boosts.extend(self.boost.iter().map(|keyword| Boost {
+            keyword: keyword.clone(),
+            factor:  self.boost_factor as usize,
+        }));
+        Ok(boosts)
     }
 }
 
-fn main() -> std::io::Result<()> {
-    // Get access to the command line arguments
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 5 {
-        print!("usage: fzero <grammar json> <output Rust file> <output binary name> <max depth>\n");
-        return Ok(());
+/// Options controlling how inputs are generated
+#[derive(Args, Clone, Debug)]
+struct GenArgs {
+    /// Maximum recursion depth during generation
+    #[arg(short = 'd', long)]
+    max_depth: usize,
+
+    /// Maximum number of iterations of right-recursive rules which have been
+    /// converted to loops. Defaults to the max depth.
+    #[arg(long)]
+    loop_budget: Option<usize>,
+
+    /// Maximum number of expansions of recursive rules in a single input.
+    /// This limits generation in addition to the max depth.
+    #[arg(long)]
+    max_steps: Option<usize>,
+
+    /// Sample derivations with this many fragment expansions uniformly at
+    /// random, instead of descending randomly until the max depth
+    #[arg(long)]
+    uniform_size: Option<usize>,
+
+    /// Print the expected and maximum output size of every rule when
+    /// generating from it, to help choose the max depth
+    #[arg(long, conflicts_with = "uniform_size")]
+    size_report: bool,
+
+    /// Chance in percent of emitting a random one of the expansions of a
+    /// rule with few of them, enumerated ahead of time, rather than
+    /// expanding the rule
+    #[arg(long, value_name = "PERCENT", default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        conflicts_with = "uniform_size")]
+    precompute: u8,
+
+    /// Maximum number of distinct expansions of a rule to enumerate for
+    /// `--precompute`
+    #[arg(long, value_name = "N", default_value_t = 256)]
+    precompute_max: usize,
+
+    /// Aim for inputs of about this many bytes, by steering the choices of
+    /// rules towards growing or finishing the input while it's too short or
+    /// too long
+    #[arg(long, value_name = "BYTES", conflicts_with = "uniform_size")]
+    target_size: Option<usize>,
+
+    /// Percentage inputs may be shorter or longer than `--target-size`
+    /// before choices are steered
+    #[arg(long, value_name = "PERCENT", default_value_t = 20,
+        value_parser = clap::value_parser!(u8).range(0..=100))]
+    target_slack: u8,
+}
+
+impl GenArgs {
+    /// Get the generation options specified by the user
+    fn options(&self) -> GenOptions {
+        GenOptions {
+            max_depth:    self.max_depth,
+            loop_budget:  self.loop_budget.unwrap_or(self.max_depth),
+            max_steps:    self.max_steps,
+            uniform_size: self.uniform_size,
+            precompute:   self.precompute as usize,
+            precompute_max: self.precompute_max,
+            trace:        false,
+            unroll:       0,
+            compact_regular: false,
+            pool:         Vec::new(),
+            holes:        Vec::new(),
+            tests:        false,
+            target_size:  self.target_size,
+            target_slack: self.target_slack as usize,
+        }
     }
+}
+
+/// Options for the `build` subcommand
+#[derive(Args, Debug)]
+struct BuildArgs {
+    #[command(flatten)]
+    grammar: GrammarArgs,
+
+    #[command(flatten)]
+    gen: GenArgs,
+
+    /// Path to write the generated Rust source to
+    #[arg(long)]
+    out_src: PathBuf,
+
+    /// Path to write the compiled binary to
+    #[arg(long, required_unless_present_any = ["source_only", "lib"])]
+    out_bin: Option<PathBuf>,
+
+    /// Only emit the Rust source, do not invoke `rustc`
+    #[arg(long)]
+    source_only: bool,
+
+    /// Emit a library exposing an API for generating inputs, to be included
+    /// as a module in another program, rather than the benchmark program.
+    /// Implies `--source-only`.
+    #[arg(long, conflicts_with = "out_bin")]
+    lib: bool,
+
+    /// Also expose a C API from the library, for building it as a `cdylib`
+    /// or `staticlib`, and write the C header describing it to this path
+    #[arg(long, requires = "lib")]
+    c_header: Option<PathBuf>,
+
+    /// Emit a program which runs a target binary with the generated inputs,
+    /// using AFL style coverage feedback to keep and mutate the inputs which
+    /// find new edges, rather than the benchmark program
+    #[arg(long, conflicts_with_all = ["lib", "uniform_size"])]
+    feedback: bool,
+
+    /// Build the binary with profile guided optimization, using a profile
+    /// collected by running an instrumented build's benchmark loop
+    #[arg(long, conflicts_with_all = ["source_only", "lib", "feedback"])]
+    pgo: bool,
+
+    /// Number of seconds to run the instrumented binary for with `--pgo`
+    #[arg(long, value_name = "SECS", default_value_t = 3.)]
+    pgo_duration: f64,
+
+    /// Number of codegen units rustc splits the program into, which LLVM
+    /// optimizes in parallel. Fewer units make a faster binary, more make a
+    /// faster build. Defaults to rustc's own default.
+    #[arg(long, value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..))]
+    codegen_units: Option<u64>,
+
+    /// Make the generated code print the expansions of rules and their
+    /// options of every input to stderr, or only of the input picked by the
+    /// `FZERO_TRACE_INPUT` environment variable at runtime
+    #[arg(long, conflicts_with = "feedback")]
+    trace: bool,
+
+    /// Give the generated code copies of the functions of the rules expanded
+    /// at the first K depths, specialized for their depth, so their depth
+    /// checks are done on constants
+    #[arg(long, value_name = "K", default_value_t = 0,
+        conflicts_with_all = ["feedback", "uniform_size"])]
+    unroll: usize,
+
+    /// Compile the parts of the grammar which derive regular languages, the
+    /// rules which aren't recursive and only pick options, concatenate and
+    /// loop, into a table walked at runtime rather than a function per
+    /// fragment. This keeps the source small for token heavy grammars.
+    #[arg(long, conflicts_with_all = ["feedback", "uniform_size", "trace",
+        "target_size"])]
+    compact_regular: bool,
+
+    /// Let the generated program keep a pool of complete expansions of RULE,
+    /// enabled with its `--pool N` option, which it mostly emits by copying
+    /// rather than expanding the rule. May be given more than once.
+    #[arg(long, value_name = "RULE",
+        conflicts_with_all = ["feedback", "uniform_size"])]
+    pool: Vec<String>,
+
+    /// Make RULE a hole, which the generated program fills in with values
+    /// from a provider instead of expanding it once one is set: a callback
+    /// registered with `set_hole` for libraries, or a file or named pipe
+    /// given with its `--hole RULE=PATH` option for programs. May be given
+    /// more than once.
+    #[arg(long, value_name = "RULE",
+        conflicts_with_all = ["feedback", "uniform_size"])]
+    hole: Vec<String>,
+
+    /// Emit unit tests into the generated source checking it still generates
+    /// the inputs fzero expects, run by compiling it with `rustc --test`
+    #[arg(long)]
+    emit_tests: bool,
+
+    /// Compile the binary even if it was already built from the same source
+    /// with the same options
+    #[arg(long)]
+    rebuild: bool,
+
+    /// Also build in the grammar at PATH, such that the program generates
+    /// from it when run with `--grammar-name NAME`. The grammar given with
+    /// `--grammar` is named after its file.
+    #[arg(long, value_name = "NAME=PATH", value_parser = parse_named_grammar,
+        conflicts_with_all = ["lib", "feedback", "uniform_size"])]
+    add_grammar: Vec<(String, PathBuf)>,
+}
+
+/// Parse a `NAME=PATH` argument of `--add-grammar`
+fn parse_named_grammar(arg: &str) -> Result<(String, PathBuf), String> {
+    match arg.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_string(), PathBuf::from(path)))
+        }
+        _ => Err(String::from("expected NAME=PATH")),
+    }
+}
+
+/// Options for the `generate` subcommand
+#[derive(Args, Debug)]
+struct GenerateArgs {
+    #[command(flatten)]
+    grammar: GrammarArgs,
+
+    #[command(flatten)]
+    gen: GenArgs,
+
+    /// Number of inputs to generate
+    #[arg(short = 'n', long, default_value_t = 1)]
+    count: usize,
+
+    /// Random seed, defaults to the same seed as generated programs
+    #[arg(long, default_value_t = codegen::DEFAULT_SEED)]
+    seed: u64,
+
+    /// Directory to write each input to as a separate file. If not
+    /// specified, inputs are written to stdout separated by newlines.
+    #[arg(long)]
+    out_dir: Option<PathBuf>,
+
+    /// Skip inputs which are identical to one generated before
+    #[arg(long)]
+    dedup: bool,
+
+    /// Write each input as a directory holding every message of the input
+    /// as a separate file, split at the grammar's `%message` boundaries
+    #[arg(long, requires = "out_dir")]
+    split_messages: bool,
+}
+
+/// Options for the `watch` subcommand
+#[derive(Args, Debug)]
+struct WatchArgs {
+    #[command(flatten)]
+    grammar: GrammarArgs,
+
+    #[command(flatten)]
+    gen: GenArgs,
+
+    /// Number of sample inputs to print after every change
+    #[arg(short = 'n', long, default_value_t = 5)]
+    count: usize,
+
+    /// Random seed of the samples, the same after every change so the
+    /// effect of an edit is easy to see
+    #[arg(long, default_value_t = codegen::DEFAULT_SEED)]
+    seed: u64,
+
+    /// Also regenerate the Rust source to this path after every change
+    #[arg(long)]
+    out_src: Option<PathBuf>,
+
+    /// Also recompile the binary to this path after every change
+    #[arg(long, requires = "out_src")]
+    out_bin: Option<PathBuf>,
+
+    /// Number of milliseconds between checks for changes
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    interval: u64,
+}
+
+/// Options for the `bench` subcommand
+#[derive(Args, Debug)]
+struct BenchArgs {
+    #[command(flatten)]
+    grammar: GrammarArgs,
+
+    #[command(flatten)]
+    gen: GenArgs,
+
+    /// Number of threads the benchmark generates inputs with
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+
+    /// Extra options to pass to the generated program, after `--`
+    #[arg(last = true)]
+    args: Vec<String>,
+}
+
+/// Options for the `selftest` subcommand
+#[derive(Args, Debug)]
+struct SelftestArgs {
+    #[command(flatten)]
+    grammar: GrammarArgs,
 
+    #[command(flatten)]
+    gen: GenArgs,
+
+    /// Manifest json file holding the expected hash of the inputs
+    #[arg(long)]
+    manifest: PathBuf,
+
+    /// Number of inputs to generate and hash
+    #[arg(short = 'n', long, default_value_t = 1000)]
+    count: u64,
+
+    /// Record the current hash in the manifest instead of checking it
+    #[arg(long)]
+    update: bool,
+
+    /// Only check the interpreter, without compiling the program
+    #[arg(long)]
+    interpreter_only: bool,
+}
+
+/// Options for the `test` subcommand
+#[derive(Args, Debug)]
+struct TestArgs {
+    #[command(flatten)]
+    grammar: GrammarArgs,
+
+    #[command(flatten)]
+    gen: GenArgs,
+
+    /// Number of seconds to run the generated program for
+    #[arg(long, default_value_t = 3.)]
+    duration: f64,
+
+    /// Minimum throughput in MiB/sec the program must reach to pass
+    #[arg(long, default_value_t = 0.)]
+    min_rate: f64,
+
+    /// Number of threads the program generates inputs with
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+
+    /// Extra options to pass to the generated program, after `--`
+    #[arg(last = true)]
+    args: Vec<String>,
+}
+
+/// Options for the `infer` subcommand
+#[derive(Args, Debug)]
+struct InferArgs {
+    /// Directory of sample inputs to learn from
+    #[arg(long)]
+    corpus: PathBuf,
+
+    /// Json file mapping token class names to the characters in each class,
+    /// eg. `{"ident": "a-zA-Z0-9_"}`. Characters in no class are tokens of
+    /// their own.
+    #[arg(long)]
+    tokens: Option<PathBuf>,
+
+    /// Path to write the inferred json grammar to
+    #[arg(long)]
+    out: PathBuf,
+
+    /// Length of the token n-grams, each token depends on the `N - 1`
+    /// tokens before it
+    #[arg(long, default_value_t = 2,
+        value_parser = clap::value_parser!(u64).range(1..))]
+    order: u64,
+
+    /// Maximum number of distinct values kept for each token class
+    #[arg(long, default_value_t = 64)]
+    max_values: usize,
+}
+
+/// Options for the `diff` subcommand
+#[derive(Args, Debug)]
+struct DiffArgs {
+    /// Grammar json file to compare against
+    old: PathBuf,
+
+    /// Grammar json file to compare
+    new: PathBuf,
+
+    /// Combine the alternatives of rules which are defined more than once
+    /// instead of failing
+    #[arg(long)]
+    merge_duplicates: bool,
+}
+
+/// Options for the `cargo-fuzz` subcommand
+#[derive(Args, Debug)]
+struct CargoFuzzArgs {
+    #[command(flatten)]
+    grammar: GrammarArgs,
+
+    #[command(flatten)]
+    gen: GenArgs,
+
+    /// Function to fuzz along with the type of its argument, one of `&[u8]`,
+    /// `&str`, `Vec<u8>` or `String`, eg. `my_crate::parse(&[u8])`
+    #[arg(long, value_name = "SIGNATURE", value_parser = Target::parse)]
+    target: Target,
+
+    /// Root of the crate to fuzz, the harness goes in its `fuzz` directory
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    crate_dir: PathBuf,
+
+    /// Name of the fuzz target. Defaults to the name of the grammar file.
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Package name of the crate to fuzz, when creating the `fuzz` crate.
+    /// Defaults to the one in its `Cargo.toml`.
+    #[arg(long)]
+    package: Option<String>,
+
+    /// Overwrite the fuzz target if it already exists
+    #[arg(long)]
+    force: bool,
+}
+
+/// Options for the `proto` subcommand
+#[derive(Args, Debug)]
+struct ProtoArgs {
+    /// `.proto` file to convert, along with the files it imports
+    #[arg(long, required_unless_present = "descriptor_set",
+        conflicts_with = "descriptor_set")]
+    proto: Option<PathBuf>,
+
+    /// `FileDescriptorSet` to convert, as written by `protoc -o`
+    #[arg(long)]
+    descriptor_set: Option<PathBuf>,
+
+    /// Directories to search for imported `.proto` files, after the
+    /// directory of the file importing them
+    #[arg(long = "import-path", short = 'I')]
+    import_paths: Vec<PathBuf>,
+
+    /// Message to generate, either its fully qualified name or just its name
+    /// if that's unambiguous. Defaults to the first top-level message.
+    #[arg(long)]
+    message: Option<String>,
+
+    /// Path to write the json grammar to
+    #[arg(long)]
+    out: PathBuf,
+}
+
+/// Options for the `json-schema` subcommand
+#[derive(Args, Debug)]
+struct JsonSchemaArgs {
+    /// JSON Schema file to convert
+    #[arg(long)]
+    schema: PathBuf,
+
+    /// Also generate near misses, documents which break the schema in a
+    /// single way, such as a number just out of bounds
+    #[arg(long)]
+    near_miss: bool,
+
+    /// Path to write the json grammar to
+    #[arg(long)]
+    out: PathBuf,
+}
+
+/// Options for the `xml-schema` subcommand
+#[derive(Args, Debug)]
+struct XmlSchemaArgs {
+    /// DTD file to convert
+    #[arg(long, required_unless_present = "xsd", conflicts_with = "xsd")]
+    dtd: Option<PathBuf>,
+
+    /// XSD file to convert
+    #[arg(long)]
+    xsd: Option<PathBuf>,
+
+    /// Element at the root of documents. Defaults to the first top-level
+    /// element which isn't used by any other.
+    #[arg(long)]
+    root: Option<String>,
+
+    /// Path to write the json grammar to
+    #[arg(long)]
+    out: PathBuf,
+}
+
+/// Options for the `asn1` subcommand
+#[derive(Args, Debug)]
+struct Asn1Args {
+    /// ASN.1 module files to convert, which may import from each other
+    #[arg(long, required = true)]
+    module: Vec<PathBuf>,
+
+    /// Type of the values to generate. Defaults to the first type assigned.
+    #[arg(long = "type")]
+    type_name: Option<String>,
+
+    /// Path to write the json grammar to
+    #[arg(long)]
+    out: PathBuf,
+}
+
+/// Options for the `minimize` subcommand
+#[derive(Args, Debug)]
+struct MinimizeArgs {
+    #[command(flatten)]
+    grammar: GrammarArgs,
+
+    /// Input to minimize
+    #[arg(long)]
+    input: PathBuf,
+
+    /// Path to write the minimized input to
+    #[arg(long)]
+    out: PathBuf,
+
+    /// Predicate command which exits successfully if an input is still
+    /// interesting. An argument of `@@` is replaced with the path of a file
+    /// holding the input, otherwise the input is given on stdin.
+    #[arg(last = true, required = true)]
+    predicate: Vec<String>,
+}
+
+/// Options for the `parse` subcommand
+#[derive(Args, Debug)]
+struct ParseArgs {
+    #[command(flatten)]
+    grammar: GrammarArgs,
+
+    /// Input file, or directory of input files, to parse
+    #[arg(long)]
+    input: PathBuf,
+
+    /// Where to write the derivation trees as json. For a single input this
+    /// is a file and defaults to stdout, for a directory of inputs this is a
+    /// directory which gets a `.json` file per input.
+    #[arg(long)]
+    out: Option<PathBuf>,
+}
+
+/// Options for the `export` subcommand
+#[derive(Args, Debug)]
+struct ExportArgs {
+    #[command(flatten)]
+    grammar: GrammarArgs,
+
+    /// Export the grammar after optimization rather than as written
+    #[arg(long)]
+    optimized: bool,
+
+    /// Write a Graphviz DOT rendering of the grammar to this path
+    #[arg(long)]
+    dot: Option<PathBuf>,
+
+    /// Write the grammar back out in the json grammar format to this path
+    #[arg(long)]
+    json: Option<PathBuf>,
+
+    /// Write an AFL/libFuzzer dictionary of the grammar's terminals to this
+    /// path
+    #[arg(long)]
+    dict: Option<PathBuf>,
+
+    /// Write the grammar in AFL++ Grammar-Mutator's json format to this path
+    #[arg(long)]
+    grammar_mutator: Option<PathBuf>,
+
+    /// Write the grammar in Nautilus' json format to this path
+    #[arg(long)]
+    nautilus: Option<PathBuf>,
+}
+
+/// Load a grammar json file and convert it to the Rust representation.
+/// Progress is reported on stderr so it never mixes with inputs written to
+/// stdout.
+fn load_grammar(args: &GrammarArgs) -> std::io::Result<GrammarRust> {
     // Load up a grammar file
-    let grammar: Grammar = serde_json::from_slice(
-        &std::fs::read(&args[1])?)?;
-    print!("Loaded grammar json\n");
+    let (mut grammar, directives) = args.load()?;
+    eprintln!("Loaded grammar json");
+
+    // Steer generation towards the keywords the user cares about
+    let boosts = args.boosts(&directives)?;
+    if args.normalize && !boosts.is_empty() {
+        eprintln!("warning: --normalize removes the alternatives repeated \
+            by boosting, so boosts have no effect");
+    }
+    for boost in boosts {
+        match grammar.boost(&boost) {
+            0 => eprintln!("warning: boosting {:?} changed nothing, no \
+                terminal contains it or every alternative is as close to it",
+                boost.keyword),
+            alts => eprintln!("Boosted {} alternatives leading to {:?}",
+                alts, boost.keyword),
+        }
+    }
+
+    // Insert whitespace between tokens if asked to
+    if let Some(whitespace) = args.whitespace(&directives)? {
+        let rules = grammar.inject_whitespace(&whitespace,
+            &args.start_rule)?;
+        eprintln!("Injected {} into {} rules", whitespace.rule, rules);
+    }
 
     // Convert the grammar file to the Rust structures
-    let mut gram = GrammarRust::new(&grammar);
-    print!("Converted grammar to binary format\n");
+    let mut gram = GrammarRust::new(&grammar,
+        &args.convert_options(&directives)?)?;
+    eprintln!("Converted grammar to binary format");
+
+    // Regenerate inputs containing any of the patterns to avoid
+    gram.avoid = args.avoid(&directives)?;
+    if !gram.avoid.is_empty() {
+        eprintln!("Avoiding {} patterns", gram.avoid.len());
+    }
+
+    // Drop rules which can't be reached from the start rule
+    let removed = gram.prune_unreachable();
+    if !removed.is_empty() {
+        eprintln!("Pruned {} rules unreachable from the start rule: {}",
+            removed.len(), removed.join(", "));
+    }
+
+    Ok(gram)
+}
 
+/// Optimize a loaded grammar, cleaning up any fragments made dead by the
+/// optimization. With `normalize` the grammar is normalized as well, see
+/// `GrammarRust::normalize`.
+fn optimize_grammar(gram: &mut GrammarRust, normalize: bool) {
     // Optimize the grammar
     gram.optimize();
-    print!("Optimized grammar\n");
+    eprintln!("Optimized grammar");
 
-    // Generate a Rust application
-    gram.program(&args[2],
-        args[4].parse().expect("Invalid digit in max depth"));
-    print!("Generated Rust source file\n");
+    // Share a single copy of identical fragments
+    let duplicates = gram.dedup();
+    eprintln!("Deduplicated {} fragments", duplicates);
 
-    // Compile the application
+    if normalize {
+        let before = grammar_stats(gram);
+
+        // Normalizing leaves more trivial and identical fragments behind,
+        // which can make more options duplicates, so keep going until
+        // nothing changes
+        let (mut spliced, mut duplicates) = (0, 0);
+        loop {
+            let changes = gram.normalize();
+            if changes == (0, 0) {
+                break;
+            }
+            spliced += changes.0;
+            duplicates += changes.1;
+            gram.optimize();
+            gram.dedup();
+        }
+        let after = grammar_stats(gram);
+        eprintln!("Normalized grammar, spliced {} nested fragments and \
+            removed {} duplicate options", spliced, duplicates);
+        eprintln!("    Fragments: {} before, {} after, {} eliminated",
+            before.0, after.0, before.0.saturating_sub(after.0));
+        eprintln!("    Options:   {} before, {} after", before.1, after.1);
+    }
+
+    // Emit prefixes shared by all options of a rule only once, and share
+    // the fragments this leaves identical
+    let factored = gram.factor_prefixes();
+    eprintln!("Factored common prefixes out of {} rules", factored);
+    gram.dedup();
+
+    // Now that recursive references point directly back at their
+    // non-terminals, turn right recursion into loops
+    let loops = gram.convert_loops();
+    eprintln!("Converted {} right-recursive fragments to loops", loops);
+
+    // The optimizer copies fragments into their callers, leaving the
+    // originals dead. Clean them up so we don't generate code for them.
+    let fragments = gram.fragments.len();
+    gram.prune_unreachable();
+    eprintln!("Pruned {} dead fragments", fragments - gram.fragments.len());
+}
+
+/// Count the fragments reachable from the start fragment, and the options
+/// of the non-terminals among them
+fn grammar_stats(gram: &GrammarRust) -> (usize, usize) {
+    let reachable = gram.reachable();
+    let fragments = gram.fragments.iter().zip(reachable)
+        .filter(|(_, reachable)| *reachable).map(|(x, _)| x);
+    fragments.fold((0, 0), |(count, options), fragment| match fragment {
+        Fragment::NonTerminal(ids) => (count + 1, options + ids.len()),
+        _ => (count + 1, options),
+    })
+}
+
+/// Load, check and optimize a grammar in preparation for generating inputs
+/// from it with `gen`
+fn prepare_grammar(args: &GrammarArgs, gen: &GenArgs)
+        -> std::io::Result<GrammarRust> {
+    let mut gram = load_grammar(args)?;
+
+    // Warn about rules which will always be truncated by the depth limit.
+    // This has to happen before optimization as the optimizer doesn't keep
+    // track of which fragments belong to which rule.
+    for warning in gram.termination_warnings(gen.max_depth) {
+        eprintln!("warning: {}", warning);
+    }
+
+    optimize_grammar(&mut gram, args.normalize);
+
+    // Pick single bytes from tables rather than through non-terminals.
+    // Uniform sampling needs the non-terminals to count derivations.
+    if gen.uniform_size.is_none() {
+        let samplers = gram.byte_samplers();
+        gram.prune_unreachable();
+        eprintln!("Replaced {} single byte rules with byte samplers",
+            samplers);
+    }
+    if gen.size_report {
+        size_report(&gram, &gen.options());
+    }
+    Ok(gram)
+}
+
+/// Print the expected and maximum output size of every rule which survived
+/// optimization, when generating from it with `options`
+fn size_report(gram: &GrammarRust, options: &GenOptions) {
+    let expected = gram.expected_output_lens(options);
+    let max = gram.max_output_lens(options);
+
+    eprintln!("Output sizes at max depth {}, for rules which weren't inlined:",
+        options.max_depth);
+    for (name, id) in &gram.name_to_fragment {
+        let max = match max[id.0] {
+            u64::MAX => "over 2^64".to_string(),
+            max => max.to_string(),
+        };
+        eprintln!("    {}: {:.1} bytes expected, at most {}", name,
+            expected[id.0], max);
+    }
+}
+
+/// Compile the generated Rust program at `src` into the binary `bin`, with
+/// the extra codegen options `codegen`
+fn compile(src: &Path, bin: &Path, codegen: &[String])
+        -> std::io::Result<()> {
     // rustc -O -g test.rs -C target-cpu=native
+    let start = std::time::Instant::now();
     let status = Command::new("rustc")
         .arg("-O")                // Optimize the binary
         .arg("-g")                // Generate debug information
-        .arg(&args[2])            // Name of the input Rust file
+        .arg(src)                 // Name of the input Rust file
         .arg("-C")                // Optimize for the current microarchitecture
         .arg("target-cpu=native")
+        .args(codegen.iter().flat_map(|x| ["-C", x]))
         .arg("-o")                // Output filename
-        .arg(&args[3]).spawn()?.wait()?;
-    assert!(status.success(), "Failed to compile Rust binary");
-    print!("Created Rust binary!\n");
+        .arg(bin).spawn()?.wait()?;
+    if !status.success() {
+        return Err(std::io::Error::other("Failed to compile Rust binary"));
+    }
+    eprintln!("Created Rust binary in {:.2} seconds",
+        start.elapsed().as_secs_f64());
 
     Ok(())
 }
 
+/// Find `llvm-profdata`, preferring the one from rustup's `llvm-tools`
+/// component as its profile format matches the LLVM used by `rustc`
+fn llvm_profdata() -> PathBuf {
+    let sysroot = Command::new("rustc").arg("--print").arg("sysroot")
+        .output().ok()
+        .and_then(|x| String::from_utf8(x.stdout).ok())
+        .map(|x| PathBuf::from(x.trim()).join("lib").join("rustlib"));
+    sysroot.and_then(|x| std::fs::read_dir(x).ok()).into_iter().flatten()
+        .filter_map(|x| Some(x.ok()?.path().join("bin").join("llvm-profdata")))
+        .find(|x| x.is_file())
+        .unwrap_or_else(|| PathBuf::from("llvm-profdata"))
+}
+
+/// Compile the generated benchmark program at `src` into the binary `bin`
+/// with profile guided optimization. An instrumented build is run for
+/// `duration` seconds to collect the profile the final build uses.
+fn compile_pgo(src: &Path, bin: &Path, duration: f64, codegen: &[String])
+        -> std::io::Result<()> {
+    let dir = std::env::temp_dir()
+        .join(format!("fzero_pgo_{}", std::process::id()));
+    let raw = dir.join("raw");
+    std::fs::create_dir_all(&raw)?;
+
+    let result = (|| {
+        let instrumented = dir.join("instrumented");
+        let mut generate = codegen.to_vec();
+        generate.push(format!("profile-generate={}", raw.display()));
+        compile(src, &instrumented, &generate)?;
+
+        eprintln!("Collecting a profile for {} seconds", duration);
+        let status = Command::new(&instrumented)
+            .arg("--duration").arg(duration.to_string())
+            .stdout(std::process::Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(std::io::Error::other(
+                "Instrumented binary failed while collecting a profile"));
+        }
+
+        let profile = dir.join("merged.profdata");
+        let profdata = llvm_profdata();
+        let status = Command::new(&profdata)
+            .arg("merge").arg("-o").arg(&profile).arg(&raw)
+            .status()
+            .map_err(|err| std::io::Error::other(format!(
+                "Failed to run {}: {}, install it with `rustup component \
+                 add llvm-tools`", profdata.display(), err)))?;
+        if !status.success() {
+            return Err(std::io::Error::other(
+                "Failed to merge the profile, `llvm-profdata` may not match \
+                 the LLVM version of rustc, use the one from `rustup \
+                 component add llvm-tools`"));
+        }
+
+        let mut profile_use = codegen.to_vec();
+        profile_use.push(format!("profile-use={}", profile.display()));
+        compile(src, bin, &profile_use)
+    })();
+
+    // Clean up the profile whether or not it worked
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+/// Load every grammar of a program built from several, paired with their
+/// names
+fn named_grammars(args: &BuildArgs)
+        -> std::io::Result<Vec<(String, GrammarRust)>> {
+    let name = args.grammar.grammar.file_stem()
+        .map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut grammars = vec![(name, prepare_grammar(&args.grammar,
+        &args.gen)?)];
+    for (name, path) in args.add_grammar.iter() {
+        if grammars.iter().any(|(x, _)| x == name) {
+            return Err(std::io::Error::other(format!(
+                "More than one grammar is named {}", name)));
+        }
+        let grammar = GrammarArgs { grammar: path.clone(),
+            ..args.grammar.clone() };
+        grammars.push((name.clone(), prepare_grammar(&grammar, &args.gen)?));
+    }
+    Ok(grammars)
+}
+
+/// Generate a Rust application and optionally compile it
+fn build(args: &BuildArgs) -> std::io::Result<()> {
+    let options = GenOptions { trace: args.trace, unroll: args.unroll,
+        compact_regular: args.compact_regular, pool: args.pool.clone(),
+        holes: args.hole.clone(), tests: args.emit_tests,
+        ..args.gen.options() };
+    if args.add_grammar.is_empty() {
+        let gram = prepare_grammar(&args.grammar, &args.gen)?;
+
+        // Generate a Rust application
+        let output = if args.c_header.is_some() {
+            Output::CLibrary
+        } else if args.lib {
+            Output::Library
+        } else if args.feedback {
+            Output::Feedback
+        } else {
+            Output::Program
+        };
+        gram.program(&args.out_src, &options, output)?;
+        eprintln!("Generated Rust source file");
+        if let Some(c_header) = &args.c_header {
+            std::fs::write(c_header, codegen::c_header(&options))?;
+            eprintln!("Generated C header");
+        }
+    } else {
+        // Generate a program combining several grammars
+        let grammars = named_grammars(args)?;
+        codegen::multi_program(&args.out_src, &grammars, &options)?;
+        eprintln!("Generated Rust source file for {} grammars",
+            grammars.len());
+    }
+
+    // Stop here if the user only wanted the source
+    let out_bin = match &args.out_bin {
+        Some(out_bin) if !args.source_only => out_bin,
+        _ => return Ok(()),
+    };
+
+    // Skip compiling if the binary was built from the same source the same
+    // way. The hash is only written once the binary is, so failed builds are
+    // never mistaken for up to date ones.
+    let hash = build_hash(args)?;
+    let hash_path = PathBuf::from(format!("{}.fzero-hash",
+        out_bin.display()));
+    if !args.rebuild && out_bin.is_file() &&
+            std::fs::read_to_string(&hash_path).is_ok_and(|x| x == hash) {
+        eprintln!("{} is up to date, skipping compilation",
+            out_bin.display());
+        return Ok(());
+    }
+    match std::fs::remove_file(&hash_path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            return Err(err);
+        }
+        _ => {}
+    }
+
+    let codegen: Vec<String> = args.codegen_units.iter()
+        .map(|x| format!("codegen-units={}", x)).collect();
+    if args.pgo {
+        compile_pgo(&args.out_src, out_bin, args.pgo_duration, &codegen)?;
+    } else {
+        compile(&args.out_src, out_bin, &codegen)?;
+    }
+    std::fs::write(&hash_path, hash)
+}
+
+/// Get the hash identifying how the binary of `build` is compiled: the
+/// generated source, which reflects the grammar and every generation option,
+/// along with the options of the compilation itself, the fzero version and
+/// the version and host target of rustc, so upgrading the toolchain rebuilds
+fn build_hash(args: &BuildArgs) -> std::io::Result<String> {
+    let rustc = Command::new("rustc").arg("-vV").output()?;
+    if !rustc.status.success() {
+        return Err(std::io::Error::other("Failed to get the rustc version"));
+    }
+
+    let mut hasher = DefaultHasher::new();
+    std::fs::read(&args.out_src)?.hash(&mut hasher);
+    args.pgo.hash(&mut hasher);
+    args.pgo_duration.to_bits().hash(&mut hasher);
+    args.codegen_units.hash(&mut hasher);
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    rustc.stdout.hash(&mut hasher);
+    Ok(format!("{:016x}\n", hasher.finish()))
+}
+
+/// Generate inputs with the interpreter
+fn generate(args: &GenerateArgs) -> std::io::Result<()> {
+    let gram = prepare_grammar(&args.grammar, &args.gen)?;
+
+    // Create the output directory if needed
+    if let Some(out_dir) = &args.out_dir {
+        std::fs::create_dir_all(out_dir)?;
+    }
+
+    let mut interp = Interpreter::new(&gram, args.seed,
+        &args.gen.options())?;
+    let mut buf = Vec::new();
+    let mut stdout = std::io::stdout().lock();
+
+    // Hashes of the inputs written so far, when skipping duplicates
+    let mut seen = BTreeSet::new();
+    let mut written = 0;
+    for _ in 0..args.count {
+        interp.generate(&mut buf);
+
+        if args.dedup {
+            let mut hasher = DefaultHasher::new();
+            buf.hash(&mut hasher);
+            if !seen.insert(hasher.finish()) {
+                continue;
+            }
+        }
+
+        if let Some(out_dir) = &args.out_dir {
+            let path = out_dir.join(format!("{:08}", written));
+            if args.split_messages {
+                std::fs::create_dir_all(&path)?;
+                for (idx, message) in
+                        interp.messages(&buf).iter().enumerate() {
+                    std::fs::write(path.join(format!("{:04}", idx)),
+                        message)?;
+                }
+            } else {
+                std::fs::write(path, &buf)?;
+            }
+        } else {
+            stdout.write_all(&buf)?;
+            stdout.write_all(b"\n")?;
+        }
+        written += 1;
+    }
+
+    if args.dedup {
+        let duplicates = args.count - written;
+        eprintln!("Skipped {} duplicate inputs ({:.2}%)", duplicates,
+            duplicates as f64 / args.count.max(1) as f64 * 100.);
+    }
+
+    Ok(())
+}
+
+/// Build the benchmark program into the temp directory and run it
+fn bench(args: &BenchArgs) -> std::io::Result<()> {
+    let gram = prepare_grammar(&args.grammar, &args.gen)?;
+
+    // Pick unique names in the temporary directory for our files
+    let tmp = std::env::temp_dir();
+    let src = tmp.join(format!("fzero_bench_{}.rs", std::process::id()));
+    let bin = tmp.join(format!("fzero_bench_{}", std::process::id()));
+
+    // Build and run the benchmark, always cleaning up after it. It usually
+    // runs until the user kills it, which kills us too, so the files are
+    // removed as soon as it started where a running binary can be deleted,
+    // and again once it exits everywhere else.
+    let run = gram.program(&src, &args.gen.options(), Output::Program)
+        .and_then(|()| {
+            eprintln!("Generated Rust source file");
+            compile(&src, &bin, &[])
+        })
+        .and_then(|()| {
+            Command::new(&bin)
+                .arg("--threads").arg(args.threads.to_string())
+                .args(&args.args)
+                .spawn()
+        })
+        .and_then(|mut child| {
+            let _ = std::fs::remove_file(&src);
+            let _ = std::fs::remove_file(&bin);
+            child.wait()
+        });
+    let _ = std::fs::remove_file(&src);
+    let _ = std::fs::remove_file(&bin);
+    run?;
+    Ok(())
+}
+
+/// Write a cargo-fuzz harness generating inputs from a grammar, adding it to
+/// the `fuzz` crate or creating that
+fn cargo_fuzz(args: &CargoFuzzArgs) -> std::io::Result<()> {
+    let gram = prepare_grammar(&args.grammar, &args.gen)?;
+
+    let stem = args.grammar.grammar.file_stem()
+        .map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = scaffold::target_name(args.name.as_deref().unwrap_or(&stem));
+    let fuzz = args.crate_dir.join("fuzz");
+    let target = fuzz.join("fuzz_targets").join(format!("{}.rs", name));
+    if target.exists() && !args.force {
+        return Err(std::io::Error::other(format!(
+            "{} already exists, pass --force to overwrite it",
+            target.display())));
+    }
+
+    // The grammar is compiled into a library next to the fuzz targets
+    std::fs::create_dir_all(fuzz.join("fuzz_targets"))?;
+    std::fs::create_dir_all(fuzz.join("grammars"))?;
+    let library = fuzz.join("grammars").join(format!("{}.rs", name));
+    gram.program(&library, &args.gen.options(), Output::Library)?;
+    std::fs::write(&target, scaffold::fuzz_target(&name,
+        &args.grammar.grammar.display().to_string(), &args.target))?;
+    eprintln!("Wrote fuzz target {} and grammar library {}",
+        target.display(), library.display());
+
+    // Declare the target in the fuzz crate, creating it if needed
+    let manifest = fuzz.join("Cargo.toml");
+    if manifest.exists() {
+        let mut toml = std::fs::read_to_string(&manifest)?;
+        if !toml.contains(&format!("\"fuzz_targets/{}.rs\"", name)) {
+            toml += &scaffold::bin_section(&name);
+            std::fs::write(&manifest, toml)?;
+            eprintln!("Added fuzz target {} to {}", name, manifest.display());
+        }
+    } else {
+        let package = args.package.clone()
+            .or_else(|| scaffold::package_name(&args.crate_dir))
+            .unwrap_or_else(|| args.target.crate_name().to_string());
+        std::fs::write(&manifest,
+            scaffold::manifest(&package) + &scaffold::bin_section(&name))?;
+        std::fs::write(fuzz.join(".gitignore"), scaffold::GITIGNORE)?;
+        eprintln!("Created fuzz crate {} depending on {}", manifest.display(),
+            package);
+    }
+
+    eprintln!("Run it with `cargo fuzz run {}` from {}", name,
+        args.crate_dir.display());
+    Ok(())
+}
+
+/// Hash the inputs generated by the interpreter and the compiled program and
+/// check them against the manifest, or record them in it
+fn selftest(args: &SelftestArgs) -> std::io::Result<()> {
+    let gram = prepare_grammar(&args.grammar, &args.gen)?;
+    let options = args.gen.options();
+
+    let hash = selftest::interpreter_hash(&gram, &options, args.count)?;
+    let mut results = vec![("interpreter", hash)];
+    if !args.interpreter_only {
+        let tmp = std::env::temp_dir();
+        let src = tmp.join(format!("fzero_selftest_{}.rs",
+            std::process::id()));
+        let bin = tmp.join(format!("fzero_selftest_{}", std::process::id()));
+        gram.program(&src, &options, Output::Program)?;
+        let hash = compile(&src, &bin, &[])
+            .and_then(|()| selftest::binary_hash(&bin, args.count));
+        let _ = std::fs::remove_file(&src);
+        let _ = std::fs::remove_file(&bin);
+        results.push(("compiled program", hash?));
+    }
+
+    // The interpreter and the compiled program must always agree, whatever
+    // the manifest says
+    if results.iter().any(|&(_, x)| x != hash) {
+        return Err(std::io::Error::other(format!(
+            "The interpreter and the compiled program disagree: {}",
+            results.iter().map(|(name, x)| format!("{} {:016x}", name, x))
+                .collect::<Vec<_>>().join(", "))));
+    }
+
+    let current = Manifest::new(&options, args.count, hash);
+    if args.update {
+        current.save(&args.manifest)?;
+        eprintln!("Recorded hash {} of {} inputs in {}", current.hash,
+            args.count, args.manifest.display());
+        return Ok(());
+    }
+
+    let expected = Manifest::load(&args.manifest)?;
+    if !expected.same_options(&current) {
+        return Err(std::io::Error::other(format!(
+            "{} was recorded with different options or input count, pass \
+             --update to record it again", args.manifest.display())));
+    }
+    if expected.hash != current.hash {
+        return Err(std::io::Error::other(format!(
+            "Generated inputs changed, expected hash {} but got {}",
+            expected.hash, current.hash)));
+    }
+    eprintln!("Hash {} of {} inputs matches {}", current.hash, args.count,
+        args.manifest.display());
+    Ok(())
+}
+
+/// Build the program into the temp directory, run it for `--duration`
+/// seconds and check it generated inputs at least at `--min-rate`, going by
+/// the final statistics it reports with `--json-stats`
+fn test(args: &TestArgs) -> std::io::Result<()> {
+    if args.duration.is_nan() || args.duration <= 0. {
+        return Err(std::io::Error::other("--duration must be positive"));
+    }
+    let gram = prepare_grammar(&args.grammar, &args.gen)?;
+
+    let tmp = std::env::temp_dir();
+    let src = tmp.join(format!("fzero_test_{}.rs", std::process::id()));
+    let bin = tmp.join(format!("fzero_test_{}", std::process::id()));
+
+    // Build and run the program, always cleaning up after it
+    let it = std::time::Instant::now();
+    let run = gram.program(&src, &args.gen.options(), Output::Program)
+        .and_then(|()| compile(&src, &bin, &[]))
+        .map(|()| it.elapsed().as_secs_f64())
+        .and_then(|build| {
+            eprintln!("Built the program in {:.1} seconds, running it for \
+                {} seconds", build, args.duration);
+            Command::new(&bin)
+                .arg("--threads").arg(args.threads.to_string())
+                .arg("--duration").arg(args.duration.to_string())
+                .arg("--json-stats")
+                .args(&args.args)
+                .stdout(std::process::Stdio::null())
+                .output()
+        });
+    let _ = std::fs::remove_file(&src);
+    let _ = std::fs::remove_file(&bin);
+    let output = run?;
+
+    // The final summary is the json line `--json-stats` marks as final,
+    // anything on stderr which isn't json is passed along
+    let mut totals = None;
+    let mut rate = None;
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(stats) if stats["final"] == true => {
+                totals = stats["inputs"].as_u64()
+                    .zip(stats["bytes"].as_u64());
+                rate = stats["mib_per_sec"].as_f64();
+            }
+            Ok(_) => {}
+            Err(_) => eprintln!("{}", line),
+        }
+    }
+
+    let mut failures = Vec::new();
+    if !output.status.success() {
+        failures.push(format!("the program exited with {}", output.status));
+    }
+    let (inputs, bytes) = totals.unwrap_or_default();
+    let rate = rate.unwrap_or_default();
+    if totals.is_none() {
+        failures.push("the program printed no final summary".to_string());
+    } else if inputs == 0 || bytes == 0 {
+        failures.push("no output was generated".to_string());
+    }
+    if rate < args.min_rate {
+        failures.push(format!("{:.4} MiB/sec is below the minimum of {} \
+            MiB/sec", rate, args.min_rate));
+    }
+
+    eprintln!("{}: {} inputs ({} bytes) in {} seconds, {:.4} MiB/sec",
+        if failures.is_empty() { "PASS" } else { "FAIL" }, inputs, bytes,
+        args.duration, rate);
+    if !failures.is_empty() {
+        return Err(std::io::Error::other(format!("Test failed: {}",
+            failures.join(", "))));
+    }
+    Ok(())
+}
+
+/// Export a grammar to all of the requested formats
+fn export(args: &ExportArgs) -> std::io::Result<()> {
+    let mut gram = load_grammar(&args.grammar)?;
+    if args.optimized {
+        optimize_grammar(&mut gram, args.grammar.normalize);
+    }
+
+    if let Some(path) = &args.dot {
+        std::fs::write(path, gram.dot())?;
+        eprintln!("Wrote DOT graph to {}", path.display());
+    }
+
+    if let Some(path) = &args.json {
+        std::fs::write(path,
+            serde_json::to_string_pretty(&gram.to_grammar())?)?;
+        eprintln!("Wrote json grammar to {}", path.display());
+    }
+
+    if let Some(path) = &args.dict {
+        let (dict, tokens) = gram.dictionary();
+        std::fs::write(path, dict)?;
+        eprintln!("Wrote {} dictionary tokens to {}", tokens, path.display());
+    }
+
+    if let Some(path) = &args.grammar_mutator {
+        let (grammar, warnings) = gram.grammar_mutator();
+        for warning in warnings {
+            eprintln!("warning: {}", warning);
+        }
+        std::fs::write(path, grammar)?;
+        eprintln!("Wrote Grammar-Mutator grammar to {}", path.display());
+    }
+
+    if let Some(path) = &args.nautilus {
+        let (grammar, warnings) = gram.nautilus();
+        for warning in warnings {
+            eprintln!("warning: {}", warning);
+        }
+        std::fs::write(path, grammar)?;
+        eprintln!("Wrote Nautilus grammar to {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Infer a grammar from a corpus of samples
+fn infer(args: &InferArgs) -> std::io::Result<()> {
+    let spec = match &args.tokens {
+        Some(path) => infer::TokenSpec::parse(&std::fs::read(path)?)?,
+        None => infer::TokenSpec::default(),
+    };
+
+    // Read all of the samples, in a consistent order
+    let mut paths = std::fs::read_dir(&args.corpus)?
+        .map(|x| x.map(|x| x.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+
+    let mut samples = Vec::new();
+    for path in paths.iter().filter(|x| x.is_file()) {
+        samples.push(String::from_utf8_lossy(&std::fs::read(path)?)
+            .into_owned());
+    }
+    if samples.is_empty() {
+        return Err(std::io::Error::other(format!(
+            "No samples found in {}", args.corpus.display())));
+    }
+    eprintln!("Read {} samples", samples.len());
+
+    let grammar = infer::infer(&samples, &spec, args.order as usize,
+        args.max_values);
+    eprintln!("Inferred grammar with {} rules", grammar.0.len());
+
+    std::fs::write(&args.out, serde_json::to_string_pretty(&grammar)?)?;
+    eprintln!("Wrote json grammar to {}", args.out.display());
+    Ok(())
+}
+
+/// Convert a protobuf schema into a grammar
+fn proto(args: &ProtoArgs) -> std::io::Result<()> {
+    let schema = match (&args.proto, &args.descriptor_set) {
+        (Some(path), _) => proto::Schema::from_proto(path, &args.import_paths)?,
+        (None, Some(path)) => {
+            proto::Schema::from_descriptor_set(&std::fs::read(path)?)?
+        }
+        (None, None) => unreachable!(),
+    };
+
+    let grammar = schema.grammar(args.message.as_deref())?;
+    eprintln!("Converted schema to a grammar with {} rules", grammar.0.len());
+
+    std::fs::write(&args.out, serde_json::to_string_pretty(&grammar)?)?;
+    eprintln!("Wrote json grammar to {}", args.out.display());
+    Ok(())
+}
+
+/// Convert a JSON Schema into a grammar
+fn json_schema(args: &JsonSchemaArgs) -> std::io::Result<()> {
+    let schema: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&args.schema)?)?;
+    let grammar = jsonschema::convert(&schema, args.near_miss)?;
+    eprintln!("Converted schema to a grammar with {} rules", grammar.0.len());
+
+    std::fs::write(&args.out, serde_json::to_string_pretty(&grammar)?)?;
+    eprintln!("Wrote json grammar to {}", args.out.display());
+    Ok(())
+}
+
+/// Convert a DTD or XSD into a grammar
+fn xml_schema(args: &XmlSchemaArgs) -> std::io::Result<()> {
+    let schema = match (&args.dtd, &args.xsd) {
+        (Some(path), _) => {
+            xml::Schema::from_dtd(&std::fs::read_to_string(path)?)?
+        }
+        (None, Some(path)) => {
+            xml::Schema::from_xsd(&std::fs::read_to_string(path)?)?
+        }
+        (None, None) => unreachable!(),
+    };
+
+    let grammar = schema.grammar(args.root.as_deref())?;
+    eprintln!("Converted schema to a grammar with {} rules", grammar.0.len());
+
+    std::fs::write(&args.out, serde_json::to_string_pretty(&grammar)?)?;
+    eprintln!("Wrote json grammar to {}", args.out.display());
+    Ok(())
+}
+
+/// Convert ASN.1 modules into a grammar
+fn asn1(args: &Asn1Args) -> std::io::Result<()> {
+    let schema = asn1::Schema::from_modules(&args.module)?;
+    let grammar = schema.grammar(args.type_name.as_deref())?;
+    eprintln!("Converted modules to a grammar with {} rules",
+        grammar.0.len());
+
+    std::fs::write(&args.out, serde_json::to_string_pretty(&grammar)?)?;
+    eprintln!("Wrote json grammar to {}", args.out.display());
+    Ok(())
+}
+
+/// Minimize an input with a predicate
+fn minimize(args: &MinimizeArgs) -> std::io::Result<()> {
+    let gram = load_grammar(&args.grammar)?;
+    let input = std::fs::read(&args.input)?;
+
+    let predicate = minimize::Predicate {
+        command: args.predicate.clone(),
+        input_path: std::env::temp_dir()
+            .join(format!(".fzero_minimize_{}", std::process::id())),
+    };
+    let result = gram.minimize(&input, |x| predicate.check(x));
+    let _ = std::fs::remove_file(&predicate.input_path);
+    let minimized = result?;
+
+    std::fs::write(&args.out, &minimized)?;
+    eprintln!("Minimized {} bytes to {} bytes, wrote {}", input.len(),
+        minimized.len(), args.out.display());
+    Ok(())
+}
+
+/// Parse inputs into derivation trees
+fn parse(args: &ParseArgs) -> std::io::Result<()> {
+    let gram = load_grammar(&args.grammar)?;
+
+    // Parse a single input, or every file in a directory of them
+    if !args.input.is_dir() {
+        let input = std::fs::read(&args.input)?;
+        let tree = gram.parse(&input).ok_or_else(|| {
+            std::io::Error::other("The grammar doesn't generate the input")
+        })?;
+
+        let json = serde_json::to_string_pretty(&gram.rule_tree(&tree))?;
+        match &args.out {
+            Some(path) => std::fs::write(path, json)?,
+            None => println!("{}", json),
+        }
+        return Ok(());
+    }
+
+    if let Some(out) = &args.out {
+        std::fs::create_dir_all(out)?;
+    }
+
+    let mut paths = std::fs::read_dir(&args.input)?
+        .map(|x| x.map(|x| x.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.sort();
+
+    let (mut parsed, mut failed) = (0, 0);
+    for path in paths.iter().filter(|x| x.is_file()) {
+        let Some(tree) = gram.parse(&std::fs::read(path)?) else {
+            eprintln!("warning: {} isn't generated by the grammar",
+                path.display());
+            failed += 1;
+            continue;
+        };
+        parsed += 1;
+
+        if let (Some(out), Some(name)) = (&args.out, path.file_name()) {
+            let mut name = name.to_os_string();
+            name.push(".json");
+            std::fs::write(out.join(name),
+                serde_json::to_string_pretty(&gram.rule_tree(&tree))?)?;
+        }
+    }
+
+    eprintln!("Parsed {} inputs, {} failed", parsed, failed);
+    Ok(())
+}
+
+/// Get the modification times of `files`, `None` for files which don't
+/// exist at the moment
+fn modified(files: &[PathBuf]) -> Vec<Option<std::time::SystemTime>> {
+    files.iter()
+        .map(|x| std::fs::metadata(x).and_then(|x| x.modified()).ok())
+        .collect()
+}
+
+/// Watch a grammar, going over it again every time it or a grammar it
+/// embeds changes. Problems with the grammar are reported without stopping,
+/// as they are expected while it's being edited.
+fn watch(args: &WatchArgs) -> std::io::Result<()> {
+    let interval = std::time::Duration::from_millis(args.interval);
+    let mut files = Grammar::files(&args.grammar.grammar);
+    let mut stamps = modified(&files);
+    loop {
+        if let Err(err) = watch_pass(args) {
+            eprintln!("error: {}", err);
+        }
+        eprintln!("Watching {} for changes", files.iter()
+            .map(|x| x.display().to_string()).collect::<Vec<_>>()
+            .join(", "));
+
+        // Wait for a change, then for the files to settle, so an editor
+        // writing a file in several steps only triggers a single pass
+        loop {
+            std::thread::sleep(interval);
+            if modified(&files) != stamps {
+                break;
+            }
+        }
+        loop {
+            stamps = modified(&files);
+            std::thread::sleep(interval);
+            if modified(&files) == stamps {
+                break;
+            }
+        }
+
+        // The set of embedded grammars may have changed with the edit
+        files = Grammar::files(&args.grammar.grammar);
+        stamps = modified(&files);
+        eprintln!();
+        eprintln!("Grammar changed, reloading");
+    }
+}
+
+/// Validate the watched grammar, rebuild it if asked to and print samples
+fn watch_pass(args: &WatchArgs) -> std::io::Result<()> {
+    validate(&args.grammar)?;
+
+    if let Some(out_src) = &args.out_src {
+        build(&BuildArgs {
+            grammar:      args.grammar.clone(),
+            gen:          args.gen.clone(),
+            out_src:      out_src.clone(),
+            out_bin:      args.out_bin.clone(),
+            source_only:  args.out_bin.is_none(),
+            lib:          false,
+            c_header:     None,
+            feedback:     false,
+            pgo:          false,
+            pgo_duration: 0.,
+            codegen_units: None,
+            trace:        false,
+            unroll:       0,
+            compact_regular: false,
+            pool:         Vec::new(),
+            hole:         Vec::new(),
+            emit_tests:   false,
+            rebuild:      false,
+            add_grammar:  Vec::new(),
+        })?;
+    }
+
+    let gram = prepare_grammar(&args.grammar, &args.gen)?;
+    let mut interp = Interpreter::new(&gram, args.seed,
+        &args.gen.options())?;
+    let mut buf = Vec::new();
+    let mut stdout = std::io::stdout().lock();
+    for _ in 0..args.count {
+        interp.generate(&mut buf);
+        stdout.write_all(&buf)?;
+        stdout.write_all(b"\n")?;
+    }
+    stdout.flush()
+}
+
+/// Lint a grammar and report all problems found
+fn validate(args: &GrammarArgs) -> std::io::Result<()> {
+    let source = std::fs::read_to_string(&args.grammar)?;
+    let (grammar, _) = args.load()?;
+
+    let diags = validate::validate(&grammar, &source, &args.start_rule);
+    for diag in &diags {
+        println!("{}:{}", args.grammar.display(), diag);
+    }
+
+    let errors = diags.iter()
+        .filter(|x| x.severity == Severity::Error).count();
+    println!("{} errors, {} warnings", errors, diags.len() - errors);
+
+    if errors > 0 {
+        return Err(std::io::Error::other("Grammar failed validation"));
+    }
+    Ok(())
+}
+
+/// Compare two grammars and print their differences
+fn diff(args: &DiffArgs) -> std::io::Result<()> {
+    let (old, old_directives, _) =
+        Grammar::load(&args.old, args.merge_duplicates)?;
+    let (new, new_directives, _) =
+        Grammar::load(&args.new, args.merge_duplicates)?;
+
+    let changes = diff::diff(&old, &old_directives, &new, &new_directives);
+    let (mut added, mut removed) = (0, 0);
+    let mut changed = BTreeSet::new();
+    for change in &changes {
+        println!("{}", change);
+        match change {
+            diff::Change::RuleAdded(..)  => added += 1,
+            diff::Change::RuleRemoved(_) => removed += 1,
+            diff::Change::AltAdded(rule, _) |
+                    diff::Change::AltRemoved(rule, _) |
+                    diff::Change::AltChanged(rule, ..) => {
+                changed.insert(rule);
+            }
+            diff::Change::Directive(..) => {}
+        }
+    }
+    println!("{} rules added, {} removed, {} changed", added, removed,
+        changed.len());
+    Ok(())
+}
+
+fn main() {
+    // Parse the command line arguments
+    let cli = Cli::parse();
+
+    let result = match &cli.command {
+        Commands::Build(args)    => build(args),
+        Commands::Generate(args) => generate(args),
+        Commands::Bench(args)    => bench(args),
+        Commands::CargoFuzz(args) => cargo_fuzz(args),
+        Commands::Export(args)   => export(args),
+        Commands::Diff(args)     => diff(args),
+        Commands::Selftest(args) => selftest(args),
+        Commands::Test(args)     => test(args),
+        Commands::Validate(args) => validate(args),
+        Commands::Infer(args)    => infer(args),
+        Commands::Minimize(args) => minimize(args),
+        Commands::Parse(args)    => parse(args),
+        Commands::Proto(args)    => proto(args),
+        Commands::JsonSchema(args) => json_schema(args),
+        Commands::XmlSchema(args)  => xml_schema(args),
+        Commands::Asn1(args)       => asn1(args),
+        Commands::Watch(args)      => watch(args),
+    };
+
+    // Report errors in a human readable way rather than via `Debug`
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use super::*;
+
+    /// A compiled program appends exactly the terminals the interpreter
+    /// does, whether they're written inline, copied from the terminal pool
+    /// or copied out of line for being large
+    #[test]
+    fn compiled_terminals_match_interpreter() {
+        let dir = std::env::temp_dir()
+            .join(format!("fzero_terminals_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let grammar = dir.join("grammar.json");
+        let manifest = dir.join("manifest.json");
+        std::fs::write(&grammar, format!(r#"{{
+            "<start>": [["<term>"], ["<term>", "<start>"]],
+            "<term>": [["a"], ["{}"], ["{}"]]
+        }}"#, "b".repeat(codegen::INLINE_TERMINAL_MAX + 1),
+            "c".repeat(codegen::LARGE_TERMINAL_MIN))).unwrap();
+
+        // Recording a manifest fails if the two disagree
+        let cli = Cli::try_parse_from(["fzero", "selftest", "--grammar",
+            grammar.to_str().unwrap(), "--max-depth", "16", "--manifest",
+            manifest.to_str().unwrap(), "--update"]).unwrap();
+        let Commands::Selftest(args) = &cli.command else { unreachable!() };
+        let result = selftest(args);
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+    }
+
+    /// A compiled program picks single bytes from its tables with the random
+    /// numbers the interpreter uses, which are the ones the rules the tables
+    /// replaced would have used
+    #[test]
+    fn compiled_byte_samplers_match_interpreter() {
+        let dir = std::env::temp_dir()
+            .join(format!("fzero_samplers_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let grammar = dir.join("grammar.json");
+        let manifest = dir.join("manifest.json");
+        std::fs::write(&grammar, r#"{
+            "<start>": [["<hex>"], ["<hex>", "<start>"]],
+            "<hex>": [["<digit>"], ["<letter>"], ["x"]],
+            "<digit>": [["0"], ["1"], ["2"]],
+            "<letter>": [["a"], ["b"]]
+        }"#).unwrap();
+
+        let cli = Cli::try_parse_from(["fzero", "selftest", "--grammar",
+            grammar.to_str().unwrap(), "--max-depth", "16", "--manifest",
+            manifest.to_str().unwrap(), "--update"]).unwrap();
+        let Commands::Selftest(args) = &cli.command else { unreachable!() };
+        let result = selftest(args);
+        let recorded = Manifest::load(&manifest);
+        let sampled = prepare_grammar(&args.grammar, &args.gen);
+        let unsampled = load_grammar(&args.grammar);
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+
+        // `<hex>` was turned into a byte sampler
+        assert!(sampled.unwrap().fragments.iter()
+            .any(|x| matches!(x, Fragment::Bytes(_))));
+
+        // And generates what the rules did
+        let mut gram = unsampled.unwrap();
+        optimize_grammar(&mut gram, false);
+        assert!(!gram.fragments.iter()
+            .any(|x| matches!(x, Fragment::Bytes(_))));
+        let hash = selftest::interpreter_hash(&gram, &args.gen.options(),
+            args.count).unwrap();
+        assert_eq!(recorded.unwrap().hash, format!("{:016x}", hash));
+    }
+
+    /// A compiled program encodes `%der_length`s like the interpreter, on
+    /// both sides of the switch to the long form and of its extra byte
+    #[test]
+    fn compiled_der_lengths_match_interpreter() {
+        let dir = std::env::temp_dir()
+            .join(format!("fzero_der_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let grammar = dir.join("grammar.json");
+        let manifest = dir.join("manifest.json");
+        let bodies: Vec<String> = [0, 0x7f, 0x80, 0xff, 0x100].iter()
+            .map(|&len| format!("[\"{}\"]", "a".repeat(len))).collect();
+        std::fs::write(&grammar, format!(r#"{{
+            "<start>": [["%der_length(<body>)", "<body>"]],
+            "<body>": [{}]
+        }}"#, bodies.join(", "))).unwrap();
+
+        let cli = Cli::try_parse_from(["fzero", "selftest", "--grammar",
+            grammar.to_str().unwrap(), "--max-depth", "16", "--manifest",
+            manifest.to_str().unwrap(), "--update"]).unwrap();
+        let Commands::Selftest(args) = &cli.command else { unreachable!() };
+        let result = selftest(args);
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+    }
+}
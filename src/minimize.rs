@@ -0,0 +1,178 @@
+//! Grammar-aware minimization of inputs. The input is parsed into a
+//! derivation tree and subtrees are repeatedly replaced with smaller
+//! expansions of the same fragment, keeping each replacement only if a
+//! user-supplied predicate command says the input is still interesting.
+//! Every candidate is generated by the grammar, so unlike byte-level
+//! minimizers this never breaks the structure of the input.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use crate::grammar::GrammarRust;
+use crate::parse::Tree;
+
+/// Command which decides whether an input is still interesting
+pub struct Predicate {
+    /// Command line to run. An argument of `@@` is replaced with the path of
+    /// a file holding the input, otherwise the input is given on stdin.
+    pub command: Vec<String>,
+
+    /// File inputs are written to for commands which take `@@`
+    pub input_path: PathBuf,
+}
+
+impl Predicate {
+    /// Run the predicate on `input`, returning `true` if it exits
+    /// successfully, meaning the input is still interesting
+    pub fn check(&self, input: &[u8]) -> std::io::Result<bool> {
+        let mut command = Command::new(&self.command[0]);
+        command.stdout(Stdio::null()).stderr(Stdio::null());
+
+        if self.command[1..].iter().any(|x| x == "@@") {
+            std::fs::write(&self.input_path, input)?;
+            for arg in &self.command[1..] {
+                if arg == "@@" {
+                    command.arg(&self.input_path);
+                } else {
+                    command.arg(arg);
+                }
+            }
+            return Ok(command.stdin(Stdio::null()).status()?.success());
+        }
+
+        let mut child = command.args(&self.command[1..])
+            .stdin(Stdio::piped()).spawn()?;
+
+        // The predicate may exit without reading all of its input
+        let mut stdin = child.stdin.take().unwrap();
+        match stdin.write_all(input) {
+            Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+                return Err(err);
+            }
+            _ => {}
+        }
+        drop(stdin);
+        Ok(child.wait()?.success())
+    }
+}
+
+/// Get the replacements to try for `node`, smallest first: the shortest
+/// output of its fragment, and the output of every smaller node of the same
+/// fragment within it. Only replacements smaller than the node are returned.
+fn candidates<'a>(node: &Tree, input: &'a [u8],
+        shortest: &'a [Option<Vec<u8>>]) -> Vec<&'a [u8]> {
+    let len = node.end - node.start;
+    let mut ret: Vec<&[u8]> = Vec::new();
+
+    if let Some(shortest) = &shortest[node.fragment.0] {
+        ret.push(shortest);
+    }
+    for inner in node.preorder().into_iter().skip(1) {
+        if inner.fragment == node.fragment {
+            ret.push(&input[inner.start..inner.end]);
+        }
+    }
+
+    ret.retain(|x| x.len() < len);
+    ret.sort_by_key(|x| x.len());
+    ret.dedup();
+    ret
+}
+
+impl GrammarRust {
+    /// Minimize `input`, which must be generated by the grammar and
+    /// interesting according to `interesting`, usually `Predicate::check`
+    pub fn minimize(&self, input: &[u8],
+            mut interesting: impl FnMut(&[u8]) -> std::io::Result<bool>)
+            -> std::io::Result<Vec<u8>> {
+        let shortest = self.shortest_outputs();
+
+        let mut input = input.to_vec();
+        if !interesting(&input)? {
+            return Err(std::io::Error::other(
+                "The predicate doesn't hold for the original input"));
+        }
+
+        // Keep making passes over the tree until nothing can be replaced.
+        // Nodes are visited in pre-order so the largest subtrees are tried
+        // first, and the tree is parsed again after every replacement.
+        'pass: loop {
+            let tree = self.parse(&input).ok_or_else(|| {
+                std::io::Error::other("The grammar doesn't generate the input")
+            })?;
+
+            for node in tree.preorder() {
+                for replacement in candidates(node, &input, &shortest) {
+                    let mut candidate = input[..node.start].to_vec();
+                    candidate.extend_from_slice(replacement);
+                    candidate.extend_from_slice(&input[node.end..]);
+
+                    if interesting(&candidate)? {
+                        eprintln!("Reduced input to {} bytes",
+                            candidate.len());
+                        input = candidate;
+                        continue 'pass;
+                    }
+                }
+            }
+
+            break;
+        }
+
+        Ok(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::tests::grammar;
+
+    /// Comma separated lists of items, which may be lists in parentheses
+    const LISTS: &str = r#"{
+        "<start>": [["<list>"]],
+        "<list>": [["<item>"], ["<item>", ",", "<list>"]],
+        "<item>": [["a"], ["b"], ["(", "<list>", ")"]]
+    }"#;
+
+    #[test]
+    fn minimize_keeps_interesting() {
+        let grammar = grammar(LISTS);
+
+        // Interesting inputs have a `b` in parentheses
+        let interesting = |x: &[u8]| {
+            let mut depth = 0usize;
+            x.iter().any(|&x| {
+                match x {
+                    b'(' => depth += 1,
+                    b')' => depth -= 1,
+                    _ => {}
+                }
+                x == b'b' && depth > 0
+            })
+        };
+        let input = b"a,(a,(b,a),a),b,(a,a)";
+        let mut checked = 0;
+        let minimized = grammar.minimize(input, |x| {
+            checked += 1;
+            Ok(interesting(x))
+        }).unwrap();
+
+        // Replacements shrink the input while keeping it in the grammar and
+        // interesting
+        assert!(checked > 1);
+        assert!(minimized.len() < input.len());
+        assert!(grammar.parse(&minimized).is_some());
+        assert!(interesting(&minimized));
+    }
+
+    #[test]
+    fn minimize_errors() {
+        let grammar = grammar(LISTS);
+        let error = |input: &[u8], result| grammar.minimize(input,
+            |_| Ok(result)).unwrap_err().to_string();
+        assert_eq!(error(b"a,b", false),
+            "The predicate doesn't hold for the original input");
+        assert_eq!(error(b"a,,b", true),
+            "The grammar doesn't generate the input");
+    }
+}
@@ -0,0 +1,490 @@
+//! Parsing of existing inputs back into derivation trees of a `GrammarRust`.
+//! This is an Earley parser working directly on the fragment graph, so it
+//! handles any grammar, including ambiguous and left-recursive ones. Terminals
+//! are matched as whole byte strings, jumping ahead in the input, and random
+//! scalars as any encoding of a value in their range. Generation
+//! helpers such as `%length` depend on the output they wrap, which can't be
+//! checked while parsing, so they are never recognized. The same goes for
+//! `%pop`, which depends on an earlier `%push`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use serde::Serialize;
+use crate::grammar::{Fragment, FragmentId, GrammarRust};
+
+/// A node of a derivation tree, the expansion of `fragment` producing the
+/// bytes `start..end` of the input
+#[derive(Clone, Debug)]
+pub struct Tree {
+    /// Fragment which was expanded
+    pub fragment: FragmentId,
+
+    /// Offset of the first byte produced by the fragment
+    pub start: usize,
+
+    /// Offset one past the last byte produced by the fragment
+    pub end: usize,
+
+    /// Sub-trees of the expansion in order. For a non-terminal this is the
+    /// option which was picked, for an expression every sub-fragment and
+    /// for a loop every iteration.
+    pub children: Vec<Tree>,
+}
+
+impl Tree {
+    /// Get every node of the tree in pre-order
+    pub fn preorder(&self) -> Vec<&Tree> {
+        let mut nodes = Vec::new();
+        let mut stack = vec![self];
+        while let Some(node) = stack.pop() {
+            nodes.push(node);
+            stack.extend(node.children.iter().rev());
+        }
+        nodes
+    }
+}
+
+/// A derivation tree in terms of the rules of the grammar as written, for
+/// writing out as json. Fragments which don't correspond to a rule are
+/// flattened into their parent rule.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum RuleTree {
+    /// Expansion of a rule producing the bytes `start..end` of the input
+    Rule {
+        rule:     String,
+        start:    usize,
+        end:      usize,
+        children: Vec<RuleTree>,
+    },
+
+    /// Literal bytes produced by a terminal
+    Terminal {
+        terminal: String,
+    },
+}
+
+/// A partially recognized fragment, which started at input offset `origin`.
+/// `dot` is the number of sub-fragments of an expression recognized so far.
+/// For non-terminals and loops a `dot` of zero means nothing has been
+/// recognized and one means the fragment is complete. Loops use a `dot` of
+/// two once a repeating option has been recognized and the rest of the loop
+/// is expected next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Item {
+    fragment: usize,
+    dot:      usize,
+    origin:   usize,
+}
+
+/// State of the Earley parser
+struct Parser<'a> {
+    /// Grammar we're parsing with
+    grammar: &'a GrammarRust,
+
+    /// Input being parsed
+    input: &'a [u8],
+
+    /// Items which have been reached at each input offset
+    sets: Vec<BTreeSet<Item>>,
+
+    /// Items at each input offset which still need processing
+    queues: Vec<Vec<Item>>,
+
+    /// Items at each input offset waiting for a given fragment to be
+    /// recognized starting at that offset
+    waiting: Vec<BTreeMap<usize, Vec<Item>>>,
+
+    /// Fragments which have been predicted at each input offset
+    predicted: Vec<BTreeSet<usize>>,
+
+    /// For each fragment and start offset, the end offsets it was
+    /// recognized up to
+    completed: BTreeMap<(usize, usize), BTreeSet<usize>>,
+
+    /// Fragment and spans on the path from the root to the node currently
+    /// being built, to avoid building cyclic derivations
+    path: BTreeSet<(usize, usize, usize)>,
+}
+
+impl Parser<'_> {
+    /// Add `item` to the set at `pos` if it isn't there yet
+    fn add(&mut self, pos: usize, item: Item) {
+        if self.sets[pos].insert(item) {
+            self.queues[pos].push(item);
+        }
+    }
+
+    /// Returns `true` if `item` has recognized all of its fragment
+    fn is_complete(&self, item: Item) -> bool {
+        match &self.grammar.fragments[item.fragment] {
+            Fragment::Expression(expr) => item.dot == expr.len(),
+            _ => item.dot == 1,
+        }
+    }
+
+    /// Get the fragments `item` is waiting to recognize next
+    fn expected(&self, item: Item) -> Vec<usize> {
+        match &self.grammar.fragments[item.fragment] {
+            Fragment::Expression(expr) => vec![expr[item.dot].0],
+            Fragment::NonTerminal(options) |
+                    Fragment::Loop { options, .. } if item.dot == 0 => {
+                options.iter().map(|x| x.0).collect()
+            }
+            Fragment::Loop { .. } => vec![item.fragment],
+
+            // Variables aren't checked, a recall is parsed like a new
+            // capture of the variable. Pushes are parsed like their body.
+            Fragment::Capture { body, .. } | Fragment::Recall { body, .. } |
+                    Fragment::Embed(body) | Fragment::Push(body)
+                    if item.dot == 0 => {
+                vec![body.0]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Get the items `item` turns into once `child` has been recognized
+    fn advance(&self, item: Item, child: usize) -> Vec<Item> {
+        match &self.grammar.fragments[item.fragment] {
+            Fragment::Loop { options, repeat } if item.dot == 0 => {
+                // The same fragment could both repeat and finish the loop
+                options.iter().zip(repeat).filter(|(x, _)| x.0 == child)
+                    .map(|(_, &repeat)| {
+                        Item { dot: if repeat { 2 } else { 1 }, ..item }
+                    }).collect::<BTreeSet<_>>().into_iter().collect()
+            }
+            Fragment::Loop { .. } => vec![Item { dot: 1, ..item }],
+            _ => vec![Item { dot: item.dot + 1, ..item }],
+        }
+    }
+
+    /// Record that `fragment` was recognized from `start` to `end`, advancing
+    /// everything which was waiting for it
+    fn complete(&mut self, fragment: usize, start: usize, end: usize) {
+        if !self.completed.entry((fragment, start)).or_default().insert(end) {
+            return;
+        }
+
+        let waiters = self.waiting[start].get(&fragment).cloned()
+            .unwrap_or_default();
+        for waiter in waiters {
+            for item in self.advance(waiter, fragment) {
+                self.add(end, item);
+            }
+        }
+    }
+
+    /// Start recognizing `fragment` at `pos`. Terminals, scalars and nops are
+    /// recognized immediately.
+    fn predict(&mut self, fragment: usize, pos: usize) {
+        match &self.grammar.fragments[fragment] {
+            Fragment::Terminal(value) | Fragment::Message(value) => {
+                if self.input[pos..].starts_with(value) {
+                    self.complete(fragment, pos, pos + value.len());
+                }
+            }
+            Fragment::Int(int) => {
+                if let Some(len) = int.recognize(&self.input[pos..]) {
+                    self.complete(fragment, pos, pos + len);
+                }
+            }
+            Fragment::Float(float) => {
+                if let Some(len) = float.recognize(&self.input[pos..]) {
+                    self.complete(fragment, pos, pos + len);
+                }
+            }
+            Fragment::Bytes(tree) => {
+                let byte = self.input.get(pos);
+                if byte.is_some_and(|x| tree.bytes().contains(x)) {
+                    self.complete(fragment, pos, pos + 1);
+                }
+            }
+            Fragment::Nop => self.complete(fragment, pos, pos),
+            _ => self.add(pos, Item { fragment, dot: 0, origin: pos }),
+        }
+    }
+
+    /// Make `item` at `pos` wait for `child` to be recognized at `pos`
+    fn wait(&mut self, pos: usize, item: Item, child: usize) {
+        self.waiting[pos].entry(child).or_default().push(item);
+
+        if self.predicted[pos].insert(child) {
+            self.predict(child, pos);
+        } else {
+            // The child may have been recognized already, eg. if it's a
+            // terminal or matches nothing at all
+            let ends = self.completed.get(&(child, pos)).cloned()
+                .unwrap_or_default();
+            for end in ends {
+                for next in self.advance(item, child) {
+                    self.add(end, next);
+                }
+            }
+        }
+    }
+
+    /// Returns `true` if `fragment` was recognized from `start` to `end`
+    fn recognized(&self, fragment: usize, start: usize, end: usize) -> bool {
+        self.completed.get(&(fragment, start))
+            .is_some_and(|x| x.contains(&end))
+    }
+
+    /// Build a derivation tree for `fragment` from `start` to `end`, which
+    /// must have been recognized
+    fn build(&mut self, fragment: usize, start: usize, end: usize)
+            -> Option<Tree> {
+        if !self.recognized(fragment, start, end) ||
+                !self.path.insert((fragment, start, end)) {
+            return None;
+        }
+
+        let children = self.build_children(fragment, start, end);
+        self.path.remove(&(fragment, start, end));
+
+        Some(Tree {
+            fragment: FragmentId(fragment),
+            start,
+            end,
+            children: children?,
+        })
+    }
+
+    /// Build the sub-trees of `fragment` from `start` to `end`
+    fn build_children(&mut self, fragment: usize, start: usize, end: usize)
+            -> Option<Vec<Tree>> {
+        match self.grammar.fragments[fragment].clone() {
+            Fragment::NonTerminal(options) => {
+                options.iter().find_map(|x| self.build(x.0, start, end))
+                    .map(|x| vec![x])
+            }
+            Fragment::Expression(expr) => {
+                // Find the children from the last one backwards. An item for
+                // the expression with `dot` children recognized at `pos`
+                // means the first `dot` children can end at `pos`.
+                let mut children = Vec::new();
+                let mut end = end;
+                for dot in (0..expr.len()).rev() {
+                    let item = Item { fragment, dot, origin: start };
+                    let child = (start..=end).rev().find_map(|pos| {
+                        if !self.sets[pos].contains(&item) {
+                            return None;
+                        }
+                        self.build(expr[dot].0, pos, end)
+                    })?;
+                    end = child.start;
+                    children.push(child);
+                }
+                children.reverse();
+                Some(children)
+            }
+            Fragment::Loop { options, repeat } => {
+                // Either a finishing option covers the rest, or a repeating
+                // option is followed by the rest of the loop
+                let finish = options.iter().zip(&repeat)
+                    .filter(|(_, &repeat)| !repeat)
+                    .find_map(|(x, _)| self.build(x.0, start, end));
+                if let Some(finish) = finish {
+                    return Some(vec![finish]);
+                }
+
+                for (option, _) in options.iter().zip(&repeat)
+                        .filter(|(_, &repeat)| repeat) {
+                    for pos in start..=end {
+                        if !self.recognized(fragment, pos, end) {
+                            continue;
+                        }
+                        let Some(first) = self.build(option.0, start, pos)
+                            else { continue };
+                        let Some(rest) = self.build(fragment, pos, end)
+                            else { continue };
+
+                        let mut children = vec![first];
+                        children.extend(rest.children);
+                        return Some(children);
+                    }
+                }
+                None
+            }
+            Fragment::Capture { body, .. } | Fragment::Recall { body, .. } |
+                    Fragment::Embed(body) | Fragment::Push(body) => {
+                self.build(body.0, start, end).map(|x| vec![x])
+            }
+            Fragment::Terminal(_) | Fragment::Message(_) |
+                Fragment::Int(_) | Fragment::Float(_) | Fragment::Bytes(_) |
+                Fragment::Nop => Some(Vec::new()),
+
+            // Helpers are never recognized, and neither are pops as the
+            // context stack isn't tracked
+            Fragment::Length(..) | Fragment::Chunked(_) |
+                Fragment::Transform(..) | Fragment::Pop => None,
+        }
+    }
+}
+
+impl GrammarRust {
+    /// Parse `input` into a derivation tree from the start fragment. Returns
+    /// `None` if the grammar can't generate the input. If there are multiple
+    /// derivations an arbitrary one is picked.
+    pub fn parse(&self, input: &[u8]) -> Option<Tree> {
+        let len = input.len();
+        let mut parser = Parser {
+            grammar: self,
+            input,
+            sets:      vec![BTreeSet::new(); len + 1],
+            queues:    vec![Vec::new(); len + 1],
+            waiting:   vec![BTreeMap::new(); len + 1],
+            predicted: vec![BTreeSet::new(); len + 1],
+            completed: BTreeMap::new(),
+            path:      BTreeSet::new(),
+        };
+
+        let start = self.start.unwrap().0;
+        parser.predicted[0].insert(start);
+        parser.predict(start, 0);
+
+        for pos in 0..=len {
+            while let Some(item) = parser.queues[pos].pop() {
+                if parser.is_complete(item) {
+                    parser.complete(item.fragment, item.origin, pos);
+                } else {
+                    for child in parser.expected(item) {
+                        parser.wait(pos, item, child);
+                    }
+                }
+            }
+        }
+
+        parser.build(start, 0, len)
+    }
+
+    /// Convert a derivation tree into one in terms of the rules of the
+    /// grammar
+    pub fn rule_tree(&self, tree: &Tree) -> RuleTree {
+        let names: BTreeMap<usize, &String> = self.name_to_fragment.iter()
+            .map(|(name, id)| (id.0, name)).collect();
+
+        /// Add the rule trees for `node` to `out`
+        fn convert(gram: &GrammarRust, names: &BTreeMap<usize, &String>,
+                node: &Tree, out: &mut Vec<RuleTree>) {
+            if let Some(name) = names.get(&node.fragment.0) {
+                let mut children = Vec::new();
+                for child in &node.children {
+                    convert(gram, names, child, &mut children);
+                }
+                out.push(RuleTree::Rule {
+                    rule: name.to_string(),
+                    start: node.start,
+                    end: node.end,
+                    children,
+                });
+            } else if let Some(name) = match &gram.fragments[node.fragment.0] {
+                Fragment::Int(int) => Some(int.name()),
+                Fragment::Float(float) => Some(float.name()),
+                _ => None,
+            } {
+                // Scalars show up like the rules they stand for
+                out.push(RuleTree::Rule {
+                    rule: name,
+                    start: node.start,
+                    end: node.end,
+                    children: Vec::new(),
+                });
+            } else if let Fragment::Terminal(value) =
+                    &gram.fragments[node.fragment.0] {
+                out.push(RuleTree::Terminal {
+                    terminal: String::from_utf8_lossy(value).into_owned(),
+                });
+            } else {
+                for child in &node.children {
+                    convert(gram, names, child, out);
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        convert(self, &names, tree, &mut out);
+        out.pop().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::tests::{generate, grammar, options};
+    use super::*;
+
+    /// Lists of words, numbers and nested lists, with binary scalars
+    const LISTS: &str = r#"{
+        "<start>": [["<list>"], ["<record>"]],
+        "<list>": [["<item>"], ["<item>", ",", "<list>"]],
+        "<item>": [["<word>"], ["%range(0, 1000)"], ["[", "<list>", "]"]],
+        "<word>": [["a"], ["ab"], ["abc"]],
+        "<record>": [["R", "<u16_be>", "<u8:1..=5>"]]
+    }"#;
+
+    /// Check `tree` covers `input`: every node is split exactly between its
+    /// children and terminals match the bytes they cover
+    fn check(grammar: &GrammarRust, tree: &Tree, input: &[u8]) {
+        assert_eq!((tree.start, tree.end), (0, input.len()));
+        for node in tree.preorder() {
+            if let Some(first) = node.children.first() {
+                assert_eq!(first.start, node.start);
+                assert_eq!(node.children.last().unwrap().end, node.end);
+                for pair in node.children.windows(2) {
+                    assert_eq!(pair[0].end, pair[1].start);
+                }
+            }
+            if let Fragment::Terminal(value) =
+                    &grammar.fragments[node.fragment.0] {
+                assert_eq!(&input[node.start..node.end], value.as_slice());
+            }
+        }
+    }
+
+    #[test]
+    fn parse_generated_inputs() {
+        // Deep enough that no list is cut short by the depth limit
+        let grammar = grammar(LISTS);
+        let inputs = generate(&grammar, &options(256, 1024), 200);
+        assert!(inputs.iter().any(|x| x.starts_with(b"R")));
+        assert!(inputs.iter().any(|x| x.contains(&b'[')));
+        for input in inputs {
+            let tree = grammar.parse(&input).unwrap_or_else(||
+                panic!("{:?} didn't parse", String::from_utf8_lossy(&input)));
+            check(&grammar, &tree, &input);
+        }
+    }
+
+    #[test]
+    fn parse_generated_inputs_after_passes() {
+        // Loops and merged fragments parse just like the rules they replace
+        let mut grammar = grammar(LISTS);
+        grammar.optimize();
+        grammar.dedup();
+        grammar.factor_prefixes();
+        assert!(grammar.convert_loops() > 0);
+        for input in generate(&grammar, &options(256, 1024), 200) {
+            let tree = grammar.parse(&input).unwrap();
+            check(&grammar, &tree, &input);
+        }
+    }
+
+    #[test]
+    fn parse_rule_tree() {
+        let grammar = grammar(LISTS);
+        let tree = grammar.parse(b"ab,[7]").unwrap();
+        check(&grammar, &tree, b"ab,[7]");
+        let json = serde_json::to_value(grammar.rule_tree(&tree)).unwrap();
+        assert_eq!(json["rule"], "<start>");
+        assert_eq!(json["end"], 6);
+        assert_eq!(json.to_string().matches("<word>").count(), 1);
+    }
+
+    #[test]
+    fn parse_rejects_other_inputs() {
+        let grammar = grammar(LISTS);
+        for input in [&b""[..], b"abcd", b"a,", b"[a", b"1001", b"R\0\0\0",
+                b"R\0\0\x06"] {
+            assert!(grammar.parse(input).is_none(), "{:?} parsed", input);
+        }
+    }
+}
@@ -0,0 +1,1161 @@
+//! Conversion of protobuf schemas into grammars which generate messages in
+//! the protobuf wire format. Schemas are read either from `.proto` source
+//! files or from a `FileDescriptorSet`, as written by `protoc -o`. Every
+//! field of a message is optional in the grammar, apart from proto2
+//! `required` fields, and repeated fields are emitted any number of times.
+//! Numeric repeated fields are also emitted packed, which decoders must
+//! accept either way.
+//!
+//! The `.proto` parser handles the parts of the language which affect the
+//! wire format: messages, enums, fields, `oneof` and `map`. Options,
+//! services, extensions and reservations are skipped, and groups aren't
+//! supported.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use crate::grammar::{bytes_token, Grammar, LengthEncoding};
+
+/// Wire type of varints
+const WIRE_VARINT: u64 = 0;
+
+/// Wire type of 64-bit values
+const WIRE_FIXED64: u64 = 1;
+
+/// Wire type of length delimited values
+const WIRE_LENGTH: u64 = 2;
+
+/// Wire type of 32-bit values
+const WIRE_FIXED32: u64 = 5;
+
+/// Create an error for a malformed schema
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Type of a field
+#[derive(Clone, Debug)]
+enum Type {
+    /// Integer types, which are all varints on the wire
+    Varint,
+
+    /// `bool`, a varint which is either 0 or 1
+    Bool,
+
+    /// `fixed32`, `sfixed32` and `float`
+    Fixed32,
+
+    /// `fixed64`, `sfixed64` and `double`
+    Fixed64,
+
+    /// `string`, which must be valid UTF-8
+    String,
+
+    /// `bytes`
+    Bytes,
+
+    /// A message or enum type, by the name it's referred to with. This is
+    /// resolved relative to the message the field is in, unless it starts
+    /// with a `.`.
+    Named(String),
+}
+
+impl Type {
+    /// Get the type of a scalar type name, if it is one
+    fn scalar(name: &str) -> Option<Self> {
+        Some(match name {
+            "int32" | "int64" | "uint32" | "uint64" | "sint32" |
+                "sint64" => Type::Varint,
+            "bool" => Type::Bool,
+            "fixed32" | "sfixed32" | "float" => Type::Fixed32,
+            "fixed64" | "sfixed64" | "double" => Type::Fixed64,
+            "string" => Type::String,
+            "bytes" => Type::Bytes,
+            _ => return None,
+        })
+    }
+}
+
+/// How many times a field may occur in a message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Label {
+    /// At most once
+    Optional,
+
+    /// Exactly once, proto2 only
+    Required,
+
+    /// Any number of times
+    Repeated,
+}
+
+/// A field of a message
+#[derive(Clone, Debug)]
+struct Field {
+    name:   String,
+    number: u64,
+    label:  Label,
+    ty:     Type,
+}
+
+/// Part of a message, in the order they're declared
+#[derive(Clone, Debug)]
+enum Member {
+    /// A plain field
+    Field(Field),
+
+    /// A `oneof`, of which at most one field is present
+    Oneof(String, Vec<Field>),
+}
+
+/// Everything the grammar is generated from, messages and enums are keyed
+/// by their fully qualified names without a leading `.`, eg. `pkg.Outer.Inner`
+#[derive(Default, Debug)]
+pub struct Schema {
+    /// Members of every message
+    messages: BTreeMap<String, Vec<Member>>,
+
+    /// Values of every enum
+    enums: BTreeMap<String, Vec<i64>>,
+
+    /// First top-level message, the default message to generate
+    first: Option<String>,
+}
+
+/// Join a scope and a name into a fully qualified name
+fn qualify(scope: &str, name: &str) -> String {
+    if scope.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", scope, name)
+    }
+}
+
+/// Add `field` to `members`, inside the oneof `oneof` if it's set, which is
+/// created where its first field is declared
+fn add_field(members: &mut Vec<Member>, field: Field, oneof: Option<&str>) {
+    let Some(oneof) = oneof else {
+        members.push(Member::Field(field));
+        return;
+    };
+
+    for member in members.iter_mut() {
+        if let Member::Oneof(name, fields) = member {
+            if name == oneof {
+                fields.push(field);
+                return;
+            }
+        }
+    }
+    members.push(Member::Oneof(oneof.to_string(), vec![field]));
+}
+
+/// Split `.proto` source into tokens, along with the line each is on
+fn tokenize(source: &str) -> std::io::Result<Vec<(String, usize)>> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut chars = source.chars().peekable();
+    while let Some(chr) = chars.next() {
+        match chr {
+            '\n' => line += 1,
+            _ if chr.is_whitespace() => {}
+            '/' if chars.peek() == Some(&'/') => {
+                // Line comment
+                for chr in chars.by_ref() {
+                    if chr == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                // Block comment
+                chars.next();
+                let mut prev = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if prev == '*' => break,
+                        Some(chr) => {
+                            line += (chr == '\n') as usize;
+                            prev = chr;
+                        }
+                        None => {
+                            return Err(invalid(format!(
+                                "Unterminated comment on line {}", line)));
+                        }
+                    }
+                }
+            }
+            '"' | '\'' => {
+                // Strings are kept along with their quotes, escapes don't
+                // matter as strings are only ever skipped or used in imports
+                let mut token = String::from(chr);
+                loop {
+                    match chars.next() {
+                        Some('\\') => {
+                            token.push('\\');
+                            token.extend(chars.next());
+                        }
+                        Some(end) if end == chr => break,
+                        Some('\n') | None => {
+                            return Err(invalid(format!(
+                                "Unterminated string on line {}", line)));
+                        }
+                        Some(other) => token.push(other),
+                    }
+                }
+                token.push(chr);
+                tokens.push((token, line));
+            }
+            _ if chr.is_alphanumeric() || chr == '_' || chr == '.' => {
+                // Identifiers, possibly qualified, and numbers
+                let mut token = String::from(chr);
+                while let Some(&chr) = chars.peek() {
+                    if !chr.is_alphanumeric() && chr != '_' && chr != '.' {
+                        break;
+                    }
+                    token.push(chr);
+                    chars.next();
+                }
+                tokens.push((token, line));
+            }
+            _ => tokens.push((chr.to_string(), line)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parse an unsigned integer literal, in decimal, hex or octal
+fn parse_int(token: &str) -> Option<u64> {
+    if let Some(hex) = token.strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()
+    } else if token.len() > 1 && token.starts_with('0') {
+        u64::from_str_radix(&token[1..], 8).ok()
+    } else {
+        token.parse().ok()
+    }
+}
+
+/// Parser for `.proto` source files
+struct Parser {
+    tokens: Vec<(String, usize)>,
+    pos: usize,
+
+    /// Path of the file, for errors
+    path: PathBuf,
+
+    /// Package declared by the file
+    package: String,
+
+    /// First top-level message of the file
+    first: Option<String>,
+}
+
+impl Parser {
+    /// Peek at the next token
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|x| x.0.as_str())
+    }
+
+    /// Create an error at the current token
+    fn error(&self, message: &str) -> std::io::Error {
+        match self.tokens.get(self.pos) {
+            Some((token, line)) => invalid(format!("{}:{}: {}, found `{}`",
+                self.path.display(), line, message, token)),
+            None => invalid(format!("{}: {}, found the end of the file",
+                self.path.display(), message)),
+        }
+    }
+
+    /// Take the next token
+    fn next(&mut self) -> std::io::Result<String> {
+        let token = self.peek().ok_or_else(|| {
+            self.error("Unexpected end of file")
+        })?.to_string();
+        self.pos += 1;
+        Ok(token)
+    }
+
+    /// Take the next token, which must be `expected`
+    fn expect(&mut self, expected: &str) -> std::io::Result<()> {
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("Expected `{}`", expected)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Take the next token if it's `token`
+    fn eat(&mut self, token: &str) -> bool {
+        let found = self.peek() == Some(token);
+        self.pos += found as usize;
+        found
+    }
+
+    /// Take an integer, which may be negative
+    fn int(&mut self) -> std::io::Result<i64> {
+        let negative = self.eat("-");
+        let token = self.peek().unwrap_or("");
+        let value = parse_int(token)
+            .ok_or_else(|| self.error("Expected an integer"))? as i64;
+        self.pos += 1;
+        Ok(if negative { value.wrapping_neg() } else { value })
+    }
+
+    /// Skip a statement we don't care about, up to its `;` or through its
+    /// body in braces
+    fn skip_statement(&mut self) -> std::io::Result<()> {
+        let mut nesting = 0usize;
+        loop {
+            match self.next()?.as_str() {
+                ";" if nesting == 0 => return Ok(()),
+                "{" => nesting += 1,
+                "}" => {
+                    nesting = nesting.checked_sub(1)
+                        .ok_or_else(|| self.error("Unbalanced `}`"))?;
+                    if nesting == 0 {
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Skip the options of a field, in square brackets, if there are any
+    fn skip_options(&mut self) -> std::io::Result<()> {
+        if self.eat("[") {
+            while self.next()? != "]" {}
+        }
+        Ok(())
+    }
+
+    /// Parse a whole file into `schema`, returning the imports it uses
+    fn file(&mut self, schema: &mut Schema) -> std::io::Result<Vec<String>> {
+        let mut imports = Vec::new();
+        while let Some(token) = self.peek() {
+            match token {
+                "package" => {
+                    self.pos += 1;
+                    self.package = self.next()?;
+                    self.expect(";")?;
+                }
+                "import" => {
+                    self.pos += 1;
+                    if self.peek() == Some("public") ||
+                            self.peek() == Some("weak") {
+                        self.pos += 1;
+                    }
+                    let quoted = self.peek()
+                        .is_some_and(|x| x.starts_with(['"', '\'']));
+                    if !quoted {
+                        return Err(self.error("Expected a path"));
+                    }
+                    let path = self.next()?;
+                    imports.push(path[1..path.len() - 1].to_string());
+                    self.expect(";")?;
+                }
+                "message" => {
+                    self.pos += 1;
+                    let package = self.package.clone();
+                    let name = self.message(schema, &package)?;
+                    self.first.get_or_insert(name);
+                }
+                "enum" => {
+                    self.pos += 1;
+                    let package = self.package.clone();
+                    self.enumeration(schema, &package)?;
+                }
+                ";" => self.pos += 1,
+                _ => self.skip_statement()?,
+            }
+        }
+        Ok(imports)
+    }
+
+    /// Parse a message in `scope`, after the `message` keyword, returning
+    /// its fully qualified name
+    fn message(&mut self, schema: &mut Schema, scope: &str)
+            -> std::io::Result<String> {
+        let name = qualify(scope, &self.next()?);
+        self.expect("{")?;
+
+        let mut members = Vec::new();
+        let mut oneof: Option<String> = None;
+        loop {
+            match self.peek() {
+                Some("}") => {
+                    self.pos += 1;
+
+                    // The end of a oneof, or of the message
+                    if oneof.take().is_none() {
+                        break;
+                    }
+                }
+                Some("message") if oneof.is_none() => {
+                    self.pos += 1;
+                    self.message(schema, &name)?;
+                }
+                Some("enum") if oneof.is_none() => {
+                    self.pos += 1;
+                    self.enumeration(schema, &name)?;
+                }
+                Some("oneof") if oneof.is_none() => {
+                    self.pos += 1;
+                    oneof = Some(self.next()?);
+                    self.expect("{")?;
+                }
+                Some("option" | "reserved" | "extensions" | "extend") => {
+                    self.skip_statement()?;
+                }
+                Some(";") => self.pos += 1,
+                Some(_) => {
+                    let field = self.field(schema, &name)?;
+                    add_field(&mut members, field, oneof.as_deref());
+                }
+                None => return Err(self.error("Expected `}`")),
+            }
+        }
+
+        if schema.messages.insert(name.clone(), members).is_some() {
+            return Err(invalid(format!("{}: message {} is defined more \
+                than once", self.path.display(), name)));
+        }
+        Ok(name)
+    }
+
+    /// Parse a field of the message `scope`. Maps are turned into a
+    /// repeated field of an entry message, the same as protoc does.
+    fn field(&mut self, schema: &mut Schema, scope: &str)
+            -> std::io::Result<Field> {
+        // Fields without a label are optional, as in proto3
+        let label = match self.peek() {
+            Some("optional") => Some(Label::Optional),
+            Some("required") => Some(Label::Required),
+            Some("repeated") => Some(Label::Repeated),
+            _ => None,
+        };
+        self.pos += label.is_some() as usize;
+        let mut label = label.unwrap_or(Label::Optional);
+
+        let mut ty = self.next()?;
+        let map = if ty == "map" {
+            self.expect("<")?;
+            let key = self.next()?;
+            self.expect(",")?;
+            let value = self.next()?;
+            self.expect(">")?;
+            Some((key, value))
+        } else {
+            if ty == "group" {
+                return Err(self.error("Groups are not supported"));
+            }
+            None
+        };
+
+        let name = self.next()?;
+        self.expect("=")?;
+        let number = self.int()?;
+        if !(1..1 << 29).contains(&number) {
+            self.pos -= 1;
+            return Err(self.error("Invalid field number"));
+        }
+        self.skip_options()?;
+        self.expect(";")?;
+
+        if let Some((key, value)) = map {
+            // Entries are named after the field in camel case
+            let mut entry = String::new();
+            let mut upper = true;
+            for chr in name.chars() {
+                if chr == '_' {
+                    upper = true;
+                } else if upper {
+                    entry.extend(chr.to_uppercase());
+                    upper = false;
+                } else {
+                    entry.push(chr);
+                }
+            }
+            entry += "Entry";
+
+            let field = |name: &str, number, ty: &str| Field {
+                name: name.to_string(),
+                number,
+                label: Label::Optional,
+                ty: Type::scalar(ty)
+                    .unwrap_or_else(|| Type::Named(ty.to_string())),
+            };
+            schema.messages.insert(qualify(scope, &entry), vec![
+                Member::Field(field("key", 1, &key)),
+                Member::Field(field("value", 2, &value)),
+            ]);
+            ty = entry;
+            label = Label::Repeated;
+        }
+
+        Ok(Field {
+            name,
+            number: number as u64,
+            label,
+            ty: Type::scalar(&ty).unwrap_or(Type::Named(ty)),
+        })
+    }
+
+    /// Parse an enum in `scope`, after the `enum` keyword
+    fn enumeration(&mut self, schema: &mut Schema, scope: &str)
+            -> std::io::Result<()> {
+        let name = qualify(scope, &self.next()?);
+        self.expect("{")?;
+
+        let mut values = Vec::new();
+        while !self.eat("}") {
+            match self.peek() {
+                Some("option" | "reserved") => self.skip_statement()?,
+                Some(";") => self.pos += 1,
+                _ => {
+                    self.next()?;
+                    self.expect("=")?;
+                    values.push(self.int()?);
+                    self.skip_options()?;
+                    self.expect(";")?;
+                }
+            }
+        }
+
+        if values.is_empty() {
+            return Err(invalid(format!("{}: enum {} has no values",
+                self.path.display(), name)));
+        }
+        schema.enums.insert(name, values);
+        Ok(())
+    }
+}
+
+/// A field of an encoded protobuf message
+enum Value<'a> {
+    /// A varint
+    Varint(u64),
+
+    /// The contents of a length delimited field
+    Bytes(&'a [u8]),
+
+    /// A fixed size value, which descriptors don't use
+    Fixed,
+}
+
+/// Decode a varint from the start of `data`
+fn read_varint(data: &mut &[u8]) -> std::io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first().ok_or_else(|| {
+            invalid("Truncated varint in descriptor set".into())
+        })?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("Overlong varint in descriptor set".into()))
+}
+
+/// Decode the fields of an encoded protobuf message
+fn decode(mut data: &[u8]) -> std::io::Result<Vec<(u64, Value<'_>)>> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let tag = read_varint(&mut data)?;
+        let value = match tag & 7 {
+            WIRE_VARINT => Value::Varint(read_varint(&mut data)?),
+            WIRE_LENGTH => {
+                let len = read_varint(&mut data)?;
+                if len > data.len() as u64 {
+                    return Err(invalid(
+                        "Truncated field in descriptor set".into()));
+                }
+                let (value, rest) = data.split_at(len as usize);
+                data = rest;
+                Value::Bytes(value)
+            }
+            wire @ (WIRE_FIXED32 | WIRE_FIXED64) => {
+                let len = if wire == WIRE_FIXED32 { 4 } else { 8 };
+                data = data.get(len..).ok_or_else(|| {
+                    invalid("Truncated field in descriptor set".into())
+                })?;
+                Value::Fixed
+            }
+            wire => {
+                return Err(invalid(format!(
+                    "Unsupported wire type {} in descriptor set", wire)));
+            }
+        };
+        fields.push((tag >> 3, value));
+    }
+    Ok(fields)
+}
+
+/// Decode a string field of a descriptor
+fn string(value: &[u8]) -> std::io::Result<String> {
+    String::from_utf8(value.to_vec()).map_err(|_| {
+        invalid("Invalid UTF-8 in descriptor set".into())
+    })
+}
+
+impl Schema {
+    /// Load a `.proto` file, along with every file it imports. Imports are
+    /// found relative to the directory of the file, and then in
+    /// `import_paths`.
+    pub fn from_proto(path: &Path, import_paths: &[PathBuf])
+            -> std::io::Result<Self> {
+        let mut schema = Schema::default();
+        let mut loaded = BTreeSet::new();
+        let mut queue = vec![path.to_path_buf()];
+        let mut dirs = vec![path.parent().unwrap_or(Path::new("")).into()];
+        dirs.extend_from_slice(import_paths);
+
+        while let Some(path) = queue.pop() {
+            if !loaded.insert(path.clone()) {
+                continue;
+            }
+
+            let source = std::fs::read_to_string(&path).map_err(|err| {
+                std::io::Error::new(err.kind(),
+                    format!("{}: {}", path.display(), err))
+            })?;
+            let mut parser = Parser {
+                tokens: tokenize(&source)?,
+                pos: 0,
+                path: path.clone(),
+                package: String::new(),
+                first: None,
+            };
+            let imports = parser.file(&mut schema)?;
+
+            // Messages defined by imports can't be the default
+            if loaded.len() == 1 {
+                schema.first = parser.first;
+            }
+
+            for import in imports {
+                // The well-known types ship with protoc rather than being
+                // next to the schema, anything using them is reported as
+                // undefined when the grammar is generated
+                let Some(found) = dirs.iter().map(|x| x.join(&import))
+                        .find(|x| x.is_file()) else {
+                    eprintln!("warning: {} imports {}, which wasn't found",
+                        path.display(), import);
+                    continue;
+                };
+                queue.push(found);
+            }
+        }
+
+        Ok(schema)
+    }
+
+    /// Load a `FileDescriptorSet`. Files come after the files they
+    /// import, so the default message is the first one of the last file.
+    pub fn from_descriptor_set(data: &[u8]) -> std::io::Result<Self> {
+        let mut schema = Schema::default();
+        for (number, value) in decode(data)? {
+            if let (1, Value::Bytes(file)) = (number, value) {
+                // A `FileDescriptorProto`
+                let fields = decode(file)?;
+                let mut first = None;
+                let mut package = String::new();
+                for (number, value) in fields.iter() {
+                    if let (2, Value::Bytes(value)) = (number, value) {
+                        package = string(value)?;
+                    }
+                }
+                for (number, value) in fields {
+                    match (number, value) {
+                        (4, Value::Bytes(message)) => {
+                            let name =
+                                schema.descriptor(message, &package)?;
+                            first.get_or_insert(name);
+                        }
+                        (5, Value::Bytes(value)) => {
+                            schema.enum_descriptor(value, &package)?;
+                        }
+                        _ => {}
+                    }
+                }
+                schema.first = first.or(schema.first);
+            }
+        }
+        Ok(schema)
+    }
+
+    /// Load a `DescriptorProto` in `scope`, returning the fully qualified
+    /// name of the message
+    fn descriptor(&mut self, data: &[u8], scope: &str)
+            -> std::io::Result<String> {
+        let fields = decode(data)?;
+        let mut name = String::new();
+        let mut oneofs = Vec::new();
+        for (number, value) in fields.iter() {
+            match (number, value) {
+                (1, Value::Bytes(value)) => name = string(value)?,
+                (8, Value::Bytes(value)) => {
+                    // A `OneofDescriptorProto`, which is just a name
+                    let mut name = String::new();
+                    for (number, value) in decode(value)? {
+                        if let (1, Value::Bytes(value)) = (number, value) {
+                            name = string(value)?;
+                        }
+                    }
+                    oneofs.push(name);
+                }
+                _ => {}
+            }
+        }
+        let name = qualify(scope, &name);
+
+        let mut members = Vec::new();
+        for (number, value) in fields {
+            match (number, value) {
+                (2, Value::Bytes(value)) => {
+                    let (field, oneof) = self.field_descriptor(value)?;
+                    let oneof = oneof.map(|x| {
+                        oneofs.get(x as usize).map(|x| x.as_str())
+                            .ok_or_else(|| invalid(format!(
+                                "Field {} of {} is in an undefined oneof",
+                                field.name, name)))
+                    }).transpose()?;
+                    add_field(&mut members, field, oneof);
+                }
+                (3, Value::Bytes(value)) => {
+                    self.descriptor(value, &name)?;
+                }
+                (4, Value::Bytes(value)) => {
+                    self.enum_descriptor(value, &name)?;
+                }
+                _ => {}
+            }
+        }
+
+        self.messages.insert(name.clone(), members);
+        Ok(name)
+    }
+
+    /// Decode a `FieldDescriptorProto`, along with the index of the oneof
+    /// it's in if it's in one
+    fn field_descriptor(&self, data: &[u8])
+            -> std::io::Result<(Field, Option<u64>)> {
+        let mut field = Field {
+            name:   String::new(),
+            number: 0,
+            label:  Label::Optional,
+            ty:     Type::Varint,
+        };
+        let mut ty = 0;
+        let mut type_name = String::new();
+        let mut oneof = None;
+        for (number, value) in decode(data)? {
+            match (number, value) {
+                (1, Value::Bytes(value)) => field.name = string(value)?,
+                (3, Value::Varint(value)) => field.number = value,
+                (4, Value::Varint(2)) => field.label = Label::Required,
+                (4, Value::Varint(3)) => field.label = Label::Repeated,
+                (5, Value::Varint(value)) => ty = value,
+                (6, Value::Bytes(value)) => type_name = string(value)?,
+                (9, Value::Varint(value)) => oneof = Some(value),
+                _ => {}
+            }
+        }
+
+        field.ty = match ty {
+            1 | 6 | 16 => Type::Fixed64,
+            2 | 7 | 15 => Type::Fixed32,
+            3 | 4 | 5 | 13 | 17 | 18 => Type::Varint,
+            8 => Type::Bool,
+            9 => Type::String,
+            12 => Type::Bytes,
+            11 | 14 => Type::Named(type_name),
+            _ => {
+                return Err(invalid(format!(
+                    "Field {} has unsupported type {}, groups are not \
+                     supported", field.name, ty)));
+            }
+        };
+        Ok((field, oneof))
+    }
+
+    /// Load an `EnumDescriptorProto` in `scope`
+    fn enum_descriptor(&mut self, data: &[u8], scope: &str)
+            -> std::io::Result<()> {
+        let mut name = String::new();
+        let mut values = Vec::new();
+        for (number, value) in decode(data)? {
+            match (number, value) {
+                (1, Value::Bytes(value)) => name = string(value)?,
+                (2, Value::Bytes(value)) => {
+                    // An `EnumValueDescriptorProto`, values are int32s
+                    // which are sign extended
+                    for (number, value) in decode(value)? {
+                        if let (2, Value::Varint(value)) = (number, value) {
+                            values.push(value as i32 as i64);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let name = qualify(scope, &name);
+        if values.is_empty() {
+            return Err(invalid(format!("Enum {} has no values", name)));
+        }
+        self.enums.insert(name, values);
+        Ok(())
+    }
+
+    /// Resolve the type `name` used in the message `scope` to a fully
+    /// qualified name. Names are looked up in the scope and then in each
+    /// enclosing scope, the same as protoc does.
+    fn resolve(&self, name: &str, scope: &str) -> std::io::Result<String> {
+        if let Some(name) = name.strip_prefix('.') {
+            if self.messages.contains_key(name) ||
+                    self.enums.contains_key(name) {
+                return Ok(name.to_string());
+            }
+        } else {
+            let mut scope = scope;
+            loop {
+                let full = qualify(scope, name);
+                if self.messages.contains_key(&full) ||
+                        self.enums.contains_key(&full) {
+                    return Ok(full);
+                }
+                if scope.is_empty() {
+                    break;
+                }
+                scope = scope.rfind('.').map_or("", |x| &scope[..x]);
+            }
+        }
+
+        Err(invalid(format!("{} uses undefined type {}", scope, name)))
+    }
+
+    /// Generate a grammar for the message `name`, or the first message of
+    /// the schema if it's `None`. `name` is either the fully qualified name
+    /// of the message, or just its name if that's unambiguous.
+    pub fn grammar(&self, name: Option<&str>) -> std::io::Result<Grammar> {
+        let root = match name {
+            Some(name) if self.messages.contains_key(name) => name.into(),
+            Some(name) => {
+                let found: Vec<&String> = self.messages.keys()
+                    .filter(|x| x.rsplit('.').next() == Some(name))
+                    .collect();
+                match found.as_slice() {
+                    [found] => found.to_string(),
+                    [] => {
+                        return Err(std::io::Error::other(format!(
+                            "Message {} is not defined", name)));
+                    }
+                    _ => {
+                        return Err(std::io::Error::other(format!(
+                            "Message {} is ambiguous, it could be any of \
+                             {}", name, found.iter().map(|x| x.as_str())
+                                .collect::<Vec<_>>().join(", "))));
+                    }
+                }
+            }
+            None => self.first.clone().ok_or_else(|| {
+                std::io::Error::other("The schema defines no messages")
+            })?,
+        };
+
+        let mut grammar = Grammar::default();
+        grammar.0.insert("<start>".into(),
+            vec![vec![format!("<message.{}>", root)]]);
+        builtins(&mut grammar);
+
+        // Emit every message which is reachable from the root
+        let mut queue = vec![root];
+        let mut done = BTreeSet::new();
+        while let Some(message) = queue.pop() {
+            if !done.insert(message.clone()) {
+                continue;
+            }
+
+            let mut body = Vec::new();
+            for member in &self.messages[&message] {
+                match member {
+                    Member::Field(field) => {
+                        let rule = format!("<field.{}.{}>", message,
+                            field.name);
+                        let alts = self.encode(&mut grammar, &mut queue,
+                            &message, field, &rule)?;
+                        let alts = match field.label {
+                            Label::Required => alts,
+                            Label::Optional => {
+                                std::iter::once(Vec::new()).chain(alts)
+                                    .collect()
+                            }
+                            Label::Repeated => {
+                                let one = format!("<field.{}.{}:one>",
+                                    message, field.name);
+                                grammar.0.insert(one.clone(), alts);
+                                vec![Vec::new(), vec![one, rule.clone()]]
+                            }
+                        };
+                        grammar.0.insert(rule.clone(), alts);
+                        body.push(rule);
+                    }
+                    Member::Oneof(name, fields) => {
+                        // At most one of the fields
+                        let rule = format!("<oneof.{}.{}>", message, name);
+                        let mut alts = vec![Vec::new()];
+                        for field in fields {
+                            let field_rule = format!("<field.{}.{}>",
+                                message, field.name);
+                            alts.extend(self.encode(&mut grammar,
+                                &mut queue, &message, field, &field_rule)?);
+                        }
+                        grammar.0.insert(rule.clone(), alts);
+                        body.push(rule);
+                    }
+                }
+            }
+            grammar.0.insert(format!("<message.{}>", message), vec![body]);
+        }
+
+        Ok(grammar)
+    }
+
+    /// Get the alternatives for a single occurrence of `field` of the
+    /// message `scope`, whose rule is `rule`. Messages the field uses are
+    /// added to `queue`, and any other rules it needs to `grammar`.
+    fn encode(&self, grammar: &mut Grammar, queue: &mut Vec<String>,
+            scope: &str, field: &Field, rule: &str)
+            -> std::io::Result<Vec<Vec<String>>> {
+        let varint = |x| bytes_token(&LengthEncoding::Varint.encode(x));
+        let tag = |wire| varint(field.number << 3 | wire);
+        let delimited = |body: String| {
+            vec![tag(WIRE_LENGTH), format!("%varint_length({})", body), body]
+        };
+
+        let (wire, value) = match &field.ty {
+            Type::Varint  => (WIRE_VARINT, "<varint>".to_string()),
+            Type::Bool    => (WIRE_VARINT, "<bool>".to_string()),
+            Type::Fixed32 => (WIRE_FIXED32, "<fixed32>".to_string()),
+            Type::Fixed64 => (WIRE_FIXED64, "<fixed64>".to_string()),
+            Type::String  => return Ok(vec![delimited("<string>".into())]),
+            Type::Bytes   => return Ok(vec![delimited("<bytes>".into())]),
+            Type::Named(name) => {
+                let name = self.resolve(name, scope)?;
+                if self.messages.contains_key(&name) {
+                    let body = format!("<message.{}>", name);
+                    queue.push(name);
+                    return Ok(vec![delimited(body)]);
+                }
+
+                // An enum, which is any of its values as a varint
+                let enum_rule = format!("<enum.{}>", name);
+                grammar.0.insert(enum_rule.clone(),
+                    self.enums[&name].iter()
+                        .map(|&x| vec![varint(x as u64)]).collect());
+                (WIRE_VARINT, enum_rule)
+            }
+        };
+
+        let mut alts = vec![vec![tag(wire), value.clone()]];
+        if field.label == Label::Repeated {
+            // Numeric repeated fields may also be packed into a single
+            // length delimited field
+            let packed = format!("{}:packed>", &rule[..rule.len() - 1]);
+            grammar.0.insert(packed.clone(),
+                vec![vec![value.clone()], vec![value, packed.clone()]]);
+            alts.push(delimited(packed));
+        }
+        Ok(alts)
+    }
+}
+
+/// Add the rules for the scalar values of the wire format to `grammar`
+fn builtins(grammar: &mut Grammar) {
+    let byte = |x: u8| vec![bytes_token(&[x])];
+    grammar.0.insert("<byte>".into(), (0..=255).map(byte).collect());
+
+    // Varints of every length, up to the 10 bytes needed for 64 bits, where
+    // the last byte only has a single bit left
+    grammar.0.insert("<varint.low>".into(), (0..0x80).map(byte).collect());
+    grammar.0.insert("<varint.high>".into(),
+        (0x80..=0xff).map(byte).collect());
+    grammar.0.insert("<varint>".into(), (1..=10).map(|len| {
+        let mut alt = vec!["<varint.high>".to_string(); len - 1];
+        alt.push(if len == 10 { "<varint.last>" } else { "<varint.low>" }
+            .into());
+        alt
+    }).collect());
+    grammar.0.insert("<varint.last>".into(), vec![byte(0), byte(1)]);
+    grammar.0.insert("<bool>".into(), vec![byte(0), byte(1)]);
+
+    grammar.0.insert("<fixed32>".into(),
+        vec![vec!["<byte>".to_string(); 4]]);
+    grammar.0.insert("<fixed64>".into(),
+        vec![vec!["<byte>".to_string(); 8]]);
+
+    // Strings must be valid UTF-8, so they're made of printable ASCII with
+    // the odd multi-byte character
+    let mut chars: Vec<Vec<String>> = (0x20u8..0x7f)
+        .map(|x| vec![(x as char).to_string()]).collect();
+    chars.extend(["\u{e9}", "\u{20ac}", "\u{1f600}"].iter()
+        .map(|x| vec![x.to_string()]));
+    grammar.0.insert("<string.char>".into(), chars);
+    grammar.0.insert("<string>".into(),
+        vec![vec![], vec!["<string.char>".into(), "<string>".into()]]);
+    grammar.0.insert("<bytes>".into(),
+        vec![vec![], vec!["<byte>".into(), "<bytes>".into()]]);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{ConvertOptions, GrammarRust};
+    use super::*;
+
+    /// Load the `.proto` source `source`, written to a file named `name`
+    fn load(name: &str, source: &str) -> std::io::Result<Schema> {
+        let dir = std::env::temp_dir()
+            .join(format!("fzero_proto_{}_{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, source).unwrap();
+        let schema = Schema::from_proto(&path, &[]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        schema
+    }
+
+    /// Get the alternatives of `rule` in `grammar`
+    fn rule<'a>(grammar: &'a Grammar, rule: &str) -> Vec<Vec<&'a str>> {
+        grammar.0[rule].iter()
+            .map(|x| x.iter().map(|x| x.as_str()).collect()).collect()
+    }
+
+    /// Encode a length delimited field `number` holding `value`
+    fn delimited(number: u64, value: &[u8]) -> Vec<u8> {
+        let mut ret = LengthEncoding::Varint.encode(number << 3 | WIRE_LENGTH);
+        ret.extend(LengthEncoding::Varint.encode(value.len() as u64));
+        ret.extend_from_slice(value);
+        ret
+    }
+
+    /// Encode a varint field `number` holding `value`
+    fn varint(number: u64, value: u64) -> Vec<u8> {
+        let mut ret = LengthEncoding::Varint.encode(number << 3 | WIRE_VARINT);
+        ret.extend(LengthEncoding::Varint.encode(value));
+        ret
+    }
+
+    #[test]
+    fn proto_messages() {
+        let schema = load("messages.proto", r#"
+            syntax = "proto3";
+            package demo;
+
+            // Fields of every kind
+            message Outer {
+                int32 id = 1;
+                repeated Kind kinds = 2 [packed = true];
+                Inner inner = 3;
+                oneof body {
+                    string name = 4;
+                    bytes data = 5;
+                }
+
+                message Inner {
+                    fixed32 crc = 1;
+                }
+                enum Kind {
+                    A = 0;
+                    B = 1;
+                    C = -1;
+                }
+            }
+        "#).unwrap();
+        let grammar = schema.grammar(None).unwrap();
+
+        assert_eq!(rule(&grammar, "<start>"), [["<message.demo.Outer>"]]);
+        assert_eq!(rule(&grammar, "<message.demo.Outer>"),
+            [["<field.demo.Outer.id>", "<field.demo.Outer.kinds>",
+              "<field.demo.Outer.inner>", "<oneof.demo.Outer.body>"]]);
+
+        // Optional fields may be left out
+        assert_eq!(rule(&grammar, "<field.demo.Outer.id>"),
+            [vec![], vec!["%bytes(08)", "<varint>"]]);
+
+        // Repeated fields occur any number of times, either one by one or
+        // packed
+        assert_eq!(rule(&grammar, "<field.demo.Outer.kinds>"),
+            [vec![], vec!["<field.demo.Outer.kinds:one>",
+                "<field.demo.Outer.kinds>"]]);
+        assert_eq!(rule(&grammar, "<field.demo.Outer.kinds:one>"),
+            [vec!["%bytes(10)", "<enum.demo.Outer.Kind>"],
+             vec!["%bytes(12)",
+                "%varint_length(<field.demo.Outer.kinds:packed>)",
+                "<field.demo.Outer.kinds:packed>"]]);
+
+        // Negative enum values are sign extended to 64 bits
+        assert_eq!(rule(&grammar, "<enum.demo.Outer.Kind>"),
+            [["%bytes(00)"], ["%bytes(01)"],
+             ["%bytes(ff ff ff ff ff ff ff ff ff 01)"]]);
+
+        // Nested messages are length delimited
+        assert_eq!(rule(&grammar, "<field.demo.Outer.inner>"),
+            [vec![], vec!["%bytes(1a)",
+                "%varint_length(<message.demo.Outer.Inner>)",
+                "<message.demo.Outer.Inner>"]]);
+        assert_eq!(rule(&grammar, "<field.demo.Outer.Inner.crc>"),
+            [vec![], vec!["%bytes(0d)", "<fixed32>"]]);
+
+        // At most one field of a oneof is present
+        assert_eq!(rule(&grammar, "<oneof.demo.Outer.body>"),
+            [vec![],
+             vec!["%bytes(22)", "%varint_length(<string>)", "<string>"],
+             vec!["%bytes(2a)", "%varint_length(<bytes>)", "<bytes>"]]);
+
+        // Every rule used is defined
+        GrammarRust::new(&grammar, &ConvertOptions::default()).unwrap();
+    }
+
+    #[test]
+    fn proto_descriptor_set() {
+        // `message M { int32 id = 1; repeated string tags = 2; }` in the
+        // package `p`, as `protoc -o` would write it
+        let id = [delimited(1, b"id"), varint(3, 1), varint(4, 1),
+            varint(5, 5)].concat();
+        let tags = [delimited(1, b"tags"), varint(3, 2), varint(4, 3),
+            varint(5, 9)].concat();
+        let message = [delimited(1, b"M"), delimited(2, &id),
+            delimited(2, &tags)].concat();
+        let file = [delimited(1, b"m.proto"), delimited(2, b"p"),
+            delimited(4, &message)].concat();
+        let set = delimited(1, &file);
+
+        let source = load("descriptor.proto", r#"
+            package p;
+            message M {
+                int32 id = 1;
+                repeated string tags = 2;
+            }
+        "#).unwrap();
+        assert_eq!(Schema::from_descriptor_set(&set).unwrap().grammar(None)
+            .unwrap().0, source.grammar(None).unwrap().0);
+    }
+
+    #[test]
+    fn proto_errors() {
+        let schema = load("errors.proto", r#"
+            message A { Missing field = 1; }
+            message B { int32 x = 1; }
+            message C { int32 x = 1; }
+        "#).unwrap();
+        let error = |name| schema.grammar(Some(name)).unwrap_err()
+            .to_string();
+        assert_eq!(error("A"), "A uses undefined type Missing");
+        assert_eq!(error("D"), "Message D is not defined");
+        assert!(schema.grammar(Some("B")).is_ok());
+    }
+}
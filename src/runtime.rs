@@ -0,0 +1,3366 @@
+//! Templates for the fixed parts of the generated Rust source. The grammar
+//! specific fragment functions are appended to these by `codegen`.
+//!
+//! Templates use placeholders which are substituted during code generation:
+//!
+//! - `__SEED__` is the default random seed
+//! - `__RESERVE__` is the number of bytes to reserve for the output buffer
+//! - `__MAX_DEPTH__` is the default maximum expansion depth
+//! - `__GRAMMARS__` is the list of grammar names of programs built from
+//!   several grammars, empty otherwise
+
+/// Standalone benchmarking program, which generates inputs in a loop on one
+/// or more threads and reports statistics about them
+pub const BENCHMARK: &str = r#"
+#![allow(unused)]
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+/// Base random seed. The first thread uses this directly, the rest derive
+/// their seeds from it.
+const SEED: u64 = __SEED__;
+
+/// Names of the grammars the program was built from, when there are several
+const GRAMMARS: &[&str] = &__GRAMMARS__;
+
+/// Index into `GRAMMARS` of the grammar inputs are generated from
+static GRAMMAR: AtomicUsize = AtomicUsize::new(0);
+
+/// Runtime options for the generated program
+struct Config {
+    /// Number of threads generating inputs
+    threads: usize,
+
+    /// Number of seconds between statistics reports
+    interval: f64,
+
+    /// Also report statistics as json lines on stderr
+    json_stats: bool,
+
+    /// Print a histogram of input sizes and how often the depth limit was
+    /// hit once done
+    histogram: bool,
+
+    /// Append events and statistics as json lines to this file
+    log: Option<PathBuf>,
+
+    /// Serve Prometheus metrics over HTTP on this address
+    metrics: Option<String>,
+
+    /// Stop after generating this many inputs in total
+    iterations: Option<u64>,
+
+    /// Stop after this many seconds
+    duration: Option<f64>,
+
+    /// Instead of benchmarking, reproduce the input with this index generated
+    /// from this seed
+    replay: Option<(u64, u64)>,
+
+    /// File to write the replayed input to, stdout if not given
+    out: Option<PathBuf>,
+
+    /// Detect duplicate inputs and leave them out of the statistics
+    dedup: bool,
+
+    /// Instead of benchmarking, write inputs to this pcap file
+    pcap: Option<PathBuf>,
+
+    /// Destination TCP port of the packets in the pcap file
+    pcap_port: u16,
+
+    /// Instead of benchmarking, write inputs to stdout
+    stream: bool,
+
+    /// Byte written after every streamed input, instead of prefixing it with
+    /// its length
+    delimiter: Option<u8>,
+
+    /// Instead of benchmarking, write inputs into a ring buffer in this
+    /// memory mapped file
+    mmap: Option<PathBuf>,
+
+    /// Number of bytes of inputs the ring buffer holds
+    mmap_size: usize,
+
+    /// Instead of benchmarking, write every input to its own file in the
+    /// `queue` directory within this directory
+    queue: Option<PathBuf>,
+
+    /// Delete the oldest inputs in the queue once it holds more than this
+    /// many
+    queue_max_files: Option<usize>,
+
+    /// Delete the oldest inputs in the queue once they take up more than
+    /// this many bytes
+    queue_max_bytes: Option<u64>,
+
+    /// Chance in percent of corrupting each input with a few random byte
+    /// mutations
+    havoc: u32,
+
+    /// Randomly disable options of the grammar, picking new ones every this
+    /// many inputs
+    swarm: Option<u64>,
+
+    /// Instead of benchmarking, check inputs with this shell command, which
+    /// accepts an input by exiting successfully
+    oracle: Option<String>,
+
+    /// Check every this many inputs with the oracle
+    oracle_every: u64,
+
+    /// Maximum expansion depth
+    max_depth: usize,
+
+    /// Number of complete expansions to keep in the pool of every rule
+    /// built with `--pool`, zero to always expand them
+    pool: usize,
+
+    /// Chance in percent of expanding a pooled rule anyway, refreshing its
+    /// pool
+    pool_refresh: usize,
+
+    /// Holes filled with the lines of a file or named pipe, with its path
+    holes: Vec<(usize, PathBuf)>,
+
+    /// Index into `GRAMMARS` of the grammar to generate inputs from
+    grammar: usize,
+}
+
+impl Config {
+    /// Parse the options from the command line, exiting on errors
+    fn parse() -> Self {
+        let mut config = Config {
+            threads:    1,
+            interval:   1.,
+            json_stats: false,
+            histogram:  false,
+            log:        None,
+            metrics:    None,
+            iterations: None,
+            duration:   None,
+            replay:     None,
+            out:        None,
+            dedup:      false,
+            pcap:       None,
+            pcap_port:  80,
+            stream:     false,
+            delimiter:  None,
+            mmap:       None,
+            mmap_size:  64 * 1024 * 1024,
+            queue:      None,
+            queue_max_files: None,
+            queue_max_bytes: None,
+            havoc:      0,
+            swarm:      None,
+            oracle:     None,
+            oracle_every: 1,
+            max_depth:  env_max_depth(),
+            pool:       0,
+            pool_refresh: 1,
+            holes:      Vec::new(),
+            grammar:    0,
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--threads" => config.threads = value(&mut args, &arg),
+                "--stats-interval" => config.interval = value(&mut args, &arg),
+                "--json-stats" => config.json_stats = true,
+                "--histogram" => config.histogram = true,
+                "--log-json" => config.log = Some(value(&mut args, &arg)),
+                "--metrics" => config.metrics = Some(value(&mut args, &arg)),
+                "--iterations" =>
+                    config.iterations = Some(value(&mut args, &arg)),
+                "--duration" => config.duration = Some(value(&mut args, &arg)),
+                "--replay" => {
+                    let replay = args.next().and_then(|x| parse_replay(&x));
+                    config.replay = Some(replay.unwrap_or_else(|| {
+                        usage("`--replay` requires a value of SEED[:N]")
+                    }));
+                }
+                "--out" => config.out = Some(value(&mut args, &arg)),
+                "--dedup" => config.dedup = true,
+                "--pcap" => config.pcap = Some(value(&mut args, &arg)),
+                "--pcap-port" => config.pcap_port = value(&mut args, &arg),
+                "--stream" => config.stream = true,
+                "--delimiter" =>
+                    config.delimiter = Some(value(&mut args, &arg)),
+                "--mmap" => config.mmap = Some(value(&mut args, &arg)),
+                "--mmap-size" => config.mmap_size = value(&mut args, &arg),
+                "--queue" => config.queue = Some(value(&mut args, &arg)),
+                "--queue-max-files" =>
+                    config.queue_max_files = Some(value(&mut args, &arg)),
+                "--queue-max-bytes" =>
+                    config.queue_max_bytes = Some(value(&mut args, &arg)),
+                "--havoc" => config.havoc = value(&mut args, &arg),
+                "--swarm" => config.swarm = Some(value(&mut args, &arg)),
+                "--oracle" => config.oracle = Some(value(&mut args, &arg)),
+                "--oracle-every" =>
+                    config.oracle_every = value(&mut args, &arg),
+                "--max-depth" => config.max_depth = value(&mut args, &arg),
+                "--pool" => config.pool = value(&mut args, &arg),
+                "--pool-refresh" =>
+                    config.pool_refresh = value(&mut args, &arg),
+                "--hole" => {
+                    let spec: String = value(&mut args, &arg);
+                    let (rule, path) = spec.split_once('=').unwrap_or_else(
+                        || usage("`--hole` requires RULE=PATH"));
+                    let hole = HOLES.iter().find(|x| x.0 == rule)
+                        .unwrap_or_else(|| {
+                            usage(&format!("`{}` wasn't built as a hole", rule))
+                        }).1;
+                    config.holes.push((hole, PathBuf::from(path)));
+                }
+                "--grammar-name" => {
+                    let name: String = value(&mut args, &arg);
+                    config.grammar = GRAMMARS.iter().position(|&x| x == name)
+                        .unwrap_or_else(|| {
+                            usage(&format!("unknown grammar `{}`", name))
+                        });
+                }
+                "-h" | "--help" => {
+                    print_usage(&mut std::io::stdout());
+                    std::process::exit(0);
+                }
+                _ => usage(&format!("unknown option `{}`", arg)),
+            }
+        }
+
+        // Every mode replaces benchmarking, so only one can be picked
+        let modes: Vec<&str> = [
+            ("--replay", config.replay.is_some()),
+            ("--pcap",   config.pcap.is_some()),
+            ("--mmap",   config.mmap.is_some()),
+            ("--queue",  config.queue.is_some()),
+            ("--oracle", config.oracle.is_some()),
+            ("--stream", config.stream),
+        ].iter().filter(|x| x.1).map(|x| x.0).collect();
+        if modes.len() > 1 {
+            usage(&format!("only one of {} can be given", modes.join(", ")));
+        }
+        if config.dedup && modes.iter().any(|&x| x != "--stream" &&
+                x != "--queue") {
+            usage("--dedup only works when benchmarking, with --stream or \
+                with --queue");
+        }
+
+        if config.threads == 0 {
+            usage("--threads must be at least 1");
+        }
+        if !(config.interval > 0.) {
+            usage("--stats-interval must be positive");
+        }
+        if config.duration.is_some_and(|x| !(x >= 0.)) {
+            usage("--duration must not be negative");
+        }
+        if config.pcap.is_some() && config.iterations.is_none() {
+            usage("--pcap requires --iterations");
+        }
+        if config.delimiter.is_some() && !config.stream {
+            usage("--delimiter requires --stream");
+        }
+        if config.mmap_size < 64 || !config.mmap_size.is_multiple_of(8) {
+            usage("--mmap-size must be a multiple of 8 of at least 64");
+        }
+        if (config.queue_max_files.is_some() ||
+                config.queue_max_bytes.is_some()) && config.queue.is_none() {
+            usage("--queue-max-files and --queue-max-bytes require --queue");
+        }
+        if config.queue_max_files == Some(0) {
+            usage("--queue-max-files must be at least 1");
+        }
+        if config.havoc > 100 {
+            usage("--havoc must be a percentage from 0 to 100");
+        }
+        if config.swarm == Some(0) {
+            usage("--swarm must be at least 1");
+        }
+        if config.oracle_every == 0 {
+            usage("--oracle-every must be at least 1");
+        }
+        if config.pool_refresh > 100 {
+            usage("--pool-refresh must be a percentage from 0 to 100");
+        }
+        config
+    }
+
+    /// Get the empty pools of the rules built with `--pool`
+    fn pool(&self) -> Pool {
+        Pool { size: self.pool, refresh: self.pool_refresh,
+            ..Pool::default() }
+    }
+
+    /// Get the providers of the holes filled from files with `--hole`,
+    /// each reading its file from the start
+    fn holes(&self) -> Vec<Option<Hole>> {
+        let mut holes: Vec<Option<Hole>> = Vec::new();
+        for (hole, path) in self.holes.iter() {
+            holes.resize_with(holes.len().max(hole + 1), || None);
+            holes[*hole] = Some(lines(path.clone()));
+        }
+        holes
+    }
+
+    /// Create a fuzzer seeded with `seed` generating with these options
+    fn fuzzer(&self, seed: u64) -> Fuzzer {
+        Fuzzer::seeded(seed, self.max_depth, self.pool(), self.holes())
+    }
+}
+
+/// Parse the value following the option `name`, exiting on errors
+fn value<T: FromStr>(args: &mut impl Iterator<Item = String>, name: &str)
+        -> T {
+    args.next().and_then(|x| x.parse().ok()).unwrap_or_else(|| {
+        usage(&format!("`{}` requires a valid value", name))
+    })
+}
+
+/// Parse a `SEED[:N]` replay specification, where the seed may be given in
+/// hex with a `0x` prefix and `N` defaults to zero
+fn parse_replay(spec: &str) -> Option<(u64, u64)> {
+    let (seed, index) = spec.split_once(':').unwrap_or((spec, "0"));
+    let seed = match seed.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => seed.parse().ok()?,
+    };
+    Some((seed, index.parse().ok()?))
+}
+
+/// Get a provider filling a hole with the lines of the file or named pipe at
+/// `path` in turn, without their line breaks. The file is opened again once
+/// all of its lines were used, which for a named pipe waits for the next
+/// writer. The hole is left empty if the file can't be read.
+fn lines(path: PathBuf) -> Hole {
+    let mut reader: Option<std::io::BufReader<std::fs::File>> = None;
+    Box::new(move |buf: &mut Vec<u8>| {
+        use std::io::BufRead;
+
+        // Try again once from the start of the file when it runs out
+        for _ in 0..2 {
+            if reader.is_none() {
+                reader = std::fs::File::open(&path).ok()
+                    .map(std::io::BufReader::new);
+            }
+            let file = match reader.as_mut() {
+                Some(file) => file,
+                None => return,
+            };
+            let start = buf.len();
+            match file.read_until(b'\n', buf) {
+                Ok(read) if read > 0 => {
+                    if buf.last() == Some(&b'\n') {
+                        buf.pop();
+                    }
+                    return;
+                }
+                _ => {
+                    buf.truncate(start);
+                    reader = None;
+                }
+            }
+        }
+    })
+}
+
+/// Report a command line error along with the usage and exit
+fn usage(err: &str) -> ! {
+    eprintln!("error: {}", err);
+    print_usage(&mut std::io::stderr());
+    std::process::exit(1);
+}
+
+/// Write the usage to `out`
+fn print_usage(out: &mut impl Write) {
+    let program = std::env::args().next().unwrap_or_default();
+    let modes = [
+        "[--threads N] [--stats-interval SECS] [--json-stats] [--histogram] \
+         [--log-json FILE] [--metrics ADDR] [--iterations N] \
+         [--duration SECS] [--dedup] [--havoc PERCENT] [--swarm N] \
+         [--max-depth N] [--pool N] [--pool-refresh PERCENT] \
+         [--hole RULE=PATH]",
+        "--replay SEED[:N] [--out FILE] [--havoc PERCENT] [--swarm N] \
+         [--max-depth N]",
+        "--pcap FILE --iterations N [--pcap-port PORT] [--havoc PERCENT] \
+         [--swarm N] [--max-depth N]",
+        "--stream [--delimiter BYTE] [--iterations N] [--dedup] \
+         [--havoc PERCENT] [--swarm N] [--max-depth N]",
+        "--mmap FILE [--mmap-size BYTES] [--iterations N] [--havoc PERCENT] \
+         [--swarm N] [--max-depth N]",
+        "--queue DIR [--queue-max-files N] [--queue-max-bytes N] \
+         [--iterations N] [--dedup] [--havoc PERCENT] [--swarm N] \
+         [--max-depth N]",
+        "--oracle CMD [--oracle-every N] [--iterations N] [--havoc PERCENT] \
+         [--swarm N] [--max-depth N]",
+    ];
+    for (i, mode) in modes.iter().enumerate() {
+        let prefix = if i == 0 { "usage:" } else { "      " };
+        let _ = writeln!(out, "{} {} {}", prefix, program, mode);
+    }
+    if !GRAMMARS.is_empty() {
+        let _ = writeln!(out,
+            "pick the grammar with [--grammar-name NAME], one of: {}",
+            GRAMMARS.join(", "));
+    }
+}
+
+/// Get the next output of the splitmix64 generator with `state`, used to
+/// derive independent seeds from the base seed
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Number of histogram buckets for each power of two of input sizes. Sizes
+/// are bucketed with a relative error of at most 1/16th.
+const SUB_BUCKETS: usize = 16;
+
+/// Total number of histogram buckets, enough for any `u64` size
+const BUCKETS: usize = (64 - 3) * SUB_BUCKETS;
+
+/// Statistics about the inputs generated
+#[derive(Clone)]
+struct Stats {
+    /// Number of inputs generated
+    inputs: u64,
+
+    /// Total number of bytes generated
+    bytes: u64,
+
+    /// Size of the largest input
+    max: u64,
+
+    /// Number of duplicate inputs, which aren't counted anywhere else
+    duplicates: u64,
+
+    /// Histogram of input sizes, see `Stats::bucket`
+    sizes: Vec<u64>,
+
+    /// Number of inputs which had expansions cut off by the depth limit
+    truncated: u64,
+
+    /// Total number of expansions cut off by the depth limit
+    cutoffs: u64,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            inputs: 0,
+            bytes: 0,
+            max: 0,
+            duplicates: 0,
+            sizes: vec![0; BUCKETS],
+            truncated: 0,
+            cutoffs: 0,
+        }
+    }
+
+    /// Get the histogram bucket for inputs of `size` bytes. Small sizes get
+    /// a bucket each, larger ones are split into `SUB_BUCKETS` buckets per
+    /// power of two.
+    fn bucket(size: u64) -> usize {
+        if size < SUB_BUCKETS as u64 {
+            return size as usize;
+        }
+        let log = 63 - size.leading_zeros() as usize;
+        let shift = log - 4;
+        (log - 3) * SUB_BUCKETS + (size >> shift) as usize % SUB_BUCKETS
+    }
+
+    /// Get the smallest size which falls into `bucket`
+    fn bucket_size(bucket: usize) -> u64 {
+        if bucket < SUB_BUCKETS {
+            return bucket as u64;
+        }
+        let shift = bucket / SUB_BUCKETS - 1;
+        ((SUB_BUCKETS + bucket % SUB_BUCKETS) as u64) << shift
+    }
+
+    /// Record an input of `size` bytes which had `cutoffs` expansions cut
+    /// off by the depth limit
+    #[inline(always)]
+    fn record(&mut self, size: u64, cutoffs: u64) {
+        self.inputs += 1;
+        self.bytes += size;
+        self.max = self.max.max(size);
+        self.sizes[Self::bucket(size)] += 1;
+        self.truncated += (cutoffs > 0) as u64;
+        self.cutoffs += cutoffs;
+    }
+
+    /// Add all of the inputs recorded in `other` to these statistics
+    fn merge(&mut self, other: &Stats) {
+        self.inputs += other.inputs;
+        self.bytes += other.bytes;
+        self.max = self.max.max(other.max);
+        self.duplicates += other.duplicates;
+        for (size, other) in self.sizes.iter_mut().zip(&other.sizes) {
+            *size += other;
+        }
+        self.truncated += other.truncated;
+        self.cutoffs += other.cutoffs;
+    }
+
+    /// Get the fraction of all inputs which were duplicates
+    fn duplicate_rate(&self) -> f64 {
+        self.duplicates as f64 / (self.inputs + self.duplicates).max(1) as f64
+    }
+
+    /// Get the mean input size
+    fn mean(&self) -> f64 {
+        self.bytes as f64 / self.inputs.max(1) as f64
+    }
+
+    /// Get the approximate median input size
+    fn median(&self) -> u64 {
+        let mut seen = 0;
+        for (bucket, &count) in self.sizes.iter().enumerate() {
+            seen += count;
+            if seen * 2 >= self.inputs && count > 0 {
+                return Self::bucket_size(bucket);
+            }
+        }
+        0
+    }
+}
+
+/// Number of independently locked shards of the set of seen inputs
+const SHARDS: usize = 64;
+
+/// Maximum number of hashes of seen inputs remembered, which bounds the
+/// memory used by `--dedup` to under a hundred MiB however long the program
+/// runs
+const SEEN_CAPACITY: usize = 1 << 22;
+
+/// Hashes of the inputs seen recently, shared by all threads. The set is
+/// split into shards by hash so threads rarely contend on the same lock.
+/// Every shard holds a newer and an older generation of hashes. Once the
+/// newer one fills up the older one is forgotten and the newer one takes its
+/// place, so at least half of `SEEN_CAPACITY` of the most recent unique
+/// inputs are always remembered.
+struct Seen(Vec<Mutex<(HashSet<u64>, HashSet<u64>)>>);
+
+impl Seen {
+    fn new() -> Self {
+        Seen((0..SHARDS).map(|_| Mutex::default()).collect())
+    }
+
+    /// Record `input`, returning `true` if it hasn't been seen recently
+    fn insert(&self, input: &[u8]) -> bool {
+        let hash = hash(input);
+        let mut shard = self.0[hash as usize % SHARDS].lock().unwrap();
+        let (newer, older) = &mut *shard;
+        if older.contains(&hash) || !newer.insert(hash) {
+            return false;
+        }
+
+        // Rotate the generations, reusing the allocation of the older one
+        if newer.len() >= SEEN_CAPACITY / SHARDS / 2 {
+            std::mem::swap(newer, older);
+            newer.clear();
+        }
+        true
+    }
+}
+
+/// Get a 64-bit hash of `bytes`, processing eight bytes at a time. Inputs
+/// are considered duplicates if their hashes are equal.
+fn hash(bytes: &[u8]) -> u64 {
+    const K: u64 = 0x9e3779b97f4a7c15;
+
+    let mut hash = bytes.len() as u64;
+    let mut chunks = bytes.chunks_exact(8);
+    for chunk in &mut chunks {
+        let word = u64::from_le_bytes(chunk.try_into().unwrap());
+        hash = (hash ^ word).wrapping_mul(K);
+        hash ^= hash >> 32;
+    }
+    for &byte in chunks.remainder() {
+        hash = (hash ^ byte as u64).wrapping_mul(K);
+        hash ^= hash >> 32;
+    }
+
+    // Finish off with the splitmix64 mixer so every bit affects the shard
+    let mut state = hash;
+    splitmix64(&mut state)
+}
+
+/// Statistics published by a single thread. Each thread gets its own cache
+/// line so threads don't contend on them.
+#[repr(align(64))]
+struct Shared(Mutex<Stats>);
+
+fn main() {
+    let config = Config::parse();
+    GRAMMAR.store(config.grammar, Ordering::Relaxed);
+    if let Some((seed, index)) = config.replay {
+        replay(&config, seed, index);
+        return;
+    }
+    if let Some(path) = &config.pcap {
+        if let Err(err) = pcap(&config, path) {
+            eprintln!("error: failed to write pcap: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(path) = &config.mmap {
+        if let Err(err) = ring(&config, path) {
+            eprintln!("error: failed to write to the ring: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(dir) = &config.queue {
+        if let Err(err) = queue(&config, dir) {
+            eprintln!("error: failed to write to the queue: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(cmd) = &config.oracle {
+        if let Err(err) = oracle(&config, cmd) {
+            eprintln!("error: failed to run the oracle: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if config.stream {
+        // The reader going away is how streaming normally ends
+        match stream(&config) {
+            Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+                eprintln!("error: failed to stream inputs: {}", err);
+                std::process::exit(1);
+            }
+            _ => return,
+        }
+    }
+
+    // The seed is a string as json parsers lose precision on numbers above
+    // 2^53
+    let mut log = Log::open(config.log.as_deref());
+    log.event("start", &format!("\"seed\":\"{:#x}\",\"threads\":{},\
+        \"max_depth\":{},\"iterations\":{},\"grammar\":{}", SEED,
+        config.threads, config.max_depth,
+        config.iterations.map_or(String::from("null"), |x| x.to_string()),
+        GRAMMARS.get(config.grammar).map_or(String::from("null"),
+            |x| json_string(x))));
+    let metrics = config.metrics.as_deref().map(Metrics::serve);
+
+    let shared: Vec<Shared> =
+        (0..config.threads).map(|_| Shared(Mutex::new(Stats::new()))).collect();
+
+    // Set to tell the workers to stop once the duration has elapsed
+    let stop = AtomicBool::new(false);
+
+    // Inputs seen by any thread, when detecting duplicates
+    let seen = config.dedup.then(Seen::new);
+
+    // Every worker holds a sender which is dropped when it finishes, so we
+    // find out once all of them are done with their iterations
+    let (done, finished) = mpsc::channel::<()>();
+
+    let it = Instant::now();
+    std::thread::scope(|s| {
+        let mut state = SEED;
+        for (thread, shared) in shared.iter().enumerate() {
+            // xorshift gets stuck on a seed of zero
+            let seed = if thread == 0 { SEED } else {
+                splitmix64(&mut state).max(1)
+            };
+            if config.threads > 1 {
+                eprintln!("Thread {} seed: {:#x}", thread, seed);
+            }
+
+            // Split the iterations evenly between the threads
+            let threads = config.threads as u64;
+            let limit = config.iterations.map_or(u64::MAX, |x| {
+                x / threads + ((thread as u64) < x % threads) as u64
+            });
+
+            let (stop, seen, done) = (&stop, seen.as_ref(), done.clone());
+            let (fuzzer, havoc, swarm) =
+                (config.fuzzer(seed), config.havoc, config.swarm);
+            s.spawn(move || {
+                worker(fuzzer, havoc, swarm, limit, shared, stop, seen, done)
+            });
+        }
+        drop(done);
+
+        // Periodically report the combined statistics of all threads until
+        // they're all done or we run out of time
+        let interval = Duration::from_secs_f64(config.interval);
+        let deadline = config.duration
+            .map(|x| it + Duration::from_secs_f64(x));
+        let mut next = it + interval;
+        loop {
+            let wake = deadline.map_or(next, |x| x.min(next));
+            let timeout = wake.saturating_duration_since(Instant::now());
+            if let Err(RecvTimeoutError::Disconnected) =
+                    finished.recv_timeout(timeout) {
+                break;
+            }
+
+            if deadline.is_some_and(|x| Instant::now() >= x) {
+                stop.store(true, Ordering::Relaxed);
+                break;
+            }
+
+            if Instant::now() >= next {
+                report(&config, &merge(&shared), it.elapsed().as_secs_f64(),
+                    &mut log, metrics.as_deref(), false);
+                next += interval;
+            }
+        }
+    });
+
+    // All workers have published their final statistics, summarize them
+    report(&config, &merge(&shared), it.elapsed().as_secs_f64(), &mut log,
+        metrics.as_deref(), true);
+}
+
+impl Fuzzer {
+    /// When swarm testing every `period` inputs, randomly pick the options
+    /// to disable for the input with `index` and the following ones. Options
+    /// which are needed to finish inputs near the depth limit are never
+    /// disabled. Doesn't consume any randomness when swarm testing is off.
+    fn pick_swarm(&mut self, period: Option<u64>, index: u64) {
+        if period.is_some_and(|x| index.is_multiple_of(x)) {
+            self.swarm.clear();
+            for _ in 0..SWARM_OPTIONS {
+                let disabled = self.rand() % 2 == 0;
+                self.swarm.push(disabled);
+            }
+        }
+    }
+
+    /// Check whether the option with swarm index `option` is disabled
+    #[inline(always)]
+    fn disabled(&self, option: usize) -> bool {
+        self.swarm.get(option).copied().unwrap_or(false)
+    }
+}
+
+/// Write the input with `index` generated from `seed`, counting from zero, to
+/// the output file. This generates exactly the same inputs as a worker
+/// thread with the same seed does.
+fn replay(config: &Config, seed: u64, index: u64) {
+    let mut fuzzer = config.fuzzer(seed);
+    for index in 0..=index {
+        fuzzer.pick_swarm(config.swarm, index);
+        fuzzer.buf.clear();
+        fuzzer.start();
+        fuzzer.havoc(config.havoc);
+    }
+
+    let result = match &config.out {
+        Some(path) => std::fs::write(path, &fuzzer.buf),
+        None => std::io::stdout().write_all(&fuzzer.buf),
+    };
+    if let Err(err) = result {
+        eprintln!("error: failed to write input: {}", err);
+        std::process::exit(1);
+    }
+}
+
+/// Maximum number of payload bytes in a single packet, limited by the 16-bit
+/// total length of the IPv4 header
+const MAX_PAYLOAD: usize = 65535 - 20 - 20;
+
+/// Write `config.iterations` inputs generated from the base seed to a pcap
+/// file at `path`. Every input is sent as its own TCP stream from a new
+/// source port, split into as few packets as possible.
+fn pcap(config: &Config, path: &PathBuf) -> std::io::Result<()> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+
+    // Global header: magic, version 2.4, UTC, snapshot length and Ethernet
+    // link type. A full packet with its Ethernet header is larger than
+    // 65535 bytes, so the snapshot length is libpcap's default of 262144 to
+    // keep readers from truncating it.
+    out.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&4u16.to_le_bytes())?;
+    out.write_all(&[0; 8])?;
+    out.write_all(&262144u32.to_le_bytes())?;
+    out.write_all(&1u32.to_le_bytes())?;
+
+    let mut fuzzer = config.fuzzer(SEED);
+
+    let mut packets = 0u64;
+    for input in 0..config.iterations.unwrap() {
+        fuzzer.pick_swarm(config.swarm, input);
+        fuzzer.buf.clear();
+        fuzzer.start();
+        fuzzer.havoc(config.havoc);
+
+        let port = 1024 + (input % 64000) as u16;
+        let mut seq = 1u32;
+        for payload in fuzzer.buf.chunks(MAX_PAYLOAD) {
+            let packet = tcp_packet(port, config.pcap_port, seq, payload);
+
+            // Give every packet its own microsecond
+            out.write_all(&((packets / 1000000) as u32).to_le_bytes())?;
+            out.write_all(&((packets % 1000000) as u32).to_le_bytes())?;
+            out.write_all(&(packet.len() as u32).to_le_bytes())?;
+            out.write_all(&(packet.len() as u32).to_le_bytes())?;
+            out.write_all(&packet)?;
+
+            seq = seq.wrapping_add(payload.len() as u32);
+            packets += 1;
+        }
+    }
+
+    out.flush()?;
+    eprintln!("Wrote {} inputs in {} packets", config.iterations.unwrap(),
+        packets);
+    Ok(())
+}
+
+/// Write inputs generated from the base seed to stdout until
+/// `config.iterations` inputs are generated, or forever. Each input is
+/// prefixed with its length as a little endian `u32`, or followed by
+/// `config.delimiter` if one was given. With `config.dedup`, inputs seen
+/// recently are skipped.
+fn stream(config: &Config) -> std::io::Result<()> {
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    let seen = config.dedup.then(Seen::new);
+
+    let mut fuzzer = config.fuzzer(SEED);
+
+    for index in 0..config.iterations.unwrap_or(u64::MAX) {
+        fuzzer.pick_swarm(config.swarm, index);
+        fuzzer.buf.clear();
+        fuzzer.start();
+        fuzzer.havoc(config.havoc);
+        if seen.as_ref().is_some_and(|x| !x.insert(&fuzzer.buf)) {
+            continue;
+        }
+
+        match config.delimiter {
+            Some(delimiter) => {
+                out.write_all(&fuzzer.buf)?;
+                out.write_all(&[delimiter])?;
+            }
+            None => {
+                let len: u32 = fuzzer.buf.len().try_into().map_err(|_| {
+                    std::io::Error::other("input too large for a length prefix")
+                })?;
+                out.write_all(&len.to_le_bytes())?;
+                out.write_all(&fuzzer.buf)?;
+            }
+        }
+    }
+
+    out.flush()
+}
+
+/// Write inputs generated from the base seed to files in the `queue`
+/// directory within `dir` until `config.iterations` inputs are written, or
+/// forever, the way tools consuming an AFL style input directory expect.
+/// Every input is named `id_<n>_<hash>` after its index and hash, and first
+/// written elsewhere in `dir` and then renamed into the queue, so readers
+/// never see partial inputs. Every input is also added to the `index` file
+/// in `dir` along with its size and the `--replay` specification which
+/// reproduces it. Once the queue holds more than `config.queue_max_files`
+/// inputs or `config.queue_max_bytes` bytes of them, the oldest inputs are
+/// deleted from it, but stay in the index. With `config.dedup`, inputs seen
+/// recently are skipped, and their indices are left out.
+fn queue(config: &Config, dir: &PathBuf) -> std::io::Result<()> {
+    let queue = dir.join("queue");
+    std::fs::create_dir_all(&queue)?;
+    let mut index = std::io::BufWriter::new(std::fs::OpenOptions::new()
+        .create(true).append(true).open(dir.join("index"))?);
+    let partial = dir.join(".partial");
+    let seen = config.dedup.then(Seen::new);
+
+    let mut fuzzer = config.fuzzer(SEED);
+
+    // Inputs in the queue from oldest to newest, and their combined size
+    let mut written: std::collections::VecDeque<(PathBuf, u64)> =
+        Default::default();
+    let mut bytes = 0u64;
+    for input in 0..config.iterations.unwrap_or(u64::MAX) {
+        fuzzer.pick_swarm(config.swarm, input);
+        fuzzer.buf.clear();
+        fuzzer.start();
+        fuzzer.havoc(config.havoc);
+        if seen.as_ref().is_some_and(|x| !x.insert(&fuzzer.buf)) {
+            continue;
+        }
+
+        let name = format!("id_{:09}_{:016x}", input, hash(&fuzzer.buf));
+        std::fs::write(&partial, &fuzzer.buf)?;
+        std::fs::rename(&partial, queue.join(&name))?;
+        writeln!(index, "{}\t{}\t{:#x}:{}", name, fuzzer.buf.len(), SEED,
+            input)?;
+
+        written.push_back((queue.join(&name), fuzzer.buf.len() as u64));
+        bytes += fuzzer.buf.len() as u64;
+        // The newest input is kept even if it's larger than the limit
+        while written.len() > config.queue_max_files.unwrap_or(usize::MAX) ||
+                (written.len() > 1 &&
+                    bytes > config.queue_max_bytes.unwrap_or(u64::MAX)) {
+            let (path, size) = written.pop_front().unwrap();
+            std::fs::remove_file(&path)?;
+            bytes -= size;
+        }
+
+        // Keep the index current for readers following along
+        if (input & 0xfff) == 0xfff {
+            index.flush()?;
+        }
+    }
+
+    index.flush()
+}
+
+/// Number of inputs checked by `--oracle` when `--iterations` isn't given
+const ORACLE_ITERATIONS: u64 = 1000;
+
+/// Number of rules `--oracle` reports as the most often involved in rejected
+/// inputs
+const ORACLE_RULES: usize = 10;
+
+/// Check inputs generated from the base seed with the shell command `cmd`,
+/// and report how many it accepts. The input is given to the command on
+/// stdin, or in a file whose path replaces `@@` in the command. In `--trace`
+/// builds the rules used by every input are known, and the ones most often
+/// involved in rejected inputs are reported too.
+fn oracle(config: &Config, cmd: &str) -> std::io::Result<()> {
+    let mut fuzzer = config.fuzzer(SEED);
+    let file = std::env::temp_dir()
+        .join(format!("fzero_oracle_{}", std::process::id()));
+    let cmd = cmd.replace("@@", &file.display().to_string());
+    trace_silence();
+
+    // Number of inputs checked and accepted, and for every rule the number
+    // of inputs checked and rejected which used it
+    let (mut checked, mut accepted) = (0u64, 0u64);
+    let mut rules: std::collections::BTreeMap<&str, (u64, u64)> =
+        Default::default();
+    let iterations = config.iterations.unwrap_or(ORACLE_ITERATIONS);
+    for index in 0..iterations {
+        fuzzer.pick_swarm(config.swarm, index);
+        fuzzer.buf.clear();
+        fuzzer.start();
+        fuzzer.havoc(config.havoc);
+        if index % config.oracle_every != 0 {
+            continue;
+        }
+
+        std::fs::write(&file, &fuzzer.buf)?;
+        let status = std::process::Command::new("sh").arg("-c").arg(&cmd)
+            .stdin(std::fs::File::open(&file)?)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()?;
+        checked += 1;
+        accepted += status.success() as u64;
+        for rule in trace_rules().into_iter().flatten() {
+            let counts = rules.entry(rule).or_default();
+            counts.0 += 1;
+            counts.1 += !status.success() as u64;
+        }
+    }
+    let _ = std::fs::remove_file(&file);
+
+    eprintln!("Oracle accepted {} of {} inputs ({:.2}%)", accepted, checked,
+        accepted as f64 / checked.max(1) as f64 * 100.);
+    if trace_rules().is_none() {
+        eprintln!("Build with --trace to find the rules involved in \
+                   rejected inputs");
+        return Ok(());
+    }
+
+    // Rank the rules by how often the inputs using them are rejected
+    let mut ranked: Vec<_> = rules.into_iter()
+        .filter(|(_, (_, rejected))| *rejected > 0).collect();
+    ranked.sort_by(|a, b| {
+        let rate = |(used, rejected): (u64, u64)| {
+            rejected as f64 / used as f64
+        };
+        rate(b.1).total_cmp(&rate(a.1)).then(b.1 .1.cmp(&a.1 .1))
+    });
+    if !ranked.is_empty() {
+        eprintln!("Rules most often involved in rejected inputs:");
+    }
+    for (rule, (used, rejected)) in ranked.into_iter().take(ORACLE_RULES) {
+        eprintln!("    {}: {} of {} inputs using it rejected ({:.2}%)", rule,
+            rejected, used, rejected as f64 / used as f64 * 100.);
+    }
+    Ok(())
+}
+
+/// Magic at the start of the `--mmap` ring, written once the rest of the
+/// header is ready
+const RING_MAGIC: u64 = u64::from_le_bytes(*b"FZRORING");
+
+/// Offsets of the `u64` fields of the ring header: the capacity in bytes, the
+/// total number of bytes written by us and the total number of bytes
+/// consumed by the reader. The heads are on cache lines of their own.
+const RING_CAPACITY: usize = 8;
+const RING_WRITE:    usize = 64;
+const RING_READ:     usize = 128;
+
+/// Offset of the data of the ring, after the header
+const RING_DATA: usize = 192;
+
+/// Length written in place of a record when the next one doesn't fit before
+/// the end of the ring, telling the reader to continue from the start
+const RING_WRAP: u32 = u32::MAX;
+
+#[cfg(unix)]
+extern "C" {
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32,
+        offset: i64) -> *mut u8;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+}
+
+/// Write inputs generated from the base seed into a ring buffer in the file
+/// at `path`, which is mapped into memory and shared with a reader, until
+/// `config.iterations` inputs are written or forever. Each input is a record
+/// of its length as a little endian `u32` followed by its bytes, padded to a
+/// multiple of 8 bytes. We wait for the reader whenever the ring is full.
+#[cfg(unix)]
+fn ring(config: &Config, path: &PathBuf) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::AtomicU64;
+
+    const PROT_READ:  i32 = 1;
+    const PROT_WRITE: i32 = 2;
+    const MAP_SHARED: i32 = 1;
+
+    let capacity = config.mmap_size;
+    let len = RING_DATA + capacity;
+    let file = std::fs::OpenOptions::new().read(true).write(true)
+        .create(true).truncate(true).open(path)?;
+    file.set_len(len as u64)?;
+    let map = unsafe {
+        mmap(std::ptr::null_mut(), len, PROT_READ | PROT_WRITE, MAP_SHARED,
+            file.as_raw_fd(), 0)
+    };
+    if map as isize == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    // The file starts out zeroed, so both heads start at zero
+    let field = |offset: usize| unsafe {
+        &*(map.add(offset) as *const AtomicU64)
+    };
+    let (write, read) = (field(RING_WRITE), field(RING_READ));
+    field(RING_CAPACITY).store(capacity as u64, Ordering::Relaxed);
+    field(0).store(RING_MAGIC, Ordering::Release);
+
+    let mut fuzzer = config.fuzzer(SEED);
+
+    let mut head = 0u64;
+    for index in 0..config.iterations.unwrap_or(u64::MAX) {
+        fuzzer.pick_swarm(config.swarm, index);
+        fuzzer.buf.clear();
+        fuzzer.start();
+        fuzzer.havoc(config.havoc);
+
+        let len: u32 = fuzzer.buf.len().try_into().ok()
+            .filter(|&x| x != RING_WRAP).ok_or_else(|| {
+                std::io::Error::other("input too large for a length prefix")
+            })?;
+        let record = (4 + fuzzer.buf.len() + 7) & !7;
+        if record > capacity {
+            return Err(std::io::Error::other(format!(
+                "input of {} bytes doesn't fit in the ring, increase \
+                 --mmap-size", fuzzer.buf.len())));
+        }
+
+        // Skip the rest of the ring if the record doesn't fit before its
+        // end. Records are aligned, so there's always room for the marker.
+        let mut offset = (head % capacity as u64) as usize;
+        let skip = if capacity - offset < record { capacity - offset }
+            else { 0 };
+
+        // Wait for the reader to make room
+        while head + (skip + record) as u64 - read.load(Ordering::Acquire) >
+                capacity as u64 {
+            std::thread::yield_now();
+        }
+
+        unsafe {
+            let data = map.add(RING_DATA);
+            if skip > 0 {
+                (data.add(offset) as *mut u32).write(RING_WRAP.to_le());
+                offset = 0;
+            }
+            (data.add(offset) as *mut u32).write(len.to_le());
+            std::ptr::copy_nonoverlapping(fuzzer.buf.as_ptr(),
+                data.add(offset + 4), fuzzer.buf.len());
+        }
+
+        // Publish the record
+        head += (skip + record) as u64;
+        write.store(head, Ordering::Release);
+    }
+
+    unsafe { munmap(map, len); }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn ring(_config: &Config, _path: &PathBuf) -> std::io::Result<()> {
+    Err(std::io::Error::other("--mmap is only supported on Unix"))
+}
+
+/// Build an Ethernet frame holding a TCP segment from 10.0.0.1 to 10.0.0.2
+/// with `payload` at sequence number `seq`
+fn tcp_packet(sport: u16, dport: u16, seq: u32, payload: &[u8]) -> Vec<u8> {
+    const SRC: [u8; 4] = [10, 0, 0, 1];
+    const DST: [u8; 4] = [10, 0, 0, 2];
+
+    let mut packet = Vec::with_capacity(14 + 20 + 20 + payload.len());
+
+    // Ethernet header with locally administered addresses
+    packet.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+    packet.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    packet.extend_from_slice(&0x0800u16.to_be_bytes());
+
+    // IPv4 header
+    let ip = packet.len();
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&((20 + 20 + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+    packet.extend_from_slice(&SRC);
+    packet.extend_from_slice(&DST);
+    let checksum = internet_checksum(&[&packet[ip..]]);
+    packet[ip + 10..ip + 12].copy_from_slice(&checksum.to_be_bytes());
+
+    // TCP header with PSH and ACK set
+    let tcp = packet.len();
+    packet.extend_from_slice(&sport.to_be_bytes());
+    packet.extend_from_slice(&dport.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&1u32.to_be_bytes());
+    packet.extend_from_slice(&[0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+    packet.extend_from_slice(payload);
+
+    // The TCP checksum also covers a pseudo-header of the addresses,
+    // protocol and segment length
+    let mut pseudo = Vec::with_capacity(12);
+    pseudo.extend_from_slice(&SRC);
+    pseudo.extend_from_slice(&DST);
+    pseudo.extend_from_slice(&[0, 6]);
+    pseudo.extend_from_slice(&((packet.len() - tcp) as u16).to_be_bytes());
+    let checksum = internet_checksum(&[&pseudo, &packet[tcp..]]);
+    packet[tcp + 16..tcp + 18].copy_from_slice(&checksum.to_be_bytes());
+
+    packet
+}
+
+/// Compute the ones' complement checksum used by IP and TCP over the
+/// concatenation of `parts`, each of which but the last has an even length
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u64;
+    for part in parts {
+        for word in part.chunks(2) {
+            let hi = word[0] as u64;
+            let lo = word.get(1).copied().unwrap_or(0) as u64;
+            sum += hi << 8 | lo;
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Combine the statistics published by every thread
+fn merge(shared: &[Shared]) -> Stats {
+    let mut stats = Stats::new();
+    for shared in shared.iter() {
+        stats.merge(&shared.0.lock().unwrap());
+    }
+    stats
+}
+
+/// Report `stats` gathered over `elapsed` seconds, also to `log` and
+/// `metrics`. `last` is set for the final summary once generation has
+/// stopped.
+fn report(config: &Config, stats: &Stats, elapsed: f64, log: &mut Log,
+        metrics: Option<&Metrics>, last: bool) {
+    let mib_per_sec = stats.bytes as f64 / elapsed / 1024. / 1024.;
+    let inputs_per_sec = stats.inputs as f64 / elapsed;
+    if last {
+        println!("Generated {} inputs ({} bytes) in {:.3} seconds",
+            stats.inputs, stats.bytes, elapsed);
+    }
+    let duplicates = if config.dedup {
+        format!(" | duplicates: {} ({:.2}%)", stats.duplicates,
+            stats.duplicate_rate() * 100.)
+    } else {
+        String::new()
+    };
+    println!("MiB/sec: {:12.4} | inputs/sec: {:12.1} | \
+              size mean: {:.1} median: {} max: {}{}",
+        mib_per_sec, inputs_per_sec, stats.mean(), stats.median(), stats.max,
+        duplicates);
+
+    let fields = format!("\"elapsed\":{:.3},\"inputs\":{},\"bytes\":{},\
+                          \"mib_per_sec\":{:.4},\"inputs_per_sec\":{:.1},\
+                          \"mean_size\":{:.1},\"median_size\":{},\
+                          \"max_size\":{},\"duplicates\":{}",
+        elapsed, stats.inputs, stats.bytes, mib_per_sec, inputs_per_sec,
+        stats.mean(), stats.median(), stats.max, stats.duplicates);
+    if config.json_stats {
+        eprintln!("{{{},\"final\":{}}}", fields, last);
+    }
+    log.event(if last { "done" } else { "stats" }, &fields);
+    if let Some(metrics) = metrics {
+        metrics.update(&[
+            ("inputs_total", "Number of inputs generated",
+                stats.inputs as f64),
+            ("bytes_total", "Number of bytes generated", stats.bytes as f64),
+            ("inputs_per_second", "Inputs generated per second",
+                inputs_per_sec),
+            ("mib_per_second", "MiB generated per second", mib_per_sec),
+            ("max_input_size_bytes", "Size of the largest input",
+                stats.max as f64),
+            ("duplicates_total", "Number of duplicate inputs, with --dedup",
+                stats.duplicates as f64),
+        ]);
+    }
+
+    if last && config.histogram {
+        histogram(stats);
+    }
+}
+
+/// Print the distribution of input sizes, by power of two, and how many of
+/// the inputs were cut short by the depth limit
+fn histogram(stats: &Stats) {
+    // Sizes of zero and one get a row each, then one per power of two
+    let mut rows = [0u64; 65];
+    for (bucket, &count) in stats.sizes.iter().enumerate() {
+        let size = Stats::bucket_size(bucket);
+        rows[64 - size.leading_zeros() as usize] += count;
+    }
+    let first = rows.iter().position(|&x| x > 0).unwrap_or(0);
+    let used = rows.iter().rposition(|&x| x > 0).map_or(0, |x| x + 1);
+    let most = rows.iter().copied().max().unwrap_or(0).max(1);
+
+    println!("Input sizes:");
+    for (row, &count) in rows.iter().enumerate().take(used).skip(first) {
+        let range = match row {
+            0 | 1 => row.to_string(),
+            _ => format!("{}-{}", 1u64 << (row - 1), (1u128 << row) - 1),
+        };
+        println!("{:>20} bytes: {:12} ({:6.2}%) {}", range, count,
+            count as f64 * 100. / stats.inputs.max(1) as f64,
+            "*".repeat((count * 40).div_ceil(most) as usize));
+    }
+    println!("Inputs cut off by the depth limit: {} ({:.2}%), {:.2} cut off \
+              expansions per input", stats.truncated,
+        stats.truncated as f64 * 100. / stats.inputs.max(1) as f64,
+        stats.cutoffs as f64 / stats.inputs.max(1) as f64);
+}
+
+/// Generate `limit` inputs with `fuzzer`, corrupting `havoc` percent of them
+/// and swarm testing every `swarm` inputs, and periodically publish
+/// statistics about them to `shared`. Inputs already in `seen` are only
+/// counted as duplicates. Stops early when `stop` is set, and drops `done`
+/// once finished.
+fn worker(mut fuzzer: Fuzzer, havoc: u32, swarm: Option<u64>, limit: u64,
+        shared: &Shared, stop: &AtomicBool, seen: Option<&Seen>,
+        done: Sender<()>) {
+    let mut stats = Stats::new();
+    for iters in 1..=limit {
+        fuzzer.pick_swarm(swarm, iters - 1);
+        fuzzer.buf.clear();
+        fuzzer.start();
+        fuzzer.havoc(havoc);
+        if seen.map_or(true, |x| x.insert(&fuzzer.buf)) {
+            stats.record(fuzzer.buf.len() as u64, fuzzer.cutoffs);
+        } else {
+            stats.duplicates += 1;
+        }
+
+        // Filter to reduce the amount of times the statistics are published
+        if (iters & 0xfff) == 0 {
+            shared.0.lock().unwrap().clone_from(&stats);
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+    }
+
+    shared.0.lock().unwrap().clone_from(&stats);
+}
+"#;
+
+/// Program which drives a target binary with generated inputs, using AFL
+/// style coverage feedback to keep the derivation trees of inputs which
+/// found new edges and mutating and splicing those
+pub const FEEDBACK: &str = r#"
+#![allow(unused)]
+use std::cell::{Cell, UnsafeCell};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Base random seed
+const SEED: u64 = __SEED__;
+
+/// Runtime options for the generated program
+struct Config {
+    /// Number of seconds between statistics reports
+    interval: f64,
+
+    /// Stop after running this many inputs
+    iterations: Option<u64>,
+
+    /// Stop after this many seconds
+    duration: Option<f64>,
+
+    /// Size of the coverage bitmap shared with the target
+    map_size: usize,
+
+    /// Directory to save inputs which found new coverage to
+    corpus: Option<PathBuf>,
+
+    /// Maximum expansion depth
+    max_depth: usize,
+
+    /// Run the target through an AFL style forkserver rather than spawning
+    /// it for every input
+    forkserver: bool,
+
+    /// Directory to save crashing inputs to
+    crashes: PathBuf,
+
+    /// Also treat a non-zero exit code as a crash, rather than only being
+    /// killed by a signal
+    crash_on_nonzero: bool,
+
+    /// Milliseconds a single run of the target may take before it's killed
+    /// and counted as a hang
+    timeout: u64,
+
+    /// Directory to save hanging inputs to
+    hangs: PathBuf,
+
+    /// Generate fresh inputs on a thread of their own, handing them over
+    /// through a ring of this many slots
+    generator: Option<usize>,
+
+    /// Append events and statistics as json lines to this file
+    log: Option<PathBuf>,
+
+    /// Serve Prometheus metrics over HTTP on this address
+    metrics: Option<String>,
+
+    /// Target command line. An argument of `@@` is replaced with the path
+    /// of a file holding the input, otherwise the input goes to stdin.
+    target: Vec<String>,
+}
+
+impl Config {
+    /// Parse the options from the command line, exiting on errors
+    fn parse() -> Self {
+        let mut config = Config {
+            interval:   1.,
+            iterations: None,
+            duration:   None,
+            map_size:   1 << 16,
+            corpus:     None,
+            max_depth:  env_max_depth(),
+            forkserver: false,
+            crashes:    PathBuf::from("crashes"),
+            crash_on_nonzero: false,
+            timeout:    1000,
+            hangs:      PathBuf::from("hangs"),
+            generator:  None,
+            log:        None,
+            metrics:    None,
+            target:     Vec::new(),
+        };
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--stats-interval" => config.interval = value(&mut args, &arg),
+                "--iterations" =>
+                    config.iterations = Some(value(&mut args, &arg)),
+                "--duration" => config.duration = Some(value(&mut args, &arg)),
+                "--map-size" => config.map_size = value(&mut args, &arg),
+                "--corpus" => config.corpus = Some(value(&mut args, &arg)),
+                "--max-depth" => config.max_depth = value(&mut args, &arg),
+                "--forkserver" => config.forkserver = true,
+                "--crashes" => config.crashes = value(&mut args, &arg),
+                "--crash-on-nonzero" => config.crash_on_nonzero = true,
+                "--timeout" => config.timeout = value(&mut args, &arg),
+                "--hangs" => config.hangs = value(&mut args, &arg),
+                "--generator-thread" => config.generator = Some(1024),
+                "--generator-slots" =>
+                    config.generator = Some(value(&mut args, &arg)),
+                "--log-json" => config.log = Some(value(&mut args, &arg)),
+                "--metrics" => config.metrics = Some(value(&mut args, &arg)),
+                "--" => {
+                    config.target = args.by_ref().collect();
+                    break;
+                }
+                "-h" | "--help" => {
+                    print_usage(&mut std::io::stdout());
+                    std::process::exit(0);
+                }
+                _ => usage(&format!("unknown option `{}`", arg)),
+            }
+        }
+
+        if config.target.is_empty() {
+            usage("no target command given");
+        }
+        if !(config.interval > 0.) {
+            usage("--stats-interval must be positive");
+        }
+        if config.map_size == 0 {
+            usage("--map-size must be at least 1");
+        }
+        if config.timeout == 0 {
+            usage("--timeout must be at least 1");
+        }
+        if config.generator == Some(0) {
+            usage("--generator-slots must be at least 1");
+        }
+        config
+    }
+}
+
+/// Parse the value following the option `name`, exiting on errors
+fn value<T: FromStr>(args: &mut impl Iterator<Item = String>, name: &str)
+        -> T {
+    args.next().and_then(|x| x.parse().ok()).unwrap_or_else(|| {
+        usage(&format!("`{}` requires a valid value", name))
+    })
+}
+
+/// Report a command line error along with the usage and exit
+fn usage(err: &str) -> ! {
+    eprintln!("error: {}", err);
+    print_usage(&mut std::io::stderr());
+    std::process::exit(1);
+}
+
+/// Write the usage to `out`
+fn print_usage(out: &mut impl Write) {
+    let _ = writeln!(out, "usage: {} [--iterations N] [--duration SECS] \
+               [--stats-interval SECS] [--map-size N] [--corpus DIR] \
+               [--max-depth N] [--forkserver] [--crashes DIR] \
+               [--crash-on-nonzero] [--timeout MS] [--hangs DIR] \
+               [--generator-thread] [--generator-slots N] \
+               [--log-json FILE] [--metrics ADDR] -- TARGET [ARGS...]",
+        std::env::args().next().unwrap_or_default());
+}
+
+extern "C" {
+    fn shmget(key: i32, size: usize, flags: i32) -> i32;
+    fn shmat(id: i32, addr: *const u8, flags: i32) -> *mut u8;
+    fn shmctl(id: i32, cmd: i32, buf: *mut u8) -> i32;
+    fn pipe2(fds: *mut i32, flags: i32) -> i32;
+    fn dup2(old: i32, new: i32) -> i32;
+    fn kill(pid: i32, sig: i32) -> i32;
+}
+
+const O_CLOEXEC: i32 = 0o2000000;
+const SIGKILL:   i32 = 9;
+
+/// File descriptor an AFL forkserver reads commands from. It writes
+/// statuses to the one after it.
+const FORKSRV_FD: i32 = 198;
+
+const IPC_PRIVATE: i32 = 0;
+const IPC_CREAT:   i32 = 0o1000;
+const IPC_EXCL:    i32 = 0o2000;
+const IPC_RMID:    i32 = 0;
+
+/// Flags of the hello of AFL++ forkservers: the hello carries options, the
+/// target wants its inputs in shared memory, and the target has a dictionary
+/// to hand over
+const FS_OPT_ENABLED:     u32 = 0x80000001;
+const FS_OPT_SHDMEM_FUZZ: u32 = 0x01000000;
+const FS_OPT_AUTODICT:    u32 = 0x10000000;
+
+/// Size of the shared memory holding the input of targets which read it from
+/// there, a length followed by the input. Like with AFL++, longer inputs are
+/// truncated.
+const TESTCASE_SIZE: usize = 4 + 1024 * 1024;
+
+/// SysV shared memory, such as the coverage bitmap handed to the target
+/// through the `__AFL_SHM_ID` environment variable like AFL does
+struct SharedMemory {
+    /// Shared memory identifier
+    id: i32,
+
+    /// Where the memory is mapped in our address space
+    ptr: *mut u8,
+
+    /// Size of the memory in bytes
+    size: usize,
+}
+
+impl SharedMemory {
+    /// Create new zeroed shared memory of `size` bytes
+    fn new(size: usize) -> Self {
+        unsafe {
+            let id = shmget(IPC_PRIVATE, size, IPC_CREAT | IPC_EXCL | 0o600);
+            if id < 0 {
+                panic!("shmget failed: {}", std::io::Error::last_os_error());
+            }
+            let ptr = shmat(id, std::ptr::null(), 0);
+            if ptr as isize == -1 {
+                shmctl(id, IPC_RMID, std::ptr::null_mut());
+                panic!("shmat failed: {}", std::io::Error::last_os_error());
+            }
+            SharedMemory { id, ptr, size }
+        }
+    }
+
+    /// Get the contents of the memory
+    fn get(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.size) }
+    }
+
+    /// Zero out the memory, such as the bitmap before running another input
+    fn clear(&mut self) {
+        unsafe { std::ptr::write_bytes(self.ptr, 0, self.size); }
+    }
+
+    /// Write `input` prefixed with its length, for targets reading their
+    /// input from shared memory, truncating what doesn't fit
+    fn write_testcase(&mut self, input: &[u8]) {
+        let len = input.len().min(self.size - 4);
+        unsafe {
+            std::ptr::write_unaligned(self.ptr as *mut u32, len as u32);
+            std::ptr::copy_nonoverlapping(input.as_ptr(), self.ptr.add(4),
+                len);
+        }
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        unsafe { shmctl(self.id, IPC_RMID, std::ptr::null_mut()); }
+    }
+}
+
+/// Kills runs of the target which take longer than the timeout. This runs on
+/// a thread of its own, so waiting for the target can simply block.
+struct Watchdog {
+    /// Process to kill, negated for a process group, and when to kill it
+    /// for the run in progress
+    armed: Arc<Mutex<Option<(i32, Instant)>>>,
+
+    /// Set once the watchdog killed the run in progress
+    fired: Arc<AtomicBool>,
+
+    /// How long a run may take
+    timeout: Duration,
+}
+
+impl Watchdog {
+    /// Start a watchdog killing runs after `timeout`
+    fn new(timeout: Duration) -> Self {
+        let armed = Arc::new(Mutex::new(None::<(i32, Instant)>));
+        let fired = Arc::new(AtomicBool::new(false));
+
+        // Check a few times per timeout, which keeps hangs from running much
+        // longer than the timeout without spinning
+        let tick = (timeout / 20).max(Duration::from_millis(1));
+        let (thread_armed, thread_fired) = (armed.clone(), fired.clone());
+        std::thread::spawn(move || loop {
+            std::thread::sleep(tick);
+            let mut armed = thread_armed.lock().unwrap();
+            if let Some((pid, deadline)) = *armed {
+                if Instant::now() >= deadline {
+                    unsafe { kill(pid, SIGKILL); }
+                    thread_fired.store(true, Ordering::SeqCst);
+                    *armed = None;
+                }
+            }
+        });
+
+        Watchdog { armed, fired, timeout }
+    }
+
+    /// Start timing a run of `pid`, which is negated to kill a whole process
+    /// group
+    fn arm(&self, pid: i32) {
+        self.fired.store(false, Ordering::SeqCst);
+        let deadline = Instant::now() + self.timeout;
+        *self.armed.lock().unwrap() = Some((pid, deadline));
+    }
+
+    /// Stop timing the run in progress, returning `true` if it was killed
+    fn disarm(&self) -> bool {
+        *self.armed.lock().unwrap() = None;
+        self.fired.load(Ordering::SeqCst)
+    }
+}
+
+/// Create a pipe, returning its read and write ends. Neither end is
+/// inherited by child processes.
+fn pipe() -> std::io::Result<(File, File)> {
+    let mut fds = [0i32; 2];
+    unsafe {
+        if pipe2(fds.as_mut_ptr(), O_CLOEXEC) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok((File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])))
+    }
+}
+
+/// The forkserver of an AFL instrumented target. The target is started once
+/// and stops before `main`, then forks a fresh copy of itself for every
+/// input we ask it to run.
+struct Forkserver {
+    /// The target process acting as the forkserver
+    process: Child,
+
+    /// Pipe we request runs on
+    control: File,
+
+    /// Pipe the forkserver reports the pid and exit status of every run on
+    status: File,
+
+    /// Input file which is the stdin of the target, when the target reads
+    /// its input from stdin. The forked copies share its file offset.
+    stdin: Option<File>,
+
+    /// Shared memory the input is written to instead, when the target asked
+    /// for its inputs there in its hello
+    testcase: Option<SharedMemory>,
+}
+
+impl Forkserver {
+    /// Run the forkserver's target with `input`, which goes in `path` when
+    /// the target reads from a file and in shared memory when it asked for
+    /// that. Returns `None` if the run hung and was killed by `watchdog`.
+    fn run(&mut self, input: &[u8], path: &PathBuf, watchdog: &Watchdog)
+            -> std::io::Result<Option<ExitStatus>> {
+        match (&mut self.testcase, &mut self.stdin) {
+            (Some(testcase), _) => testcase.write_testcase(input),
+            (None, Some(file)) => {
+                file.set_len(0)?;
+                file.seek(SeekFrom::Start(0))?;
+                file.write_all(input)?;
+                file.seek(SeekFrom::Start(0))?;
+            }
+            (None, None) => std::fs::write(path, input)?,
+        }
+
+        // Request a run, we get the pid of the copy running the input back
+        // immediately and its wait status once it exits
+        let died = |_| std::io::Error::other("forkserver died");
+        self.control.write_all(&0u32.to_ne_bytes()).map_err(died)?;
+        let mut pid = [0u8; 4];
+        self.status.read_exact(&mut pid).map_err(died)?;
+        watchdog.arm(i32::from_ne_bytes(pid));
+        let mut status = [0u8; 4];
+        let result = self.status.read_exact(&mut status);
+        let hung = watchdog.disarm();
+        result.map_err(died)?;
+        Ok((!hung).then(|| ExitStatus::from_raw(i32::from_ne_bytes(status))))
+    }
+}
+
+impl Drop for Forkserver {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Target program which inputs are run against
+struct Target {
+    /// Target command line
+    args: Vec<String>,
+
+    /// File inputs are written to when the target reads them from a file,
+    /// or from stdin through a forkserver
+    input_path: Option<PathBuf>,
+
+    /// Coverage bitmap shared with the target
+    bitmap: SharedMemory,
+
+    /// File the stderr of the target goes to, which holds the stderr of
+    /// the last run
+    stderr: File,
+
+    /// Forkserver running the target, if we're using one
+    forkserver: Option<Forkserver>,
+
+    /// Watchdog killing runs which hang
+    watchdog: Watchdog,
+}
+
+impl Target {
+    /// Returns `true` if the target reads its input from a file
+    fn reads_file(&self) -> bool {
+        self.args.iter().any(|x| x == "@@")
+    }
+
+    /// Get the command running the target, with `@@` replaced by the input
+    /// file
+    fn command(&self) -> std::io::Result<Command> {
+        let mut command = Command::new(&self.args[0]);
+        command
+            .stdout(Stdio::null())
+            .stderr(self.stderr.try_clone()?)
+            .env("__AFL_SHM_ID", self.bitmap.id.to_string())
+            .env("AFL_MAP_SIZE", self.bitmap.size.to_string());
+        for arg in &self.args[1..] {
+            match &self.input_path {
+                Some(path) if arg == "@@" => command.arg(path),
+                _ => command.arg(arg),
+            };
+        }
+        Ok(command)
+    }
+
+    /// Get what the target wrote to stderr during the last run
+    fn last_stderr(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut stderr = Vec::new();
+        self.stderr.seek(SeekFrom::Start(0))?;
+        self.stderr.read_to_end(&mut stderr)?;
+        Ok(stderr)
+    }
+
+    /// Start the target as a forkserver, which all following runs go
+    /// through
+    fn start_forkserver(&mut self) -> std::io::Result<()> {
+        let (control_read, control) = pipe()?;
+        let (status, status_write) = pipe()?;
+
+        // Inputs read from stdin come from the input file as well, we can't
+        // hand a pipe to every forked copy
+        let mut command = self.command()?;
+        let stdin = if self.reads_file() {
+            command.stdin(Stdio::null());
+            None
+        } else {
+            let file = OpenOptions::new().read(true).write(true).create(true)
+                .truncate(true).open(self.input_path.as_ref().unwrap())?;
+            command.stdin(file.try_clone()?);
+            Some(file)
+        };
+
+        // Targets built to read their inputs from shared memory need it
+        // from the start, whether they end up asking for it or not
+        let testcase = SharedMemory::new(TESTCASE_SIZE);
+        command.env("__AFL_SHM_FUZZ_ID", testcase.id.to_string());
+
+        // Hand the pipes to the forkserver where it expects them. Newer
+        // versions of AFL++ speak a different protocol unless asked not to.
+        let fds = (control_read.as_raw_fd(), status_write.as_raw_fd());
+        command.env("AFL_OLD_FORKSERVER", "1");
+        unsafe {
+            command.pre_exec(move || {
+                if dup2(fds.0, FORKSRV_FD) < 0 ||
+                        dup2(fds.1, FORKSRV_FD + 1) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let process = command.spawn()?;
+        drop(control_read);
+        drop(status_write);
+
+        let mut server = Forkserver { process, control, status, stdin,
+            testcase: None };
+
+        // The forkserver says hello once it's up. AFL++ targets may ask for
+        // their inputs in shared memory, or offer a dictionary, and then
+        // wait for the options we accept. We take the shared memory and
+        // leave the dictionary.
+        let mut hello = [0u8; 4];
+        server.status.read_exact(&mut hello).map_err(|_| {
+            std::io::Error::other(
+                "target didn't start a forkserver, is it built with afl-cc?")
+        })?;
+        let hello = u32::from_ne_bytes(hello);
+        if hello & FS_OPT_ENABLED == FS_OPT_ENABLED &&
+                hello & (FS_OPT_SHDMEM_FUZZ | FS_OPT_AUTODICT) != 0 {
+            let mut accepted = FS_OPT_ENABLED;
+            if hello & FS_OPT_SHDMEM_FUZZ != 0 {
+                accepted |= FS_OPT_SHDMEM_FUZZ;
+                server.testcase = Some(testcase);
+                eprintln!("Target reads its inputs from shared memory");
+            }
+            server.control.write_all(&accepted.to_ne_bytes())?;
+        }
+        self.forkserver = Some(server);
+        Ok(())
+    }
+
+    /// Run the target with `input`, leaving its coverage in the bitmap.
+    /// Returns `None` if the run hung and was killed.
+    fn run(&mut self, input: &[u8]) -> std::io::Result<Option<ExitStatus>> {
+        self.bitmap.clear();
+
+        // The forked copies of a forkserver share the file offset, so this
+        // works for them too
+        self.stderr.set_len(0)?;
+        self.stderr.seek(SeekFrom::Start(0))?;
+
+        if let Some(server) = &mut self.forkserver {
+            return server.run(input, self.input_path.as_ref().unwrap(),
+                &self.watchdog);
+        }
+
+        // The target gets a process group of its own, so a hang kills
+        // anything it started as well
+        let mut command = self.command()?;
+        command.process_group(0);
+        if let Some(path) = &self.input_path {
+            std::fs::write(path, input)?;
+            command.stdin(Stdio::null());
+        } else {
+            command.stdin(Stdio::piped());
+        }
+        let mut child = command.spawn()?;
+        self.watchdog.arm(-(child.id() as i32));
+
+        // The target may exit without reading all of its input. If it hangs
+        // without reading it the watchdog kills it, which gets us unstuck.
+        let mut result = Ok(());
+        if let Some(mut stdin) = child.stdin.take() {
+            result = match stdin.write_all(input) {
+                Err(err) if err.kind() !=
+                    std::io::ErrorKind::BrokenPipe => Err(err),
+                _ => Ok(()),
+            };
+        }
+        let status = child.wait();
+        let hung = self.watchdog.disarm();
+        result?;
+        Ok((!hung).then_some(status?))
+    }
+}
+
+/// Get the kind of crash the target had if it exited with `status`, eg.
+/// `signal6`. Non-zero exit codes only count with `crash_on_nonzero`.
+fn crash_kind(status: ExitStatus, crash_on_nonzero: bool) -> Option<String> {
+    match (status.signal(), status.code()) {
+        (Some(signal), _) => Some(format!("signal{}", signal)),
+        (_, Some(code)) if code != 0 && crash_on_nonzero => {
+            Some(format!("exit{}", code))
+        }
+        _ => None,
+    }
+}
+
+/// Crashing or hanging inputs found so far, which are saved to a directory
+struct Findings {
+    /// Directory the inputs are saved to
+    dir: PathBuf,
+
+    /// Edges hit by any input so far
+    virgin: Vec<bool>,
+
+    /// Kinds of findings so far, such as the signal of a crash
+    kinds: HashSet<String>,
+
+    /// Number of inputs saved
+    saved: usize,
+
+    /// Number of inputs found, including the ones which weren't saved
+    found: u64,
+}
+
+impl Findings {
+    /// Create an empty set of findings, saved to `dir`
+    fn new(dir: &PathBuf, map_size: usize) -> Self {
+        Findings {
+            dir:    dir.clone(),
+            virgin: vec![false; map_size],
+            kinds:  HashSet::new(),
+            saved:  0,
+            found:  0,
+        }
+    }
+
+    /// Record a finding of `kind` for `input`, which hit the edges in
+    /// `bitmap`. Only inputs which hit an edge no earlier finding did or are
+    /// of a new kind are saved, which weeds out most duplicates of the same
+    /// bug. They get a directory of their own holding the input, the
+    /// target's stderr and `info` on how the input came about, which is
+    /// returned if the input was saved.
+    fn record(&mut self, kind: &str, bitmap: &[u8], input: &[u8],
+            target: &mut Target, info: &str)
+            -> std::io::Result<Option<PathBuf>> {
+        self.found += 1;
+
+        let mut new = self.kinds.insert(kind.to_string());
+        for (idx, &hit) in bitmap.iter().enumerate() {
+            if hit != 0 && !self.virgin[idx] {
+                self.virgin[idx] = true;
+                new = true;
+            }
+        }
+        if !new {
+            return Ok(None);
+        }
+
+        let dir = self.dir.join(format!("{:06}_{}", self.saved, kind));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("input"), input)?;
+        std::fs::write(dir.join("stderr"), target.last_stderr()?)?;
+        std::fs::write(dir.join("info"), info)?;
+        self.saved += 1;
+        Ok(Some(dir))
+    }
+}
+
+/// An input which found new coverage, kept for mutation
+struct Entry {
+    /// Bytes of the input
+    input: Vec<u8>,
+
+    /// Derivation tree of the input
+    nodes: Vec<Node>,
+}
+
+/// Lock-free ring of inputs handed from a single generator thread to a single
+/// executor thread. Slots keep their buffers once emptied, so after warming
+/// up neither side allocates.
+struct Ring {
+    /// Inputs in the ring, only touched by the side which owns them
+    slots: Box<[UnsafeCell<Entry>]>,
+
+    /// Number of slots ever filled by the producer
+    head: AtomicUsize,
+
+    /// Number of slots ever emptied by the consumer
+    tail: AtomicUsize,
+}
+
+// Slots are only accessed by one side at a time, as handed over by the heads
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    /// Create an empty ring with `slots` slots
+    fn new(slots: usize) -> Self {
+        Ring {
+            slots: (0..slots).map(|_| UnsafeCell::new(Entry {
+                input: Vec::new(),
+                nodes: Vec::new(),
+            })).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Fill the next free slot with `fill`, returning `false` without
+    /// calling it if the ring is full. Must only be called by the producer.
+    fn push(&self, fill: impl FnOnce(&mut Entry)) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        if head - self.tail.load(Ordering::Acquire) == self.slots.len() {
+            return false;
+        }
+        fill(unsafe { &mut *self.slots[head % self.slots.len()].get() });
+        self.head.store(head + 1, Ordering::Release);
+        true
+    }
+
+    /// Pass the oldest filled slot to `take` and free it, returning `false`
+    /// without calling it if the ring is empty. Must only be called by the
+    /// consumer.
+    fn pop(&self, take: impl FnOnce(&Entry)) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        take(unsafe { &*self.slots[tail % self.slots.len()].get() });
+        self.tail.store(tail + 1, Ordering::Release);
+        true
+    }
+}
+
+/// Generate fresh inputs into `ring` until `stop` is set, waiting whenever
+/// the executor falls behind
+fn generator(ring: &Ring, stop: &AtomicBool, max_depth: usize) {
+    // Use a different stream of random numbers than the executor
+    let mut fuzzer = Fuzzer::seeded(SEED ^ 0x9e3779b97f4a7c15, max_depth,
+        Pool::default(), Vec::new());
+
+    while !stop.load(Ordering::Relaxed) {
+        fuzzer.buf.clear();
+        fuzzer.start();
+        while !ring.push(|entry| {
+            entry.input.clear();
+            entry.input.extend_from_slice(&fuzzer.buf);
+            entry.nodes.clear();
+            entry.nodes.extend_from_slice(&fuzzer.nodes);
+        }) {
+            if stop.load(Ordering::Relaxed) {
+                return;
+            }
+            std::thread::yield_now();
+        }
+    }
+}
+
+/// Create a new input in `fuzzer` by replacing a random subtree of `entry`.
+/// Half of the time the replacement is a subtree of the same fragment taken
+/// from `donor`, otherwise or if there is none it's a fresh expansion of the
+/// fragment.
+fn mutate(fuzzer: &mut Fuzzer, entry: &Entry, donor: &Entry) {
+    let target = fuzzer.rand() % entry.nodes.len();
+    let node = entry.nodes[target];
+
+    // Look for a subtree to splice in
+    let mut splice = None;
+    if fuzzer.rand() % 2 == 0 {
+        let candidates: Vec<usize> = (0..donor.nodes.len())
+            .filter(|&x| donor.nodes[x].fragment == node.fragment).collect();
+        if !candidates.is_empty() {
+            splice = Some(candidates[fuzzer.rand() % candidates.len()]);
+        }
+    }
+
+    // Get the replacement bytes along with their subtree, relative to the
+    // start of the replacement
+    let (bytes, nodes): (Vec<u8>, Vec<Node>) = match splice {
+        Some(idx) => {
+            let root = donor.nodes[idx];
+            let subtree = &donor.nodes[idx + 1..=idx + root.descendants];
+            (donor.input[root.start..root.end].to_vec(),
+             subtree.iter().map(|x| Node {
+                depth: x.depth - root.depth + node.depth,
+                start: x.start - root.start,
+                end:   x.end - root.start,
+                ..*x
+             }).collect())
+        }
+        None => {
+            fuzzer.buf.clear();
+            fuzzer.nodes.clear();
+            fuzzer.steps = 0;
+            fuzzer.expand(node.fragment, node.depth as usize);
+
+            // Skip the root of the expansion, it's the node being replaced
+            (fuzzer.buf.clone(), fuzzer.nodes[1..].to_vec())
+        }
+    };
+
+    // Build the new input and its derivation tree
+    let delta = bytes.len() as isize - (node.end - node.start) as isize;
+    let shift = |x: usize| (x as isize + delta) as usize;
+    let (removed, added) = (node.descendants, nodes.len());
+
+    fuzzer.buf.clear();
+    fuzzer.buf.extend_from_slice(&entry.input[..node.start]);
+    fuzzer.buf.extend_from_slice(&bytes);
+    fuzzer.buf.extend_from_slice(&entry.input[node.end..]);
+
+    fuzzer.nodes.clear();
+    for (idx, &x) in entry.nodes[..target].iter().enumerate() {
+        // Ancestors of the replaced node grow or shrink with it
+        if idx + x.descendants >= target {
+            fuzzer.nodes.push(Node {
+                end: shift(x.end),
+                descendants: x.descendants - removed + added,
+                ..x
+            });
+        } else {
+            fuzzer.nodes.push(x);
+        }
+    }
+    fuzzer.nodes.push(Node {
+        end: node.start + bytes.len(),
+        descendants: added,
+        ..node
+    });
+    fuzzer.nodes.extend(nodes.iter().map(|x| Node {
+        start: x.start + node.start,
+        end:   x.end + node.start,
+        ..*x
+    }));
+    fuzzer.nodes.extend(entry.nodes[target + 1 + removed..].iter()
+        .map(|x| Node { start: shift(x.start), end: shift(x.end), ..*x }));
+}
+
+fn main() {
+    let config = Config::parse();
+
+    // Inputs go in a file if the target asks for one with `@@`, or if it
+    // runs through a forkserver
+    let input_path = (config.forkserver ||
+            config.target.iter().any(|x| x == "@@")).then(|| {
+        std::env::temp_dir()
+            .join(format!(".fzero_input_{}", std::process::id()))
+    });
+    let stderr_path = std::env::temp_dir()
+        .join(format!(".fzero_stderr_{}", std::process::id()));
+    let stderr = OpenOptions::new().read(true).write(true).create(true)
+        .truncate(true).open(&stderr_path)
+        .expect("Failed to create stderr file");
+    let mut target = Target {
+        args: config.target.clone(),
+        input_path,
+        bitmap: SharedMemory::new(config.map_size),
+        stderr,
+        forkserver: None,
+        watchdog: Watchdog::new(Duration::from_millis(config.timeout)),
+    };
+    if config.forkserver {
+        if let Err(err) = target.start_forkserver() {
+            eprintln!("error: failed to start forkserver: {}", err);
+            if let Some(path) = &target.input_path {
+                let _ = std::fs::remove_file(path);
+            }
+            let _ = std::fs::remove_file(&stderr_path);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(corpus) = &config.corpus {
+        std::fs::create_dir_all(corpus).expect("Failed to create corpus");
+    }
+
+    // The seed is a string as json parsers lose precision on numbers above
+    // 2^53
+    let mut log = Log::open(config.log.as_deref());
+    let target_args: Vec<String> =
+        config.target.iter().map(|x| json_string(x)).collect();
+    log.event("start", &format!("\"seed\":\"{:#x}\",\"max_depth\":{},\
+        \"target\":[{}]", SEED, config.max_depth, target_args.join(",")));
+    let metrics = config.metrics.as_deref().map(Metrics::serve);
+
+    let mut fuzzer = Fuzzer::seeded(SEED, config.max_depth,
+        Pool::default(), Vec::new());
+
+    // Start generating fresh inputs ahead of time if asked to
+    let stop = Arc::new(AtomicBool::new(false));
+    let ring = config.generator.map(|slots| Arc::new(Ring::new(slots)));
+    let generator_thread = ring.clone().map(|ring| {
+        let (stop, max_depth) = (stop.clone(), config.max_depth);
+        std::thread::spawn(move || generator(&ring, &stop, max_depth))
+    });
+
+    // Edges seen so far by any input
+    let mut virgin = vec![false; config.map_size];
+    let mut edges = 0usize;
+    let mut corpus: Vec<Entry> = Vec::new();
+
+    let mut crashes = Findings::new(&config.crashes, config.map_size);
+    let mut hangs = Findings::new(&config.hangs, config.map_size);
+
+    let it = Instant::now();
+    let interval = Duration::from_secs_f64(config.interval);
+    let mut next = it + interval;
+    let mut execs = 0u64;
+    while config.iterations.map_or(true, |x| execs < x) &&
+            config.duration.map_or(true, |x| it.elapsed().as_secs_f64() < x) {
+        // Generate a fresh input a quarter of the time, otherwise mutate one
+        // which found new coverage
+        let origin = if corpus.is_empty() || fuzzer.rand() % 4 == 0 {
+            // Take the input from the generator thread when it has one
+            // ready, rather than waiting on it when the target is fast
+            let ready = ring.as_ref().is_some_and(|ring| ring.pop(|entry| {
+                fuzzer.buf.clear();
+                fuzzer.buf.extend_from_slice(&entry.input);
+                fuzzer.nodes.clear();
+                fuzzer.nodes.extend_from_slice(&entry.nodes);
+            }));
+            if !ready {
+                fuzzer.buf.clear();
+                fuzzer.start();
+            }
+            String::from("generated")
+        } else {
+            let (a, b) = (fuzzer.rand() % corpus.len(),
+                fuzzer.rand() % corpus.len());
+            mutate(&mut fuzzer, &corpus[a], &corpus[b]);
+            format!("mutated from corpus entry {} with donor {}", a, b)
+        };
+
+        let status = match target.run(&fuzzer.buf) {
+            Ok(status) => status,
+            Err(err) => {
+                eprintln!("error: failed to run target: {}", err);
+                std::process::exit(1);
+            }
+        };
+        execs += 1;
+
+        // Save crashes and hangs, they're never kept for mutation
+        let crash = match status {
+            Some(status) => crash_kind(status, config.crash_on_nonzero),
+            None => Some(String::from("hang")),
+        };
+        if let Some(kind) = &crash {
+            let status = status.map_or_else(|| {
+                format!("killed after {} ms", config.timeout)
+            }, |x| x.to_string());
+            let info = format!("status: {}\nseed: {:#x}\nexec: {}\n\
+                origin: {}\n", status, SEED, execs, origin);
+            let findings = if kind == "hang" { &mut hangs } else {
+                &mut crashes
+            };
+            let bitmap = target.bitmap.get().to_vec();
+            let saved = findings.record(kind, &bitmap, &fuzzer.buf,
+                &mut target, &info).expect("Failed to save finding");
+
+            // Only log findings which were saved, duplicates are just counted
+            if let Some(dir) = saved {
+                log.event(if kind == "hang" { "hang" } else { "crash" },
+                    &format!("\"exec\":{},\"kind\":{},\"status\":{},\
+                        \"origin\":{},\"path\":{}", execs,
+                        json_string(kind), json_string(&status),
+                        json_string(&origin),
+                        json_string(&dir.to_string_lossy())));
+            }
+        }
+
+        // Keep the input if it hit any edge we haven't seen before
+        let mut new = false;
+        for (idx, &hit) in target.bitmap.get().iter().enumerate() {
+            if hit != 0 && !virgin[idx] {
+                virgin[idx] = true;
+                edges += 1;
+                new = true;
+            }
+        }
+        if new && crash.is_none() {
+            if let Some(dir) = &config.corpus {
+                let path = dir.join(format!("{:08}", corpus.len()));
+                std::fs::write(path, &fuzzer.buf)
+                    .expect("Failed to save corpus input");
+            }
+            corpus.push(Entry {
+                input: fuzzer.buf.clone(),
+                nodes: fuzzer.nodes.clone(),
+            });
+            log.event("corpus", &format!("\"exec\":{},\"entry\":{},\
+                \"edges\":{},\"size\":{},\"origin\":{}", execs,
+                corpus.len() - 1, edges, fuzzer.buf.len(),
+                json_string(&origin)));
+        }
+
+        if Instant::now() >= next {
+            let fields = report(execs, corpus.len(), edges, &crashes, &hangs,
+                it.elapsed().as_secs_f64(), metrics.as_deref());
+            log.event("stats", &fields);
+            next += interval;
+        }
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    if let Some(thread) = generator_thread {
+        thread.join().expect("Generator thread panicked");
+    }
+
+    if let Some(path) = &target.input_path {
+        let _ = std::fs::remove_file(path);
+    }
+    let _ = std::fs::remove_file(&stderr_path);
+    let fields = report(execs, corpus.len(), edges, &crashes, &hangs,
+        it.elapsed().as_secs_f64(), metrics.as_deref());
+    log.event("done", &fields);
+}
+
+/// Report the progress of fuzzing after `elapsed` seconds, also to
+/// `metrics`, returning it as fields for the json log
+fn report(execs: u64, corpus: usize, edges: usize, crashes: &Findings,
+        hangs: &Findings, elapsed: f64, metrics: Option<&Metrics>)
+        -> String {
+    println!("execs: {:10} | execs/sec: {:10.1} | corpus: {:6} | \
+              edges: {:6} | crashes: {:4} | hangs: {:4} ({:.2}%)",
+        execs, execs as f64 / elapsed, corpus, edges, crashes.saved,
+        hangs.saved, hangs.found as f64 * 100. / execs.max(1) as f64);
+    if let Some(metrics) = metrics {
+        metrics.update(&[
+            ("execs_total", "Number of runs of the target", execs as f64),
+            ("execs_per_second", "Runs of the target per second",
+                execs as f64 / elapsed),
+            ("corpus_size", "Number of inputs kept for finding new edges",
+                corpus as f64),
+            ("edges", "Number of edges covered", edges as f64),
+            ("crashes_total", "Number of crashing inputs saved",
+                crashes.saved as f64),
+            ("crashes_found_total", "Number of crashing inputs found",
+                crashes.found as f64),
+            ("hangs_total", "Number of hanging inputs saved",
+                hangs.saved as f64),
+            ("hangs_found_total", "Number of hanging inputs found",
+                hangs.found as f64),
+        ]);
+    }
+    format!("\"elapsed\":{:.3},\"execs\":{},\"execs_per_sec\":{:.1},\
+             \"corpus\":{},\"edges\":{},\"crashes\":{},\"hangs\":{},\
+             \"crashes_found\":{},\"hangs_found\":{}", elapsed, execs,
+        execs as f64 / elapsed, corpus, edges, crashes.saved, hangs.saved,
+        crashes.found, hangs.found)
+}
+"#;
+
+/// Public API of the generated library, which lets other programs pull
+/// inputs out of the fuzzer
+pub const LIBRARY: &str = r#"
+#![allow(unused)]
+use std::cell::Cell;
+
+/// Default random seed, which produces the same inputs as `fzero generate`
+pub const SEED: u64 = __SEED__;
+
+impl Default for Fuzzer {
+    fn default() -> Self {
+        Self::new(SEED)
+    }
+}
+
+impl Fuzzer {
+    /// Create a new fuzzer seeded with `seed`. A seed of zero is replaced
+    /// with one, as xorshift gets stuck on zero.
+    pub fn new(seed: u64) -> Self {
+        Fuzzer::seeded(seed, MAX_DEPTH, Pool::default(), Vec::new())
+    }
+
+    /// Set the maximum expansion depth used for the following inputs, which
+    /// defaults to `MAX_DEPTH`. This has no effect on grammars built with
+    /// `--uniform-size`.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Save the state of the random number generator. Restoring it with
+    /// `restore_state` makes the fuzzer generate the same inputs again from
+    /// this point on, as long as the max depth is the same.
+    pub fn save_state(&self) -> u64 {
+        self.seed.get() as u64
+    }
+
+    /// Restore a state saved with `save_state`
+    pub fn restore_state(&mut self, state: u64) {
+        self.seed.set(state.max(1) as usize);
+    }
+
+    /// Keep a pool of `size` complete expansions of every rule built with
+    /// `--pool`, which are mostly emitted rather than expanded again, and
+    /// expand them anyway `refresh` percent of the time to replace a random
+    /// entry. A size of zero, the default, turns pooling off. The pools are
+    /// emptied, and aren't part of the state saved with `save_state`.
+    pub fn set_pool(&mut self, size: usize, refresh: usize) {
+        self.pool = Pool { size, refresh: refresh.min(100),
+            ..Pool::default() };
+    }
+
+    /// Fill `rule`, a rule built with `--hole`, by calling `fill` with the
+    /// output buffer to append its value to, rather than expanding the rule.
+    /// Returns `false` if `rule` isn't a hole.
+    pub fn set_hole<F>(&mut self, rule: &str, fill: F) -> bool
+            where F: FnMut(&mut Vec<u8>) + Send + 'static {
+        let hole = match HOLES.iter().find(|x| x.0 == rule) {
+            Some(&(_, hole)) => hole,
+            None => return false,
+        };
+        self.holes.resize_with(self.holes.len().max(hole + 1), || None);
+        self.holes[hole] = Some(Box::new(fill));
+        true
+    }
+
+    /// Stop filling the hole `rule`, such that it's expanded again
+    pub fn clear_hole(&mut self, rule: &str) {
+        if let Some(&(_, hole)) = HOLES.iter().find(|x| x.0 == rule) {
+            if let Some(provider) = self.holes.get_mut(hole) {
+                *provider = None;
+            }
+        }
+    }
+
+    /// Generate a new input into `out`. `out` is cleared first, but its
+    /// allocation is reused.
+    pub fn fill(&mut self, out: &mut Vec<u8>) {
+        out.clear();
+        std::mem::swap(&mut self.buf, out);
+        self.start();
+        std::mem::swap(&mut self.buf, out);
+    }
+
+    /// Get an iterator which generates a freshly allocated input each
+    /// iteration, forever
+    pub fn inputs(&mut self) -> Inputs<'_> {
+        Inputs(self)
+    }
+
+    /// Split `input`, the last input generated, into its messages. Inputs
+    /// without `%message` boundaries are a single message.
+    pub fn messages<'a>(&self, input: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut messages = Vec::new();
+        let mut start = 0;
+        for &(sep_start, sep_end) in self.messages.iter() {
+            messages.push(&input[start..sep_start]);
+            start = sep_end;
+        }
+        messages.push(&input[start..]);
+        messages
+    }
+
+    /// Generate inputs into a reused buffer and invoke `callback` with each
+    /// of them, until `callback` returns `false`
+    pub fn for_each_input<F: FnMut(&[u8]) -> bool>(&mut self,
+            mut callback: F) {
+        loop {
+            self.buf.clear();
+            self.start();
+            if !callback(&self.buf) {
+                break;
+            }
+        }
+    }
+}
+
+/// Iterator over generated inputs, created with `Fuzzer::inputs`
+pub struct Inputs<'a>(&'a mut Fuzzer);
+
+impl Iterator for Inputs<'_> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let mut input = Vec::new();
+        self.0.fill(&mut input);
+        Some(input)
+    }
+}
+"#;
+
+/// The fuzzer itself, shared by all kinds of output. The fragment functions
+/// are appended to its `impl` block.
+pub const FUZZER: &str = r#"
+/// Maximum expansion depth the grammar was built with, which is the default
+pub const MAX_DEPTH: usize = __MAX_DEPTH__;
+
+/// Get the default maximum expansion depth, which is taken from the
+/// `FZERO_MAX_DEPTH` environment variable if it's set, and otherwise is
+/// `MAX_DEPTH`. Exits if the variable isn't a valid depth.
+fn env_max_depth() -> usize {
+    match std::env::var("FZERO_MAX_DEPTH") {
+        Ok(depth) => depth.parse().unwrap_or_else(|_| {
+            eprintln!("error: FZERO_MAX_DEPTH must be a valid depth");
+            std::process::exit(1);
+        }),
+        Err(_) => MAX_DEPTH,
+    }
+}
+
+/// Maximum number of byte-level mutations applied to an input by `havoc`
+const HAVOC_MUTATIONS: usize = 4;
+
+/// Encode `value` as a protobuf style varint, for `%varint_length`
+fn varint(mut value: u64) -> Vec<u8> {
+    let mut ret = Vec::new();
+    while value >= 0x80 {
+        ret.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    ret.push(value as u8);
+    ret
+}
+
+/// Encode `value` as an ASN.1 DER definite length, for `%der_length`
+fn der_length(value: u64) -> Vec<u8> {
+    if value < 0x80 {
+        return vec![value as u8];
+    }
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|&&x| x == 0).count();
+    let mut ret = vec![0x80 | (8 - skip) as u8];
+    ret.extend_from_slice(&bytes[skip..]);
+    ret
+}
+
+/// Percent-encode `body` into `buf`, for `%url_encode`
+fn url_encode(body: &[u8], buf: &mut Vec<u8>) {
+    for &byte in body {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            buf.push(byte);
+        } else {
+            buf.extend_from_slice(format!("%{:02X}", byte).as_bytes());
+        }
+    }
+}
+
+/// Backslash-escape `body` into `buf`, for `%escape`
+fn escape(body: &[u8], buf: &mut Vec<u8>) {
+    for &byte in body {
+        match byte {
+            b'\\' | b'"' | b'\'' => buf.extend_from_slice(&[b'\\', byte]),
+            b'\n' => buf.extend_from_slice(b"\\n"),
+            b'\r' => buf.extend_from_slice(b"\\r"),
+            b'\t' => buf.extend_from_slice(b"\\t"),
+            0x20..=0x7e => buf.push(byte),
+            _ => buf.extend_from_slice(format!("\\x{:02x}", byte).as_bytes()),
+        }
+    }
+}
+
+/// Base64 encode `body` into `buf`, for `%base64`
+fn base64(body: &[u8], buf: &mut Vec<u8>) {
+    const ALPHABET: &[u8] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    for chunk in body.chunks(3) {
+        let bits = chunk.iter().enumerate()
+            .fold(0u32, |acc, (ii, &x)| acc | (x as u32) << (16 - ii * 8));
+        for ii in 0..4 {
+            if ii <= chunk.len() {
+                buf.push(ALPHABET[((bits >> (18 - ii * 6)) & 0x3f) as usize]);
+            } else {
+                buf.push(b'=');
+            }
+        }
+    }
+}
+
+/// Hex encode `body` into `buf`, for `%hex`
+fn hex(body: &[u8], buf: &mut Vec<u8>) {
+    for &byte in body {
+        buf.extend_from_slice(format!("{:02x}", byte).as_bytes());
+    }
+}
+
+/// Pack the lowest bit of every byte of `body` into `buf`, most significant
+/// bit first, for `%pack`
+fn pack(body: &[u8], buf: &mut Vec<u8>) {
+    for chunk in body.chunks(8) {
+        buf.push(chunk.iter().enumerate()
+            .fold(0, |acc, (ii, x)| acc | (x & 1) << (7 - ii)));
+    }
+}
+
+/// Generator for random inputs from the grammar
+pub struct Fuzzer {
+    seed:  Cell<usize>,
+    buf:   Vec<u8>,
+
+    /// Maximum expansion depth
+    max_depth: usize,
+
+    /// Number of steps used by the current input, when there's a step
+    /// budget
+    steps: usize,
+
+    /// Number of expansions of the current input which were cut off by the
+    /// depth limit or the step budget
+    cutoffs: u64,
+
+    /// Derivation tree of the current input, when recording trees for
+    /// coverage feedback
+    nodes: Vec<Node>,
+
+    /// Values of the `%capture` variables for the current input
+    vars: Vec<Option<Vec<u8>>>,
+
+    /// Spans of the separators between the messages of the current input
+    messages: Vec<(usize, usize)>,
+
+    /// Values pushed by `%push` which haven't been popped yet
+    context: Vec<Vec<u8>>,
+
+    /// Options disabled by swarm testing for the current input, indexed by
+    /// their swarm index. Empty when swarm testing is off.
+    swarm: Vec<bool>,
+
+    /// Pools of complete expansions of the rules built with `--pool`
+    pool: Pool,
+
+    /// Providers filling the rules built with `--hole`, indexed by hole.
+    /// Holes without a provider are expanded like any other rule.
+    holes: Vec<Option<Hole>>,
+}
+
+/// Provider of the values of a hole, which appends the next value to the
+/// output buffer it's given
+pub type Hole = Box<dyn FnMut(&mut Vec<u8>) + Send>;
+
+/// Names of the rules built with `--hole`, with the hole filling each
+const HOLES: &[(&str, usize)] = &__HOLES__;
+
+/// Pools of complete expansions of the rules built with `--pool`, which are
+/// mostly emitted from their pool rather than expanded again
+#[derive(Clone, Default)]
+struct Pool {
+    /// Number of expansions kept for every pooled rule, zero when pooling is
+    /// off
+    size: usize,
+
+    /// Chance in percent of expanding a pooled rule anyway once its pool is
+    /// full, replacing a random entry of the pool
+    refresh: usize,
+
+    /// Expansions of every pooled rule, indexed by the rule's pool
+    entries: Vec<Vec<Vec<u8>>>,
+
+    /// Set to expand the next pooled rule for real rather than from its pool
+    bypass: bool,
+}
+
+/// A node of a derivation tree. Trees are stored as a list of nodes in
+/// pre-order, so the subtree of a node immediately follows it.
+#[derive(Clone, Copy, Debug)]
+pub struct Node {
+    /// Fragment which was expanded
+    fragment: u32,
+
+    /// Depth the fragment was expanded at
+    depth: u32,
+
+    /// Offset of the start of the fragment's output in the input
+    start: usize,
+
+    /// Offset of the end of the fragment's output in the input
+    end: usize,
+
+    /// Number of nodes in the subtree below this one
+    descendants: usize,
+}
+
+impl Fuzzer {
+    /// Create a fuzzer seeded with `seed` which expands up to `max_depth`,
+    /// expands the rules built with `--pool` from `pool` and fills the ones
+    /// built with `--hole` from `holes`. A seed of zero is replaced with one,
+    /// as xorshift gets stuck on zero. The output buffer is reserved once up
+    /// front. It's cleared between inputs, which keeps the capacity, so
+    /// terminals rarely have to grow it.
+    fn seeded(seed: u64, max_depth: usize, pool: Pool,
+            holes: Vec<Option<Hole>>) -> Self {
+        Fuzzer {
+            seed:  Cell::new(seed.max(1) as usize),
+            max_depth,
+            buf:   Vec::with_capacity(__RESERVE__),
+            steps: 0,
+            cutoffs: 0,
+            nodes: Vec::new(),
+            vars:  Vec::new(),
+            messages: Vec::new(),
+            context: Vec::new(),
+            swarm: Vec::new(),
+            pool,
+            holes,
+        }
+    }
+
+    /// Start recording a node for an expansion of `fragment` at `depth`,
+    /// returning its index
+    #[inline(always)]
+    fn enter(&mut self, fragment: u32, depth: usize) -> usize {
+        let start = self.buf.len();
+        self.nodes.push(Node { fragment, depth: depth as u32, start,
+            end: start, descendants: 0 });
+        self.nodes.len() - 1
+    }
+
+    /// Emit a message boundary with `separator`
+    #[inline(always)]
+    fn message(&mut self, separator: &[u8]) {
+        let start = self.buf.len();
+        self.buf.extend_from_slice(separator);
+        self.messages.push((start, self.buf.len()));
+    }
+
+    /// Emit the value on top of the context stack and pop it, if there is
+    /// one
+    #[inline(always)]
+    fn pop(&mut self) {
+        if let Some(value) = self.context.pop() {
+            self.buf.extend_from_slice(&value);
+        }
+    }
+
+    /// Finish recording the node at `idx` once its expansion is done
+    #[inline(always)]
+    fn leave(&mut self, idx: usize) {
+        let descendants = self.nodes.len() - idx - 1;
+        let node = &mut self.nodes[idx];
+        node.end = self.buf.len();
+        node.descendants = descendants;
+    }
+
+    fn rand(&self) -> usize {
+        let mut seed = self.seed.get();
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 43;
+        self.seed.set(seed);
+        seed
+    }
+
+    /// With a chance of `percent` in 100, corrupt the current input with a
+    /// few random bit flips, byte insertions and byte deletions. Doesn't
+    /// consume any randomness when `percent` is zero.
+    fn havoc(&mut self, percent: u32) {
+        if percent == 0 || self.rand() % 100 >= percent as usize {
+            return;
+        }
+        for _ in 0..1 + self.rand() % HAVOC_MUTATIONS {
+            let len = self.buf.len();
+            match self.rand() % 3 {
+                0 if len > 0 => {
+                    let idx = self.rand() % len;
+                    self.buf[idx] ^= 1 << (self.rand() % 8);
+                }
+                1 => {
+                    let idx = self.rand() % (len + 1);
+                    let byte = self.rand() as u8;
+                    self.buf.insert(idx, byte);
+                }
+                2 if len > 0 => {
+                    let idx = self.rand() % len;
+                    self.buf.remove(idx);
+                }
+                _ => {}
+            }
+        }
+    }
+"#;
+
+/// C API of the library, for building it as a `cdylib` or `staticlib`. This
+/// must match `C_HEADER`.
+pub const C_API: &str = r#"
+/// Options for creating a fuzzer through the C API
+#[repr(C)]
+pub struct FzeroOptions {
+    /// Random seed, zero is replaced with one
+    pub seed: u64,
+
+    /// Maximum expansion depth
+    pub max_depth: usize,
+}
+
+/// Get the default options, which produce the same inputs as
+/// `fzero generate`
+#[no_mangle]
+pub extern "C" fn fzero_default_options() -> FzeroOptions {
+    FzeroOptions { seed: SEED, max_depth: MAX_DEPTH }
+}
+
+/// Create a new fuzzer with `options`, or the default options if it's null.
+/// The fuzzer must be freed with `fzero_free`.
+#[no_mangle]
+pub unsafe extern "C" fn fzero_new(options: *const FzeroOptions)
+        -> *mut Fuzzer {
+    let options = match options.as_ref() {
+        Some(x) => FzeroOptions { seed: x.seed, max_depth: x.max_depth },
+        None => fzero_default_options(),
+    };
+    let mut fuzzer = Fuzzer::new(options.seed);
+    fuzzer.set_max_depth(options.max_depth);
+    Box::into_raw(Box::new(fuzzer))
+}
+
+/// Generate a new input, storing its length in `len`. The returned bytes
+/// belong to the fuzzer and are valid until the next call with it.
+#[no_mangle]
+pub unsafe extern "C" fn fzero_generate(fuzzer: *mut Fuzzer, len: *mut usize)
+        -> *const u8 {
+    let fuzzer = &mut *fuzzer;
+    fuzzer.buf.clear();
+    fuzzer.start();
+    *len = fuzzer.buf.len();
+    fuzzer.buf.as_ptr()
+}
+
+/// Save the state of the fuzzer's random number generator, see
+/// `Fuzzer::save_state`
+#[no_mangle]
+pub unsafe extern "C" fn fzero_save_state(fuzzer: *const Fuzzer) -> u64 {
+    (*fuzzer).save_state()
+}
+
+/// Restore a state saved with `fzero_save_state`
+#[no_mangle]
+pub unsafe extern "C" fn fzero_restore_state(fuzzer: *mut Fuzzer,
+        state: u64) {
+    (*fuzzer).restore_state(state)
+}
+
+/// Free a fuzzer created with `fzero_new`. Null is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn fzero_free(fuzzer: *mut Fuzzer) {
+    if !fuzzer.is_null() {
+        drop(Box::from_raw(fuzzer));
+    }
+}
+"#;
+
+/// C header describing `C_API`, in the style `cbindgen` produces
+pub const C_HEADER: &str = r#"/* Generated by fzero, do not edit */
+
+#ifndef FZERO_H
+#define FZERO_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+/* Default random seed, which produces the same inputs as `fzero generate` */
+#define FZERO_SEED __SEED__ULL
+
+/* Maximum expansion depth the grammar was built with, which is the default */
+#define FZERO_MAX_DEPTH __MAX_DEPTH__
+
+/* Generator for random inputs from the grammar */
+typedef struct Fuzzer Fuzzer;
+
+/* Options for creating a fuzzer */
+typedef struct FzeroOptions {
+  /* Random seed, zero is replaced with one */
+  uint64_t seed;
+  /* Maximum expansion depth */
+  size_t max_depth;
+} FzeroOptions;
+
+#ifdef __cplusplus
+extern "C" {
+#endif /* __cplusplus */
+
+/* Get the default options, which produce the same inputs as
+ * `fzero generate` */
+FzeroOptions fzero_default_options(void);
+
+/* Create a new fuzzer with `options`, or the default options if it's NULL.
+ * The fuzzer must be freed with `fzero_free`. */
+Fuzzer *fzero_new(const FzeroOptions *options);
+
+/* Generate a new input, storing its length in `len`. The returned bytes
+ * belong to the fuzzer and are valid until the next call with it. */
+const uint8_t *fzero_generate(Fuzzer *fuzzer, size_t *len);
+
+/* Save the state of the fuzzer's random number generator. Restoring it with
+ * `fzero_restore_state` makes the fuzzer generate the same inputs again from
+ * this point on, as long as the max depth is the same. */
+uint64_t fzero_save_state(const Fuzzer *fuzzer);
+
+/* Restore a state saved with `fzero_save_state` */
+void fzero_restore_state(Fuzzer *fuzzer, uint64_t state);
+
+/* Free a fuzzer created with `fzero_new`. NULL is ignored. */
+void fzero_free(Fuzzer *fuzzer);
+
+#ifdef __cplusplus
+} /* extern "C" */
+#endif /* __cplusplus */
+
+#endif /* FZERO_H */
+"#;
+
+/// Expansion tracing of programs and libraries built with `--trace`
+pub const TRACE: &str = r#"
+thread_local! {
+    /// Index of the current input on this thread, counting from zero
+    static TRACE_INPUT: Cell<u64> = Cell::new(0);
+
+    /// Nesting of the expansion being traced
+    static TRACE_DEPTH: Cell<usize> = Cell::new(0);
+
+    /// Whether the current input is traced
+    static TRACE_ON: Cell<bool> = Cell::new(false);
+
+    /// Whether tracing is silenced, for when only the rules are needed
+    static TRACE_SILENT: Cell<bool> = Cell::new(false);
+
+    /// Rules expanded by the current input
+    static TRACE_RULES: std::cell::RefCell<Vec<&'static str>> =
+        std::cell::RefCell::new(Vec::new());
+}
+
+/// Start tracing the next input. Every input is traced, unless the
+/// `FZERO_TRACE_INPUT` environment variable picks the index of the one input
+/// on each thread to trace.
+fn trace_input() {
+    let index = TRACE_INPUT.with(|x| x.replace(x.get() + 1));
+    let on = match std::env::var("FZERO_TRACE_INPUT") {
+        Ok(pick) => pick.parse() == Ok(index),
+        Err(_) => true,
+    } && !TRACE_SILENT.with(|x| x.get());
+    TRACE_ON.with(|x| x.set(on));
+    TRACE_DEPTH.with(|x| x.set(0));
+    TRACE_RULES.with(|x| x.borrow_mut().clear());
+    if on {
+        eprintln!("trace of input {}:", index);
+    }
+}
+
+/// Print the start of an expansion of `what`, part of `rule`, indented by
+/// its nesting
+fn trace_enter(what: &str, rule: &'static str) {
+    TRACE_RULES.with(|x| x.borrow_mut().push(rule));
+    if TRACE_ON.with(|x| x.get()) {
+        let depth = TRACE_DEPTH.with(|x| x.replace(x.get() + 1));
+        eprintln!("{:1$}{2}", "", 2 + depth * 2, what);
+    }
+}
+
+/// Stop printing traces on this thread
+fn trace_silence() {
+    TRACE_SILENT.with(|x| x.set(true));
+}
+
+/// Get the distinct rules expanded by the current input
+fn trace_rules() -> Option<Vec<&'static str>> {
+    let mut rules = TRACE_RULES.with(|x| x.borrow().clone());
+    rules.sort_unstable();
+    rules.dedup();
+    rules.retain(|x| !x.is_empty());
+    Some(rules)
+}
+
+/// Finish the expansion started by the last `trace_enter`
+fn trace_leave() {
+    if TRACE_ON.with(|x| x.get()) {
+        TRACE_DEPTH.with(|x| x.set(x.get() - 1));
+    }
+}
+"#;
+
+/// Stand-ins for the tracing functions the benchmark program uses, in
+/// programs built without `--trace`
+pub const NO_TRACE: &str = r#"
+fn trace_silence() {}
+
+fn trace_rules() -> Option<Vec<&'static str>> {
+    None
+}
+"#;
+
+/// Structured log of a run as json lines, shared by the benchmark and the
+/// feedback program
+pub const LOG: &str = r#"
+/// Log of the statistics and notable events of a run, appended as json lines
+/// for dashboards to follow. Every line has the unix time and the kind of
+/// event, followed by the fields of the event.
+struct Log {
+    /// File the events are appended to, nothing is logged without one
+    file: Option<std::fs::File>,
+}
+
+impl Log {
+    /// Open the log at `path`, if any, exiting on errors
+    fn open(path: Option<&std::path::Path>) -> Self {
+        let file = path.map(|path| {
+            std::fs::OpenOptions::new().create(true).append(true).open(path)
+                .unwrap_or_else(|err| {
+                    eprintln!("error: failed to open log {}: {}",
+                        path.display(), err);
+                    std::process::exit(1);
+                })
+        });
+        Log { file }
+    }
+
+    /// Log an `event` with `fields`, a comma separated list of json members
+    fn event(&mut self, event: &str, fields: &str) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0., |x| x.as_secs_f64());
+        let separator = if fields.is_empty() { "" } else { "," };
+        let line = format!("{{\"time\":{:.3},\"event\":\"{}\"{}{}}}\n",
+            time, event, separator, fields);
+
+        // Each line goes out in a single write, so readers never see half of
+        // one. Losing the log isn't worth stopping the run over.
+        if let Err(err) = file.write_all(line.as_bytes()) {
+            eprintln!("warning: failed to write to the log, disabling it: {}",
+                err);
+            self.file = None;
+        }
+    }
+}
+
+/// Quote `string` as a json string
+fn json_string(string: &str) -> String {
+    let mut quoted = String::from("\"");
+    for chr in string.chars() {
+        match chr {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            '\n' => quoted += "\\n",
+            chr if (chr as u32) < 0x20 => {
+                quoted += &format!("\\u{:04x}", chr as u32);
+            }
+            chr => quoted.push(chr),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+"#;
+
+/// Prometheus metrics endpoint, shared by the benchmark and the feedback
+/// program
+pub const METRICS: &str = r##"
+/// Latest statistics of the run in the Prometheus text format, served over
+/// HTTP on a thread of its own so monitoring can alert on stalled runs
+struct Metrics {
+    /// Time the run started, for the uptime
+    start: std::time::Instant,
+
+    /// Metrics as of the latest report, without the uptime
+    body: std::sync::Mutex<String>,
+}
+
+impl Metrics {
+    /// Start serving metrics on `addr`, exiting on errors
+    fn serve(addr: &str) -> std::sync::Arc<Self> {
+        let listener = std::net::TcpListener::bind(addr).unwrap_or_else(|err| {
+            eprintln!("error: failed to serve metrics on {}: {}", addr, err);
+            std::process::exit(1);
+        });
+        let metrics = std::sync::Arc::new(Metrics {
+            start: std::time::Instant::now(),
+            body:  std::sync::Mutex::new(String::new()),
+        });
+
+        // Requests are answered one at a time, scrapes are rare and tiny
+        let shared = metrics.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = shared.respond(stream);
+            }
+        });
+        metrics
+    }
+
+    /// Replace the metrics with `metrics`, a list of names, without the
+    /// `fzero_` prefix, along with their help text and value. Names ending
+    /// in `_total` are counters, the rest are gauges.
+    fn update(&self, metrics: &[(&str, &str, f64)]) {
+        let mut body = String::new();
+        for &(name, help, value) in metrics {
+            let kind = if name.ends_with("_total") { "counter" } else {
+                "gauge"
+            };
+            body += &format!("# HELP fzero_{0} {1}\n# TYPE fzero_{0} {2}\n\
+                              fzero_{0} {3}\n", name, help, kind, value);
+        }
+        *self.body.lock().unwrap() = body;
+    }
+
+    /// Answer a single HTTP request on `stream`, with the metrics if it's
+    /// for `/metrics`
+    fn respond(&self, mut stream: std::net::TcpStream)
+            -> std::io::Result<()> {
+        use std::io::{Read, Write};
+
+        // Only the request line matters, which fits in the first read
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+        let mut request = [0u8; 1024];
+        let bytes = stream.read(&mut request)?;
+        let request = String::from_utf8_lossy(&request[..bytes]);
+        let path = request.split_whitespace().nth(1).unwrap_or("");
+
+        let (status, body) = if path == "/metrics" {
+            let mut body = self.body.lock().unwrap().clone();
+            body += &format!("# HELP fzero_uptime_seconds Seconds since the \
+                              run started\n\
+                              # TYPE fzero_uptime_seconds gauge\n\
+                              fzero_uptime_seconds {:.3}\n",
+                self.start.elapsed().as_secs_f64());
+            ("200 OK", body)
+        } else {
+            ("404 Not Found", String::from("Metrics are at /metrics\n"))
+        };
+        write!(stream, "HTTP/1.1 {}\r\n\
+                        Content-Type: text/plain; version=0.0.4\r\n\
+                        Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, body.len(), body)
+    }
+}
+"##;
+
+/// Helpers for sampling derivations uniformly, added to the fuzzer when a
+/// uniform size is used. These mirror `uniform::choose` and `Counts::split`.
+pub const UNIFORM: &str = r#"
+    /// Randomly pick an index below `count`, weighted by `weight`
+    fn choose(&self, count: usize, weight: impl Fn(usize) -> f64) -> usize {
+        let rand = self.rand() as u64;
+        let total: f64 = (0..count).map(&weight).sum();
+        let mut point = (rand >> 11) as f64 / (1u64 << 53) as f64 * total;
+
+        let mut last = 0;
+        for idx in 0..count {
+            let weight = weight(idx);
+            if weight > 0. {
+                if point < weight {
+                    return idx;
+                }
+                point -= weight;
+                last = idx;
+            }
+        }
+        last
+    }
+
+    /// Randomly pick a size for the first part of a derivation of `size`
+    /// split into two parts, counted by the tables at `first` and `rest`
+    fn split(&self, first: usize, rest: usize, size: usize) -> usize {
+        1 + self.choose(size - 1, |k| {
+            COUNTS[first + k + 1] * COUNTS[rest + size - k - 1]
+        })
+    }
+"#;
+
+/// Methods of the fuzzer for grammars with byte samplers, which pick a byte
+/// from the `BYTE_TREES` table the same way the non-terminals they replace
+/// would have. Every node is the number of options followed by an entry per
+/// option, either a byte or 256 plus the index of the node to continue from.
+pub const BYTE_TREE: &str = r#"
+    /// Pick a byte from the tree starting at `node` in `BYTE_TREES`
+    #[inline(always)]
+    fn byte_tree(&self, mut node: usize) -> u8 {
+        loop {
+            let len = BYTE_TREES[node] as usize;
+            let entry = BYTE_TREES[node + 1 + self.rand() % len];
+            if entry < 256 {
+                return entry as u8;
+            }
+            node = entry as usize - 256;
+        }
+    }
+"#;
+
+/// Methods of the fuzzer for grammars compiled with `--compact-regular`,
+/// which expand the regions of the grammar packed into the `WALK` table the
+/// same way their fragment functions would have. Every instruction is an
+/// opcode followed by its operands:
+///
+/// * 0: return to the instruction on top of the stack, or finish the region
+/// * 1 OFFSET LEN: emit a terminal from the terminal pool
+/// * 2 ENTRY: emit a byte, `ENTRY` itself or one picked from the byte tree
+///   at `ENTRY - 256`
+/// * 3 TARGET: call the instructions at `TARGET`, then continue
+/// * 4 N TARGET...: continue at one of `N` targets picked at random
+/// * 5: start a loop, pushing its iteration count
+/// * 6 N EXIT (TARGET REPEAT)...: iterate a loop, calling one of `N` targets
+///   picked at random and then iterating again if it repeats, or continuing
+///   at `EXIT` once the loop budget is exhausted or the target doesn't repeat
+/// * 7: finish a loop, popping its iteration count
+pub const WALK: &str = r#"
+    /// Expand the region of the grammar starting at `state` in `WALK`
+    #[inline(never)]
+    fn walk(&mut self, mut state: usize) {
+        let mut stack = [0u32; WALK_DEPTH];
+        let mut calls = 0;
+        let mut iterations = [0usize; WALK_DEPTH];
+        let mut loops = 0;
+        loop {
+            match WALK[state] {
+                0 => {
+                    if calls == 0 {
+                        return;
+                    }
+                    calls -= 1;
+                    state = stack[calls] as usize;
+                }
+                1 => {
+                    self.emit(WALK[state + 1] as usize,
+                        WALK[state + 2] as usize);
+                    state += 3;
+                }
+                2 => {
+                    let byte = match WALK[state + 1] {
+                        entry @ 0..=255 => entry as u8,
+                        entry => self.byte_tree(entry as usize - 256),
+                    };
+                    self.buf.push(byte);
+                    state += 2;
+                }
+                3 => {
+                    stack[calls] = state as u32 + 2;
+                    calls += 1;
+                    state = WALK[state + 1] as usize;
+                }
+                4 => {
+                    let len = WALK[state + 1] as usize;
+                    state = WALK[state + 2 + self.rand() % len] as usize;
+                }
+                5 => {
+                    iterations[loops] = 0;
+                    loops += 1;
+                    state += 1;
+                }
+                6 => {
+                    let exit = WALK[state + 2];
+                    if iterations[loops - 1] == __LOOP_BUDGET__ {
+                        state = exit as usize;
+                        continue;
+                    }
+                    iterations[loops - 1] += 1;
+
+                    let len = WALK[state + 1] as usize;
+                    let option = state + 3 + 2 * (self.rand() % len);
+                    stack[calls] = if WALK[option + 1] != 0 {
+                        state as u32
+                    } else {
+                        exit
+                    };
+                    calls += 1;
+                    state = WALK[option] as usize;
+                }
+                7 => {
+                    loops -= 1;
+                    state += 1;
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+"#;
+
+/// Methods of the fuzzer for grammars built with `--pool`, which emit the
+/// rules from their pools of complete expansions, see `Pool`
+pub const POOL: &str = r#"
+    /// Expand the pooled rule with pool `slot` by emitting a random entry
+    /// of its pool, once the pool is full. Until then, and `refresh` percent
+    /// of the time after that, the rule is expanded at `depth` with `expand`
+    /// instead, and the expansion is added to the pool or replaces a random
+    /// entry of it.
+    #[inline(never)]
+    fn pooled(&mut self, slot: usize, depth: usize,
+            expand: fn(&mut Self, usize)) {
+        if self.pool.entries.len() <= slot {
+            self.pool.entries.resize(slot + 1, Vec::new());
+        }
+        let len = self.pool.entries[slot].len();
+        if len == self.pool.size && self.rand() % 100 >= self.pool.refresh {
+            let entry = self.rand() % len;
+            self.buf.extend_from_slice(&self.pool.entries[slot][entry]);
+            return;
+        }
+
+        let start = self.buf.len();
+        self.pool.bypass = true;
+        expand(self, depth);
+        let expansion = self.buf[start..].to_vec();
+        if len < self.pool.size {
+            self.pool.entries[slot].push(expansion);
+        } else {
+            let entry = self.rand() % len;
+            self.pool.entries[slot][entry] = expansion;
+        }
+    }
+"#;
+
+/// Unit tests emitted at the end of the source with `GenOptions::tests`,
+/// which catch regressions of the code generator when the source is
+/// generated again by a newer fzero
+pub const TESTS: &str = r#"
+/// Tests checking the compiled code generates the inputs fzero expected it
+/// to when generating it. Run them by compiling the source with `--test`.
+#[cfg(test)]
+mod fzero_tests {
+    use super::*;
+
+    /// Number of inputs generated at every max depth
+    const INPUTS: u64 = __TEST_INPUTS__;
+
+    /// Max depths to generate inputs at, with the FNV-1a hash of the first
+    /// `INPUTS` inputs generated from `SEED` at each of them, each prefixed
+    /// by its length as a little endian `u32`, as computed by the fzero
+    /// interpreter
+    const HASHES: &[(usize, u64)] = &[__TEST_HASHES__];
+
+    /// Create a fuzzer generating from `SEED` at `max_depth`
+    fn fuzzer(max_depth: usize) -> Fuzzer {
+        Fuzzer::seeded(SEED, max_depth, Pool::default(), Vec::new())
+    }
+
+    /// Generate `INPUTS` inputs with `fuzzer`, checking each of them is a
+    /// well formed buffer, and get their hash
+    fn generate(fuzzer: &mut Fuzzer) -> u64 {
+        let mut hash = 0xcbf29ce484222325u64;
+        for _ in 0..INPUTS {
+            fuzzer.buf.clear();
+            fuzzer.start();
+
+            // Message separators follow each other within the input
+            let mut end = 0;
+            for &(start, stop) in fuzzer.messages.iter() {
+                assert!(end <= start && start <= stop &&
+                    stop <= fuzzer.buf.len(),
+                    "message separator {}..{} out of place in an input of \
+                     {} bytes", start, stop, fuzzer.buf.len());
+                end = stop;
+            }
+
+            let len = fuzzer.buf.len() as u32;
+            for &byte in len.to_le_bytes().iter().chain(fuzzer.buf.iter()) {
+                hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
+    }
+
+    /// The same seed always generates the same inputs, and those are the
+    /// inputs fzero generates from it
+    #[test]
+    fn deterministic() {
+        let hash = generate(&mut fuzzer(MAX_DEPTH));
+        assert_eq!(hash, generate(&mut fuzzer(MAX_DEPTH)),
+            "inputs generated from the same seed differ");
+        let expected = HASHES.iter().find(|x| x.0 == MAX_DEPTH).unwrap().1;
+        assert_eq!(hash, expected,
+            "inputs generated at the max depth of {} changed", MAX_DEPTH);
+    }
+
+    /// Inputs are cut off at every lower max depth the same way fzero cuts
+    /// them off
+    #[test]
+    fn depth_limits() {
+        for &(max_depth, expected) in HASHES.iter() {
+            assert_eq!(generate(&mut fuzzer(max_depth)), expected,
+                "inputs generated at the max depth of {} changed",
+                max_depth);
+        }
+    }
+}
+"#;
+
+/// Filling the rules built with `--hole` from their providers, appended to the
+/// `impl` block of the fuzzer when there are any
+pub const HOLE: &str = r#"
+    /// Append a value of the hole `hole` to the output from its provider.
+    /// Returns `false` if the hole has no provider, then the rule is
+    /// expanded instead.
+    #[inline(always)]
+    fn fill_hole(&mut self, hole: usize) -> bool {
+        match self.holes.get_mut(hole) {
+            Some(Some(provider)) => {
+                provider(&mut self.buf);
+                true
+            }
+            _ => false,
+        }
+    }
+"#;
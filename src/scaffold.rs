@@ -0,0 +1,183 @@
+//! Scaffolding of cargo-fuzz harnesses. Given a grammar and the function to
+//! fuzz, this writes a `fuzz/` directory in the layout `cargo fuzz init`
+//! creates, holding the grammar compiled as a library and a `fuzz_target!`
+//! which generates an input from it and hands it to the function. The bytes
+//! libFuzzer provides only pick the seed of the generator, so libFuzzer's
+//! coverage feedback steers which grammar inputs are kept.
+
+use std::path::Path;
+
+/// How the function being fuzzed takes its input
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Argument {
+    /// `&[u8]`, the input as generated
+    Bytes,
+
+    /// `&str`, the input with invalid UTF-8 replaced
+    Str,
+
+    /// `Vec<u8>`, the input as generated and owned
+    Vec,
+
+    /// `String`, the input with invalid UTF-8 replaced and owned
+    String,
+}
+
+/// The function a harness feeds inputs to
+#[derive(Clone, Debug)]
+pub struct Target {
+    /// Path of the function, starting with the name of its crate
+    pub path: String,
+
+    /// Type of the function's only argument
+    pub argument: Argument,
+}
+
+impl Target {
+    /// Parse a target signature like `my_crate::parse(&[u8])`
+    pub fn parse(signature: &str) -> Result<Self, String> {
+        let err = || format!("expected a signature like \
+            `my_crate::parse(&[u8])` taking one of &[u8], &str, Vec<u8> or \
+            String, got `{}`", signature);
+
+        let (path, argument) = signature.trim().strip_suffix(')')
+            .and_then(|x| x.split_once('(')).ok_or_else(err)?;
+        let argument: String =
+            argument.chars().filter(|x| !x.is_whitespace()).collect();
+        let argument = match argument.as_str() {
+            "&[u8]" => Argument::Bytes,
+            "&str" => Argument::Str,
+            "Vec<u8>" => Argument::Vec,
+            "String" => Argument::String,
+            _ => return Err(err()),
+        };
+
+        // The crate has to be named, as the harness is a crate of its own
+        let path = path.trim();
+        let valid = path.split("::").all(|segment| {
+            segment.chars().next()
+                .is_some_and(|x| x.is_ascii_alphabetic() || x == '_') &&
+                segment.chars().all(|x| x.is_ascii_alphanumeric() || x == '_')
+        });
+        if !valid || !path.contains("::") {
+            return Err(err());
+        }
+        Ok(Target { path: path.to_string(), argument })
+    }
+
+    /// Name of the crate the function is in, as used in Rust paths
+    pub fn crate_name(&self) -> &str {
+        self.path.split("::").next().unwrap()
+    }
+
+    /// Expression calling the function with `input`, a `Vec<u8>`
+    fn call(&self, input: &str) -> String {
+        match self.argument {
+            Argument::Bytes => format!("{}(&{})", self.path, input),
+            Argument::Str => format!("{}(&String::from_utf8_lossy(&{}))",
+                self.path, input),
+            Argument::Vec => format!("{}({})", self.path, input),
+            Argument::String => format!(
+                "{}(String::from_utf8_lossy(&{}).into_owned())",
+                self.path, input),
+        }
+    }
+}
+
+/// Turn `name` into a valid name for a fuzz target and module
+pub fn target_name(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|x| if x.is_ascii_alphanumeric() { x.to_ascii_lowercase() }
+            else { '_' })
+        .collect();
+    if name.starts_with(|x: char| x.is_ascii_alphabetic()) {
+        name
+    } else {
+        format!("fuzz_{}", name)
+    }
+}
+
+/// Find the package name in the `Cargo.toml` of the crate at `dir`, which
+/// the harness depends on. This only understands the plain `name = "..."`
+/// line of the `[package]` table, which is all `cargo new` ever writes.
+pub fn package_name(dir: &Path) -> Option<String> {
+    let manifest = std::fs::read_to_string(dir.join("Cargo.toml")).ok()?;
+    let mut package = false;
+    for line in manifest.lines().map(str::trim) {
+        if line.starts_with('[') {
+            package = line == "[package]";
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) if package && key.trim() == "name" => {
+                return Some(value.trim().trim_matches('"').to_string());
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Source of the fuzz target `name`, which generates inputs with the grammar
+/// library in `../grammars/{name}.rs` and feeds them to `target`
+pub fn fuzz_target(name: &str, grammar: &str, target: &Target) -> String {
+    format!(r#"//! Generated by fzero from {grammar}, which is compiled into
+//! `fuzz/grammars/{name}.rs`. Rerun `fzero cargo-fuzz` with `--force` rather
+//! than editing either when the grammar changes.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../grammars/{name}.rs"]
+mod grammar;
+
+fuzz_target!(|data: &[u8]| {{
+    // libFuzzer's data only picks the seed, folding in every byte so any
+    // mutation of it changes the input
+    let mut seed = [0u8; 8];
+    for (idx, byte) in data.iter().enumerate() {{
+        seed[idx % seed.len()] ^= byte;
+    }}
+    let mut fuzzer = grammar::Fuzzer::new(u64::from_le_bytes(seed));
+
+    let mut input = Vec::new();
+    fuzzer.fill(&mut input);
+    let _ = {call};
+}});
+"#, grammar = grammar, name = name, call = target.call("input"))
+}
+
+/// `Cargo.toml` of a new `fuzz/` crate depending on `package`, the crate in
+/// its parent directory
+pub fn manifest(package: &str) -> String {
+    format!(r#"[package]
+name = "{package}-fuzz"
+version = "0.0.0"
+publish = false
+edition = "2021"
+
+[package.metadata]
+cargo-fuzz = true
+
+[dependencies]
+libfuzzer-sys = "0.4"
+
+[dependencies.{package}]
+path = ".."
+"#, package = package)
+}
+
+/// Section of `Cargo.toml` declaring the fuzz target `name`
+pub fn bin_section(name: &str) -> String {
+    format!(r#"
+[[bin]]
+name = "{name}"
+path = "fuzz_targets/{name}.rs"
+test = false
+doc = false
+bench = false
+"#, name = name)
+}
+
+/// `.gitignore` of a new `fuzz/` crate, keeping libFuzzer's state out
+pub const GITIGNORE: &str = "target\ncorpus\nartifacts\ncoverage\n";
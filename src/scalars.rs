@@ -0,0 +1,171 @@
+//! Random scalar generators. Numeric fields usually accept a range of values,
+//! and spelling every one of them out as an alternative is impractical, so a
+//! few generators stand in for whole ranges of terminals:
+//!
+//! * `%range(0, 65535)` emits a decimal integer from the inclusive range
+//! * `%float(-1.0, 1.0)` emits a decimal floating point number from the range
+//! * `%oneof_bytes(30-39 61-66)` emits one byte from a set of hex bytes and
+//!   ranges of them, like `%bytes`
+//!
+//! Integers and floats get fragments of their own which draw the value with a
+//! single random number, while byte sets are plain alternatives, one per
+//! byte. `%range` is the decimal counterpart of the builtin binary integer
+//! rules like `<u16_le:0..=1500>` and shares their `RandomInt`, see `ints`.
+
+use crate::ints::RandomInt;
+
+/// A random scalar generator used as a token of an alternative
+#[derive(Clone, Debug, PartialEq)]
+pub enum Generator {
+    /// An integer from a range, or one of the builtin integer rules
+    Int(RandomInt),
+
+    /// A floating point number from a range
+    Float(RandomFloat),
+
+    /// One of a set of bytes, in ascending order
+    Bytes(Vec<u8>),
+}
+
+/// A random floating point number emitted by `%float(low, high)`, picked
+/// uniformly from `low` up to `high`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RandomFloat {
+    /// Bits of the smallest value
+    low: u64,
+
+    /// Bits of the largest value
+    high: u64,
+}
+
+impl RandomFloat {
+    /// Create a random floating point number from `low` to `high`. Returns
+    /// `None` unless both are finite, `low` is at most `high` and the width
+    /// of the range is finite too.
+    pub fn new(low: f64, high: f64) -> Option<Self> {
+        ((high - low).is_finite() && low <= high).then(|| RandomFloat {
+            low: low.to_bits(),
+            high: high.to_bits(),
+        })
+    }
+
+    /// Smallest value
+    pub fn low(&self) -> f64 {
+        f64::from_bits(self.low)
+    }
+
+    /// Width of the range, which the generated code is given as is so it
+    /// computes exactly the same values as `value`
+    pub fn width(&self) -> f64 {
+        f64::from_bits(self.high) - self.low()
+    }
+
+    /// Get the generator token emitting this number
+    pub fn name(&self) -> String {
+        format!("%float({:?}, {:?})", self.low(), f64::from_bits(self.high))
+    }
+
+    /// Draw a value from the random numbers of `rand`, using the top 53 bits
+    /// of a random number as the fraction of the way through the range. This
+    /// must match the code generated by `Codegen`.
+    pub fn value(&self, mut rand: impl FnMut() -> usize) -> f64 {
+        let unit = (rand() >> 11) as f64 / 9007199254740992.;
+        self.low() + self.width() * unit
+    }
+
+    /// Encode `value` as the shortest decimal which reads back as it
+    pub fn encode(value: f64) -> Vec<u8> {
+        value.to_string().into_bytes()
+    }
+
+    /// Get the length of a number in range at the start of `input`, if there
+    /// is one. Every digit is taken, as numbers are never followed by more.
+    pub fn recognize(&self, input: &[u8]) -> Option<usize> {
+        let sign = (input.first() == Some(&b'-')) as usize;
+        let whole = input[sign..].iter()
+            .take_while(|x| x.is_ascii_digit()).count();
+        let mut len = sign + whole;
+        if input.get(len) == Some(&b'.') {
+            len += 1 + input[len + 1..].iter()
+                .take_while(|x| x.is_ascii_digit()).count();
+        }
+        let value: f64 = std::str::from_utf8(&input[..len]).ok()?
+            .parse().ok()?;
+        (whole > 0 && self.low() <= value &&
+            value <= f64::from_bits(self.high)).then_some(len)
+    }
+
+    /// Get bounds on the shortest and longest encoding of any value. Values
+    /// are written out in full rather than with an exponent, so this takes
+    /// the largest magnitude for the digits before the point and the
+    /// smallest non-zero magnitude for the zeros after it.
+    pub fn lens(&self) -> (usize, usize) {
+        let (low, high) = (self.low(), f64::from_bits(self.high));
+        let sign = (low < 0.) as usize;
+        let whole = Self::encode(low.abs().max(high.abs()).trunc()).len();
+
+        // Values near zero are at least a fraction of the range apart, down
+        // to the precision of the numbers involved
+        let smallest = if low > 0. || high < 0. {
+            low.abs().min(high.abs())
+        } else {
+            self.width() / 2f64.powi(106)
+        };
+        let zeros = if smallest >= 1. || smallest == 0. { 0 } else {
+            (-smallest.log10()).ceil() as usize
+        };
+        (1, sign + whole + 1 + zeros + 17)
+    }
+}
+
+/// If `token` is a random scalar generator, get it, or an error if its
+/// arguments are invalid. This includes references to the builtin integer
+/// rules like `<u16_le>`, see `ints`.
+pub fn generator(token: &str) -> Option<std::io::Result<Generator>> {
+    if let Some(int) = RandomInt::parse(token) {
+        return Some(int.map(Generator::Int));
+    }
+
+    let (name, args) = token.strip_suffix(')')?.split_once('(')?;
+    let invalid = |expected: &str| std::io::Error::other(format!(
+        "Invalid arguments in {}, expected {}", token, expected));
+    let bounds = || args.split_once(',')
+        .map(|(low, high)| (low.trim(), high.trim()));
+    let generator = match name {
+        "%range" => bounds()
+            .and_then(|(low, high)| {
+                RandomInt::decimal(low.parse().ok()?, high.parse().ok()?)
+            })
+            .map(Generator::Int)
+            .ok_or_else(|| invalid("the smallest and largest value, \
+                which fit in an i64 or u64, like %range(0, 65535)")),
+        "%float" => bounds()
+            .and_then(|(low, high)| {
+                RandomFloat::new(low.parse().ok()?, high.parse().ok()?)
+            })
+            .map(Generator::Float)
+            .ok_or_else(|| invalid("the smallest and largest finite \
+                value, like %float(-1.0, 1.0)")),
+        "%oneof_bytes" => byte_set(args).map(Generator::Bytes)
+            .ok_or_else(|| invalid("hex bytes and ranges of them, like \
+                %oneof_bytes(0a 30-39)")),
+        _ => return None,
+    };
+    Some(generator)
+}
+
+/// Parse a set of bytes written as hex bytes and ranges of them separated by
+/// whitespace, eg. `0a 30-39`
+fn byte_set(set: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for item in set.split_whitespace() {
+        let (first, last) = item.split_once('-').unwrap_or((item, item));
+        let byte = |x: &str| {
+            if x.len() != 2 { None } else { u8::from_str_radix(x, 16).ok() }
+        };
+        bytes.extend(byte(first)?..=byte(last)?);
+    }
+    bytes.sort_unstable();
+    bytes.dedup();
+    (!bytes.is_empty()).then_some(bytes)
+}
@@ -0,0 +1,139 @@
+//! Golden output checks. A manifest records a hash of the first inputs
+//! generated from the default seed along with the options they were
+//! generated with, so refactors of the optimizer, the interpreter or the
+//! code generator can prove they didn't change what a grammar generates.
+//! Inputs are hashed in the `--stream` format of the generated program, a
+//! little endian `u32` length followed by the input, such that the
+//! interpreter and the compiled program can be checked against the same hash.
+
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use serde::{Deserialize, Serialize};
+use crate::codegen::DEFAULT_SEED;
+use crate::grammar::{GenOptions, GrammarRust};
+use crate::interp::Interpreter;
+
+/// Expected output of a grammar, as stored in a manifest file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    /// Maximum recursion depth the inputs were generated with
+    pub max_depth: usize,
+
+    /// Loop budget the inputs were generated with
+    pub loop_budget: usize,
+
+    /// Step budget the inputs were generated with, if any
+    pub max_steps: Option<usize>,
+
+    /// Derivation size the inputs were sampled uniformly with, if any
+    pub uniform_size: Option<usize>,
+
+    /// Number of inputs hashed
+    pub count: u64,
+
+    /// FNV-1a hash of the inputs, in hex
+    pub hash: String,
+}
+
+impl Manifest {
+    /// Create a manifest for `count` inputs generated with `options`
+    /// hashing to `hash`
+    pub fn new(options: &GenOptions, count: u64, hash: u64) -> Self {
+        Manifest {
+            max_depth:    options.max_depth,
+            loop_budget:  options.loop_budget,
+            max_steps:    options.max_steps,
+            uniform_size: options.uniform_size,
+            count,
+            hash: format!("{:016x}", hash),
+        }
+    }
+
+    /// Load a manifest from the json file at `path`
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        let data = std::fs::read(path)?;
+        serde_json::from_slice(&data).map_err(|err| std::io::Error::other(
+            format!("Invalid manifest {}: {}", path.display(), err)))
+    }
+
+    /// Save the manifest as json to `path`
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        std::fs::write(path, json)
+    }
+
+    /// Returns `true` if the manifest was recorded with the same options and
+    /// number of inputs as `other`, such that their hashes are comparable
+    pub fn same_options(&self, other: &Manifest) -> bool {
+        (self.max_depth, self.loop_budget, self.max_steps, self.uniform_size,
+            self.count) == (other.max_depth, other.loop_budget,
+            other.max_steps, other.uniform_size, other.count)
+    }
+}
+
+/// Incremental 64-bit FNV-1a hash, which unlike `DefaultHasher` is stable
+/// across Rust versions
+pub struct Fnv(pub u64);
+
+impl Fnv {
+    pub fn new() -> Self {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+/// Hash formatted text, such that large values can be hashed without
+/// formatting them into a string first
+impl std::fmt::Write for Fnv {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        self.update(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Hash the first `count` inputs generated by the interpreter
+pub fn interpreter_hash(grammar: &GrammarRust, options: &GenOptions,
+        count: u64) -> std::io::Result<u64> {
+    let mut interp = Interpreter::new(grammar, DEFAULT_SEED, options)?;
+    let mut buf = Vec::new();
+    let mut hash = Fnv::new();
+    for _ in 0..count {
+        interp.generate(&mut buf);
+        hash.update(&(buf.len() as u32).to_le_bytes());
+        hash.update(&buf);
+    }
+    Ok(hash.0)
+}
+
+/// Hash the first `count` inputs generated by the compiled benchmark program
+/// `bin`, streaming them from it
+pub fn binary_hash(bin: &Path, count: u64) -> std::io::Result<u64> {
+    let mut child = Command::new(bin)
+        .arg("--stream").arg("--iterations").arg(count.to_string())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut stdout = child.stdout.take().unwrap();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut hash = Fnv::new();
+    loop {
+        let bytes = stdout.read(&mut buf)?;
+        if bytes == 0 {
+            break;
+        }
+        hash.update(&buf[..bytes]);
+    }
+
+    if !child.wait()?.success() {
+        return Err(std::io::Error::other(
+            "Compiled program failed while streaming inputs"));
+    }
+    Ok(hash.0)
+}
@@ -0,0 +1,294 @@
+//! Parameterized rules. A rule named like `<list(item, sep)>` is a template,
+//! which refers to its parameters as `<item>` and `<sep>`. Tokens such as
+//! `<list(<expr>, ",")>` instantiate it, creating a concrete copy of the
+//! rule with the parameters substituted. Arguments are either tokens, eg. a
+//! reference to a rule or another instantiation, or literal text in double
+//! quotes. Templates are expanded when the grammar is loaded, so the rest of
+//! fzero only ever sees plain rules.
+
+use std::collections::{BTreeMap, BTreeSet};
+use crate::grammar::{helper, Grammar};
+
+/// Maximum number of rules instantiated from templates, to catch templates
+/// which instantiate themselves with ever growing arguments
+const MAX_INSTANCES: usize = 10000;
+
+/// An argument of a template instantiation
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Arg {
+    /// A token, such as a reference to a rule
+    Token(String),
+
+    /// Literal text, given in double quotes
+    Literal(String),
+}
+
+impl Arg {
+    /// Get the token this argument expands to when substituted for a
+    /// parameter
+    fn token(&self) -> &str {
+        match self {
+            Arg::Token(token) | Arg::Literal(token) => token,
+        }
+    }
+
+    /// Get the form of this argument used in the name of an instance. This
+    /// drops the angle brackets of references and escapes literals such
+    /// that the name is still shaped like a reference.
+    fn mangle(&self) -> String {
+        match self {
+            Arg::Token(token) => token.strip_prefix('<')
+                .and_then(|x| x.strip_suffix('>')).unwrap_or(token).into(),
+            Arg::Literal(text) => {
+                let mut ret = String::from("\"");
+                for chr in text.chars() {
+                    if chr.is_whitespace() || "<>\"\\".contains(chr) {
+                        ret += &format!("\\x{:02x}", chr as u32);
+                    } else {
+                        ret.push(chr);
+                    }
+                }
+                ret + "\""
+            }
+        }
+    }
+}
+
+/// Split `token` into a template name and arguments if it has the shape of
+/// an instantiation, `<name(arg, ...)>`
+fn split(token: &str) -> Option<(&str, Vec<String>)> {
+    let inner = token.strip_prefix('<')?.strip_suffix(")>")?;
+    let (name, args) = inner.split_once('(')?;
+    if name.is_empty() || !name.chars()
+            .all(|x| x.is_alphanumeric() || x == '_' || x == '-') {
+        return None;
+    }
+
+    // Split at commas which aren't nested in a reference, an instantiation
+    // or a literal
+    let mut ret = Vec::new();
+    let mut cur = String::new();
+    let mut nesting = 0i32;
+    let mut quoted = false;
+    let mut chars = args.chars();
+    while let Some(chr) = chars.next() {
+        match chr {
+            '\\' if quoted => {
+                cur.push(chr);
+                cur.extend(chars.next());
+                continue;
+            }
+            '"' => quoted = !quoted,
+            '<' | '(' if !quoted => nesting += 1,
+            '>' | ')' if !quoted => nesting -= 1,
+            ',' if !quoted && nesting == 0 => {
+                ret.push(cur.trim().to_string());
+                cur.clear();
+                continue;
+            }
+            _ => {}
+        }
+        cur.push(chr);
+    }
+    ret.push(cur.trim().to_string());
+
+    if quoted || nesting != 0 || ret.iter().any(|x| x.is_empty()) {
+        return None;
+    }
+    Some((name, ret))
+}
+
+/// Parse an argument of an instantiation
+fn parse_arg(arg: &str) -> std::io::Result<Arg> {
+    let Some(quoted) = arg.strip_prefix('"') else {
+        return Ok(Arg::Token(arg.to_string()));
+    };
+
+    let mut text = String::new();
+    let mut chars = quoted.strip_suffix('"').ok_or_else(|| {
+        std::io::Error::other(format!("Unterminated literal {}", arg))
+    })?.chars();
+    while let Some(chr) = chars.next() {
+        if chr == '\\' {
+            // Either an escaped character or a `\xNN` escape from a mangled
+            // name
+            match chars.next() {
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    let chr = u32::from_str_radix(&hex, 16).ok()
+                        .and_then(char::from_u32).ok_or_else(|| {
+                            std::io::Error::other(format!(
+                                "Invalid escape in literal {}", arg))
+                        })?;
+                    text.push(chr);
+                }
+                Some(chr) => text.push(chr),
+                None => {}
+            }
+        } else {
+            text.push(chr);
+        }
+    }
+    Ok(Arg::Literal(text))
+}
+
+/// A template rule
+struct Template {
+    /// Names of the parameters, as references, eg. `<item>`
+    params: Vec<String>,
+
+    /// Alternatives of the rule
+    alts: Vec<Vec<String>>,
+}
+
+/// State used while instantiating templates
+struct Instantiator {
+    /// Templates by name
+    templates: BTreeMap<String, Template>,
+
+    /// Names of the plain rules, which are never instantiations even if
+    /// they look like one, eg. instances written out by `fzero export`
+    rules: BTreeSet<String>,
+
+    /// Rules created so far, by name
+    instances: BTreeMap<String, Vec<Vec<String>>>,
+
+    /// Instances which still need their alternatives resolved, along with
+    /// the template they're instances of and its parameter bindings. This
+    /// is a queue rather than recursion so long chains of instances can't
+    /// overflow our stack.
+    queue: Vec<(String, String, BTreeMap<String, Arg>)>,
+}
+
+impl Instantiator {
+    /// Resolve `token` in a rule whose parameters are bound to `bindings`,
+    /// instantiating templates as needed. Returns the token to use instead.
+    fn resolve(&mut self, token: &str, bindings: &BTreeMap<String, Arg>)
+            -> std::io::Result<String> {
+        // Parameters are replaced with their arguments
+        if let Some(arg) = bindings.get(token) {
+            return Ok(arg.token().to_string());
+        }
+
+        // Substitute in the rule a helper applies to
+        if let Some((_, rule)) = helper(token) {
+            let name = &token[..token.len() - rule.len() - 1];
+            return Ok(format!("{}{})", name, self.resolve(rule, bindings)?));
+        }
+
+        let split = split(token).filter(|_| !self.rules.contains(token));
+        let Some((name, args)) = split else {
+            return Ok(token.to_string());
+        };
+        let params = self.templates.get(name).ok_or_else(|| {
+            std::io::Error::other(format!(
+                "{} instantiates undefined template {}", token, name))
+        })?.params.clone();
+        if params.len() != args.len() {
+            return Err(std::io::Error::other(format!(
+                "{} has {} arguments, but template {} has {} parameters",
+                token, args.len(), name, params.len())));
+        }
+
+        // Resolve the arguments themselves first, they may use parameters
+        // or instantiate templates of their own
+        let args = args.iter().map(|arg| {
+            Ok(match parse_arg(arg)? {
+                Arg::Token(token) => {
+                    match bindings.get(&token) {
+                        Some(arg) => arg.clone(),
+                        None => Arg::Token(self.resolve(&token, bindings)?),
+                    }
+                }
+                literal => literal,
+            })
+        }).collect::<std::io::Result<Vec<Arg>>>()?;
+
+        // Name the instance after its arguments, and create it if it doesn't
+        // exist yet
+        let instance = format!("<{}({})>", name, args.iter()
+            .map(|x| x.mangle()).collect::<Vec<_>>().join(","));
+        if !self.instances.contains_key(&instance) {
+            if self.instances.len() >= MAX_INSTANCES {
+                return Err(std::io::Error::other(format!(
+                    "More than {} template instances, {} probably \
+                     instantiates itself forever", MAX_INSTANCES, name)));
+            }
+
+            let bindings = params.into_iter().zip(args).collect();
+            self.instances.insert(instance.clone(), Vec::new());
+            self.queue.push((instance.clone(), name.to_string(), bindings));
+        }
+
+        Ok(instance)
+    }
+
+    /// Resolve every token of `alts`, with parameters bound to `bindings`
+    fn resolve_alts(&mut self, alts: &[Vec<String>],
+            bindings: &BTreeMap<String, Arg>)
+            -> std::io::Result<Vec<Vec<String>>> {
+        alts.iter().map(|alt| {
+            alt.iter().map(|token| self.resolve(token, bindings)).collect()
+        }).collect()
+    }
+}
+
+impl Grammar {
+    /// Replace all templates with the instances of them which are used,
+    /// see the module documentation
+    pub fn instantiate(&mut self) -> std::io::Result<()> {
+        let mut inst = Instantiator {
+            templates: BTreeMap::new(),
+            rules:     BTreeSet::new(),
+            instances: BTreeMap::new(),
+            queue:     Vec::new(),
+        };
+
+        // Pull out the templates, whose names have identifiers as arguments
+        let names: Vec<String> = self.0.keys().cloned().collect();
+        for name in names {
+            let Some((template, params)) = split(&name) else { continue };
+            if params.iter().any(|x| {
+                !x.chars().all(|x| x.is_alphanumeric() || x == '_')
+            }) {
+                continue;
+            }
+
+            let template = template.to_string();
+            let params = params.iter().map(|x| format!("<{}>", x)).collect();
+            let alts = self.0.remove(&name).unwrap();
+            if inst.templates.insert(template.clone(),
+                    Template { params, alts }).is_some() {
+                return Err(std::io::Error::other(format!(
+                    "Template {} is defined more than once", template)));
+            }
+        }
+
+        if inst.templates.is_empty() {
+            return Ok(());
+        }
+        inst.rules = self.0.keys().cloned().collect();
+
+        // Resolve every token of the plain rules, which creates the
+        // instances they use
+        for alts in self.0.values_mut() {
+            *alts = inst.resolve_alts(alts, &BTreeMap::new())?;
+        }
+
+        // Then resolve the instances, which may create more of them
+        while let Some((instance, template, bindings)) = inst.queue.pop() {
+            let alts = inst.templates[&template].alts.clone();
+            let alts = inst.resolve_alts(&alts, &bindings)?;
+            inst.instances.insert(instance, alts);
+        }
+
+        for (name, alts) in inst.instances {
+            if self.0.insert(name.clone(), alts).is_some() {
+                return Err(std::io::Error::other(format!(
+                    "Template instance {} is already defined", name)));
+            }
+        }
+
+        Ok(())
+    }
+}
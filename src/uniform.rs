@@ -0,0 +1,240 @@
+//! Uniform sampling of derivations of a fixed size, using the recursive
+//! method. At build time we count the number of derivations of every fragment
+//! for every size up to the target size, and generation uses these counts to
+//! weight each random choice such that every derivation of the target size is
+//! equally likely.
+//!
+//! The size of a derivation is the number of fragment expansions in it, where
+//! every fragment expansion (including terminals, nops and each iteration of a
+//! loop) counts as one. Counting expansions rather than bytes guarantees that
+//! every sub-derivation is strictly smaller than its parent, so the counts are
+//! always well defined.
+//!
+//! The counts are stored as `f64`s as they grow exponentially with the size.
+//! Both the interpreter and the generated code perform exactly the same
+//! floating point operations on exactly the same tables, so they still make
+//! the same choices for a given seed.
+
+use std::collections::BTreeMap;
+use crate::grammar::{Fragment, FragmentId, GrammarRust};
+
+/// Tables of derivation counts for a grammar
+pub struct Counts {
+    /// Size of the derivations being sampled. This is the largest size no
+    /// larger than the requested size for which the start fragment has any
+    /// derivations.
+    pub size: usize,
+
+    /// Number of entries in each table, one more than the requested size
+    pub stride: usize,
+
+    /// All count tables, each `stride` entries long and indexed by size. The
+    /// table for fragment `N` is the `N`th table.
+    pub tables: Vec<f64>,
+
+    /// For each expression and child index `j > 0`, the offset of the table
+    /// counting derivations of children `j..` of the expression combined
+    pub suffixes: BTreeMap<(FragmentId, usize), usize>,
+
+    /// For each loop and repeating option, the offset of the table counting
+    /// derivations of the option followed by the rest of the loop
+    pub repeats: BTreeMap<(FragmentId, usize), usize>,
+}
+
+impl Counts {
+    /// Get the offset of the count table for fragment `id`
+    pub fn fragment(&self, id: FragmentId) -> usize {
+        id.0 * self.stride
+    }
+
+    /// Randomly pick a size for the first part of a derivation of `size`
+    /// split into two parts, counted by the tables at `first` and `rest`.
+    /// Each part is at least one expansion.
+    pub fn split(&self, rand: u64, first: usize, rest: usize, size: usize)
+            -> usize {
+        1 + choose(rand, size - 1, |k| {
+            self.tables[first + k + 1] * self.tables[rest + size - k - 1]
+        })
+    }
+}
+
+/// Randomly pick an index below `count`, weighted by `weight`, with the
+/// random number `rand`. Indices with a weight of zero are never picked.
+pub fn choose(rand: u64, count: usize, weight: impl Fn(usize) -> f64)
+        -> usize {
+    let total: f64 = (0..count).map(&weight).sum();
+    let mut point = (rand >> 11) as f64 / (1u64 << 53) as f64 * total;
+
+    // Fall back to the last possible pick in case rounding carries us past
+    // the end
+    let mut last = 0;
+    for idx in 0..count {
+        let weight = weight(idx);
+        if weight > 0. {
+            if point < weight {
+                return idx;
+            }
+            point -= weight;
+            last = idx;
+        }
+    }
+    last
+}
+
+impl GrammarRust {
+    /// Count the derivations of every fragment for every size up to `size`
+    pub fn counts(&self, size: usize) -> std::io::Result<Counts> {
+        // Helpers change the output of a derivation without changing its
+        // size, and recalls skip expanding their body entirely, there's no
+        // sensible way to weight them
+        if self.fragments.iter().any(|x| {
+            matches!(x, Fragment::Length(..) | Fragment::Chunked(_) |
+                Fragment::Capture { .. } | Fragment::Recall { .. })
+        }) {
+            return Err(std::io::Error::other("Uniform sampling doesn't \
+                support %length, %chunked, %capture or %recall"));
+        }
+
+        let stride = size + 1;
+        let mut counts = Counts {
+            size,
+            stride,
+            tables: vec![0.; self.fragments.len() * stride],
+            suffixes: BTreeMap::new(),
+            repeats: BTreeMap::new(),
+        };
+
+        // Allocate the extra tables for expressions and loops
+        let mut next = counts.tables.len();
+        for (idx, fragment) in self.fragments.iter().enumerate() {
+            match fragment {
+                Fragment::Expression(expr) => {
+                    for child in 1..expr.len() {
+                        counts.suffixes.insert((FragmentId(idx), child), next);
+                        next += stride;
+                    }
+                }
+                Fragment::Loop { repeat, .. } => {
+                    for (option, _) in repeat.iter().enumerate()
+                            .filter(|(_, &repeat)| repeat) {
+                        counts.repeats.insert((FragmentId(idx), option), next);
+                        next += stride;
+                    }
+                }
+                _ => {}
+            }
+        }
+        counts.tables.resize(next, 0.);
+
+        // Number of ways to split `size` between the tables at `first` and
+        // `rest`, each part being at least one expansion
+        let convolve = |tables: &[f64], first: usize, rest: usize,
+                size: usize| -> f64 {
+            (1..size).map(|k| tables[first + k] * tables[rest + size - k])
+                .sum()
+        };
+
+        for n in 1..=size {
+            // Compute the combined tables for size `n - 1` first, as the
+            // fragments of size `n` are built from them. These only depend on
+            // smaller sizes, and suffixes are computed from the end of the
+            // expression backwards.
+            let s = n - 1;
+            for (idx, fragment) in self.fragments.iter().enumerate() {
+                let id = FragmentId(idx);
+                match fragment {
+                    Fragment::Expression(expr) => {
+                        for child in (1..expr.len()).rev() {
+                            let first = counts.fragment(expr[child]);
+                            let count = if child + 1 == expr.len() {
+                                counts.tables[first + s]
+                            } else {
+                                convolve(&counts.tables, first,
+                                    counts.suffixes[&(id, child + 1)], s)
+                            };
+                            counts.tables[counts.suffixes[&(id, child)] + s] =
+                                count;
+                        }
+                    }
+                    Fragment::Loop { options, repeat } => {
+                        for (option, _) in repeat.iter().enumerate()
+                                .filter(|(_, &repeat)| repeat) {
+                            let count = convolve(&counts.tables,
+                                counts.fragment(options[option]),
+                                counts.fragment(id), s);
+                            counts.tables[counts.repeats[&(id, option)] + s] =
+                                count;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // Now count the fragments themselves, each expansion costs one
+            for (idx, fragment) in self.fragments.iter().enumerate() {
+                let id = FragmentId(idx);
+                let count = match fragment {
+                    Fragment::NonTerminal(options) => {
+                        options.iter()
+                            .map(|&x| counts.tables[counts.fragment(x) + s])
+                            .sum()
+                    }
+                    Fragment::Expression(expr) => {
+                        match expr.len() {
+                            0 => (s == 0) as u8 as f64,
+                            1 => counts.tables[counts.fragment(expr[0]) + s],
+                            _ => convolve(&counts.tables,
+                                counts.fragment(expr[0]),
+                                counts.suffixes[&(id, 1)], s),
+                        }
+                    }
+                    Fragment::Loop { options, repeat } => {
+                        options.iter().zip(repeat).enumerate()
+                            .map(|(option, (&x, &repeat))| {
+                                let table = if repeat {
+                                    counts.repeats[&(id, option)]
+                                } else {
+                                    counts.fragment(x)
+                                };
+                                counts.tables[table + s]
+                            }).sum()
+                    }
+                    Fragment::Embed(body) | Fragment::Push(body) |
+                            Fragment::Transform(body, _) => {
+                        counts.tables[counts.fragment(*body) + s]
+                    }
+                    Fragment::Terminal(_) | Fragment::Message(_) |
+                            Fragment::Pop | Fragment::Int(_) |
+                            Fragment::Float(_) | Fragment::Nop => {
+                        (s == 0) as u8 as f64
+                    }
+                    // Byte samplers aren't used when sampling uniformly
+                    Fragment::Length(..) | Fragment::Chunked(_) |
+                            Fragment::Capture { .. } |
+                            Fragment::Recall { .. } |
+                            Fragment::Bytes(_) => unreachable!(),
+                };
+                let table = counts.fragment(id);
+                counts.tables[table + n] = count;
+            }
+
+            // Counts only grow with the size, so give up as soon as they
+            // overflow
+            if counts.tables.iter().any(|x| !x.is_finite()) {
+                return Err(std::io::Error::other(format!(
+                    "Too many derivations to count with a target size of {}, \
+                     they overflow at size {}", size, n)));
+            }
+        }
+
+        // Use the largest size we can actually generate
+        let start = counts.fragment(self.start.unwrap());
+        counts.size = (1..=size).rev()
+            .find(|&n| counts.tables[start + n] > 0.)
+            .ok_or_else(|| std::io::Error::other(format!(
+                "The start rule has no derivations of size {} or smaller",
+                size)))?;
+
+        Ok(counts)
+    }
+}
@@ -0,0 +1,257 @@
+//! Linting of grammar files. This operates on the grammar as it was loaded
+//! from json, before any conversion, so problems can be reported in terms of
+//! the rule names the user actually wrote.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use crate::grammar::{helper, is_reference_shaped, referenced, Grammar};
+use crate::scalars::generator;
+
+/// How bad a reported problem is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The grammar works, but probably doesn't do what the author intended
+    Warning,
+
+    /// The grammar is broken and will generate garbage or fail to build
+    Error,
+}
+
+/// A single problem found in a grammar
+#[derive(Debug)]
+pub struct Diagnostic {
+    /// How bad the problem is
+    pub severity: Severity,
+
+    /// Name of the rule the problem was found in
+    pub rule: String,
+
+    /// 1-indexed line and column of the rule definition in the json source,
+    /// if we could find it
+    pub location: Option<(usize, usize)>,
+
+    /// Human readable description of the problem
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((line, col)) = self.location {
+            write!(f, "{}:{}:", line, col)?;
+        }
+
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error   => "error",
+        };
+        write!(f, " {}: {}: {}", severity, self.rule, self.message)
+    }
+}
+
+/// Find the 1-indexed line and column of the definition of `rule` in the json
+/// `source`. This is a textual search for the quoted key followed by a `:`,
+/// which is good enough for pointing a human at the right spot.
+pub fn locate(source: &str, rule: &str) -> Option<(usize, usize)> {
+    let key = serde_json::to_string(rule).ok()?;
+
+    let mut offset = 0;
+    while let Some(found) = source[offset..].find(&key) {
+        let start = offset + found;
+        offset = start + key.len();
+
+        // Make sure this is a key and not a value in some rule body
+        if source[offset..].trim_start().starts_with(':') {
+            let line = source[..start].matches('\n').count() + 1;
+            let col = start - source[..start].rfind('\n')
+                .map(|x| x + 1).unwrap_or(0) + 1;
+            return Some((line, col));
+        }
+    }
+
+    None
+}
+
+/// Lints a grammar
+struct Validator<'a> {
+    /// Grammar being validated
+    grammar: &'a Grammar,
+
+    /// Json source of the grammar, used for locating rules
+    source: &'a str,
+
+    /// Problems found so far
+    diags: Vec<Diagnostic>,
+}
+
+impl<'a> Validator<'a> {
+    /// Record a new problem for `rule`
+    fn report(&mut self, severity: Severity, rule: &str, message: String) {
+        self.diags.push(Diagnostic {
+            severity,
+            rule: rule.to_string(),
+            location: locate(self.source, rule),
+            message,
+        });
+    }
+
+    /// Iterate over all tokens in `rule` which refer to other defined rules
+    fn references(&self, rule: &str) -> impl Iterator<Item = &'a str> {
+        let grammar = self.grammar;
+        grammar.0[rule].iter().flatten().map(|x| referenced(x))
+            .filter(move |x| grammar.0.contains_key(*x))
+    }
+
+    /// Report references which look like non-terminals but are not defined
+    fn check_undefined(&mut self) {
+        for (rule, alts) in self.grammar.0.iter() {
+            for (alt_id, alt) in alts.iter().enumerate() {
+                for token in alt {
+                    // Generation helpers must apply to a defined rule
+                    if let Some((_, target)) = helper(token) {
+                        if !self.grammar.0.contains_key(target) {
+                            self.report(Severity::Error, rule, format!(
+                                "alternative {} uses {} on undefined rule \
+                                 {}", alt_id, token, target));
+                        }
+                        continue;
+                    }
+
+                    // Random scalars only need valid arguments
+                    if self.grammar.0.contains_key(token) {
+                        continue;
+                    }
+                    match generator(token) {
+                        Some(Ok(_)) => continue,
+                        Some(Err(err)) => {
+                            self.report(Severity::Error, rule, format!(
+                                "alternative {}: {}", alt_id, err));
+                            continue;
+                        }
+                        None => {}
+                    }
+
+                    if is_reference_shaped(token) {
+                        self.report(Severity::Error, rule, format!(
+                            "alternative {} references undefined \
+                             non-terminal {}, it will be emitted as a \
+                             literal", alt_id, token));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Report rules which can't be reached from the start rule
+    fn check_unreachable(&mut self, start: &str) {
+        if !self.grammar.0.contains_key(start) {
+            let roots = self.grammar.roots().join(", ");
+            self.report(Severity::Error, start, format!(
+                "start rule is not defined, top-level rules are: {}",
+                roots));
+            return;
+        }
+
+        // Walk the grammar from the start rule
+        let mut reachable = BTreeSet::new();
+        let mut queue = vec![start];
+        while let Some(rule) = queue.pop() {
+            if reachable.insert(rule) {
+                queue.extend(self.references(rule));
+            }
+        }
+
+        for rule in self.grammar.0.keys() {
+            if !reachable.contains(rule.as_str()) {
+                self.report(Severity::Warning, rule, format!(
+                    "rule is unreachable from {}", start));
+            }
+        }
+    }
+
+    /// Report rules which can never expand to a string of only terminals, no
+    /// matter how deep we are allowed to go
+    fn check_termination(&mut self) {
+        // Rules known to be able to terminate. Iterate to a fixed point,
+        // adding a rule once any of its alternatives only refers to
+        // terminals or rules that can terminate.
+        let mut terminates = BTreeSet::new();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (rule, alts) in self.grammar.0.iter() {
+                if terminates.contains(rule.as_str()) {
+                    continue;
+                }
+
+                if alts.iter().any(|alt| alt.iter().all(|token| {
+                    let token = referenced(token);
+                    !self.grammar.0.contains_key(token) ||
+                        terminates.contains(token)
+                })) {
+                    terminates.insert(rule.as_str());
+                    changed = true;
+                }
+            }
+        }
+
+        for (rule, alts) in self.grammar.0.iter() {
+            if !alts.is_empty() && !terminates.contains(rule.as_str()) {
+                self.report(Severity::Error, rule,
+                    "rule can never terminate, every alternative recurses \
+                     infinitely".into());
+            }
+        }
+    }
+
+    /// Report rules with no alternatives and other suspicious constructs
+    fn check_suspicious(&mut self) {
+        for (rule, alts) in self.grammar.0.iter() {
+            if alts.is_empty() {
+                self.report(Severity::Error, rule,
+                    "rule has no alternatives".into());
+            }
+
+            if !is_reference_shaped(rule) {
+                self.report(Severity::Warning, rule,
+                    "rule name is not of the form <name>, terminals equal \
+                     to it will be expanded as references".into());
+            }
+
+            // Track alternatives we've seen to detect duplicates
+            let mut seen = BTreeMap::new();
+            for (alt_id, alt) in alts.iter().enumerate() {
+                if let Some(&prev) = seen.get(alt) {
+                    self.report(Severity::Warning, rule, format!(
+                        "alternative {} is a duplicate of alternative {}",
+                        alt_id, prev));
+                } else {
+                    seen.insert(alt, alt_id);
+                }
+
+                if alt.len() == 1 && &alt[0] == rule {
+                    self.report(Severity::Warning, rule, format!(
+                        "alternative {} only refers to the rule itself",
+                        alt_id));
+                }
+            }
+        }
+    }
+}
+
+/// Validate `grammar`, loaded from the json `source`, returning all problems
+/// found
+pub fn validate(grammar: &Grammar, source: &str, start: &str)
+        -> Vec<Diagnostic> {
+    let mut validator = Validator {
+        grammar,
+        source,
+        diags: Vec::new(),
+    };
+
+    validator.check_undefined();
+    validator.check_unreachable(start);
+    validator.check_termination();
+    validator.check_suspicious();
+
+    validator.diags
+}
@@ -0,0 +1,133 @@
+//! Automatic whitespace injection. Grammars for programming languages and
+//! other free-form text need optional whitespace between nearly every pair
+//! of tokens, and writing it out by hand doubles the size of the grammar.
+//! Instead the `%whitespace` directive names a rule, eg.
+//! `"%whitespace": [["<ws>"]]`, which is inserted between the tokens of every
+//! alternative with a probability in percent given as an optional second
+//! token, eg. `[["<ws>", "30"]]`. Rules listed in `%no_whitespace`, such as
+//! identifiers and string literals, are left alone, as are the rules which
+//! can only be reached through them.
+
+use std::collections::BTreeSet;
+use crate::grammar::{referenced, Directives, Grammar};
+
+/// Name of the rule inserted between tokens, which expands to either the
+/// whitespace rule or nothing
+const SEPARATOR: &str = "<%whitespace>";
+
+/// Probability in percent of inserting whitespace when none is given
+pub const DEFAULT_PERCENT: u32 = 50;
+
+/// Configuration of whitespace injection
+#[derive(Debug)]
+pub struct Whitespace {
+    /// Rule generating the whitespace
+    pub rule: String,
+
+    /// Probability in percent of inserting the rule between two tokens
+    pub percent: u32,
+
+    /// Rules whose alternatives are left alone
+    pub exclude: BTreeSet<String>,
+}
+
+impl Directives {
+    /// Get the whitespace injection configured by the `%whitespace` and
+    /// `%no_whitespace` directives, if any
+    pub fn whitespace(&self) -> std::io::Result<Option<Whitespace>> {
+        let exclude = self.0.get("%no_whitespace").into_iter().flatten()
+            .flatten().cloned().collect();
+        let (rule, percent) = match self.0.get("%whitespace")
+                .map(|x| x.as_slice()) {
+            None => return Ok(None),
+            Some([alt]) => match alt.as_slice() {
+                [rule] => (rule, Some(DEFAULT_PERCENT)),
+                [rule, percent] => {
+                    (rule, percent.parse().ok().filter(|&x| x <= 100))
+                }
+                _ => (&alt[0], None),
+            },
+            Some(_) => return Err(std::io::Error::other(
+                "The %whitespace directive must have exactly one \
+                 alternative")),
+        };
+        let percent = percent.ok_or_else(|| {
+            std::io::Error::other("Invalid %whitespace directive, expected \
+                a rule and optionally a probability from 0 to 100 percent")
+        })?;
+
+        Ok(Some(Whitespace { rule: rule.clone(), percent, exclude }))
+    }
+}
+
+/// Get the greatest common divisor of `a` and `b`
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+impl Grammar {
+    /// Insert whitespace between the tokens of the alternatives of every
+    /// rule reachable from `start`, see the module documentation. Returns
+    /// the number of rules whitespace was inserted into.
+    pub fn inject_whitespace(&mut self, whitespace: &Whitespace, start: &str)
+            -> std::io::Result<usize> {
+        if self.0.contains_key(SEPARATOR) {
+            return Err(std::io::Error::other(format!(
+                "Can't inject whitespace, the rule {} is already defined",
+                SEPARATOR)));
+        }
+        for rule in std::iter::once(&whitespace.rule)
+                .chain(&whitespace.exclude) {
+            if !self.0.contains_key(rule) {
+                return Err(std::io::Error::other(format!(
+                    "Can't inject whitespace, the rule {} is not defined",
+                    rule)));
+            }
+        }
+
+        // Find the rules which can be reached without going through an
+        // excluded rule or the whitespace itself
+        let mut reachable = BTreeSet::new();
+        let mut queue = vec![start];
+        while let Some(name) = queue.pop() {
+            if name == whitespace.rule || whitespace.exclude.contains(name) ||
+                    !reachable.insert(name.to_string()) {
+                continue;
+            }
+            for token in self.0.get(name).into_iter().flatten().flatten() {
+                let rule = referenced(token);
+                if self.0.contains_key(rule) {
+                    queue.push(rule);
+                }
+            }
+        }
+
+        // Interleave the separator with the tokens, apart from around
+        // message boundaries, which shouldn't start or end with whitespace
+        for name in &reachable {
+            for alt in self.0.get_mut(name).unwrap() {
+                let mut injected = Vec::with_capacity(alt.len() * 2);
+                for (ii, token) in alt.drain(..).enumerate() {
+                    let boundary = token == "%message" ||
+                        injected.last().is_some_and(|x| x == "%message");
+                    if ii > 0 && !boundary {
+                        injected.push(SEPARATOR.to_string());
+                    }
+                    injected.push(token);
+                }
+                *alt = injected;
+            }
+        }
+
+        // The probability of whitespace is the share of alternatives of the
+        // separator which generate it
+        let divisor = gcd(whitespace.percent, 100);
+        let with = (whitespace.percent / divisor) as usize;
+        let without = ((100 - whitespace.percent) / divisor) as usize;
+        let mut alts = vec![vec![whitespace.rule.clone()]; with];
+        alts.extend(std::iter::repeat_n(Vec::new(), without));
+        self.0.insert(SEPARATOR.to_string(), alts);
+
+        Ok(reachable.len())
+    }
+}
@@ -0,0 +1,1897 @@
+//! Conversion of XML schemas into grammars which generate documents
+//! conforming to them. Schemas are either DTDs or XSDs, which are both
+//! read into the same model: elements with attributes and a content model
+//! of nested sequences, choices and repetitions of other elements. Every
+//! element gets a rule generating its open tag, attributes, content and
+//! matching close tag, so tags always match and nest.
+//!
+//! DTDs are read along with their parameter entities and conditional
+//! sections. Only the structural parts of XSDs are supported: elements,
+//! complex types with sequences, choices, groups and extensions, attributes
+//! and simple types with enumerations, lists and unions. Facets other than
+//! enumerations are ignored, as are identity constraints, so values of
+//! `ID` attributes may repeat and `IDREF`s may not refer to anything.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::btree_map::Entry;
+use crate::grammar::Grammar;
+
+/// Namespace of XSD elements and built-in types
+const XSD_NAMESPACE: &str = "http://www.w3.org/2001/XMLSchema";
+
+/// Maximum number of optional repetitions emitted for content with a
+/// bounded number of occurrences, larger maximums are cut down to this
+const MAX_OPTIONAL: usize = 16;
+
+/// Maximum size of a DTD after expanding parameter entities, to catch
+/// entities which expand exponentially
+const MAX_EXPANSION: usize = 16 * 1024 * 1024;
+
+/// Maximum depth of XSD type derivations and group references
+const MAX_DEPTH: usize = 64;
+
+/// Create an error for a malformed schema
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// Value of an attribute or of an element with simple content
+#[derive(Clone, Debug)]
+enum Value {
+    /// Any text
+    Text,
+
+    /// One of a set of strings
+    Enum(Vec<String>),
+
+    /// A built-in rule, such as `<xml.integer>`
+    Builtin(String),
+
+    /// An integer within optional bounds
+    Range(Option<i128>, Option<i128>),
+
+    /// Text of a minimum and optional maximum number of characters
+    Chars(usize, Option<usize>),
+
+    /// A whitespace separated list of values
+    List(Box<Value>),
+
+    /// Any of a set of values
+    Union(Vec<Value>),
+}
+
+/// An attribute of an element
+#[derive(Clone, Debug)]
+struct Attribute {
+    /// Name of the attribute as it's emitted, including any prefix
+    name: String,
+
+    /// Values of the attribute
+    value: Value,
+
+    /// Whether the attribute must be present
+    required: bool,
+}
+
+/// Part of a content model
+#[derive(Clone, Debug)]
+enum Particle {
+    /// An element, by its key
+    Element(String),
+
+    /// Text, which may be empty
+    Text,
+
+    /// Each of the particles in order
+    Seq(Vec<Particle>),
+
+    /// One of the particles
+    Choice(Vec<Particle>),
+
+    /// A particle repeated a minimum and optional maximum number of times
+    Repeat(Box<Particle>, usize, Option<usize>),
+}
+
+impl Particle {
+    /// Wrap the particle to occur between `min` and `max` times
+    fn occurs(self, min: usize, max: Option<usize>) -> Self {
+        if min == 1 && max == Some(1) {
+            self
+        } else {
+            Particle::Repeat(Box::new(self), min, max)
+        }
+    }
+
+    /// Allow text after every element in the particle, for mixed content
+    fn mixed(self) -> Self {
+        match self {
+            Particle::Element(_) => Particle::Seq(vec![self, Particle::Text]),
+            Particle::Text => self,
+            Particle::Seq(x) => {
+                Particle::Seq(x.into_iter().map(Self::mixed).collect())
+            }
+            Particle::Choice(x) => {
+                Particle::Choice(x.into_iter().map(Self::mixed).collect())
+            }
+            Particle::Repeat(x, min, max) => {
+                Particle::Repeat(Box::new(x.mixed()), min, max)
+            }
+        }
+    }
+
+    /// Add the keys of the elements used by the particle to `keys`
+    fn elements<'a>(&'a self, keys: &mut BTreeSet<&'a str>) {
+        match self {
+            Particle::Element(key) => { keys.insert(key); }
+            Particle::Text => {}
+            Particle::Seq(x) | Particle::Choice(x) => {
+                x.iter().for_each(|x| x.elements(keys));
+            }
+            Particle::Repeat(x, _, _) => x.elements(keys),
+        }
+    }
+}
+
+/// Content model of an element
+#[derive(Clone, Debug)]
+enum Content {
+    /// No content, the element may be self-closing
+    Empty,
+
+    /// Any text and any of the top-level elements
+    Any,
+
+    /// A single value
+    Simple(Value),
+
+    /// Elements, and text if the content is mixed
+    Children(Particle),
+}
+
+/// An element
+#[derive(Clone, Debug)]
+struct Element {
+    /// Name of the element as it's emitted, including any prefix
+    name: String,
+
+    /// Attributes of the element, in the order they're emitted
+    attributes: Vec<Attribute>,
+
+    /// Content of the element
+    content: Content,
+}
+
+/// An XML schema. Elements are identified by keys, which are their names
+/// in DTDs. Elements local to an XSD type are keyed by their scope and
+/// name, as different types may have different elements of the same name.
+#[derive(Default)]
+pub struct Schema {
+    /// Elements by key
+    elements: BTreeMap<String, Element>,
+
+    /// Keys of the elements which may be the root of a document, in the
+    /// order they were declared
+    globals: Vec<String>,
+
+    /// Namespace declaration emitted on the root element, if any
+    namespace: Option<String>,
+}
+
+/// Escape `text` for use in attribute values and content
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Get the alternatives of single characters for each of `chars`
+fn chars(chars: &str) -> Vec<Vec<String>> {
+    chars.chars().map(|x| vec![x.to_string()]).collect()
+}
+
+/// Get the alternatives for integers between `min` and `max`. These are
+/// the interesting values in range and up to two digit numbers, which are
+/// in range of every XSD integer type.
+fn integers(min: Option<i128>, max: Option<i128>) -> Vec<Vec<String>> {
+    let in_range = |x: i128| {
+        min.is_none_or(|min| x >= min) && max.is_none_or(|max| x <= max)
+    };
+
+    let mut alts: Vec<Vec<String>> = Vec::new();
+    let mut values = vec![0, 1, -1, 127, -128, 255, 32767, -32768, 65535,
+        i32::MAX as i128, i32::MIN as i128, u32::MAX as i128,
+        i64::MAX as i128, i64::MIN as i128, u64::MAX as i128];
+    values.extend(min.iter().chain(max.iter()));
+    for value in values {
+        if in_range(value) && !alts.contains(&vec![value.to_string()]) {
+            alts.push(vec![value.to_string()]);
+        }
+    }
+    if in_range(0) && in_range(9) {
+        alts.push(vec!["0".into(), "<xml.digit>".into()]);
+    }
+    if in_range(99) {
+        alts.push(vec!["<xml.digit1>".into()]);
+        alts.push(vec!["<xml.digit1>".into(), "<xml.digit>".into()]);
+    }
+    if in_range(-99) {
+        alts.push(vec!["-".into(), "<xml.digit1>".into()]);
+        alts.push(vec!["-".into(), "<xml.digit1>".into(),
+            "<xml.digit>".into()]);
+    }
+    if max.is_none() {
+        alts.push(vec!["123456789012345678901234567890".into()]);
+    }
+    alts
+}
+
+/// Get the bounds of the built-in integer rule `name`, if it is one
+fn integer_range(name: &str) -> Option<(Option<i128>, Option<i128>)> {
+    Some(match name {
+        "<xml.integer>" => (None, None),
+        "<xml.long>" => (Some(i64::MIN as i128), Some(i64::MAX as i128)),
+        "<xml.int>" => (Some(i32::MIN as i128), Some(i32::MAX as i128)),
+        "<xml.short>" => (Some(-32768), Some(32767)),
+        "<xml.byte>" => (Some(-128), Some(127)),
+        "<xml.nonNegativeInteger>" => (Some(0), None),
+        "<xml.positiveInteger>" => (Some(1), None),
+        "<xml.nonPositiveInteger>" => (None, Some(0)),
+        "<xml.negativeInteger>" => (None, Some(-1)),
+        "<xml.unsignedLong>" => (Some(0), Some(u64::MAX as i128)),
+        "<xml.unsignedInt>" => (Some(0), Some(u32::MAX as i128)),
+        "<xml.unsignedShort>" => (Some(0), Some(65535)),
+        "<xml.unsignedByte>" => (Some(0), Some(255)),
+        _ => return None,
+    })
+}
+
+/// Get the alternatives of a built-in rule, which are named `<xml.*>`
+fn builtin_rule(name: &str) -> Vec<Vec<String>> {
+    if let Some((min, max)) = integer_range(name) {
+        return integers(min, max);
+    }
+
+    let alts: &[&[&str]] = match name {
+        "<xml.text>" => &[&[], &["<xml.char>", "<xml.text>"],
+            &[" ", "<xml.text>"], &["\t", "<xml.text>"],
+            &["\n", "<xml.text>"]],
+        "<xml.char>" => {
+            // Every alternative is a single character which isn't
+            // whitespace, so lengths are kept when whitespace is collapsed
+            let mut alts: Vec<Vec<String>> = (0x21u8..0x7f)
+                .filter(|x| !b"<>&\"".contains(x))
+                .map(|x| vec![(x as char).to_string()]).collect();
+            alts.extend(["&lt;", "&gt;", "&amp;", "&quot;",
+                "&apos;", "&#233;", "&#x1F600;", "\u{e9}", "\u{20ac}",
+                "\u{1f600}"].iter().map(|x| vec![x.to_string()]));
+            return alts;
+        }
+        "<xml.name>" => &[&["<xml.namestart>", "<xml.namechars>"]],
+        "<xml.nmtoken>" => &[&["<xml.namechar>", "<xml.namechars>"]],
+        "<xml.namestart>" => {
+            return chars(
+                "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_");
+        }
+        "<xml.namechar>" => &[&["<xml.namestart>"], &["<xml.digit>"],
+            &["-"], &["."]],
+        "<xml.namechars>" => &[&[], &["<xml.namechar>", "<xml.namechars>"]],
+        "<xml.digit>" => return chars("0123456789"),
+        "<xml.digit1>" => return chars("123456789"),
+        "<xml.digits>" => &[&[], &["<xml.digit>", "<xml.digits>"]],
+        "<xml.hex>" => return chars("0123456789abcdefABCDEF"),
+        "<xml.base64>" => {
+            return chars("ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz\
+                0123456789+/");
+        }
+
+        "<xml.decimal>" => &[&["<xml.integer>"],
+            &["<xml.integer>", ".", "<xml.digit>", "<xml.digits>"],
+            &[".5"], &["-0.0"], &["+1.25"]],
+        "<xml.double>" => &[&["<xml.decimal>"], &["<xml.decimal>", "E",
+            "<xml.integer>"], &["INF"], &["-INF"], &["NaN"], &["1e308"],
+            &["4.9E-324"]],
+        "<xml.boolean>" => &[&["true"], &["false"], &["1"], &["0"]],
+        "<xml.date>" => &[&["<xml.year>", "-", "<xml.month>", "-",
+            "<xml.day>"]],
+        "<xml.time>" => &[&["<xml.hour>", ":", "<xml.minute>", ":",
+            "<xml.minute>"], &["<xml.hour>", ":", "<xml.minute>", ":",
+            "<xml.minute>", ".", "<xml.digit>", "<xml.digits>"]],
+        "<xml.dateTime>" => &[&["<xml.date>", "T", "<xml.time>",
+            "<xml.timezone>"]],
+        "<xml.timezone>" => &[&[], &["Z"], &["+01:00"], &["-14:00"]],
+        "<xml.year>" => &[&["2000"], &["1970"], &["0001"], &["9999"],
+            &["<xml.digit1>", "<xml.digit>", "<xml.digit>", "<xml.digit>"]],
+        "<xml.month>" => &[&["0", "<xml.digit1>"], &["10"], &["11"],
+            &["12"]],
+        "<xml.day>" => &[&["0", "<xml.digit1>"], &["1", "<xml.digit>"],
+            &["2", "<xml.digit08>"]],
+        "<xml.digit08>" => return chars("012345678"),
+        "<xml.hour>" => &[&["0", "<xml.digit>"], &["1", "<xml.digit>"],
+            &["20"], &["23"]],
+        "<xml.minute>" => &[&["0", "<xml.digit>"], &["59"], &["30"]],
+        "<xml.gYear>" => &[&["<xml.year>"]],
+        "<xml.duration>" => &[&["P1Y2M3DT4H5M6S"], &["P0D"], &["PT1.5S"],
+            &["-P1D"], &["P", "<xml.digit1>", "<xml.digits>", "D"]],
+        "<xml.hexBinary>" => &[&[], &["<xml.hex>", "<xml.hex>",
+            "<xml.hexBinary>"]],
+        "<xml.base64Binary>" => &[&[], &["AA=="], &["AAA="],
+            &["<xml.base64>", "<xml.base64>", "<xml.base64>", "<xml.base64>",
+              "<xml.base64Binary>"]],
+        "<xml.anyURI>" => &[&["http://example.com/"], &["urn:fzero"],
+            &["a/b?c=d#e"], &[], &["<xml.name>"]],
+        "<xml.language>" => &[&["en"], &["en-US"], &["de"], &["x-fzero"]],
+        _ => unreachable!("Unknown built-in rule {}", name),
+    };
+    alts.iter().map(|alt| alt.iter().map(|x| x.to_string()).collect())
+        .collect()
+}
+
+/// Get the value of the XSD built-in type `name`, if it's a simple type
+fn xsd_builtin(name: &str) -> Option<Value> {
+    Some(match name {
+        "string" | "normalizedString" | "token" | "anySimpleType" => {
+            Value::Text
+        }
+        "integer" | "long" | "int" | "short" | "byte" |
+            "nonNegativeInteger" | "positiveInteger" |
+            "nonPositiveInteger" | "negativeInteger" | "unsignedLong" |
+            "unsignedInt" | "unsignedShort" | "unsignedByte" | "decimal" |
+            "boolean" | "date" | "time" | "dateTime" | "gYear" |
+            "duration" | "hexBinary" | "base64Binary" | "anyURI" |
+            "language" => Value::Builtin(format!("<xml.{}>", name)),
+        "float" | "double" => Value::Builtin("<xml.double>".into()),
+        "Name" | "NCName" | "QName" | "ID" | "IDREF" | "ENTITY" |
+            "NOTATION" => Value::Builtin("<xml.name>".into()),
+        "NMTOKEN" => Value::Builtin("<xml.nmtoken>".into()),
+        "IDREFS" | "ENTITIES" => {
+            Value::List(Box::new(Value::Builtin("<xml.name>".into())))
+        }
+        "NMTOKENS" => {
+            Value::List(Box::new(Value::Builtin("<xml.nmtoken>".into())))
+        }
+        _ => return None,
+    })
+}
+
+/// Split a DTD declaration into names, quoted strings and the punctuation
+/// of content models
+fn dtd_tokens(decl: &str) -> std::io::Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = decl.char_indices().peekable();
+    while let Some(&(start, chr)) = chars.peek() {
+        if chr.is_whitespace() {
+            chars.next();
+        } else if "()|,?*+".contains(chr) {
+            tokens.push(chr.to_string());
+            chars.next();
+        } else if chr == '"' || chr == '\'' {
+            let end = decl[start + 1..].find(chr).ok_or_else(|| {
+                invalid(format!("Unterminated string in <!{}>", decl))
+            })? + start + 1;
+            tokens.push(decl[start..=end].to_string());
+            while chars.next_if(|&(x, _)| x <= end).is_some() {}
+        } else {
+            let mut end = decl.len();
+            while let Some(&(pos, chr)) = chars.peek() {
+                if chr.is_whitespace() || "()|,?*+\"'".contains(chr) {
+                    end = pos;
+                    break;
+                }
+                chars.next();
+            }
+            tokens.push(decl[start..end].to_string());
+        }
+    }
+    Ok(tokens)
+}
+
+/// Strip the quotes from a quoted DTD token
+fn unquote(token: &str) -> Option<&str> {
+    token.strip_prefix('"').and_then(|x| x.strip_suffix('"'))
+        .or_else(|| token.strip_prefix('\'').and_then(|x| x.strip_suffix('\'')))
+}
+
+/// Replace the character and predefined entity references in `text`
+fn unescape(text: &str) -> String {
+    let mut ret = String::new();
+    let mut rest = text;
+    while let Some(pos) = rest.find('&') {
+        ret += &rest[..pos];
+        rest = &rest[pos..];
+        let Some(end) = rest.find(';') else { break };
+        let name = &rest[1..end];
+        let chr = match name {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => {
+                name.strip_prefix("#x").map(|x| u32::from_str_radix(x, 16))
+                    .or_else(|| name.strip_prefix('#').map(|x| x.parse()))
+                    .and_then(|x| x.ok()).and_then(char::from_u32)
+            }
+        };
+        match chr {
+            Some(chr) => {
+                ret.push(chr);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                ret.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    ret + rest
+}
+
+/// State used while reading a DTD
+struct Dtd {
+    /// The schema read so far
+    schema: Schema,
+
+    /// Parameter entities by name, along with their replacement text
+    entities: BTreeMap<String, String>,
+
+    /// Elements used in content models, which must all be declared
+    used: BTreeSet<String>,
+
+    /// Number of bytes parameter entities have expanded to
+    expanded: usize,
+}
+
+impl Dtd {
+    /// Replace the parameter entity references in `text`
+    fn expand(&mut self, text: &str) -> std::io::Result<String> {
+        self.expand_within(text, &mut Vec::new())
+    }
+
+    /// Replace the parameter entity references in `text`, which is the
+    /// replacement text of the entities in `stack`
+    fn expand_within(&mut self, text: &str, stack: &mut Vec<String>)
+            -> std::io::Result<String> {
+        let mut ret = String::new();
+        let mut rest = text;
+        while let Some(pos) = rest.find('%') {
+            ret += &rest[..pos];
+            rest = &rest[pos + 1..];
+
+            // A `%` followed by whitespace is the marker of a parameter
+            // entity declaration rather than a reference
+            let name_len = rest.find(';').filter(|&x| {
+                x > 0 && !rest[..x].contains(|x: char| x.is_whitespace())
+            });
+            let Some(name_len) = name_len else {
+                ret.push('%');
+                continue;
+            };
+            let name = &rest[..name_len];
+            rest = &rest[name_len + 1..];
+
+            if stack.iter().any(|x| x == name) {
+                return Err(invalid(format!(
+                    "Parameter entity %{}; refers to itself", name)));
+            }
+            let value = self.entities.get(name).cloned().ok_or_else(|| {
+                invalid(format!("Undefined parameter entity %{};", name))
+            })?;
+
+            // The replacement text may itself contain references, and is
+            // padded with spaces
+            stack.push(name.to_string());
+            let value = self.expand_within(&value, stack)?;
+            stack.pop();
+            self.expanded += value.len();
+            if self.expanded > MAX_EXPANSION {
+                return Err(invalid(format!("Parameter entities expand to \
+                    more than {} bytes", MAX_EXPANSION)));
+            }
+            ret += &format!(" {} ", value);
+        }
+        Ok(ret + rest)
+    }
+
+    /// Read the DTD in `source`
+    fn read(&mut self, source: &str) -> std::io::Result<()> {
+        let mut rest = source.to_string();
+        let mut sections = 0usize;
+        loop {
+            rest = rest.trim_start().to_string();
+            if rest.is_empty() {
+                break;
+            }
+
+            let skip_to = |rest: &str, end: &str| {
+                rest.find(end).map(|x| rest[x + end.len()..].to_string())
+                    .ok_or_else(|| invalid(format!(
+                        "Unterminated {} in DTD", &rest[..2])))
+            };
+            if rest.starts_with("<!--") {
+                rest = skip_to(&rest, "-->")?;
+            } else if rest.starts_with("<?") {
+                rest = skip_to(&rest, "?>")?;
+            } else if let Some(section) = rest.strip_prefix("<![") {
+                let open = section.find('[').ok_or_else(|| {
+                    invalid("Unterminated conditional section in DTD".into())
+                })?;
+                let keyword = self.expand(&section[..open])?;
+                match keyword.trim() {
+                    "INCLUDE" => {
+                        sections += 1;
+                        rest = section[open + 1..].to_string();
+                    }
+                    "IGNORE" => {
+                        // Skip to the matching end, ignored sections nest
+                        let mut depth = 1;
+                        let mut pos = open + 1;
+                        while depth > 0 {
+                            let next = section[pos..].find("<![");
+                            let end = section[pos..].find("]]>")
+                                .ok_or_else(|| invalid(
+                                    "Unterminated IGNORE section in DTD"
+                                    .into()))?;
+                            match next {
+                                Some(next) if next < end => {
+                                    depth += 1;
+                                    pos += next + 3;
+                                }
+                                _ => {
+                                    depth -= 1;
+                                    pos += end + 3;
+                                }
+                            }
+                        }
+                        rest = section[pos..].to_string();
+                    }
+                    keyword => {
+                        return Err(invalid(format!(
+                            "Unknown conditional section {}", keyword)));
+                    }
+                }
+            } else if let Some(after) = rest.strip_prefix("]]>") {
+                sections = sections.checked_sub(1).ok_or_else(|| {
+                    invalid("Unmatched ]]> in DTD".into())
+                })?;
+                rest = after.to_string();
+            } else if rest.starts_with('%') {
+                // A reference outside of declarations includes the entity's
+                // declarations
+                let end = rest.find(';').ok_or_else(|| {
+                    invalid("Unterminated parameter entity reference".into())
+                })?;
+                let expanded = self.expand(&rest[..=end])?;
+                rest = expanded + &rest[end + 1..];
+            } else if let Some(decl) = rest.strip_prefix("<!") {
+                // Find the end of the declaration, skipping over quoted
+                // strings which may contain `>`
+                let mut quote = None;
+                let end = decl.find(|x| {
+                    match quote {
+                        Some(q) if x == q => quote = None,
+                        Some(_) => {}
+                        None if x == '"' || x == '\'' => quote = Some(x),
+                        None => return x == '>',
+                    }
+                    false
+                }).ok_or_else(|| {
+                    invalid("Unterminated declaration in DTD".into())
+                })?;
+                let decl_text = decl[..end].to_string();
+                rest = decl[end + 1..].to_string();
+                self.declaration(&decl_text)?;
+            } else {
+                return Err(invalid(format!("Unexpected text in DTD: {}",
+                    rest.chars().take(40).collect::<String>())));
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a single declaration, without its `<!` and `>`
+    fn declaration(&mut self, decl: &str) -> std::io::Result<()> {
+        let keyword = decl.split_whitespace().next().unwrap_or("");
+        match keyword {
+            "ENTITY" => {
+                let tokens = dtd_tokens(decl)?;
+                if tokens.get(1).map(|x| x.as_str()) != Some("%") {
+                    // General entities are only used by documents
+                    return Ok(());
+                }
+                let name = tokens.get(2).ok_or_else(|| {
+                    invalid(format!("Malformed <!{}>", decl))
+                })?;
+                let value = match tokens.get(3).and_then(|x| unquote(x)) {
+                    Some(value) => unescape(value),
+                    None => {
+                        eprintln!("warning: external parameter entity %{}; \
+                            isn't loaded", name);
+                        String::new()
+                    }
+                };
+
+                // The first declaration of an entity is binding
+                self.entities.entry(name.clone()).or_insert(value);
+            }
+            "ELEMENT" => {
+                let tokens = dtd_tokens(&self.expand(decl)?)?;
+                let name = tokens.get(1).ok_or_else(|| {
+                    invalid(format!("Malformed <!{}>", decl))
+                })?.clone();
+                let content = match tokens.get(2).map(|x| x.as_str()) {
+                    Some("EMPTY") => Content::Empty,
+                    Some("ANY") => Content::Any,
+                    _ => {
+                        let mut pos = 2;
+                        let particle = self.particle(&tokens, &mut pos)?;
+                        if pos != tokens.len() {
+                            return Err(invalid(format!(
+                                "Trailing tokens in <!{}>", decl)));
+                        }
+                        Content::Children(particle)
+                    }
+                };
+
+                let element = self.schema.elements.entry(name.clone())
+                    .or_insert_with(|| Element {
+                        name: name.clone(),
+                        attributes: Vec::new(),
+                        content: Content::Empty,
+                    });
+                element.content = content;
+                if !self.schema.globals.contains(&name) {
+                    self.schema.globals.push(name);
+                }
+            }
+            "ATTLIST" => {
+                let tokens = dtd_tokens(&self.expand(decl)?)?;
+                let name = tokens.get(1).ok_or_else(|| {
+                    invalid(format!("Malformed <!{}>", decl))
+                })?.clone();
+                let mut attributes = Vec::new();
+                let mut pos = 2;
+                while pos < tokens.len() {
+                    attributes.push(Self::attribute(&tokens, &mut pos)
+                        .ok_or_else(|| {
+                            invalid(format!("Malformed <!{}>", decl))
+                        })?);
+                }
+
+                // Attributes may be declared before their element, and the
+                // first declaration of an attribute is binding
+                let element = self.schema.elements.entry(name.clone())
+                    .or_insert_with(|| Element {
+                        name,
+                        attributes: Vec::new(),
+                        content: Content::Empty,
+                    });
+                for attribute in attributes {
+                    if !element.attributes.iter()
+                            .any(|x| x.name == attribute.name) {
+                        element.attributes.push(attribute);
+                    }
+                }
+            }
+            "NOTATION" => {}
+            _ => return Err(invalid(format!("Unknown declaration <!{}>",
+                decl))),
+        }
+        Ok(())
+    }
+
+    /// Parse a content particle starting at `tokens[*pos]`, along with its
+    /// occurrence indicator
+    fn particle(&mut self, tokens: &[String], pos: &mut usize)
+            -> std::io::Result<Particle> {
+        let token = tokens.get(*pos).ok_or_else(|| {
+            invalid("Unterminated content model in DTD".into())
+        })?;
+        *pos += 1;
+
+        let particle = if token == "(" {
+            // A group, of either a sequence or a choice
+            let mut items = Vec::new();
+            let mut separator = None;
+            loop {
+                if tokens.get(*pos).map(|x| x.as_str()) == Some("#PCDATA") {
+                    *pos += 1;
+                    items.push(Particle::Text);
+                } else {
+                    items.push(self.particle(tokens, pos)?);
+                }
+                let token = tokens.get(*pos).map(|x| x.as_str());
+                *pos += 1;
+                match token {
+                    Some(")") => break,
+                    Some(sep @ ("," | "|"))
+                            if separator.is_none_or(|x| x == sep) => {
+                        separator = Some(sep);
+                    }
+                    _ => {
+                        return Err(invalid(
+                            "Malformed content model in DTD".into()));
+                    }
+                }
+            }
+            if separator == Some("|") {
+                Particle::Choice(items)
+            } else {
+                Particle::Seq(items)
+            }
+        } else if token.chars().all(|x| !"()|,?*+\"'".contains(x)) {
+            self.used.insert(token.clone());
+            Particle::Element(token.clone())
+        } else {
+            return Err(invalid(format!("Unexpected {} in content model",
+                token)));
+        };
+
+        Ok(match tokens.get(*pos).map(|x| x.as_str()) {
+            Some("?") => { *pos += 1; particle.occurs(0, Some(1)) }
+            Some("*") => { *pos += 1; particle.occurs(0, None) }
+            Some("+") => { *pos += 1; particle.occurs(1, None) }
+            _ => particle,
+        })
+    }
+
+    /// Parse an attribute definition starting at `tokens[*pos]`
+    fn attribute(tokens: &[String], pos: &mut usize) -> Option<Attribute> {
+        let name = tokens.get(*pos)?.clone();
+        let ty = tokens.get(*pos + 1)?;
+        *pos += 2;
+
+        let value = match ty.as_str() {
+            "CDATA" => Value::Text,
+            "ID" | "IDREF" | "ENTITY" => Value::Builtin("<xml.name>".into()),
+            "IDREFS" | "ENTITIES" => {
+                Value::List(Box::new(Value::Builtin("<xml.name>".into())))
+            }
+            "NMTOKEN" => Value::Builtin("<xml.nmtoken>".into()),
+            "NMTOKENS" => {
+                Value::List(Box::new(Value::Builtin("<xml.nmtoken>".into())))
+            }
+            "NOTATION" | "(" => {
+                // An enumeration, `(a | b | c)`
+                if ty == "NOTATION" {
+                    (tokens.get(*pos)?.as_str() == "(").then_some(())?;
+                    *pos += 1;
+                }
+                let mut values = Vec::new();
+                loop {
+                    values.push(tokens.get(*pos)?.clone());
+                    *pos += 2;
+                    match tokens.get(*pos - 1)?.as_str() {
+                        ")" => break,
+                        "|" => {}
+                        _ => return None,
+                    }
+                }
+                Value::Enum(values)
+            }
+            _ => return None,
+        };
+
+        let (value, required) = match tokens.get(*pos)?.as_str() {
+            "#REQUIRED" => { *pos += 1; (value, true) }
+            "#IMPLIED" => { *pos += 1; (value, false) }
+            "#FIXED" => {
+                *pos += 2;
+                let fixed = unescape(unquote(tokens.get(*pos - 1)?)?);
+                (Value::Enum(vec![fixed]), false)
+            }
+            default => {
+                unquote(default)?;
+                *pos += 1;
+                (value, false)
+            }
+        };
+        Some(Attribute { name, value, required })
+    }
+}
+
+/// An element of an XML document, as much of one as schemas need. Text is
+/// dropped.
+#[derive(Debug)]
+struct Node {
+    /// Name of the element, including its prefix
+    name: String,
+
+    /// Attributes of the element
+    attrs: BTreeMap<String, String>,
+
+    /// Child elements
+    children: Vec<Node>,
+}
+
+impl Node {
+    /// Get the name of the element without its prefix
+    fn local(&self) -> &str {
+        self.name.rsplit(':').next().unwrap()
+    }
+
+    /// Get the value of the attribute `name`
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.get(name).map(|x| x.as_str())
+    }
+
+    /// Get the child elements, other than annotations
+    fn elements(&self) -> impl Iterator<Item = &Node> {
+        self.children.iter().filter(|x| x.local() != "annotation")
+    }
+
+    /// Get the first child element named `local`, ignoring its prefix
+    fn child(&self, local: &str) -> Option<&Node> {
+        self.children.iter().find(|x| x.local() == local)
+    }
+}
+
+/// Parse the XML document in `source` into its root element
+fn parse_document(source: &str) -> std::io::Result<Node> {
+    let mut rest = source.trim_start_matches('\u{feff}');
+    let mut stack: Vec<Node> = Vec::new();
+    loop {
+        let Some(pos) = rest.find('<') else {
+            return Err(invalid("Unterminated XML document".into()));
+        };
+        rest = &rest[pos..];
+
+        fn skip_to<'a>(rest: &'a str, end: &str)
+                -> std::io::Result<&'a str> {
+            rest.find(end).map(|x| &rest[x + end.len()..]).ok_or_else(|| {
+                invalid(format!("Unterminated {} in XML document", &rest[..2]))
+            })
+        }
+        if rest.starts_with("<!--") {
+            rest = skip_to(rest, "-->")?;
+        } else if rest.starts_with("<?") {
+            rest = skip_to(rest, "?>")?;
+        } else if rest.starts_with("<![CDATA[") {
+            rest = skip_to(rest, "]]>")?;
+        } else if rest.starts_with("<!") {
+            // A document type declaration, which may have an internal
+            // subset in brackets
+            let bracket = rest.find('[');
+            let end = rest.find('>').unwrap_or(rest.len());
+            rest = match bracket {
+                Some(bracket) if bracket < end => {
+                    skip_to(&rest[bracket..], "]")?
+                }
+                _ => &rest[1..],
+            };
+            rest = skip_to(rest, ">")?;
+        } else if let Some(tail) = rest.strip_prefix("</") {
+            let end = tail.find('>').ok_or_else(|| {
+                invalid("Unterminated close tag in XML document".into())
+            })?;
+            let node = stack.pop().filter(|x| x.name == tail[..end].trim())
+                .ok_or_else(|| invalid(format!(
+                    "Mismatched close tag </{}>", tail[..end].trim())))?;
+            rest = &tail[end + 1..];
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(node),
+                None => return Ok(node),
+            }
+        } else {
+            // An open tag, with its attributes
+            let tail = &rest[1..];
+            let name_end = tail.find(|x: char| {
+                x.is_whitespace() || x == '/' || x == '>'
+            }).unwrap_or(tail.len());
+            let mut node = Node {
+                name: tail[..name_end].to_string(),
+                attrs: BTreeMap::new(),
+                children: Vec::new(),
+            };
+            rest = tail[name_end..].trim_start();
+            loop {
+                if let Some(tail) = rest.strip_prefix("/>") {
+                    rest = tail;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => return Ok(node),
+                    }
+                    break;
+                } else if let Some(tail) = rest.strip_prefix('>') {
+                    rest = tail;
+                    stack.push(node);
+                    break;
+                }
+
+                let malformed = || invalid(format!(
+                    "Malformed attributes in <{}>", node.name));
+                let (name, tail) = rest.split_once('=')
+                    .ok_or_else(malformed)?;
+                let tail = tail.trim_start();
+                let quote = tail.chars().next()
+                    .filter(|&x| x == '"' || x == '\'')
+                    .ok_or_else(malformed)?;
+                let end = tail[1..].find(quote).ok_or_else(malformed)? + 1;
+                node.attrs.insert(name.trim().to_string(),
+                    unescape(&tail[1..end]));
+                rest = tail[end + 1..].trim_start();
+            }
+        }
+    }
+}
+
+/// Apply the range and length facets of the restriction `def` to `value`
+fn facets(def: &Node, value: Value) -> Value {
+    let facet = |name: &str| {
+        def.elements().find(|x| x.local() == name)
+            .and_then(|x| x.attr("value"))
+    };
+    let integer = |name: &str| facet(name).and_then(|x| x.parse::<i128>().ok());
+    let length = |name: &str| facet(name).and_then(|x| x.parse::<usize>().ok());
+
+    match value {
+        Value::Builtin(_) | Value::Range(..) => {
+            let (mut min, mut max) = match &value {
+                Value::Builtin(name) => match integer_range(name) {
+                    Some(range) => range,
+                    None => return value,
+                },
+                Value::Range(min, max) => (*min, *max),
+                _ => unreachable!(),
+            };
+
+            // Tighten the bounds of the base type with the facets
+            let lower = integer("minInclusive")
+                .or_else(|| integer("minExclusive").map(|x| x + 1));
+            let upper = integer("maxInclusive")
+                .or_else(|| integer("maxExclusive").map(|x| x - 1));
+            if lower.is_none() && upper.is_none() {
+                return value;
+            }
+            min = min.max(lower);
+            max = match (max, upper) {
+                (Some(x), Some(y)) => Some(x.min(y)),
+                (x, y) => x.or(y),
+            };
+            Value::Range(min, max)
+        }
+        Value::Text | Value::Chars(..) => {
+            let (mut min, mut max) = match value {
+                Value::Chars(min, max) => (min, max),
+                _ => (0, None),
+            };
+            if let Some(exact) = length("length") {
+                (min, max) = (exact, Some(exact));
+            }
+            min = length("minLength").unwrap_or(min);
+            max = length("maxLength").or(max);
+            if (min, max) == (0, None) {
+                Value::Text
+            } else {
+                Value::Chars(min, max)
+            }
+        }
+        _ => value,
+    }
+}
+
+/// State used while reading an XSD
+struct Xsd<'a> {
+    /// The schema read so far
+    schema: Schema,
+
+    /// Prefixes bound to the XSD namespace, with `""` for the default
+    /// namespace
+    prefixes: BTreeSet<String>,
+
+    /// Prefix used for names in the target namespace, if there is one
+    prefix: Option<&'static str>,
+
+    /// Whether local elements and attributes are in the target namespace
+    /// by default
+    qualified: [bool; 2],
+
+    /// Top-level declarations by kind, eg. `element`, and name
+    globals: BTreeMap<(&'a str, &'a str), &'a Node>,
+
+    /// Elements which still need to be read, by key
+    queue: Vec<(String, &'a Node)>,
+}
+
+impl<'a> Xsd<'a> {
+    /// Split a qualified name into whether it's in the XSD namespace and
+    /// its local name
+    fn qname<'b>(&self, name: &'b str) -> (bool, &'b str) {
+        match name.split_once(':') {
+            Some((prefix, local)) => (self.prefixes.contains(prefix), local),
+            None => (self.prefixes.contains(""), name),
+        }
+    }
+
+    /// Look up the top-level declaration of `kind` referred to by `name`
+    fn global(&self, kind: &str, name: &str) -> std::io::Result<&'a Node> {
+        let (_, local) = self.qname(name);
+        self.globals.get(&(kind, local)).copied().ok_or_else(|| {
+            invalid(format!("Undefined {} {}", kind, name))
+        })
+    }
+
+    /// Get the name `local` is emitted with, prefixed if it's `qualified`
+    fn emitted(&self, local: &str, qualified: bool) -> String {
+        match self.prefix.filter(|_| qualified) {
+            Some(prefix) => format!("{}:{}", prefix, local),
+            None => local.to_string(),
+        }
+    }
+
+    /// Get the key of the element declared by `node` in `scope`, queueing
+    /// it to be read if it's new
+    fn element(&mut self, node: &'a Node, scope: &str)
+            -> std::io::Result<String> {
+        if let Some(reference) = node.attr("ref") {
+            let (_, local) = self.qname(reference);
+            self.global("element", reference)?;
+            return Ok(local.to_string());
+        }
+
+        let name = node.attr("name").ok_or_else(|| {
+            invalid("Element without a name or ref".into())
+        })?;
+        let key = format!("{}/{}", scope, name);
+        if !self.schema.elements.contains_key(&key) {
+            // Reserve the key until the element is read
+            self.schema.elements.insert(key.clone(), Element {
+                name: String::new(),
+                attributes: Vec::new(),
+                content: Content::Empty,
+            });
+            self.queue.push((key.clone(), node));
+        }
+        Ok(key)
+    }
+
+    /// Read the element declared by `node`, whose key is `key`
+    fn read_element(&mut self, key: &str, node: &'a Node, global: bool)
+            -> std::io::Result<Element> {
+        let local = node.attr("name").unwrap_or_default();
+        let qualified = global || match node.attr("form") {
+            Some(form) => form == "qualified",
+            None => self.qualified[0],
+        };
+        let mut element = Element {
+            name: self.emitted(local, qualified),
+            attributes: Vec::new(),
+            content: Content::Any,
+        };
+
+        if let Some(ty) = node.attr("type") {
+            let (builtin, name) = self.qname(ty);
+            if builtin && name == "anyType" {
+                element.content = Content::Any;
+            } else if let Some(value) = builtin.then(|| xsd_builtin(name))
+                    .flatten() {
+                element.content = Content::Simple(value);
+            } else {
+                let def = self.global("type", ty)?;
+                if def.local() == "complexType" {
+                    let scope = format!("type.{}", name);
+                    (element.attributes, element.content) =
+                        self.complex_type(def, &scope, 0)?;
+                } else {
+                    element.content = Content::Simple(
+                        self.simple_type(def, 0)?);
+                }
+            }
+        } else if let Some(def) = node.child("complexType") {
+            (element.attributes, element.content) =
+                self.complex_type(def, key, 0)?;
+        } else if let Some(def) = node.child("simpleType") {
+            element.content = Content::Simple(self.simple_type(def, 0)?);
+        }
+
+        if let Some(fixed) = node.attr("fixed") {
+            if matches!(element.content, Content::Simple(_)) {
+                element.content =
+                    Content::Simple(Value::Enum(vec![fixed.into()]));
+            }
+        }
+        Ok(element)
+    }
+
+    /// Get the value of the simple type named `name`
+    fn type_value(&mut self, name: &str, depth: usize)
+            -> std::io::Result<Value> {
+        let (builtin, local) = self.qname(name);
+        if builtin {
+            return Ok(xsd_builtin(local).unwrap_or_else(|| {
+                eprintln!("warning: built-in type {} isn't supported, it's \
+                    generated as text", name);
+                Value::Text
+            }));
+        }
+
+        let def = self.global("type", name)?;
+        if def.local() == "complexType" {
+            // Only complex types with simple content have a value
+            let scope = format!("type.{}", local);
+            return match self.complex_type(def, &scope, depth + 1)?.1 {
+                Content::Simple(value) => Ok(value),
+                _ => Err(invalid(format!(
+                    "Type {} is used for a value, but it isn't simple",
+                    name))),
+            };
+        }
+        self.simple_type(def, depth + 1)
+    }
+
+    /// Get the value of the simple type defined by `node`
+    fn simple_type(&mut self, node: &'a Node, depth: usize)
+            -> std::io::Result<Value> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("Type derivations are nested too deeply, \
+                there's probably a cycle".into()));
+        }
+
+        // The type is either a derivation given directly, or a type which
+        // is itself a simple type
+        let Some(def) = node.elements().find(|x| {
+            matches!(x.local(), "restriction" | "list" | "union")
+        }) else {
+            return Ok(Value::Text);
+        };
+        let inline = def.child("simpleType");
+        match def.local() {
+            "restriction" | "extension" => {
+                let values: Vec<String> = def.elements()
+                    .filter(|x| x.local() == "enumeration")
+                    .filter_map(|x| x.attr("value").map(String::from))
+                    .collect();
+                if !values.is_empty() {
+                    return Ok(Value::Enum(values));
+                }
+                let base = match (def.attr("base"), inline) {
+                    (Some(base), _) => self.type_value(base, depth)?,
+                    (None, Some(inline)) => {
+                        self.simple_type(inline, depth + 1)?
+                    }
+                    (None, None) => Value::Text,
+                };
+                Ok(facets(def, base))
+            }
+            "list" => {
+                let item = match (def.attr("itemType"), inline) {
+                    (Some(item), _) => self.type_value(item, depth)?,
+                    (None, Some(inline)) => {
+                        self.simple_type(inline, depth + 1)?
+                    }
+                    (None, None) => Value::Text,
+                };
+                Ok(Value::List(Box::new(item)))
+            }
+            _ => {
+                let mut members = Vec::new();
+                for member in def.attr("memberTypes").unwrap_or_default()
+                        .split_whitespace() {
+                    members.push(self.type_value(member, depth)?);
+                }
+                for inline in def.elements()
+                        .filter(|x| x.local() == "simpleType") {
+                    members.push(self.simple_type(inline, depth + 1)?);
+                }
+                Ok(Value::Union(members))
+            }
+        }
+    }
+
+    /// Get the attributes and content of the complex type defined by
+    /// `node`. Local elements are keyed within `scope`.
+    fn complex_type(&mut self, node: &'a Node, scope: &str, depth: usize)
+            -> std::io::Result<(Vec<Attribute>, Content)> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("Type derivations are nested too deeply, \
+                there's probably a cycle".into()));
+        }
+
+        let mixed = node.attr("mixed") == Some("true");
+        let mut attributes = Vec::new();
+        let mut particle = None;
+        let mut content = None;
+        for child in node.elements() {
+            match child.local() {
+                "sequence" | "choice" | "all" | "group" => {
+                    particle = Some(self.particle(child, scope, depth)?);
+                }
+                "attribute" | "attributeGroup" => {
+                    self.attributes(child, &mut attributes, depth)?;
+                }
+                "simpleContent" | "complexContent" => {
+                    let def = child.elements().find(|x| {
+                        matches!(x.local(), "extension" | "restriction")
+                    }).ok_or_else(|| invalid(format!(
+                        "{} without an extension or restriction",
+                        child.local())))?;
+                    content = Some(self.derivation(child.local(), def, scope,
+                        mixed || child.attr("mixed") == Some("true"),
+                        &mut attributes, depth)?);
+                }
+                _ => {}
+            }
+        }
+
+        let content = content.unwrap_or_else(|| match particle {
+            Some(particle) if mixed => {
+                Content::Children(Particle::Seq(vec![Particle::Text,
+                    particle.mixed()]))
+            }
+            Some(particle) => Content::Children(particle),
+            None if mixed => Content::Simple(Value::Text),
+            None => Content::Empty,
+        });
+        Ok((attributes, content))
+    }
+
+    /// Get the content of a type derived from another in a
+    /// `simpleContent` or `complexContent`, adding its attributes to
+    /// `attributes`
+    fn derivation(&mut self, kind: &str, def: &'a Node, scope: &str,
+            mixed: bool, attributes: &mut Vec<Attribute>, depth: usize)
+            -> std::io::Result<Content> {
+        let base = def.attr("base").ok_or_else(|| {
+            invalid(format!("{} without a base", def.local()))
+        })?;
+        let (builtin, local) = self.qname(base);
+
+        // Start with the base type's content, apart from restrictions of
+        // complex content which restate it
+        let mut own = Vec::new();
+        for child in def.elements() {
+            if matches!(child.local(), "attribute" | "attributeGroup") {
+                self.attributes(child, &mut own, depth)?;
+            }
+        }
+        let mut content = Content::Empty;
+        if !builtin {
+            let base_def = self.global("type", base)?;
+            if base_def.local() == "complexType" {
+                let (base_attributes, base_content) = self.complex_type(
+                    base_def, &format!("type.{}", local), depth + 1)?;
+                if kind == "simpleContent" || def.local() == "extension" {
+                    content = base_content;
+                }
+                attributes.extend(base_attributes.into_iter()
+                    .filter(|x| !own.iter().any(|y| y.name == x.name)));
+            } else {
+                content = Content::Simple(self.simple_type(base_def,
+                    depth + 1)?);
+            }
+        } else if kind == "simpleContent" {
+            content = Content::Simple(self.type_value(base, depth)?);
+        }
+        attributes.extend(own);
+
+        if kind == "simpleContent" {
+            // Enumerations restrict the base type's value
+            let values: Vec<String> = def.elements()
+                .filter(|x| x.local() == "enumeration")
+                .filter_map(|x| x.attr("value").map(String::from)).collect();
+            if !values.is_empty() {
+                content = Content::Simple(Value::Enum(values));
+            } else if let Content::Simple(value) = content {
+                content = Content::Simple(facets(def, value));
+            }
+            return Ok(content);
+        }
+
+        // Extensions append their particle to the base type's
+        let Some(particle) = def.elements().find(|x| {
+            matches!(x.local(), "sequence" | "choice" | "all" | "group")
+        }) else {
+            return Ok(content);
+        };
+        let mut particle = self.particle(particle, scope, depth)?;
+        if mixed {
+            particle = Particle::Seq(vec![Particle::Text, particle.mixed()]);
+        }
+        Ok(match content {
+            Content::Children(base) => {
+                Content::Children(Particle::Seq(vec![base, particle]))
+            }
+            _ => Content::Children(particle),
+        })
+    }
+
+    /// Add the attributes declared by the `attribute` or `attributeGroup`
+    /// element `node` to `attributes`
+    fn attributes(&mut self, node: &'a Node, attributes: &mut Vec<Attribute>,
+            depth: usize) -> std::io::Result<()> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("Attribute groups are nested too deeply, \
+                there's probably a cycle".into()));
+        }
+
+        if node.local() == "attributeGroup" {
+            let group = match node.attr("ref") {
+                Some(reference) => self.global("attributeGroup", reference)?,
+                None => node,
+            };
+            for child in group.elements() {
+                if matches!(child.local(), "attribute" | "attributeGroup") {
+                    self.attributes(child, attributes, depth + 1)?;
+                }
+            }
+            return Ok(());
+        }
+
+        let usage = node.attr("use").unwrap_or("optional");
+        if usage == "prohibited" {
+            return Ok(());
+        }
+
+        // References are to top-level attributes, which are qualified
+        let (def, qualified) = match node.attr("ref") {
+            Some(reference) => (self.global("attribute", reference)?, true),
+            None => (node, match node.attr("form") {
+                Some(form) => form == "qualified",
+                None => self.qualified[1],
+            }),
+        };
+        let local = def.attr("name").ok_or_else(|| {
+            invalid("Attribute without a name or ref".into())
+        })?;
+
+        let fixed = node.attr("fixed").or_else(|| def.attr("fixed"));
+        let value = match (fixed, def.attr("type"), def.child("simpleType")) {
+            (Some(fixed), _, _) => Value::Enum(vec![fixed.into()]),
+            (None, Some(ty), _) => self.type_value(ty, depth)?,
+            (None, None, Some(inline)) => self.simple_type(inline, depth)?,
+            (None, None, None) => Value::Text,
+        };
+        let name = self.emitted(local, qualified);
+        if !attributes.iter().any(|x| x.name == name) {
+            attributes.push(Attribute {
+                name,
+                value,
+                required: usage == "required",
+            });
+        }
+        Ok(())
+    }
+
+    /// Get the particle of a `sequence`, `choice`, `all`, `group`,
+    /// `element` or `any`
+    fn particle(&mut self, node: &'a Node, scope: &str, depth: usize)
+            -> std::io::Result<Particle> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("Groups are nested too deeply, there's \
+                probably a cycle".into()));
+        }
+
+        let min = match node.attr("minOccurs") {
+            Some(min) => min.parse().map_err(|_| {
+                invalid(format!("Invalid minOccurs {}", min))
+            })?,
+            None => 1,
+        };
+        let max = match node.attr("maxOccurs") {
+            Some("unbounded") => None,
+            Some(max) => Some(max.parse().map_err(|_| {
+                invalid(format!("Invalid maxOccurs {}", max))
+            })?),
+            None => Some(1),
+        };
+
+        let particle = match node.local() {
+            "element" => Particle::Element(self.element(node, scope)?),
+            "group" => {
+                let group = match node.attr("ref") {
+                    Some(reference) => self.global("group", reference)?,
+                    None => node,
+                };
+                let model = group.elements().find(|x| {
+                    matches!(x.local(), "sequence" | "choice" | "all")
+                }).ok_or_else(|| invalid("Empty group".into()))?;
+                self.particle(model, scope, depth + 1)?
+            }
+            "any" => {
+                Particle::Choice(self.globals.iter()
+                    .filter(|((kind, _), _)| *kind == "element")
+                    .map(|((_, name), _)| Particle::Element(name.to_string()))
+                    .collect())
+            }
+            kind => {
+                // Elements of an `all` may occur in any order, declaration
+                // order is one of them
+                let mut items = Vec::new();
+                for child in node.elements() {
+                    if matches!(child.local(), "element" | "group" | "choice" |
+                            "sequence" | "any") {
+                        items.push(self.particle(child, scope, depth + 1)?);
+                    }
+                }
+                if kind == "choice" {
+                    Particle::Choice(items)
+                } else {
+                    Particle::Seq(items)
+                }
+            }
+        };
+        Ok(particle.occurs(min, max))
+    }
+}
+
+/// Get `tokens` repeated `count` times
+fn repeat(tokens: &[String], count: usize) -> Vec<String> {
+    std::iter::repeat_n(tokens, count).flatten().cloned().collect()
+}
+
+/// State used while generating a grammar from a schema
+struct Generator<'a> {
+    /// The schema to generate documents of
+    schema: &'a Schema,
+
+    /// The grammar generated so far
+    grammar: Grammar,
+
+    /// Number of helper rules created for content models, used to name
+    /// them uniquely
+    helpers: usize,
+}
+
+impl Generator<'_> {
+    /// Get the token for a value, creating rules named after `rule` for it
+    /// as needed
+    fn value(&mut self, rule: &str, value: &Value) -> String {
+        match value {
+            Value::Text => "<xml.text>".into(),
+            Value::Builtin(name) => name.clone(),
+            Value::Range(min, max) => {
+                self.grammar.0.insert(rule.into(), integers(*min, *max));
+                rule.into()
+            }
+            Value::Chars(min, max) => {
+                let mut alt = vec!["<xml.char>".to_string(); *min];
+                match max {
+                    None => alt.push("<xml.text>".into()),
+                    Some(max) if max > min => {
+                        let more = format!("{}:more>", &rule[..rule.len() - 1]);
+                        let optional = (max - min).min(MAX_OPTIONAL);
+                        let alts = (0..=optional).map(|x| {
+                            vec!["<xml.char>".to_string(); x]
+                        }).collect();
+                        self.grammar.0.insert(more.clone(), alts);
+                        alt.push(more);
+                    }
+                    Some(_) => {}
+                }
+                self.grammar.0.insert(rule.into(), vec![alt]);
+                rule.into()
+            }
+            Value::Enum(values) => {
+                let alts = values.iter().map(|x| {
+                    if x.is_empty() { Vec::new() } else { vec![escape(x)] }
+                }).collect();
+                self.grammar.0.insert(rule.into(), alts);
+                rule.into()
+            }
+            Value::List(item) => {
+                let item_rule = format!("{}:item>", &rule[..rule.len() - 1]);
+                let item = self.value(&item_rule, item);
+                self.grammar.0.insert(rule.into(), vec![vec![item.clone()],
+                    vec![item, " ".into(), rule.into()]]);
+                rule.into()
+            }
+            Value::Union(members) => {
+                let alts = members.iter().enumerate().map(|(ii, x)| {
+                    let member = format!("{}:{}>", &rule[..rule.len() - 1], ii);
+                    vec![self.value(&member, x)]
+                }).collect();
+                self.grammar.0.insert(rule.into(), alts);
+                rule.into()
+            }
+        }
+    }
+
+    /// Get the tokens for a particle of the content of the element `key`
+    fn particle(&mut self, key: &str, particle: &Particle) -> Vec<String> {
+        let helper = |gen: &mut Self, alts| {
+            gen.helpers += 1;
+            let name = format!("<content.{}:{}>", key, gen.helpers);
+            gen.grammar.0.insert(name.clone(), alts);
+            name
+        };
+        match particle {
+            Particle::Element(key) => vec![format!("<element.{}>", key)],
+            Particle::Text => vec!["<xml.text>".into()],
+            Particle::Seq(items) => {
+                items.iter().flat_map(|x| self.particle(key, x)).collect()
+            }
+            Particle::Choice(items) => {
+                let alts = items.iter().map(|x| self.particle(key, x))
+                    .collect();
+                vec![helper(self, alts)]
+            }
+            Particle::Repeat(item, min, max) => {
+                let tokens = self.particle(key, item);
+                let mut ret = repeat(&tokens, *min);
+                match max {
+                    None => {
+                        // Any number of repetitions, with a recursive rule
+                        // whose name is only known once it's created
+                        let name = helper(self, Vec::new());
+                        let mut alt = tokens;
+                        alt.push(name.clone());
+                        self.grammar.0.insert(name.clone(),
+                            vec![Vec::new(), alt]);
+                        ret.push(name);
+                    }
+                    Some(max) if max > min => {
+                        let optional = (max - min).min(MAX_OPTIONAL);
+                        let alts = (0..=optional).map(|x| repeat(&tokens, x))
+                            .collect();
+                        ret.push(helper(self, alts));
+                    }
+                    Some(_) => {}
+                }
+                ret
+            }
+        }
+    }
+
+    /// Get the alternatives for the element `key`, with `extra` text in its
+    /// open tag
+    fn element(&mut self, key: &str, extra: &[String])
+            -> std::io::Result<Vec<Vec<String>>> {
+        let element = self.schema.elements.get(key).ok_or_else(|| {
+            invalid(format!("Element {} isn't declared", key))
+        })?;
+
+        let mut open = vec![format!("<{}", element.name)];
+        open.extend_from_slice(extra);
+        for attribute in &element.attributes {
+            let rule = format!("<attr.{}.{}>", key, attribute.name);
+            let value = self.value(&format!("<value.{}.{}>", key,
+                attribute.name), &attribute.value);
+            self.grammar.0.insert(rule.clone(), vec![vec![
+                format!(" {}=\"", attribute.name), value, "\"".into()]]);
+            if attribute.required {
+                open.push(rule);
+            } else {
+                let optional = format!("<attr.{}.{}:opt>", key,
+                    attribute.name);
+                self.grammar.0.insert(optional.clone(),
+                    vec![Vec::new(), vec![rule]]);
+                open.push(optional);
+            }
+        }
+
+        let close = [format!("</{}", element.name), ">".into()];
+        let content = format!("<content.{}>", key);
+        let content_alts = match &element.content {
+            Content::Empty => {
+                let mut short = open.clone();
+                short.push("/>".into());
+                let mut long = open;
+                long.push(">".into());
+                long.extend(close);
+                return Ok(vec![short, long]);
+            }
+            Content::Any => {
+                let mut alts = vec![Vec::new(),
+                    vec!["<xml.text>".into(), content.clone()]];
+                alts.extend(self.schema.globals.iter().map(|x| {
+                    vec![format!("<element.{}>", x), content.clone()]
+                }));
+                alts
+            }
+            Content::Simple(value) => {
+                vec![vec![self.value(&format!("<value.{}>", key), value)]]
+            }
+            Content::Children(particle) => {
+                vec![self.particle(key, particle)]
+            }
+        };
+        self.grammar.0.insert(content.clone(), content_alts);
+
+        open.push(">".into());
+        open.push(content);
+        open.extend(close);
+        Ok(vec![open])
+    }
+}
+
+impl Schema {
+    /// Read the DTD in `source`
+    pub fn from_dtd(source: &str) -> std::io::Result<Self> {
+        let mut dtd = Dtd {
+            schema: Schema::default(),
+            entities: BTreeMap::new(),
+            used: BTreeSet::new(),
+            expanded: 0,
+        };
+        dtd.read(source)?;
+
+        // Elements which are used but not declared are invalid wherever
+        // they appear, they're generated empty so documents are at least
+        // well-formed
+        for name in &dtd.used {
+            if !dtd.schema.elements.contains_key(name) {
+                eprintln!("warning: element {} is used but not declared",
+                    name);
+                dtd.schema.elements.insert(name.clone(), Element {
+                    name: name.clone(),
+                    attributes: Vec::new(),
+                    content: Content::Empty,
+                });
+            }
+        }
+        Ok(dtd.schema)
+    }
+
+    /// Read the XSD in `source`
+    pub fn from_xsd(source: &str) -> std::io::Result<Self> {
+        let root = parse_document(source)?;
+        if root.local() != "schema" {
+            return Err(invalid(format!("Expected an XSD schema, found <{}>",
+                root.name)));
+        }
+
+        let mut xsd = Xsd {
+            schema: Schema::default(),
+            prefixes: root.attrs.iter().filter(|(_, x)| *x == XSD_NAMESPACE)
+                .map(|(name, _)| {
+                    name.strip_prefix("xmlns").unwrap_or(name)
+                        .trim_start_matches(':').to_string()
+                }).collect(),
+            prefix: None,
+            qualified: [root.attr("elementFormDefault") == Some("qualified"),
+                root.attr("attributeFormDefault") == Some("qualified")],
+            globals: BTreeMap::new(),
+            queue: Vec::new(),
+        };
+
+        // Names in the target namespace are emitted with a prefix, which is
+        // declared on the root element
+        if let Some(namespace) = root.attr("targetNamespace") {
+            xsd.prefix = Some("fz");
+            xsd.schema.namespace = Some(format!(" xmlns:fz=\"{}\"",
+                escape(namespace)));
+        }
+
+        for child in root.elements() {
+            let kind = match child.local() {
+                "simpleType" | "complexType" => "type",
+                kind @ ("element" | "attribute" | "group" |
+                    "attributeGroup") => kind,
+                kind @ ("include" | "import" | "redefine" | "override") => {
+                    eprintln!("warning: {} of {} isn't supported", kind,
+                        child.attr("schemaLocation").unwrap_or("a schema"));
+                    continue;
+                }
+                _ => continue,
+            };
+            let name = child.attr("name").ok_or_else(|| {
+                invalid(format!("Top-level {} without a name", kind))
+            })?;
+            if xsd.globals.insert((kind, name), child).is_some() {
+                return Err(invalid(format!("{} {} is declared more than \
+                    once", kind, name)));
+            }
+            if kind == "element" {
+                xsd.schema.globals.push(name.to_string());
+            }
+        }
+
+        // Read the top-level elements, and the local elements they use
+        for key in xsd.schema.globals.clone() {
+            let node = xsd.globals[&("element", key.as_str())];
+            let element = xsd.read_element(&key, node, true)?;
+            xsd.schema.elements.insert(key, element);
+        }
+        while let Some((key, node)) = xsd.queue.pop() {
+            let element = xsd.read_element(&key, node, false)?;
+            xsd.schema.elements.insert(key, element);
+        }
+        Ok(xsd.schema)
+    }
+
+    /// Generate a grammar for documents whose root is the element `root`,
+    /// or the first declared element which isn't used by any other if it's
+    /// `None`
+    pub fn grammar(&self, root: Option<&str>) -> std::io::Result<Grammar> {
+        let root = match root {
+            Some(root) => {
+                self.globals.iter().find(|x| *x == root).ok_or_else(|| {
+                    std::io::Error::other(format!(
+                        "Element {} is not declared", root))
+                })?
+            }
+            None => {
+                let mut used = BTreeSet::new();
+                for element in self.elements.values() {
+                    if let Content::Children(particle) = &element.content {
+                        particle.elements(&mut used);
+                    }
+                }
+                self.globals.iter().find(|x| !used.contains(x.as_str()))
+                    .or_else(|| self.globals.first()).ok_or_else(|| {
+                        std::io::Error::other(
+                            "The schema declares no elements")
+                    })?
+            }
+        };
+
+        let mut gen = Generator {
+            schema: self,
+            grammar: Grammar::default(),
+            helpers: 0,
+        };
+        for key in self.elements.keys() {
+            let alts = gen.element(key, &[])?;
+            gen.grammar.0.insert(format!("<element.{}>", key), alts);
+        }
+
+        // The root element also declares the namespace
+        let extra: Vec<String> = self.namespace.iter().cloned().collect();
+        let start = gen.element(root, &extra)?.into_iter().map(|mut alt| {
+            alt.insert(0, "<?xml version=\"1.0\"?>\n".into());
+            alt
+        }).collect();
+        gen.grammar.0.insert("<start>".into(), start);
+
+        // Add the built-in rules which are used, and the ones they use
+        let mut queue: Vec<String> = gen.grammar.0.values().flatten()
+            .flatten().filter(|x| x.starts_with("<xml.")).cloned().collect();
+        while let Some(name) = queue.pop() {
+            if let Entry::Vacant(entry) = gen.grammar.0.entry(name) {
+                let alts = builtin_rule(entry.key());
+                queue.extend(alts.iter().flatten()
+                    .filter(|x| x.starts_with("<xml.")).cloned());
+                entry.insert(alts);
+            }
+        }
+
+        // Drop the elements which can't be reached from the root
+        let mut reachable = BTreeSet::new();
+        let mut queue = vec!["<start>".to_string()];
+        while let Some(rule) = queue.pop() {
+            if reachable.insert(rule.clone()) {
+                queue.extend(gen.grammar.0[&rule].iter().flatten()
+                    .filter(|x| gen.grammar.0.contains_key(*x)).cloned());
+            }
+        }
+        gen.grammar.0.retain(|name, _| reachable.contains(name));
+        Ok(gen.grammar)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grammar::{ConvertOptions, GrammarRust};
+    use crate::grammar::tests::{generate, options};
+    use super::*;
+
+    /// Generate `count` documents from `schema` and parse them
+    fn documents(schema: &Schema, count: usize) -> Vec<Node> {
+        let grammar = schema.grammar(None).unwrap();
+        let grammar = GrammarRust::new(&grammar, &ConvertOptions::default())
+            .unwrap();
+        generate(&grammar, &options(64, 64), count).iter().map(|x| {
+            parse_document(std::str::from_utf8(x).unwrap()).unwrap()
+        }).collect()
+    }
+
+    /// Get the names of the children of `node`
+    fn names(node: &Node) -> Vec<&str> {
+        node.children.iter().map(|x| x.name.as_str()).collect()
+    }
+
+    const DTD: &str = r#"
+        <!ENTITY % common "id ID #IMPLIED">
+        <!ELEMENT list (item+, note?)>
+        <!ELEMENT item (#PCDATA)>
+        <!ATTLIST item %common; kind (a|b) #REQUIRED>
+        <!ELEMENT note EMPTY>
+    "#;
+
+    #[test]
+    fn dtd_rules() {
+        let grammar = Schema::from_dtd(DTD).unwrap().grammar(None).unwrap();
+        let rule = |name: &str| grammar.0[name].clone();
+
+        // The root is the element no other uses
+        assert_eq!(rule("<start>"), [["<?xml version=\"1.0\"?>\n", "<list",
+            ">", "<content.list>", "</list", ">"]]);
+
+        // Parameter entities are expanded, and only required attributes
+        // are always present
+        assert_eq!(rule("<value.item.kind>"), [["a"], ["b"]]);
+        assert_eq!(rule("<attr.item.kind>"),
+            [[" kind=\"", "<value.item.kind>", "\""]]);
+        assert_eq!(rule("<attr.item.id:opt>"),
+            [vec![], vec!["<attr.item.id>"]]);
+        assert_eq!(rule("<element.item>")[0][..3],
+            ["<item", "<attr.item.id:opt>", "<attr.item.kind>"]);
+
+        // Empty elements may close themselves
+        assert_eq!(rule("<element.note>"),
+            [vec!["<note", "/>"], vec!["<note", ">", "</note", ">"]]);
+    }
+
+    #[test]
+    fn dtd_documents_conform() {
+        let schema = Schema::from_dtd(DTD).unwrap();
+        let documents = documents(&schema, 500);
+        for list in &documents {
+            assert_eq!(list.name, "list");
+            let names = names(list);
+            let items = names.iter().take_while(|x| **x == "item").count();
+            assert!(items >= 1);
+            assert!(names[items..].is_empty() || names[items..] == ["note"]);
+            for item in &list.children[..items] {
+                assert!(matches!(item.attr("kind"), Some("a" | "b")));
+            }
+        }
+
+        // Items repeat, and the note is optional
+        assert!(documents.iter().any(|x| x.children.len() > 2));
+        assert!(documents.iter().any(|x| names(x).contains(&"note")));
+    }
+
+    const XSD: &str = r#"<?xml version="1.0"?>
+        <xs:schema xmlns:xs="http://www.w3.org/2001/XMLSchema">
+          <xs:element name="order">
+            <xs:complexType>
+              <xs:sequence>
+                <xs:element name="line" maxOccurs="3">
+                  <xs:complexType>
+                    <xs:attribute name="qty" type="xs:unsignedByte"
+                        use="required"/>
+                  </xs:complexType>
+                </xs:element>
+                <xs:choice>
+                  <xs:element name="paid" type="xs:boolean"/>
+                  <xs:element name="due" type="xs:date"/>
+                </xs:choice>
+              </xs:sequence>
+              <xs:attribute name="status">
+                <xs:simpleType>
+                  <xs:restriction base="xs:string">
+                    <xs:enumeration value="new"/>
+                    <xs:enumeration value="done"/>
+                  </xs:restriction>
+                </xs:simpleType>
+              </xs:attribute>
+            </xs:complexType>
+          </xs:element>
+        </xs:schema>
+    "#;
+
+    #[test]
+    fn xsd_documents_conform() {
+        let schema = Schema::from_xsd(XSD).unwrap();
+        let documents = documents(&schema, 500);
+        for order in &documents {
+            assert_eq!(order.name, "order");
+            assert!(matches!(order.attr("status"), None | Some("new" |
+                "done")));
+
+            // One to three lines, then whether it's paid or when it's due
+            let names = names(order);
+            let lines = names.len() - 1;
+            assert!((1..=3).contains(&lines));
+            assert!(names[..lines].iter().all(|x| *x == "line"));
+            assert!(["paid", "due"].contains(&names[lines]));
+            for line in &order.children[..lines] {
+                assert!(line.attr("qty").unwrap().parse::<u8>().is_ok());
+            }
+        }
+
+        // The optional attribute and every number of lines show up
+        assert!(documents.iter().any(|x| x.attr("status").is_some()));
+        for lines in 1..=3 {
+            assert!(documents.iter().any(|x| x.children.len() == lines + 1));
+        }
+    }
+
+    #[test]
+    fn xml_errors() {
+        let schema = Schema::from_dtd(DTD).unwrap();
+        assert_eq!(schema.grammar(Some("missing")).unwrap_err().to_string(),
+            "Element missing is not declared");
+        assert_eq!(Schema::from_xsd("<root/>").err().unwrap().to_string(),
+            "Expected an XSD schema, found <root>");
+    }
+}